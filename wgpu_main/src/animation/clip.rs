@@ -0,0 +1,475 @@
+use crate::scene::Transform;
+use cgmath::{InnerSpace, Quaternion, Vector3, VectorSpace};
+
+/// How values between two keyframes are computed. These map one to one onto the interpolation modes
+/// of glTF animation samplers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Interpolation {
+    /// The value of the previous keyframe is held until the next one.
+    Step,
+    /// Translations and scales are lerped, rotations are slerped.
+    Linear,
+    /// Every keyframe stores an in-tangent, a value and an out-tangent, and the curve between two
+    /// keyframes is a cubic Hermite spline.
+    CubicSpline,
+}
+
+impl Interpolation {
+    /// How many values each keyframe takes up (per morph target for weight channels).
+    pub(crate) fn stride(self) -> usize {
+        match self {
+            Interpolation::CubicSpline => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// The keyframe values of a channel. For `CubicSpline` channels each keyframe takes up three
+/// consecutive entries: the in-tangent, the value and the out-tangent.
+pub(crate) enum ChannelValues {
+    Translations(Vec<Vector3<f32>>),
+    Rotations(Vec<Quaternion<f32>>),
+    Scales(Vec<Vector3<f32>>),
+    /// One weight per morph target per keyframe (per entry for `CubicSpline`), keyframe by keyframe.
+    MorphWeights(Vec<f32>),
+}
+
+impl ChannelValues {
+    /// The number of values, counting each morph target weight separately.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            ChannelValues::Translations(v) | ChannelValues::Scales(v) => v.len(),
+            ChannelValues::Rotations(r) => r.len(),
+            ChannelValues::MorphWeights(w) => w.len(),
+        }
+    }
+}
+
+/// What a channel animates.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ChannelTarget {
+    /// The transform of the joint at this index in the [super::Skeleton].
+    Joint(usize),
+    /// The morph target weights of the mesh at this index in [crate::model::Model::meshes].
+    MorphWeights(usize),
+}
+
+/// A channel animates a single property of a single joint, or the morph weights of a single mesh.
+pub(crate) struct Channel {
+    pub(crate) target: ChannelTarget,
+    pub(crate) interpolation: Interpolation,
+    pub(crate) times: Vec<f32>,
+    pub(crate) values: ChannelValues,
+}
+
+/// Finds the keyframes surrounding `time` and how far between them we are, or `None` if there
+/// are no keyframes at all.
+fn find_keyframes(times: &[f32], time: f32) -> Option<(usize, usize, f32, f32)> {
+    let last = times.len().checked_sub(1)?;
+    if time <= times[0] {
+        return Some((0, 0, 0.0, 0.0));
+    }
+    if time >= times[last] {
+        return Some((last, last, 0.0, 0.0));
+    }
+
+    let next = times.partition_point(|&t| t <= time);
+    let prev = next - 1;
+    let dt = times[next] - times[prev];
+    Some((prev, next, (time - times[prev]) / dt, dt))
+}
+
+/// Evaluates a cubic Hermite spline between `v0` and `v1` with the (already scaled) tangents `m0`
+/// and `m1`.
+fn hermite<V: VectorSpace<Scalar = f32>>(v0: V, m0: V, v1: V, m1: V, t: f32) -> V {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    v0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + m0 * (t3 - 2.0 * t2 + t)
+        + v1 * (-2.0 * t3 + 3.0 * t2)
+        + m1 * (t3 - t2)
+}
+
+impl Channel {
+    /// Whether `values` holds a full set of values for every keyframe. The loader already skips
+    /// channels that don't, this only keeps sampling from indexing out of bounds.
+    fn has_values(&self, count: usize) -> bool {
+        count >= self.times.len() * self.interpolation.stride()
+    }
+
+    fn sample_vector(&self, values: &[Vector3<f32>], time: f32) -> Option<Vector3<f32>> {
+        if !self.has_values(values.len()) {
+            return None;
+        }
+        let (prev, next, t, dt) = find_keyframes(&self.times, time)?;
+        Some(match self.interpolation {
+            Interpolation::Step => values[prev],
+            Interpolation::Linear => values[prev].lerp(values[next], t),
+            Interpolation::CubicSpline if prev == next => values[prev * 3 + 1],
+            Interpolation::CubicSpline => hermite(
+                values[prev * 3 + 1],
+                values[prev * 3 + 2] * dt,
+                values[next * 3 + 1],
+                values[next * 3] * dt,
+                t,
+            ),
+        })
+    }
+
+    fn sample_rotation(&self, values: &[Quaternion<f32>], time: f32) -> Option<Quaternion<f32>> {
+        if !self.has_values(values.len()) {
+            return None;
+        }
+        let (prev, next, t, dt) = find_keyframes(&self.times, time)?;
+        Some(match self.interpolation {
+            Interpolation::Step => values[prev],
+            Interpolation::Linear => values[prev].slerp(values[next], t),
+            Interpolation::CubicSpline if prev == next => values[prev * 3 + 1],
+            Interpolation::CubicSpline => hermite(
+                values[prev * 3 + 1],
+                values[prev * 3 + 2] * dt,
+                values[next * 3 + 1],
+                values[next * 3] * dt,
+                t,
+            )
+            .normalize(),
+        })
+    }
+
+    fn sample_weights(&self, values: &[f32], time: f32, weights: &mut [f32]) {
+        let Some((prev, next, t, dt)) = find_keyframes(&self.times, time) else {
+            return;
+        };
+        let n = weights.len();
+        let stride = self.interpolation.stride();
+        // Keyframes with fewer weights than the mesh has targets are treated as missing data.
+        if !self.has_values(values.len() / n.max(1)) {
+            return;
+        }
+        let key = |keyframe: usize, entry: usize, target: usize| {
+            values[(keyframe * stride + entry) * n + target]
+        };
+
+        for (target, weight) in weights.iter_mut().enumerate() {
+            *weight = match self.interpolation {
+                Interpolation::Step => key(prev, 0, target),
+                Interpolation::Linear => {
+                    let a = key(prev, 0, target);
+                    a + (key(next, 0, target) - a) * t
+                }
+                Interpolation::CubicSpline if prev == next => key(prev, 1, target),
+                Interpolation::CubicSpline => {
+                    let t2 = t * t;
+                    let t3 = t2 * t;
+                    key(prev, 1, target) * (2.0 * t3 - 3.0 * t2 + 1.0)
+                        + key(prev, 2, target) * dt * (t3 - 2.0 * t2 + t)
+                        + key(next, 1, target) * (-2.0 * t3 + 3.0 * t2)
+                        + key(next, 0, target) * dt * (t3 - t2)
+                }
+            };
+        }
+    }
+
+    /// Writes the value of this channel at `time` into the matching joint of `pose`. Channels that
+    /// don't target a joint, or that have no keyframes, are skipped.
+    pub(crate) fn apply(&self, time: f32, pose: &mut [Transform]) {
+        let ChannelTarget::Joint(joint) = self.target else {
+            return;
+        };
+        let Some(joint) = pose.get_mut(joint) else {
+            return;
+        };
+        match &self.values {
+            ChannelValues::Translations(values) => {
+                if let Some(translation) = self.sample_vector(values, time) {
+                    joint.translation = translation;
+                }
+            }
+            ChannelValues::Rotations(values) => {
+                if let Some(rotation) = self.sample_rotation(values, time) {
+                    joint.rotation = rotation;
+                }
+            }
+            ChannelValues::Scales(values) => {
+                if let Some(scale) = self.sample_vector(values, time) {
+                    joint.scale = scale;
+                }
+            }
+            ChannelValues::MorphWeights(_) => {}
+        }
+    }
+}
+
+/// An animation clip is a set of channels that share a timeline, such as "walk" or "idle".
+pub(crate) struct AnimationClip {
+    pub(crate) name: String,
+    pub(crate) channels: Vec<Channel>,
+    /// The time of the last keyframe in any channel, in seconds.
+    pub(crate) duration: f32,
+}
+
+impl AnimationClip {
+    pub(crate) fn new(name: String, channels: Vec<Channel>) -> Self {
+        let duration = channels
+            .iter()
+            .filter_map(|c| c.times.last().copied())
+            .fold(0.0, f32::max);
+        Self {
+            name,
+            channels,
+            duration,
+        }
+    }
+
+    /// Overwrites the joints animated by this clip in `pose` with their values at `time`. Joints
+    /// without a channel keep whatever transform they already had, which is usually the rest pose.
+    pub(crate) fn sample(&self, time: f32, pose: &mut [Transform]) {
+        for channel in &self.channels {
+            channel.apply(time, pose);
+        }
+    }
+
+    /// Overwrites `weights` with the morph target weights of `mesh` at `time`. If the clip doesn't
+    /// animate that mesh, `weights` is left as is.
+    pub(crate) fn sample_morph_weights(&self, time: f32, mesh: usize, weights: &mut [f32]) {
+        for channel in &self.channels {
+            if let (ChannelTarget::MorphWeights(m), ChannelValues::MorphWeights(values)) =
+                (channel.target, &channel.values)
+            {
+                if m == mesh {
+                    channel.sample_weights(values, time, weights);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps track of the playback state of a single clip.
+pub(crate) struct AnimationPlayer {
+    pub(crate) clip: usize,
+    pub(crate) time: f32,
+    pub(crate) speed: f32,
+    pub(crate) looping: bool,
+}
+
+impl AnimationPlayer {
+    pub(crate) fn new(clip: usize) -> Self {
+        Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+        }
+    }
+
+    /// Advances the playback time of `clip` by `dt` seconds.
+    pub(crate) fn advance(&mut self, clip: &AnimationClip, dt: f32) {
+        self.time += dt * self.speed;
+        if clip.duration <= 0.0 {
+            self.time = 0.0;
+        } else if self.looping {
+            self.time = self.time.rem_euclid(clip.duration);
+        } else {
+            self.time = self.time.clamp(0.0, clip.duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rotation3};
+
+    fn translations(interpolation: Interpolation, times: &[f32], values: &[[f32; 3]]) -> Channel {
+        Channel {
+            target: ChannelTarget::Joint(0),
+            interpolation,
+            times: times.to_vec(),
+            values: ChannelValues::Translations(values.iter().map(|&v| v.into()).collect()),
+        }
+    }
+
+    fn sample(channel: Channel, time: f32) -> Transform {
+        let clip = AnimationClip::new("test".to_string(), vec![channel]);
+        let mut pose = [Transform::default()];
+        clip.sample(time, &mut pose);
+        pose[0]
+    }
+
+    fn walk() -> Channel {
+        translations(
+            Interpolation::Linear,
+            &[1.0, 2.0, 4.0],
+            &[[0.0, 0.0, 0.0], [2.0, 0.0, 0.0], [2.0, 4.0, 0.0]],
+        )
+    }
+
+    #[test]
+    fn holds_the_first_key_before_it() {
+        for time in [-1.0, 0.0, 1.0] {
+            assert_eq!(
+                sample(walk(), time).translation,
+                Vector3::new(0.0, 0.0, 0.0)
+            );
+        }
+    }
+
+    #[test]
+    fn holds_the_last_key_after_it() {
+        for time in [4.0, 5.0, 100.0] {
+            assert_eq!(
+                sample(walk(), time).translation,
+                Vector3::new(2.0, 4.0, 0.0)
+            );
+        }
+    }
+
+    #[test]
+    fn interpolates_between_keys() {
+        assert_eq!(sample(walk(), 1.5).translation, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(sample(walk(), 2.0).translation, Vector3::new(2.0, 0.0, 0.0));
+        assert_eq!(sample(walk(), 3.0).translation, Vector3::new(2.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn steps_hold_the_previous_key() {
+        let channel = || {
+            translations(
+                Interpolation::Step,
+                &[0.0, 1.0],
+                &[[1.0, 0.0, 0.0], [2.0, 0.0, 0.0]],
+            )
+        };
+        assert_eq!(
+            sample(channel(), 0.99).translation,
+            Vector3::new(1.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            sample(channel(), 1.0).translation,
+            Vector3::new(2.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn cubic_splines_pass_through_the_keys() {
+        // In-tangent, value and out-tangent for both keys, with the tangents pointing up
+        let channel = || {
+            translations(
+                Interpolation::CubicSpline,
+                &[0.0, 2.0],
+                &[
+                    [0.0, 1.0, 0.0],
+                    [0.0, 0.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                    [4.0, 0.0, 0.0],
+                    [0.0, 1.0, 0.0],
+                ],
+            )
+        };
+        assert_eq!(
+            sample(channel(), 0.0).translation,
+            Vector3::new(0.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            sample(channel(), 2.0).translation,
+            Vector3::new(4.0, 0.0, 0.0)
+        );
+        // Halfway, the tangents cancel out along y, and x is halfway between the keys
+        assert_eq!(
+            sample(channel(), 1.0).translation,
+            Vector3::new(2.0, 0.0, 0.0)
+        );
+        assert!(sample(channel(), 0.5).translation.y > 0.0);
+    }
+
+    #[test]
+    fn slerps_rotations() {
+        let channel = Channel {
+            target: ChannelTarget::Joint(0),
+            interpolation: Interpolation::Linear,
+            times: vec![0.0, 1.0],
+            values: ChannelValues::Rotations(vec![
+                Quaternion::from_angle_y(Deg(0.0)),
+                Quaternion::from_angle_y(Deg(90.0)),
+            ]),
+        };
+        let rotation = sample(channel, 0.5).rotation;
+        let expected = Quaternion::from_angle_y(Deg(45.0));
+        assert!(rotation.dot(expected) > 0.9999, "{rotation:?}");
+    }
+
+    #[test]
+    fn skips_channels_without_keys() {
+        let mut pose = [Transform::new(
+            Vector3::new(1.0, 2.0, 3.0),
+            Quaternion::from_angle_y(Deg(30.0)),
+        )];
+        let clip = AnimationClip::new(
+            "empty".to_string(),
+            vec![translations(Interpolation::Linear, &[], &[])],
+        );
+        clip.sample(0.5, &mut pose);
+        assert_eq!(pose[0].translation, Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(clip.duration, 0.0);
+
+        let mut weights = [0.25];
+        let morph = Channel {
+            target: ChannelTarget::MorphWeights(0),
+            interpolation: Interpolation::Linear,
+            times: Vec::new(),
+            values: ChannelValues::MorphWeights(Vec::new()),
+        };
+        AnimationClip::new("empty".to_string(), vec![morph]).sample_morph_weights(
+            0.5,
+            0,
+            &mut weights,
+        );
+        assert_eq!(weights, [0.25]);
+    }
+
+    #[test]
+    fn interpolates_morph_weights() {
+        let channel = Channel {
+            target: ChannelTarget::MorphWeights(0),
+            interpolation: Interpolation::Linear,
+            times: vec![0.0, 1.0],
+            // Two targets, keyframe by keyframe
+            values: ChannelValues::MorphWeights(vec![0.0, 1.0, 1.0, 0.0]),
+        };
+        let clip = AnimationClip::new("blink".to_string(), vec![channel]);
+        let mut weights = [0.0; 2];
+        clip.sample_morph_weights(0.25, 0, &mut weights);
+        assert_eq!(weights, [0.25, 0.75]);
+        clip.sample_morph_weights(2.0, 0, &mut weights);
+        assert_eq!(weights, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn ignores_channels_missing_values() {
+        // A cubic spline needs three values per keyframe
+        let channel = translations(
+            Interpolation::CubicSpline,
+            &[0.0, 1.0],
+            &[[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 0.0]],
+        );
+        assert_eq!(
+            sample(channel, 0.5).translation,
+            Transform::default().translation
+        );
+    }
+
+    #[test]
+    fn the_player_loops_or_stops_at_the_end() {
+        let clip = AnimationClip::new("walk".to_string(), vec![walk()]);
+        assert_eq!(clip.duration, 4.0);
+
+        let mut player = AnimationPlayer::new(0);
+        player.advance(&clip, 5.0);
+        assert_eq!(player.time, 1.0);
+
+        player.looping = false;
+        player.advance(&clip, 5.0);
+        assert_eq!(player.time, 4.0);
+    }
+}
@@ -0,0 +1,7 @@
+mod clip;
+mod skeleton;
+
+pub(crate) use clip::{
+    AnimationClip, AnimationPlayer, Channel, ChannelTarget, ChannelValues, Interpolation,
+};
+pub(crate) use skeleton::{Joint, JointPalette, Skeleton, MAX_JOINTS};
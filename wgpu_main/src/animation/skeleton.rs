@@ -0,0 +1,144 @@
+use crate::scene::Transform;
+use cgmath::{Matrix4, SquareMatrix};
+use wgpu::util::DeviceExt;
+
+/// The skinning shader reads the joint palette from a uniform array, so the number of joints a
+/// skeleton can have is fixed at compile time. 128 `mat4x4<f32>` take up 8 KiB, which fits well
+/// inside the 16 KiB uniform buffer limit that every backend (including WebGL2) guarantees.
+pub(crate) const MAX_JOINTS: usize = 128;
+
+pub(crate) struct Joint {
+    /// Index of the parent joint in [Skeleton::joints], or `None` for a root joint.
+    pub(crate) parent: Option<usize>,
+    /// The transform of the joint when no animation is applied.
+    pub(crate) rest: Transform,
+    /// Transforms a vertex from model space into the local space of this joint in the bind pose.
+    pub(crate) inverse_bind: Matrix4<f32>,
+}
+
+/// # Skeletal Animation
+/// A skinned mesh is deformed by a hierarchy of joints (often called bones). Every vertex stores up
+/// to four joint indices along with a weight for each of them, and its final position is the weighted
+/// sum of the vertex transformed by each of those joints.
+///
+/// To transform a vertex by a joint, we first move it from model space into the joint's local space
+/// as it was when the mesh was bound to the skeleton (the *inverse bind matrix*), and then back out
+/// into model space using the joint's current, animated global transform. The product of those two
+/// matrices for every joint is called the *joint palette*, and it is the only thing the vertex shader
+/// needs to know about the skeleton.
+///
+/// The palette is computed on the CPU: the hierarchy is small, and walking it on the GPU would
+/// require one dispatch per level of the tree.
+pub(crate) struct Skeleton {
+    /// The joints are stored in the same order as the skin that references them, since that's the
+    /// order the `joints` vertex attribute indexes into.
+    pub(crate) joints: Vec<Joint>,
+    /// The joints sorted so that every parent comes before its children.
+    order: Vec<usize>,
+    /// The transform of whatever non-joint nodes sit above the root joints.
+    root_transform: Matrix4<f32>,
+}
+
+impl Skeleton {
+    pub(crate) fn new(joints: Vec<Joint>, root_transform: Matrix4<f32>) -> Self {
+        let depth = |mut joint: usize| {
+            let mut depth = 0;
+            while let Some(parent) = joints[joint].parent {
+                joint = parent;
+                depth += 1;
+            }
+            depth
+        };
+        let mut order = (0..joints.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| depth(i));
+
+        Self {
+            joints,
+            order,
+            root_transform,
+        }
+    }
+
+    /// The pose the skeleton is in when no animation is applied.
+    pub(crate) fn rest_pose(&self) -> Vec<Transform> {
+        self.joints.iter().map(|j| j.rest).collect()
+    }
+
+    /// Computes the joint palette for the given pose. `pose` must hold one local transform per joint.
+    pub(crate) fn compute_palette(&self, pose: &[Transform]) -> Vec<Matrix4<f32>> {
+        let mut globals = vec![Matrix4::identity(); self.joints.len()];
+        for &i in &self.order {
+            let parent = match self.joints[i].parent {
+                Some(p) => globals[p],
+                None => self.root_transform,
+            };
+            globals[i] = parent * pose[i].to_matrix();
+        }
+
+        globals
+            .iter()
+            .zip(&self.joints)
+            .map(|(global, joint)| global * joint.inverse_bind)
+            .collect()
+    }
+}
+
+/// The joint palette as it lives on the GPU. The skinned pipeline binds it as group 3, right after
+/// the texture, camera and light bind groups.
+pub(crate) struct JointPalette {
+    buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl JointPalette {
+    pub(crate) fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Joint Palette Bind Group Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        })
+    }
+
+    pub(crate) fn new(device: &wgpu::Device, layout: &wgpu::BindGroupLayout) -> Self {
+        let identity: [[f32; 4]; 4] = Matrix4::<f32>::identity().into();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Joint Palette Buffer"),
+            contents: bytemuck::cast_slice(&[identity; MAX_JOINTS]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Joint Palette Bind Group"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+        });
+
+        Self { buffer, bind_group }
+    }
+
+    /// Uploads a palette computed by [Skeleton::compute_palette]. Joints past [MAX_JOINTS] are
+    /// dropped.
+    pub(crate) fn update(&self, queue: &wgpu::Queue, palette: &[Matrix4<f32>]) {
+        let data = palette
+            .iter()
+            .take(MAX_JOINTS)
+            .map(|m| -> [[f32; 4]; 4] { (*m).into() })
+            .collect::<Vec<_>>();
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&data));
+    }
+
+    pub(crate) fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
@@ -0,0 +1,219 @@
+use crate::post_process::{
+    create_fullscreen_pipeline, InputBindGroups, PostContext, PostEffect, PostTarget,
+};
+use crate::texture::Texture;
+use std::collections::HashMap;
+
+/// The adjustable parts of [Fxaa], with the defaults of the reference implementation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct FxaaSettings {
+    /// How much features smaller than a pixel get blended away, from 0 (not at all, keeping the
+    /// image sharper) to 1.
+    pub(crate) subpix: f32,
+    /// How much contrast a pixel needs relative to its brightest neighbour to count as being on
+    /// an edge. Lower values smooth more edges, but also blur more detail.
+    pub(crate) edge_threshold: f32,
+    /// Pixels whose neighbourhood is darker than this are left alone, since aliasing is hard to
+    /// see there anyway.
+    pub(crate) edge_threshold_min: f32,
+}
+
+impl Default for FxaaSettings {
+    fn default() -> Self {
+        Self {
+            subpix: 0.75,
+            edge_threshold: 0.166,
+            edge_threshold_min: 0.0833,
+        }
+    }
+}
+
+// This has to match `FxaaUniform` in fxaa.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FxaaUniform {
+    subpix: f32,
+    edge_threshold: f32,
+    edge_threshold_min: f32,
+    _padding: u32,
+}
+
+impl From<FxaaSettings> for FxaaUniform {
+    fn from(settings: FxaaSettings) -> Self {
+        Self {
+            subpix: settings.subpix,
+            edge_threshold: settings.edge_threshold,
+            edge_threshold_min: settings.edge_threshold_min,
+            _padding: 0,
+        }
+    }
+}
+
+/// # FXAA
+/// Fast Approximate Anti-Aliasing (FXAA 3.11 by Timothy Lottes) works on the finished image in a
+/// single pass. For every pixel with enough contrast around it, it works out whether it sits on a
+/// horizontal or vertical edge, searches along the edge in both directions for its ends, and
+/// shifts where it samples the image towards the other side of the edge, by how far the pixel is
+/// from the nearer end. Features smaller than a pixel, which have no ends to find, are blended with
+/// their neighbours instead.
+///
+/// It is cheap, but it only has the colors to go on, so it also softens some detail that isn't
+/// aliasing, such as text or fine textures.
+pub(crate) struct Fxaa {
+    pub(crate) settings: FxaaSettings,
+    uniform: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    layout: wgpu::BindGroupLayout,
+    /// The bind groups reading the images the effect has been handed.
+    bind_groups: InputBindGroups,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    /// One for every format the effect has written so far, since it writes the format it reads.
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl Fxaa {
+    pub(crate) const LABEL: &'static str = "FXAA";
+
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("FXAA Uniform"),
+            size: std::mem::size_of::<FxaaUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // The search along edges samples between pixels
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("fxaa_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("fxaa_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("FXAA Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/fxaa.wgsl"));
+
+        Self {
+            settings: FxaaSettings::default(),
+            uniform,
+            sampler,
+            layout,
+            bind_groups: InputBindGroups::default(),
+            pipeline_layout,
+            shader,
+            pipelines: HashMap::new(),
+        }
+    }
+}
+
+impl PostEffect for Fxaa {
+    fn label(&self) -> &'static str {
+        Self::LABEL
+    }
+
+    fn invalidate(&mut self) {
+        self.bind_groups.clear();
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, _dt: instant::Duration) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[FxaaUniform::from(self.settings)]),
+        );
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        let device = context.device;
+        let bind_group = self.bind_groups.get(input, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("fxaa_bind_group"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniform.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&input.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+            })
+        });
+        let pipeline = self.pipelines.entry(output.format).or_insert_with(|| {
+            create_fullscreen_pipeline(
+                context.device,
+                &self.pipeline_layout,
+                &self.shader,
+                "FXAA Pipeline",
+                "fs_main",
+                output.format,
+                wgpu::BlendState::REPLACE,
+            )
+        });
+
+        let mut render_pass = context
+            .encoder
+            .begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("FXAA Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: output.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,50 @@
+mod fxaa;
+mod smaa;
+mod smaa_textures;
+mod taa;
+
+pub(crate) use fxaa::Fxaa;
+pub(crate) use smaa::Smaa;
+pub(crate) use taa::Taa;
+
+use crate::post_process::PostProcessStack;
+
+/// # Post-Process Anti-Aliasing
+/// MSAA only smooths the edges of triangles, and takes a lot of memory and bandwidth at high
+/// resolutions. It also does nothing for aliasing that comes from shading, like specular highlights
+/// flickering on bumpy surfaces. Post-process anti-aliasing works on the finished image instead,
+/// finding edges by their contrast no matter where they come from.
+///
+/// FXAA and SMAA run on the tonemapped image, since edges are judged by how bright they look, so
+/// they come after tonemapping in the [PostProcessStack]. TAA comes first instead, see [Taa]. All
+/// the stages are always in the stack, and picking a method switches the one it uses on and the
+/// others off.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum AntiAliasing {
+    #[default]
+    None,
+    /// See [Fxaa].
+    Fxaa,
+    /// See [Smaa].
+    Smaa,
+    /// See [Taa].
+    Taa,
+}
+
+impl AntiAliasing {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Self::None => Self::Fxaa,
+            Self::Fxaa => Self::Smaa,
+            Self::Smaa => Self::Taa,
+            Self::Taa => Self::None,
+        }
+    }
+
+    /// Switches on the stage for this method in `post_process`, and the others off.
+    pub(crate) fn apply(self, post_process: &mut PostProcessStack) {
+        post_process.set_enabled(Fxaa::LABEL, self == Self::Fxaa);
+        post_process.set_enabled(Smaa::LABEL, self == Self::Smaa);
+        post_process.set_enabled(Taa::LABEL, self == Self::Taa);
+    }
+}
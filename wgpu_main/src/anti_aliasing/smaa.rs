@@ -0,0 +1,443 @@
+use super::smaa_textures;
+use crate::post_process::{
+    create_fullscreen_pipeline, InputBindGroups, PostContext, PostEffect, PostTarget,
+};
+use crate::texture::Texture;
+use std::collections::HashMap;
+
+/// The adjustable parts of [Smaa], with the defaults of the reference implementation's high
+/// preset.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct SmaaSettings {
+    /// How much the luma of neighbouring pixels has to differ for an edge between them. Lower
+    /// values find more edges, but also treat more detail as edges.
+    pub(crate) threshold: f32,
+    /// How many steps of two pixels the searches for the ends of an edge take at most. Longer
+    /// edges get treated as if they ended there.
+    pub(crate) max_search_steps: u32,
+    /// How much sharp corners are kept from being smoothed over, from 0 to 1.
+    pub(crate) corner_rounding: f32,
+}
+
+impl Default for SmaaSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            max_search_steps: 16,
+            corner_rounding: 0.25,
+        }
+    }
+}
+
+// This has to match `SmaaUniform` in smaa.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SmaaUniform {
+    threshold: f32,
+    max_search_steps: f32,
+    corner_rounding: f32,
+    _padding: u32,
+}
+
+impl From<SmaaSettings> for SmaaUniform {
+    fn from(settings: SmaaSettings) -> Self {
+        Self {
+            threshold: settings.threshold,
+            max_search_steps: settings.max_search_steps as f32,
+            corner_rounding: settings.corner_rounding,
+            _padding: 0,
+        }
+    }
+}
+
+/// # SMAA
+/// Subpixel Morphological Anti-Aliasing (SMAA 1x by Jorge Jimenez et al.) looks for the shapes the
+/// edges in the image make, and works out how the edge would have covered each pixel had it been
+/// drawn smoothly. It takes three passes:
+/// - Edge detection marks where the luma changes enough between neighbouring pixels, keeping only
+///   the strongest of nearby edges.
+/// - Blending weight calculation searches along every edge for its ends, and for the edges crossing
+///   it there. The precomputed area texture turns the distances to the ends and the kind of
+///   crossing edges into how much of the pixel lies on the other side of the smooth line. The
+///   searches read two pixels at once with bilinear filtering, and the precomputed search texture
+///   turns the mixed values back into how far the edge goes on.
+/// - Neighborhood blending mixes every pixel with the neighbour across its strongest edge by those
+///   weights.
+///
+/// It keeps edges sharper than [Fxaa](super::Fxaa) and blurs less of the rest of the image, at the
+/// cost of more passes and two intermediate textures. Diagonal edges are smoothed as stairs of
+/// short horizontal and vertical ones, as in the lower quality presets of the reference.
+pub(crate) struct Smaa {
+    pub(crate) settings: SmaaSettings,
+    uniform: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    area_texture: Texture,
+    search_texture: Texture,
+    /// Written by the edge detection pass, red for edges to the left of pixels, green for edges
+    /// above.
+    edges: Texture,
+    /// Written by the blending weight calculation pass.
+    blend: Texture,
+    edge_layout: wgpu::BindGroupLayout,
+    weight_layout: wgpu::BindGroupLayout,
+    blend_layout: wgpu::BindGroupLayout,
+    /// Only depends on the size of the image, so it is only recreated when that changes.
+    weight_bind_group: wgpu::BindGroup,
+    /// The bind groups reading the images the effect has been handed, for the edge detection and
+    /// the neighborhood blending pass.
+    input_bind_groups: InputBindGroups<[wgpu::BindGroup; 2]>,
+    blend_pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    edge_pipeline: wgpu::RenderPipeline,
+    weight_pipeline: wgpu::RenderPipeline,
+    /// One for every format the effect has written so far, since it writes the format it reads.
+    blend_pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl Smaa {
+    pub(crate) const LABEL: &'static str = "SMAA";
+    const EDGES_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg8Unorm;
+    const BLEND_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+    /// `width` and `height` are the size of the images the effect reads.
+    pub(crate) fn new(device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) -> Self {
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SMAA Uniform"),
+            size: std::mem::size_of::<SmaaUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // The searches and the blending rely on bilinear filtering to read between pixels
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("smaa_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let lookup = |label, width, height, format, bytes_per_texel, data: &[u8]| {
+            let texture = Texture::create_2d_texture(
+                device,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                wgpu::FilterMode::Linear,
+                Some(label),
+            );
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_texel * width),
+                    rows_per_image: Some(height),
+                },
+                texture.size,
+            );
+            texture
+        };
+        let area_texture = lookup(
+            "smaa_area_texture",
+            smaa_textures::AREA_SIZE,
+            smaa_textures::AREA_SIZE,
+            wgpu::TextureFormat::Rg8Unorm,
+            2,
+            &smaa_textures::area_texture(),
+        );
+        let search_texture = lookup(
+            "smaa_search_texture",
+            smaa_textures::SEARCH_WIDTH,
+            smaa_textures::SEARCH_HEIGHT,
+            wgpu::TextureFormat::R8Unorm,
+            1,
+            &smaa_textures::search_texture(),
+        );
+
+        // Every pass binds the uniform and the sampler, followed by the textures it reads at the
+        // bindings smaa.wgsl declares them at
+        let create_layout = |label, textures: &[u32]| {
+            let mut entries = vec![
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ];
+            entries.extend(textures.iter().map(|&binding| wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }));
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some(label),
+                entries: &entries,
+            })
+        };
+        // The image
+        let edge_layout = create_layout("smaa_edge_bind_group_layout", &[2]);
+        // The edges, the area texture and the search texture
+        let weight_layout = create_layout("smaa_weight_bind_group_layout", &[3, 4, 5]);
+        // The image and the blending weights
+        let blend_layout = create_layout("smaa_blend_bind_group_layout", &[2, 6]);
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/smaa.wgsl"));
+        let create_pipeline_layout = |label, layout| {
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            })
+        };
+        let edge_pipeline = create_fullscreen_pipeline(
+            device,
+            &create_pipeline_layout("SMAA Edge Pipeline Layout", &edge_layout),
+            &shader,
+            "SMAA Edge Detection Pipeline",
+            "fs_edge_detection",
+            Self::EDGES_FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        let weight_pipeline = create_fullscreen_pipeline(
+            device,
+            &create_pipeline_layout("SMAA Weight Pipeline Layout", &weight_layout),
+            &shader,
+            "SMAA Blending Weight Pipeline",
+            "fs_blend_weights",
+            Self::BLEND_FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        let blend_pipeline_layout =
+            create_pipeline_layout("SMAA Blend Pipeline Layout", &blend_layout);
+
+        let (edges, blend) = Self::create_targets(device, width, height);
+        let weight_bind_group = Self::create_weight_bind_group(
+            device,
+            &weight_layout,
+            &uniform,
+            &sampler,
+            [&edges, &area_texture, &search_texture],
+        );
+
+        Self {
+            settings: SmaaSettings::default(),
+            uniform,
+            sampler,
+            area_texture,
+            search_texture,
+            edges,
+            blend,
+            edge_layout,
+            weight_layout,
+            blend_layout,
+            weight_bind_group,
+            input_bind_groups: InputBindGroups::default(),
+            blend_pipeline_layout,
+            shader,
+            edge_pipeline,
+            weight_pipeline,
+            blend_pipelines: HashMap::new(),
+        }
+    }
+
+    fn create_targets(device: &wgpu::Device, width: u32, height: u32) -> (Texture, Texture) {
+        let create = |format, label| {
+            Texture::create_2d_texture(
+                device,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                wgpu::FilterMode::Linear,
+                Some(label),
+            )
+        };
+        (
+            create(Self::EDGES_FORMAT, "smaa_edges_texture"),
+            create(Self::BLEND_FORMAT, "smaa_blend_texture"),
+        )
+    }
+
+    /// A bind group with the uniform and the sampler, and `textures` at the bindings they come
+    /// with.
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform: &wgpu::Buffer,
+        sampler: &wgpu::Sampler,
+        textures: &[(u32, &wgpu::TextureView)],
+    ) -> wgpu::BindGroup {
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ];
+        entries.extend(
+            textures
+                .iter()
+                .map(|&(binding, view)| wgpu::BindGroupEntry {
+                    binding,
+                    resource: wgpu::BindingResource::TextureView(view),
+                }),
+        );
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("smaa_bind_group"),
+            layout,
+            entries: &entries,
+        })
+    }
+
+    fn create_weight_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform: &wgpu::Buffer,
+        sampler: &wgpu::Sampler,
+        [edges, area, search]: [&Texture; 3],
+    ) -> wgpu::BindGroup {
+        Self::create_bind_group(
+            device,
+            layout,
+            uniform,
+            sampler,
+            &[(3, &edges.view), (4, &area.view), (5, &search.view)],
+        )
+    }
+}
+
+impl PostEffect for Smaa {
+    fn label(&self) -> &'static str {
+        Self::LABEL
+    }
+
+    fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.edges, self.blend) = Self::create_targets(device, width, height);
+        self.weight_bind_group = Self::create_weight_bind_group(
+            device,
+            &self.weight_layout,
+            &self.uniform,
+            &self.sampler,
+            [&self.edges, &self.area_texture, &self.search_texture],
+        );
+    }
+
+    fn invalidate(&mut self) {
+        self.input_bind_groups.clear();
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, _dt: instant::Duration) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[SmaaUniform::from(self.settings)]),
+        );
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        let device = context.device;
+        let pass = |encoder: &mut wgpu::CommandEncoder, label, target, pipeline, bind_group| {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        // Pixels without edges are discarded, so they have to start out empty
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        };
+
+        let [edge_bind_group, blend_bind_group] = self.input_bind_groups.get(input, || {
+            [
+                Self::create_bind_group(
+                    device,
+                    &self.edge_layout,
+                    &self.uniform,
+                    &self.sampler,
+                    &[(2, &input.view)],
+                ),
+                Self::create_bind_group(
+                    device,
+                    &self.blend_layout,
+                    &self.uniform,
+                    &self.sampler,
+                    &[(2, &input.view), (6, &self.blend.view)],
+                ),
+            ]
+        });
+        pass(
+            context.encoder,
+            "SMAA Edge Detection Pass",
+            &self.edges.view,
+            &self.edge_pipeline,
+            edge_bind_group,
+        );
+        pass(
+            context.encoder,
+            "SMAA Blending Weight Pass",
+            &self.blend.view,
+            &self.weight_pipeline,
+            &self.weight_bind_group,
+        );
+
+        let blend_pipeline = self
+            .blend_pipelines
+            .entry(output.format)
+            .or_insert_with(|| {
+                create_fullscreen_pipeline(
+                    device,
+                    &self.blend_pipeline_layout,
+                    &self.shader,
+                    "SMAA Neighborhood Blending Pipeline",
+                    "fs_neighborhood_blending",
+                    output.format,
+                    wgpu::BlendState::REPLACE,
+                )
+            });
+        pass(
+            context.encoder,
+            "SMAA Neighborhood Blending Pass",
+            output.view,
+            blend_pipeline,
+            blend_bind_group,
+        );
+    }
+}
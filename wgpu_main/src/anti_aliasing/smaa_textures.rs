@@ -0,0 +1,324 @@
+// The lookup textures of SMAA, computed the same way as `AreaTex.py` and `SearchTex.py` of the
+// reference implementation (https://github.com/iryoku/smaa). Only the parts SMAA 1x without
+// diagonal edges reads are generated, which takes a moment at startup instead of shipping the
+// textures as files.
+
+/// The area texture has a 16 by 16 tile for each combination of crossing edges at both ends of an
+/// edge, 5 tiles across and down. This has to match `AREA_SIZE` in smaa.wgsl.
+pub(super) const AREA_SIZE: u32 = 80;
+/// The longest distance to the end of an edge the area texture covers, before it gets squared.
+/// This has to match `AREA_MAX_DISTANCE` in smaa.wgsl.
+const AREA_MAX_DISTANCE: usize = 16;
+/// U shaped edges shorter than this get rounded off rather than revectorized in full.
+const SMOOTH_MAX_DISTANCE: f32 = 32.0;
+
+/// The search texture has the lookups for searches to the left, then to the right, side by side.
+/// Both are 33 by 33 texels, one for every value a bilinear fetch of the edges can return. This
+/// has to match `SEARCH_RIGHT_OFFSET` in smaa.wgsl.
+pub(super) const SEARCH_WIDTH: u32 = 66;
+pub(super) const SEARCH_HEIGHT: u32 = 33;
+
+/// Where the tile of every pattern of crossing edges starts in the area texture, in tiles. The
+/// shader reads the crossing edges at both ends between two pixels, getting 0, 0.25, 0.75 or 1
+/// depending on whether neither, the one going up, the one going down or both are there, which it
+/// multiplies by 4.
+const EDGES_ORTHO: [(usize, usize); 16] = [
+    (0, 0),
+    (3, 0),
+    (0, 3),
+    (3, 3),
+    (1, 0),
+    (4, 0),
+    (1, 3),
+    (4, 3),
+    (0, 1),
+    (3, 1),
+    (0, 4),
+    (3, 4),
+    (1, 1),
+    (4, 1),
+    (1, 4),
+    (4, 4),
+];
+
+/// How much of pixel `x` lies under the line from `p1` to `p2`, which crosses the edge at height
+/// 0. The first value is the area below the edge, the second the area above it.
+fn area(p1: (f32, f32), p2: (f32, f32), x: f32) -> [f32; 2] {
+    let d = (p2.0 - p1.0, p2.1 - p1.1);
+    let x1 = x;
+    let x2 = x + 1.0;
+    let y1 = p1.1 + d.1 * (x1 - p1.0) / d.0;
+    let y2 = p1.1 + d.1 * (x2 - p1.0) / d.0;
+
+    let inside = (x1 >= p1.0 && x1 < p2.0) || (x2 > p1.0 && x2 <= p2.0);
+    if !inside {
+        return [0.0, 0.0];
+    }
+
+    let is_trapezoid = y1.signum() == y2.signum() || y1.abs() < 1e-4 || y2.abs() < 1e-4;
+    if is_trapezoid {
+        let a = (y1 + y2) / 2.0;
+        return if a < 0.0 { [-a, 0.0] } else { [0.0, a] };
+    }
+
+    // The line crosses the edge within the pixel, making up a triangle on either side
+    let x = -p1.1 * d.0 / d.1 + p1.0;
+    let a1 = if x > p1.0 { y1 * x.fract() / 2.0 } else { 0.0 };
+    let a2 = if x < p2.0 {
+        y2 * (1.0 - x.fract()) / 2.0
+    } else {
+        0.0
+    };
+    let a = if a1.abs() > a2.abs() { a1 } else { -a2 };
+    if a < 0.0 {
+        [a1.abs(), a2.abs()]
+    } else {
+        [a2.abs(), a1.abs()]
+    }
+}
+
+/// Blends the areas of the two halves of a U shape towards a rounder curve the shorter it is.
+fn smooth_area(d: f32, a1: [f32; 2], a2: [f32; 2]) -> [f32; 2] {
+    let p = (d / SMOOTH_MAX_DISTANCE).clamp(0.0, 1.0);
+    let smooth = |a: f32| {
+        let b = (a * 2.0).sqrt() * 0.5;
+        b + (a - b) * p
+    };
+    [smooth(a1[0]) + smooth(a2[0]), smooth(a1[1]) + smooth(a2[1])]
+}
+
+/// The area for the pixel `left` pixels from the left end of an edge that goes on for `right`
+/// more pixels, with the crossing edges of `pattern` at its ends. The bits of the pattern are
+/// whether the left end goes down, the right end goes down, the left end goes up and the right end
+/// goes up.
+fn area_ortho(pattern: usize, left: f32, right: f32) -> [f32; 2] {
+    let d = left + right + 1.0;
+    // The revectorized line goes from half a pixel above or below the edge at one end to the
+    // middle of the edge, or to half a pixel above or below at the other end
+    let o1 = 0.5;
+    let o2 = -0.5;
+    let middle = (d / 2.0, 0.0);
+    match pattern {
+        // Only the end with the crossing edge is revectorized for L shapes, so that they blend
+        // into flat edges
+        1 if left <= right => area((0.0, o2), middle, left),
+        2 if left >= right => area(middle, (d, o2), left),
+        4 if left <= right => area((0.0, o1), middle, left),
+        8 if left >= right => area(middle, (d, o1), left),
+        3 => smooth_area(
+            d,
+            area((0.0, o2), middle, left),
+            area(middle, (d, o2), left),
+        ),
+        12 => smooth_area(
+            d,
+            area((0.0, o1), middle, left),
+            area(middle, (d, o1), left),
+        ),
+        // Z shapes
+        6 | 7 | 14 => area((0.0, o1), (d, o2), left),
+        9 | 11 | 13 => area((0.0, o2), (d, o1), left),
+        _ => [0.0, 0.0],
+    }
+}
+
+/// Two bytes per texel, for an `Rg8Unorm` texture of [AREA_SIZE] by [AREA_SIZE] texels.
+pub(super) fn area_texture() -> Vec<u8> {
+    let size = AREA_SIZE as usize;
+    let mut data = vec![0; size * size * 2];
+    for (pattern, &(e1, e2)) in EDGES_ORTHO.iter().enumerate() {
+        for left in 0..AREA_MAX_DISTANCE {
+            for right in 0..AREA_MAX_DISTANCE {
+                // The distances are stored quadratically, to reach further with the same size
+                let a = area_ortho(pattern, (left * left) as f32, (right * right) as f32);
+                let x = e1 * AREA_MAX_DISTANCE + left;
+                let y = e2 * AREA_MAX_DISTANCE + right;
+                let i = (y * size + x) * 2;
+                data[i] = (a[0] * 255.0).round() as u8;
+                data[i + 1] = (a[1] * 255.0).round() as u8;
+            }
+        }
+    }
+    data
+}
+
+/// What a bilinear fetch a quarter pixel left and an eighth of a pixel up from the middle of a
+/// pixel returns for the edges `e` of the pixel above and to the left, above, to the left and the
+/// pixel itself, in 32nds.
+fn bilinear(e: [u8; 4]) -> usize {
+    (e[0] + 3 * e[1] + 7 * e[2] + 21 * e[3]) as usize
+}
+
+/// How much further a search to the left goes on, given the `left` and `top` edges of the last
+/// pixels it read.
+fn delta_left(left: [u8; 4], top: [u8; 4]) -> u8 {
+    let mut d = 0;
+    // If there is an edge, continue
+    if top[3] == 1 {
+        d += 1;
+    }
+    // If there was an edge, there is another one and no crossing edges, continue
+    if d == 1 && top[2] == 1 && left[1] != 1 && left[3] != 1 {
+        d += 1;
+    }
+    d
+}
+
+/// How much further a search to the right goes on, given the `left` and `top` edges of the last
+/// pixels it read.
+fn delta_right(left: [u8; 4], top: [u8; 4]) -> u8 {
+    let mut d = 0;
+    // If there is an edge and no crossing edges, continue
+    if top[3] == 1 && left[1] != 1 && left[3] != 1 {
+        d += 1;
+    }
+    // If there was an edge, there is another one and no crossing edges, continue
+    if d == 1 && top[2] == 1 && left[0] != 1 && left[2] != 1 {
+        d += 1;
+    }
+    d
+}
+
+/// One byte per texel, for an `R8Unorm` texture of [SEARCH_WIDTH] by [SEARCH_HEIGHT] texels.
+pub(super) fn search_texture() -> Vec<u8> {
+    // Every combination of edges gives a different value, so they can be told apart again
+    let mut edges = [None; 33];
+    for bits in 0..16u8 {
+        let e = [bits & 1, (bits >> 1) & 1, (bits >> 2) & 1, (bits >> 3) & 1];
+        edges[bilinear(e)] = Some(e);
+    }
+
+    let width = SEARCH_WIDTH as usize;
+    let mut data = vec![0; width * SEARCH_HEIGHT as usize];
+    for (y, top) in edges.iter().enumerate() {
+        for (x, left) in edges.iter().enumerate() {
+            if let (Some(left), Some(top)) = (*left, *top) {
+                // Scaled to match the reference texture, the shader scales them back
+                data[y * width + x] = 127 * delta_left(left, top);
+                data[y * width + width / 2 + x] = 127 * delta_right(left, top);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The texel of the area texture for a pixel `left` and `right` (before squaring) from the
+    /// ends of an edge, in the tile at `tile`.
+    fn area_texel(data: &[u8], tile: (usize, usize), left: usize, right: usize) -> [u8; 2] {
+        let x = tile.0 * AREA_MAX_DISTANCE + left;
+        let y = tile.1 * AREA_MAX_DISTANCE + right;
+        let i = (y * AREA_SIZE as usize + x) * 2;
+        [data[i], data[i + 1]]
+    }
+
+    /// Within one step of the reference values, which were worked out by hand with the formulas
+    /// of `AreaTex.py` and scaled to bytes.
+    fn assert_close(actual: [u8; 2], reference: [f32; 2]) {
+        for (a, r) in actual.iter().zip(reference) {
+            assert!(
+                (*a as f32 - r * 255.0).abs() <= 1.0,
+                "{actual:?} is not {reference:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn area_texture_matches_the_reference() {
+        let data = area_texture();
+        assert_eq!(data.len(), (AREA_SIZE * AREA_SIZE * 2) as usize);
+
+        // No crossing edges, nothing to blend
+        assert_close(area_texel(&data, EDGES_ORTHO[0], 0, 0), [0.0, 0.0]);
+        // An L going down at the left end, one pixel long: a triangle of half a pixel by half a
+        // pixel below the edge
+        assert_close(area_texel(&data, EDGES_ORTHO[1], 0, 0), [0.125, 0.0]);
+        // The same L, in the middle of a 3 pixel edge: y goes from -1/6 to 1/6, and the
+        // triangle up to the middle is a sixth by a half
+        assert_close(area_texel(&data, EDGES_ORTHO[1], 1, 1), [1.0 / 24.0, 0.0]);
+        // Only the shorter side of an L is revectorized
+        assert_close(area_texel(&data, EDGES_ORTHO[1], 2, 1), [0.0, 0.0]);
+        assert_close(area_texel(&data, EDGES_ORTHO[2], 0, 0), [0.125, 0.0]);
+        assert_close(area_texel(&data, EDGES_ORTHO[4], 0, 0), [0.0, 0.125]);
+        // A U going down at both ends, one pixel long: both halves are rounded off almost
+        // entirely into 0.25 each, by sqrt(2 * 0.125) / 2
+        assert_close(
+            area_texel(&data, EDGES_ORTHO[3], 0, 0),
+            [2.0 * (0.25 - 0.125 / 32.0), 0.0],
+        );
+        // A Z from half a pixel up on the left to half a pixel down on the right crosses the edge
+        // in the middle of a one pixel edge, leaving an eighth on either side
+        assert_close(area_texel(&data, EDGES_ORTHO[6], 0, 0), [0.125, 0.125]);
+        // And from 4 pixels on the left, with 9 to go on the right, it is a trapezoid above the
+        // edge: the line is at 0.5 - 4.5 / 14 in the middle of the pixel
+        assert_close(
+            area_texel(&data, EDGES_ORTHO[6], 2, 3),
+            [0.0, 0.5 - 4.5 / 14.0],
+        );
+        assert_close(
+            area_texel(&data, EDGES_ORTHO[9], 2, 3),
+            [0.5 - 4.5 / 14.0, 0.0],
+        );
+    }
+
+    #[test]
+    fn area_texture_mirrors_left_and_right() {
+        let data = area_texture();
+        // An L at the right end is the mirror image of one at the left end
+        for left in 0..AREA_MAX_DISTANCE {
+            for right in 0..AREA_MAX_DISTANCE {
+                assert_eq!(
+                    area_texel(&data, EDGES_ORTHO[1], left, right),
+                    area_texel(&data, EDGES_ORTHO[2], right, left)
+                );
+                assert_eq!(
+                    area_texel(&data, EDGES_ORTHO[4], left, right),
+                    area_texel(&data, EDGES_ORTHO[8], right, left)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn area_texture_leaves_the_unused_tiles_empty() {
+        let data = area_texture();
+        for i in 0..5 {
+            for (left, right) in [(0, 0), (7, 3), (15, 15)] {
+                assert_eq!(area_texel(&data, (2, i), left, right), [0, 0]);
+                assert_eq!(area_texel(&data, (i, 2), left, right), [0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn search_texture_matches_the_reference() {
+        let data = search_texture();
+        assert_eq!(data.len(), (SEARCH_WIDTH * SEARCH_HEIGHT) as usize);
+        let width = SEARCH_WIDTH as usize;
+        let left = |x: usize, y: usize| data[y * width + x];
+        let right = |x: usize, y: usize| data[y * width + width / 2 + x];
+
+        // The edges come out of `bilinear` in `SearchTex.py` at 1, 3, 7 and 21 32nds. Without
+        // any edges on top, both searches stop.
+        for x in 0..33 {
+            assert_eq!((left(x, 0), right(x, 0)), (0, 0));
+        }
+        // An edge on top of the current pixel only
+        assert_eq!((left(0, 21), right(0, 21)), (127, 127));
+        // On top of both pixels, with no crossing edges
+        assert_eq!((left(0, 28), right(0, 28)), (254, 254));
+        // With a crossing edge above the current pixel, the search right stops right away
+        assert_eq!((left(3, 28), right(3, 28)), (127, 0));
+        // With one above the other pixel, it stops one pixel further
+        assert_eq!((left(1, 28), right(1, 28)), (254, 127));
+        // With one at the current pixel, the search left stops one pixel further
+        assert_eq!((left(21, 28), right(21, 28)), (127, 0));
+        // No combination of edges fetches 2 32nds, so nothing is there
+        for y in 0..33 {
+            assert_eq!((left(2, y), right(2, y)), (0, 0));
+        }
+    }
+}
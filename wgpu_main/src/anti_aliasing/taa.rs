@@ -0,0 +1,392 @@
+use crate::post_process::{InputBindGroups, PostContext, PostEffect, PostTarget};
+use crate::texture::Texture;
+use crate::velocity::VelocityBuffer;
+use cgmath::Vector2;
+use std::collections::HashMap;
+
+/// The adjustable parts of [Taa].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct TaaSettings {
+    /// How much of every new frame goes into the image, with the rest coming from the history.
+    /// Lower values smooth more, but take longer to catch up with changes.
+    pub(crate) current_weight: f32,
+    /// How many different positions the jitter goes through before it starts over.
+    pub(crate) jitter_length: u32,
+}
+
+impl Default for TaaSettings {
+    fn default() -> Self {
+        Self {
+            current_weight: 0.1,
+            jitter_length: 8,
+        }
+    }
+}
+
+// This has to match `TaaUniform` in taa.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TaaUniform {
+    current_weight: f32,
+    history_valid: u32,
+}
+
+/// The `index`th number of the Halton sequence in `base`, which mirrors the digits of `index` in
+/// that base around the decimal point. Consecutive numbers of the sequence spread out evenly
+/// between 0 and 1, no matter how many of them are taken.
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// # TAA
+/// Temporal Anti-Aliasing spreads the samples of supersampling out over time. Every frame is
+/// rendered with the projection shifted by a different fraction of a pixel, along the Halton
+/// sequence in bases 2 and 3 (see [crate::camera::Projection]), and blended into a history of the
+/// frames before it. Standing still, the history converges to the average over many positions
+/// within each pixel, which smooths both the edges of triangles and the aliasing from shading.
+///
+/// Things move, though, so the history is reprojected: the [VelocityBuffer] says how far the
+/// surface in each pixel moved on the screen since the last frame, and the history is read from
+/// where it was back then. Nothing is drawn into the velocity buffer where only the sky is, so
+/// there the velocity comes from how the camera moved instead. Surfaces that were hidden in the last frame, or that changed, have no
+/// good history to go back to, so the history is clamped to the range of colors around the pixel
+/// in the current frame, which throws away whatever doesn't belong there anymore.
+///
+/// The history is thrown away when the window resizes or the camera cuts somewhere else, since it
+/// doesn't show anything that is still on the screen then. It runs on the HDR image, before bloom,
+/// so that everything after it works on a stable image.
+pub(crate) struct Taa {
+    pub(crate) settings: TaaSettings,
+    uniform: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    layout: wgpu::BindGroupLayout,
+    /// The bind groups reading the images the effect has been handed, with either image of
+    /// `history`.
+    bind_groups: InputBindGroups<[wgpu::BindGroup; 2]>,
+    scene_layout: wgpu::BindGroupLayout,
+    /// The velocity and depth of the scene, see [Self::set_scene].
+    scene_bind_group: wgpu::BindGroup,
+    /// For working out how far the background moved, where the velocity pass didn't draw.
+    camera_bind_group: wgpu::BindGroup,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    /// One for every format the effect has written so far, since it writes the format it reads.
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+    /// Every frame reads the history from one image and writes the new one into the other.
+    history: [Texture; 2],
+    /// The image of `history` the last frame wrote.
+    last_history: usize,
+    history_valid: bool,
+    frame: u32,
+}
+
+impl Taa {
+    pub(crate) const LABEL: &'static str = "TAA";
+    /// The history is as bright as the HDR image, so it needs the same range.
+    const HISTORY_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    /// `camera`, `velocity` and `depth` are what the scene was rendered with.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        camera: &wgpu::Buffer,
+        velocity: &VelocityBuffer,
+        depth: &Texture,
+    ) -> Self {
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("TAA Uniform"),
+            size: std::mem::size_of::<TaaUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // The history gets read between pixels wherever things moved
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("taa_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let texture = |binding, sample_type| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let float = wgpu::TextureSampleType::Float { filterable: true };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("taa_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture(1, float),
+                texture(2, float),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let scene_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("taa_scene_bind_group_layout"),
+            entries: &[
+                texture(0, float),
+                texture(1, wgpu::TextureSampleType::Depth),
+            ],
+        });
+        // The camera buffer stays the same when the window resizes, unlike the textures
+        let camera_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("taa_camera_bind_group_layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("taa_camera_bind_group"),
+            layout: &camera_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: camera.as_entire_binding(),
+            }],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("TAA Pipeline Layout"),
+            bind_group_layouts: &[&layout, &scene_layout, &camera_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/taa.wgsl"));
+
+        let scene_bind_group =
+            Self::create_scene_bind_group(device, &scene_layout, velocity, depth);
+        let history = Self::create_history(device, depth.size.width, depth.size.height);
+
+        Self {
+            settings: TaaSettings::default(),
+            uniform,
+            sampler,
+            layout,
+            bind_groups: InputBindGroups::default(),
+            scene_layout,
+            scene_bind_group,
+            camera_bind_group,
+            pipeline_layout,
+            shader,
+            pipelines: HashMap::new(),
+            history,
+            last_history: 0,
+            history_valid: false,
+            frame: 0,
+        }
+    }
+
+    fn create_history(device: &wgpu::Device, width: u32, height: u32) -> [Texture; 2] {
+        let create = || {
+            Texture::create_2d_texture(
+                device,
+                width,
+                height,
+                Self::HISTORY_FORMAT,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                wgpu::FilterMode::Linear,
+                Some("taa_history_texture"),
+            )
+        };
+        [create(), create()]
+    }
+
+    fn create_scene_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        velocity: &VelocityBuffer,
+        depth: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("taa_scene_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&velocity.texture().view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&depth.view),
+                },
+            ],
+        })
+    }
+
+    /// Reads the velocity and depth from these from now on, after they were recreated.
+    pub(crate) fn set_scene(
+        &mut self,
+        device: &wgpu::Device,
+        velocity: &VelocityBuffer,
+        depth: &Texture,
+    ) {
+        self.scene_bind_group =
+            Self::create_scene_bind_group(device, &self.scene_layout, velocity, depth);
+    }
+
+    /// Starts over from the next frame alone, for when the history doesn't show anything that is
+    /// still on the screen.
+    pub(crate) fn reset_history(&mut self) {
+        self.history_valid = false;
+    }
+
+    /// How far to shift the projection for the next frame, in pixels, between -0.5 and 0.5.
+    pub(crate) fn jitter(&self) -> Vector2<f32> {
+        // The sequence starts at 0 in every base, which would repeat the middle of the pixel
+        let index = self.frame % self.settings.jitter_length.max(1) + 1;
+        Vector2::new(halton(index, 2) - 0.5, halton(index, 3) - 0.5)
+    }
+}
+
+impl PostEffect for Taa {
+    fn label(&self) -> &'static str {
+        Self::LABEL
+    }
+
+    fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.history = Self::create_history(device, width, height);
+        self.reset_history();
+    }
+
+    fn invalidate(&mut self) {
+        self.bind_groups.clear();
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, _dt: instant::Duration) {
+        self.frame = self.frame.wrapping_add(1);
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[TaaUniform {
+                current_weight: self.settings.current_weight,
+                history_valid: self.history_valid.into(),
+            }]),
+        );
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        let (read, write) = (self.last_history, 1 - self.last_history);
+        let device = context.device;
+        let bind_groups = self.bind_groups.get(input, || {
+            self.history.each_ref().map(|history| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("taa_bind_group"),
+                    layout: &self.layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: self.uniform.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::TextureView(&input.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: wgpu::BindingResource::TextureView(&history.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: wgpu::BindingResource::Sampler(&self.sampler),
+                        },
+                    ],
+                })
+            })
+        });
+        let pipeline = self.pipelines.entry(output.format).or_insert_with(|| {
+            let target = |format| {
+                Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })
+            };
+            context
+                .device
+                .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    label: Some("TAA Pipeline"),
+                    layout: Some(&self.pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &self.shader,
+                        entry_point: "vs_main",
+                        buffers: &[],
+                    },
+                    // The result goes both to the next stage and into the history
+                    fragment: Some(wgpu::FragmentState {
+                        module: &self.shader,
+                        entry_point: "fs_main",
+                        targets: &[target(output.format), target(Self::HISTORY_FORMAT)],
+                    }),
+                    primitive: wgpu::PrimitiveState::default(),
+                    depth_stencil: None,
+                    multisample: wgpu::MultisampleState::default(),
+                    multiview: None,
+                })
+        });
+
+        let attachment = |view| {
+            Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })
+        };
+        let mut render_pass = context
+            .encoder
+            .begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("TAA Pass"),
+                color_attachments: &[
+                    attachment(output.view),
+                    attachment(&self.history[write].view),
+                ],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_groups[read], &[]);
+        render_pass.set_bind_group(1, &self.scene_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.camera_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+
+        self.last_history = write;
+        self.history_valid = true;
+    }
+}
@@ -0,0 +1,247 @@
+use crate::post_process::InputBindGroups;
+use crate::texture::Texture;
+
+/// How [AutoExposure] measures the scene and adapts to it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct AutoExposureSettings {
+    /// The range of luminances the histogram covers, in EV. Anything outside is counted in the
+    /// second or last bin, since the first one only holds the pixels that are (almost) black.
+    pub(crate) min_log_luminance: f32,
+    pub(crate) max_log_luminance: f32,
+    /// The darkest and brightest fractions of the pixels are left out of the average, so that a
+    /// few very dark or very bright pixels can't throw the exposure off.
+    pub(crate) low_percentile: f32,
+    pub(crate) high_percentile: f32,
+    /// How quickly the exposure follows the scene when it gets brighter and darker. The eye
+    /// adapts to brightness much faster than to darkness.
+    pub(crate) speed_brighten: f32,
+    pub(crate) speed_darken: f32,
+}
+
+impl Default for AutoExposureSettings {
+    fn default() -> Self {
+        Self {
+            min_log_luminance: -8.0,
+            max_log_luminance: 4.0,
+            low_percentile: 0.1,
+            high_percentile: 0.9,
+            speed_brighten: 3.0,
+            speed_darken: 1.0,
+        }
+    }
+}
+
+// This has to match `AutoExposureParams` in auto_exposure.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct AutoExposureParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    low_percentile: f32,
+    high_percentile: f32,
+    speed_brighten: f32,
+    speed_darken: f32,
+    delta_time: f32,
+    _padding: u32,
+}
+
+/// # Automatic Exposure
+/// A fixed exposure only suits one part of a scene: walk from a bright spot into a dark one and the
+/// image goes murky, walk back and it washes out. Like the eye, [AutoExposure] measures how bright
+/// the scene is and slowly adapts the exposure to it.
+///
+/// The first compute pass sorts every pixel of the HDR image into a histogram by the logarithm of
+/// its luminance. The second one, a single workgroup, averages the histogram, leaving out the
+/// darkest and brightest pixels below [AutoExposureSettings::low_percentile] and above
+/// [AutoExposureSettings::high_percentile]. Rather than jumping to the new average, the adapted
+/// luminance moves towards it a bit every frame, faster when the scene gets brighter than when it
+/// gets darker. The tonemapping pass reads the adapted luminance from [Self::adaptation_buffer] and
+/// exposes the image so that it comes out as middle gray.
+///
+/// Everything stays on the GPU, so the exposure is never read back to the CPU.
+pub(crate) struct AutoExposure {
+    pub(crate) settings: AutoExposureSettings,
+    params: wgpu::Buffer,
+    histogram: wgpu::Buffer,
+    /// The adapted log luminance, carried over from frame to frame.
+    adaptation: wgpu::Buffer,
+    layout: wgpu::BindGroupLayout,
+    bind_groups: InputBindGroups,
+    histogram_pipeline: wgpu::ComputePipeline,
+    average_pipeline: wgpu::ComputePipeline,
+}
+
+impl AutoExposure {
+    /// The histogram pass works on tiles of this many pixels across and down.
+    const TILE_SIZE: u32 = 16;
+    /// This has to match `NUM_BINS` in auto_exposure.wgsl
+    const NUM_BINS: u64 = 256;
+
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let buffer = |label, size, usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let params = buffer(
+            "Auto Exposure Params",
+            std::mem::size_of::<AutoExposureParams>() as u64,
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        );
+        // Starts out zeroed, which is what the histogram pass needs
+        let histogram = buffer(
+            "Luminance Histogram",
+            std::mem::size_of::<u32>() as u64 * Self::NUM_BINS,
+            wgpu::BufferUsages::STORAGE,
+        );
+        let adaptation = buffer(
+            "Adapted Luminance",
+            std::mem::size_of::<f32>() as u64,
+            wgpu::BufferUsages::STORAGE,
+        );
+
+        let storage = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("AutoExposure::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // The HDR image
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                storage(2),
+                storage(3),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("AutoExposure::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = device.create_shader_module(wgpu::include_wgsl!("shaders/auto_exposure.wgsl"));
+        let create = |label, entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module: &module,
+                entry_point,
+            })
+        };
+        let histogram_pipeline = create("AutoExposure::histogram_pipeline", "build_histogram");
+        let average_pipeline = create("AutoExposure::average_pipeline", "average_histogram");
+
+        Self {
+            settings: AutoExposureSettings::default(),
+            params,
+            histogram,
+            adaptation,
+            layout,
+            bind_groups: InputBindGroups::default(),
+            histogram_pipeline,
+            average_pipeline,
+        }
+    }
+
+    /// Holds a single `f32`: the adapted log2 luminance of the scene.
+    pub(crate) fn adaptation_buffer(&self) -> &wgpu::Buffer {
+        &self.adaptation
+    }
+
+    /// Uploads the settings, and how much time passed since the last frame for the adaptation.
+    pub(crate) fn update(&self, queue: &wgpu::Queue, dt: instant::Duration) {
+        let settings = self.settings;
+        let params = AutoExposureParams {
+            min_log_luminance: settings.min_log_luminance,
+            log_luminance_range: settings.max_log_luminance - settings.min_log_luminance,
+            low_percentile: settings.low_percentile,
+            high_percentile: settings.high_percentile,
+            speed_brighten: settings.speed_brighten,
+            speed_darken: settings.speed_darken,
+            delta_time: dt.as_secs_f32(),
+            _padding: 0,
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&[params]));
+    }
+
+    /// Forgets the bind groups made for the images [Self::compute] was handed, once the image gets
+    /// recreated, e.g. when the window resizes.
+    pub(crate) fn invalidate(&mut self) {
+        self.bind_groups.clear();
+    }
+
+    /// Records the passes that measure the HDR image `source` and adapt to it. This needs to run
+    /// after everything has been drawn into the image, and before it gets tonemapped.
+    pub(crate) fn compute(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        source: &Texture,
+    ) {
+        let bind_group = self.bind_groups.get(source, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("AutoExposure::bind_group"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&source.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: self.histogram.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: self.adaptation.as_entire_binding(),
+                    },
+                ],
+            })
+        });
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Auto Exposure Pass"),
+            timestamp_writes: None,
+        });
+        pass.set_bind_group(0, bind_group, &[]);
+        pass.set_pipeline(&self.histogram_pipeline);
+        pass.dispatch_workgroups(
+            source.size.width.div_ceil(Self::TILE_SIZE),
+            source.size.height.div_ceil(Self::TILE_SIZE),
+            1,
+        );
+        pass.set_pipeline(&self.average_pipeline);
+        pass.dispatch_workgroups(1, 1, 1);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::material_pipelines::{MaterialPass, MaterialPipelines};
+use crate::model::{AlphaMode, DrawModel, Material, Mesh, Model};
+use std::ops::Range;
+
+/// What a [DrawItem] draws.
+pub(crate) enum DrawArgs<'a> {
+    /// A range of instances, using the level of detail `lod` of the mesh.
+    Instanced { lod: usize, instances: Range<u32> },
+    /// Arguments written by the GPU, as a `wgpu::util::DrawIndexedIndirectArgs` at `offset`.
+    Indirect {
+        buffer: &'a wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+    },
+}
+
+/// A single mesh waiting to be drawn by a [DrawBatch].
+pub(crate) struct DrawItem<'a> {
+    pub(crate) pipeline: &'a wgpu::RenderPipeline,
+    pub(crate) mesh: &'a Mesh,
+    pub(crate) material: &'a Material,
+    /// The instance buffer, bound to slot 1 starting at the given offset.
+    pub(crate) instance_buffer: (&'a wgpu::Buffer, wgpu::BufferAddress),
+    /// Bound to group 3, for pipelines that need more than the material, camera and light, like the
+    /// joint palette of skinned models.
+    pub(crate) extra_bind_group: Option<&'a wgpu::BindGroup>,
+    pub(crate) args: DrawArgs<'a>,
+}
+
+/// How much work the last [DrawBatch::submit] recorded.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct BatchStats {
+    pub(crate) draws: u32,
+    /// Calls to `set_pipeline`, `set_bind_group`, `set_vertex_buffer` and `set_index_buffer`.
+    pub(crate) state_changes: u32,
+}
+
+/// # Draw Batching
+/// Every state change in a render pass costs time on the CPU, both in wgpu's validation and in the
+/// driver. Drawing model after model, each with its own pipeline, material and buffers, means
+/// binding all of them again for every single mesh, even when the previous mesh used the same ones.
+///
+/// Instead, all the draws of a pass are collected into a `DrawBatch` first. Submitting the batch
+/// sorts them by pipeline, then by material, then by mesh, so that draws sharing state end up next to
+/// each other, and then only records the calls for the state that actually changed. Meshes from the
+/// same [crate::geometry::GeometryArena] page share their buffers too, so they can be drawn one after
+/// the other without binding anything but the material.
+///
+/// Sorting changes the order the meshes are drawn in, so everything in a batch has to look the same
+/// in any order, which holds for opaque meshes with a depth test. Blended meshes are sorted after all
+/// the others, so at least the opaque ones show through them. Blended meshes that need a particular
+/// order go into an [DrawBatch::ordered] batch instead, which draws them in the order they were
+/// pushed, or into a [MaterialPass::WeightedBlended] batch, for which the order doesn't matter.
+pub(crate) struct DrawBatch<'a> {
+    items: Vec<DrawItem<'a>>,
+    sorted: bool,
+    pass: MaterialPass,
+}
+
+impl<'a> DrawBatch<'a> {
+    /// A batch that keeps the order of its draws, only skipping the binds that are redundant anyway.
+    pub(crate) fn ordered() -> Self {
+        Self {
+            items: Vec::new(),
+            sorted: false,
+            pass: MaterialPass::Forward,
+        }
+    }
+
+    /// A batch for `pass`, for which [Self::push_model] picks the pipelines of that pass.
+    pub(crate) fn for_pass(pass: MaterialPass) -> Self {
+        Self {
+            items: Vec::new(),
+            sorted: true,
+            pass,
+        }
+    }
+
+    pub(crate) fn push(&mut self, item: DrawItem<'a>) {
+        self.items.push(item);
+    }
+
+    /// Queues every mesh of `model`, with `material` instead of the meshes' own materials if there is
+    /// one, and the pipeline from `pipelines` that the material needs in the batch's pass. With
+    /// [DrawArgs::Indirect], the buffer holds one set of arguments per mesh, starting at the given
+    /// offset.
+    ///
+    /// The G-buffer can't hold blended meshes, so [MaterialPass::GBuffer] batches leave them out. They
+    /// have to be drawn in a forward pass after the lighting. [MaterialPass::Velocity] batches leave
+    /// them out too, since the pixels they are drawn in mostly show what is behind them.
+    /// [MaterialPass::DepthPrepass] batches only draw opaque meshes.
+    ///
+    /// # Skinning
+    ///
+    /// The [skinned](Mesh::skinned) meshes of the model use the pipelines and joint palette bind
+    /// group in `skinning` instead, the rest of the model is drawn with `pipelines`.
+    pub(crate) fn push_model(
+        &mut self,
+        pipelines: &'a MaterialPipelines,
+        model: &'a Model,
+        material: Option<&'a Material>,
+        instance_buffer: (&'a wgpu::Buffer, wgpu::BufferAddress),
+        skinning: Option<(&'a MaterialPipelines, &'a wgpu::BindGroup)>,
+        args: DrawArgs<'a>,
+    ) {
+        let args_size = std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>();
+        for (i, mesh) in model.meshes.iter().enumerate() {
+            let args = match &args {
+                DrawArgs::Instanced { lod, instances } => DrawArgs::Instanced {
+                    lod: *lod,
+                    instances: instances.clone(),
+                },
+                DrawArgs::Indirect { buffer, offset } => DrawArgs::Indirect {
+                    buffer,
+                    offset: offset + (i * args_size) as wgpu::BufferAddress,
+                },
+            };
+            let material = material.unwrap_or(&model.materials[mesh.material]);
+            let skipped = match self.pass {
+                MaterialPass::GBuffer | MaterialPass::Velocity => {
+                    material.alpha_mode == AlphaMode::Blend
+                }
+                MaterialPass::DepthPrepass => material.alpha_mode != AlphaMode::Opaque,
+                MaterialPass::Forward | MaterialPass::WeightedBlended | MaterialPass::Overdraw => {
+                    false
+                }
+            };
+            if skipped {
+                continue;
+            }
+            let (pipelines, extra_bind_group) = match skinning {
+                Some((skinned_pipelines, joints)) if mesh.skinned => {
+                    (skinned_pipelines, Some(joints))
+                }
+                _ => (pipelines, None),
+            };
+            self.push(DrawItem {
+                pipeline: pipelines.get(material, self.pass),
+                mesh,
+                material,
+                instance_buffer,
+                extra_bind_group,
+                args,
+            });
+        }
+    }
+
+    /// Records all the queued draws into `pass`.
+    pub(crate) fn submit(
+        mut self,
+        pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        scene_bind_group: &'a wgpu::BindGroup,
+    ) -> BatchStats {
+        if self.sorted {
+            self.items.sort_by_key(|item| {
+                (
+                    item.material.alpha_mode == AlphaMode::Blend,
+                    item.pipeline.global_id(),
+                    item.material.bind_group.global_id(),
+                    item.mesh.geometry.vertex_buffer.global_id(),
+                    std::ptr::from_ref(item.mesh),
+                )
+            });
+        }
+
+        // The camera and the scene are the same for every draw
+        pass.set_bind_group(1, camera_bind_group, &[]);
+        pass.set_bind_group(2, scene_bind_group, &[]);
+        let mut stats = BatchStats {
+            draws: 0,
+            state_changes: 2,
+        };
+
+        // What is currently bound, so that we can skip binding it again
+        let mut pipeline = None;
+        let mut material = None;
+        let mut extra_bind_group = None;
+        let mut instance_buffer = None;
+        let mut vertex_buffer = None;
+        let mut index_buffer = None;
+        for item in &self.items {
+            if pipeline != Some(item.pipeline.global_id()) {
+                pass.set_pipeline(item.pipeline);
+                pipeline = Some(item.pipeline.global_id());
+                stats.state_changes += 1;
+            }
+            if let Some(bind_group) = item.extra_bind_group {
+                if extra_bind_group != Some(bind_group.global_id()) {
+                    pass.set_bind_group(3, bind_group, &[]);
+                    extra_bind_group = Some(bind_group.global_id());
+                    stats.state_changes += 1;
+                }
+            }
+            let (buffer, offset) = item.instance_buffer;
+            if instance_buffer != Some((buffer.global_id(), offset)) {
+                pass.set_vertex_buffer(1, buffer.slice(offset..));
+                instance_buffer = Some((buffer.global_id(), offset));
+                stats.state_changes += 1;
+            }
+
+            // Morphed meshes draw every instance from its own copy of the deformed vertices, which
+            // the `DrawModel` methods already know how to do. They bind everything else again on the
+            // way.
+            let deformed = item
+                .mesh
+                .morph_targets
+                .as_ref()
+                .and_then(|morph| morph.deformed_buffer());
+            if deformed.is_some() {
+                match &item.args {
+                    DrawArgs::Instanced { lod, instances } => {
+                        pass.draw_mesh_lod(
+                            item.mesh,
+                            item.material,
+                            camera_bind_group,
+                            scene_bind_group,
+                            *lod,
+                            instances.clone(),
+                        );
+                        stats.draws += instances.len() as u32;
+                    }
+                    DrawArgs::Indirect { buffer, offset } => {
+                        pass.draw_mesh_indirect(
+                            item.mesh,
+                            item.material,
+                            camera_bind_group,
+                            scene_bind_group,
+                            buffer,
+                            *offset,
+                        );
+                        stats.draws += 1;
+                    }
+                }
+                material = Some(item.material.bind_group.global_id());
+                vertex_buffer = None;
+                index_buffer = Some(item.mesh.geometry.index_buffer.global_id());
+                stats.state_changes += 5;
+                continue;
+            }
+
+            if material != Some(item.material.bind_group.global_id()) {
+                pass.set_bind_group(0, &item.material.bind_group, &[]);
+                material = Some(item.material.bind_group.global_id());
+                stats.state_changes += 1;
+            }
+            let geometry = &item.mesh.geometry;
+            if vertex_buffer != Some(geometry.vertex_buffer.global_id()) {
+                pass.set_vertex_buffer(0, geometry.vertex_buffer.slice(..));
+                vertex_buffer = Some(geometry.vertex_buffer.global_id());
+                stats.state_changes += 1;
+            }
+            if index_buffer != Some(geometry.index_buffer.global_id()) {
+                pass.set_index_buffer(geometry.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                index_buffer = Some(geometry.index_buffer.global_id());
+                stats.state_changes += 1;
+            }
+
+            match &item.args {
+                DrawArgs::Instanced { lod, instances } => {
+                    let level = item.mesh.lod(*lod);
+                    pass.draw_indexed(level.indices.clone(), level.base_vertex, instances.clone());
+                }
+                DrawArgs::Indirect { buffer, offset } => {
+                    pass.draw_indexed_indirect(buffer, *offset)
+                }
+            }
+            stats.draws += 1;
+        }
+
+        stats
+    }
+}
@@ -0,0 +1,430 @@
+use crate::post_process::{
+    create_fullscreen_pipeline, InputBindGroups, PostContext, PostEffect, PostTarget,
+};
+use crate::texture::Texture;
+use std::collections::HashMap;
+
+/// The adjustable parts of [Bloom].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct BloomSettings {
+    /// How much of the blurred light is added to the scene.
+    pub(crate) intensity: f32,
+    /// How bright a pixel has to be to start glowing.
+    pub(crate) threshold: f32,
+    /// How far below the threshold the glow starts fading in, as a fraction of the threshold, so
+    /// that it doesn't switch on abruptly.
+    pub(crate) soft_knee: f32,
+    /// How far apart the samples of each upsample are, in texels. Larger values spread the glow
+    /// further.
+    pub(crate) radius: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            intensity: 0.1,
+            threshold: 1.0,
+            soft_knee: 0.5,
+            radius: 1.0,
+        }
+    }
+}
+
+// This has to match `BloomUniform` in bloom.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct BloomUniform {
+    threshold: f32,
+    soft_knee: f32,
+    radius: f32,
+    intensity: f32,
+}
+
+impl From<BloomSettings> for BloomUniform {
+    fn from(settings: BloomSettings) -> Self {
+        Self {
+            threshold: settings.threshold,
+            soft_knee: settings.soft_knee,
+            radius: settings.radius,
+            intensity: settings.intensity,
+        }
+    }
+}
+
+/// # Bloom
+/// Very bright light spills over onto its surroundings, both in the eye and in a camera lens. On a
+/// screen that can't show anything brighter than white, that glow is what makes a highlight read as
+/// bright rather than merely white.
+///
+/// The bright parts of the HDR image are blurred and added back onto it, before it gets tonemapped.
+/// Blurring wide enough in a single pass would take far too many samples, so the blur is built up
+/// from a chain of ever smaller textures instead, following the approach of Call of Duty: Advanced
+/// Warfare (<https://www.iryoku.com/next-generation-post-processing-in-call-of-duty-advanced-warfare>):
+/// - The prefilter pass halves the HDR image into the first texture of the chain. A soft threshold
+///   keeps only the light above [BloomSettings::threshold], and a Karis average of the samples
+///   keeps single very bright pixels from flickering as they move.
+/// - Every downsample pass halves the previous texture with a 13-tap filter, which blurs a little
+///   along the way without the blocky artifacts of plain bilinear downsampling.
+/// - The upsample passes go back up the chain, blurring each texture with a 3x3 tent filter and
+///   adding it onto the next larger one. Each level adds a wider, softer layer of glow.
+/// - Finally, the composite pass copies the HDR image to the output, adding the first texture of
+///   the chain upsampled and scaled by [BloomSettings::intensity].
+///
+/// The chain is owned by the effect, since it is smaller than the images of the
+/// [PostProcessStack](crate::post_process::PostProcessStack).
+pub(crate) struct Bloom {
+    pub(crate) settings: BloomSettings,
+    uniform: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    layout: wgpu::BindGroupLayout,
+    /// Reads the input of the effect as well as the first texture of the chain.
+    composite_layout: wgpu::BindGroupLayout,
+    composite_pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    /// Every texture is half the size of the one before it.
+    mips: Vec<Texture>,
+    /// Reads each texture of the chain.
+    mip_bind_groups: Vec<wgpu::BindGroup>,
+    /// The bind groups reading the images the effect has been handed, for the prefilter and the
+    /// composite pass.
+    input_bind_groups: InputBindGroups<[wgpu::BindGroup; 2]>,
+    prefilter_pipeline: wgpu::RenderPipeline,
+    downsample_pipeline: wgpu::RenderPipeline,
+    upsample_pipeline: wgpu::RenderPipeline,
+    /// One for every format the effect has written so far, since it writes the format it reads.
+    composite_pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl Bloom {
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    /// More levels than this hardly widen the glow any further.
+    const MAX_MIPS: u32 = 6;
+
+    /// `width` and `height` are the size of the images the effect reads.
+    pub(crate) fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Bloom Uniform"),
+            size: std::mem::size_of::<BloomUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // The filters rely on bilinear filtering to read four texels with every sample
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("bloom_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let texture = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let entries = [
+            texture(0),
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // The first texture of the chain, for the composite pass
+            texture(3),
+        ];
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("bloom_bind_group_layout"),
+            entries: &entries[..3],
+        });
+        let composite_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("bloom_composite_bind_group_layout"),
+            entries: &entries,
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/bloom.wgsl"));
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Bloom Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let composite_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Bloom Composite Pipeline Layout"),
+                bind_group_layouts: &[&composite_layout],
+                push_constant_ranges: &[],
+            });
+        let create = |label, entry_point, blend| {
+            create_fullscreen_pipeline(
+                device,
+                &pipeline_layout,
+                &shader,
+                label,
+                entry_point,
+                Self::FORMAT,
+                blend,
+            )
+        };
+        let replace = wgpu::BlendState::REPLACE;
+        let prefilter_pipeline = create("Bloom Prefilter Pipeline", "fs_prefilter", replace);
+        let downsample_pipeline = create("Bloom Downsample Pipeline", "fs_downsample", replace);
+        let upsample_pipeline = create(
+            "Bloom Upsample Pipeline",
+            "fs_upsample",
+            wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+        );
+
+        let (mips, mip_bind_groups) =
+            Self::create_mips(device, &layout, &sampler, &uniform, width, height);
+
+        Self {
+            settings: BloomSettings::default(),
+            uniform,
+            sampler,
+            layout,
+            composite_layout,
+            composite_pipeline_layout,
+            shader,
+            mips,
+            mip_bind_groups,
+            input_bind_groups: InputBindGroups::default(),
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            composite_pipelines: HashMap::new(),
+        }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform: &wgpu::Buffer,
+        view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("bloom_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    /// The chain of textures for an HDR image of `width` by `height` pixels, starting at half its
+    /// size and stopping before the textures get smaller than a pixel.
+    fn create_mips(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform: &wgpu::Buffer,
+        width: u32,
+        height: u32,
+    ) -> (Vec<Texture>, Vec<wgpu::BindGroup>) {
+        let num_mips = (width.min(height) / 2)
+            .max(1)
+            .ilog2()
+            .clamp(1, Self::MAX_MIPS);
+        let mips = (1..=num_mips)
+            .map(|level| {
+                Texture::create_2d_texture(
+                    device,
+                    (width >> level).max(1),
+                    (height >> level).max(1),
+                    Self::FORMAT,
+                    wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    wgpu::FilterMode::Linear,
+                    Some("bloom_texture"),
+                )
+            })
+            .collect::<Vec<_>>();
+        let bind_groups = mips
+            .iter()
+            .map(|mip| Self::create_bind_group(device, layout, sampler, uniform, &mip.view))
+            .collect();
+        (mips, bind_groups)
+    }
+}
+
+impl PostEffect for Bloom {
+    fn label(&self) -> &'static str {
+        "Bloom"
+    }
+
+    fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.mips, self.mip_bind_groups) = Self::create_mips(
+            device,
+            &self.layout,
+            &self.sampler,
+            &self.uniform,
+            width,
+            height,
+        );
+    }
+
+    fn invalidate(&mut self) {
+        self.input_bind_groups.clear();
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, _dt: instant::Duration) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[BloomUniform::from(self.settings)]),
+        );
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        let device = context.device;
+        let pass = |encoder: &mut wgpu::CommandEncoder,
+                    label,
+                    target: &wgpu::TextureView,
+                    load,
+                    pipeline,
+                    bind_group| {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        };
+        let clear = wgpu::LoadOp::Clear(wgpu::Color::BLACK);
+
+        let [source_bind_group, composite_bind_group] = self.input_bind_groups.get(input, || {
+            [
+                Self::create_bind_group(
+                    device,
+                    &self.layout,
+                    &self.sampler,
+                    &self.uniform,
+                    &input.view,
+                ),
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("bloom_composite_bind_group"),
+                    layout: &self.composite_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: wgpu::BindingResource::TextureView(&input.view),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: wgpu::BindingResource::Sampler(&self.sampler),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 2,
+                            resource: self.uniform.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 3,
+                            resource: wgpu::BindingResource::TextureView(&self.mips[0].view),
+                        },
+                    ],
+                }),
+            ]
+        });
+        pass(
+            context.encoder,
+            "Bloom Prefilter Pass",
+            &self.mips[0].view,
+            clear,
+            &self.prefilter_pipeline,
+            source_bind_group,
+        );
+        for i in 1..self.mips.len() {
+            pass(
+                context.encoder,
+                "Bloom Downsample Pass",
+                &self.mips[i].view,
+                clear,
+                &self.downsample_pipeline,
+                &self.mip_bind_groups[i - 1],
+            );
+        }
+        for i in (1..self.mips.len()).rev() {
+            pass(
+                context.encoder,
+                "Bloom Upsample Pass",
+                &self.mips[i - 1].view,
+                wgpu::LoadOp::Load,
+                &self.upsample_pipeline,
+                &self.mip_bind_groups[i],
+            );
+        }
+
+        let composite_pipeline = self
+            .composite_pipelines
+            .entry(output.format)
+            .or_insert_with(|| {
+                create_fullscreen_pipeline(
+                    device,
+                    &self.composite_pipeline_layout,
+                    &self.shader,
+                    "Bloom Composite Pipeline",
+                    "fs_composite",
+                    output.format,
+                    wgpu::BlendState::REPLACE,
+                )
+            });
+        pass(
+            context.encoder,
+            "Bloom Composite Pass",
+            output.view,
+            clear,
+            composite_pipeline,
+            composite_bind_group,
+        );
+    }
+}
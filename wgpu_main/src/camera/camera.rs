@@ -1,4 +1,4 @@
-use cgmath::{InnerSpace, Matrix4, Point3, Rad, Vector3};
+use cgmath::{InnerSpace, Matrix4, Point3, Rad, Vector2, Vector3};
 use std::f32::consts::FRAC_PI_2;
 
 /// A camera that can be moved and rotated, in FPS style - so we'll store the position and the yaw
@@ -60,11 +60,20 @@ impl Camera {
 }
 
 /// The projection only needs to change if the window resizes, so we'll store it separately.
+///
+/// # Jitter
+/// Temporal anti-aliasing renders every frame from a slightly different position within the
+/// pixels, so that over a few frames the pixels get sampled in many places, like they would with
+/// supersampling. Moving the camera by less than a pixel would shift near objects more than far
+/// ones, so the projection is shifted instead: the jitter is added to x and y in clip space, scaled
+/// by w so that it stays the same after the perspective divide.
 pub struct Projection {
     aspect: f32,
     fovy: Rad<f32>,
     znear: f32,
     zfar: f32,
+    /// The offset in normalized device coordinates.
+    jitter: Vector2<f32>,
 }
 
 impl Projection {
@@ -74,6 +83,7 @@ impl Projection {
             fovy: fovy.into(),
             znear,
             zfar,
+            jitter: Vector2::new(0.0, 0.0),
         }
     }
 
@@ -81,7 +91,41 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    /// The tangent of half the vertical field of view, which relates distances to sizes on screen.
+    pub(crate) fn tan_half_fovy(&self) -> f32 {
+        (self.fovy / 2.0).0.tan()
+    }
+
+    pub(crate) fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub(crate) fn zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    pub(crate) fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
+    /// Shifts the projection by `offset` pixels, for a target `width` by `height` pixels big.
+    pub(crate) fn set_jitter(&mut self, offset: Vector2<f32>, width: u32, height: u32) {
+        // Normalized device coordinates go from -1 to 1, so a pixel is 2 / size across, and y
+        // points up while pixels count down
+        self.jitter = Vector2::new(
+            2.0 * offset.x / width as f32,
+            -2.0 * offset.y / height as f32,
+        );
+    }
+
+    /// The projection matrix, shifted by the jitter.
     pub fn calc_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.jitter.extend(0.0)) * self.calc_unjittered_matrix()
+    }
+
+    /// The projection matrix without the jitter, for working out where things actually moved on the
+    /// screen.
+    pub(crate) fn calc_unjittered_matrix(&self) -> Matrix4<f32> {
         OPENGL_TO_WGPU_MATRIX * cgmath::perspective(self.fovy, self.aspect, self.znear, self.zfar)
     }
 }
@@ -94,6 +138,15 @@ pub struct CameraUniform {
     view_position: [f32; 4],
     // We can't use cgmath with bytemuck directly, so we'll convert the Matrix4 into a 4x4 f32 array.
     view_proj: [[f32; 4]; 4],
+    // For turning positions on the screen back into world positions, like the deferred lighting pass
+    // does with the depth buffer
+    inv_view_proj: [[f32; 4]; 4],
+    // For shaders that work in view space, like the light assignment of clustered shading
+    view: [[f32; 4]; 4],
+    // Without the jitter of the projection, this frame and the last, for working out how far
+    // everything moved on the screen in between
+    unjittered_view_proj: [[f32; 4]; 4],
+    previous_view_proj: [[f32; 4]; 4],
 }
 
 impl CameraUniform {
@@ -102,12 +155,62 @@ impl CameraUniform {
         Self {
             view_position: [0.0; 4],
             view_proj: cgmath::Matrix4::identity().into(),
+            inv_view_proj: cgmath::Matrix4::identity().into(),
+            view: cgmath::Matrix4::identity().into(),
+            unjittered_view_proj: cgmath::Matrix4::identity().into(),
+            previous_view_proj: cgmath::Matrix4::identity().into(),
         }
     }
 
     pub(crate) fn update_view_proj(&mut self, camera: &Camera, projection: &Projection) {
+        use cgmath::SquareMatrix;
+        self.update_view_proj_attached(camera, projection, Matrix4::identity());
+    }
+
+    pub(crate) fn view_proj(&self) -> Matrix4<f32> {
+        self.view_proj.into()
+    }
+
+    pub(crate) fn view(&self) -> Matrix4<f32> {
+        self.view.into()
+    }
+
+    pub(crate) fn view_position(&self) -> Point3<f32> {
+        Point3::from_homogeneous(self.view_position.into())
+    }
+
+    const CUT_DISTANCE: f32 = 5.0;
+    const CUT_ANGLE: cgmath::Deg<f32> = cgmath::Deg(45.0);
+
+    /// Whether the camera jumped somewhere else since `previous`, rather than moving there, so that
+    /// nothing on the screen can be found where it was in the last frame. Any camera moving further
+    /// than [Self::CUT_DISTANCE] or turning further than [Self::CUT_ANGLE] in a single frame counts.
+    pub(crate) fn is_cut_from(&self, previous: &CameraUniform) -> bool {
+        // The third row of the view matrix is the direction the camera looks away from
+        let forward = |view: Matrix4<f32>| Vector3::new(view.x.z, view.y.z, view.z.z);
+        let distance = (self.view_position() - previous.view_position()).magnitude();
+        let angle = forward(self.view()).angle(forward(previous.view()));
+        distance > Self::CUT_DISTANCE || angle > Rad::from(Self::CUT_ANGLE)
+    }
+
+    /// Like [Self::update_view_proj], but for a camera attached to a scene node with the world matrix
+    /// `parent`. The camera's position and orientation are then relative to that node, so the view
+    /// matrix has to undo the node's transform before applying the camera's own.
+    pub(crate) fn update_view_proj_attached(
+        &mut self,
+        camera: &Camera,
+        projection: &Projection,
+        parent: Matrix4<f32>,
+    ) {
+        use cgmath::SquareMatrix;
         // We're using Vector4 because of the uniforms 16byte alignment requirement
-        self.view_position = camera.position.to_homogeneous().into();
-        self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into();
+        self.view_position = (parent * camera.position.to_homogeneous()).into();
+        let view = camera.calc_matrix() * parent.invert().unwrap_or(Matrix4::identity());
+        let view_proj = projection.calc_matrix() * view;
+        self.view_proj = view_proj.into();
+        self.inv_view_proj = view_proj.invert().unwrap_or(Matrix4::identity()).into();
+        self.view = view.into();
+        self.previous_view_proj = self.unjittered_view_proj;
+        self.unjittered_view_proj = (projection.calc_unjittered_matrix() * view).into();
     }
 }
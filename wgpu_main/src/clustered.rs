@@ -0,0 +1,227 @@
+use crate::camera::{CameraUniform, Projection};
+
+/// How many clusters the view frustum is divided into, across, down and in depth. These have to
+/// match the constants of the same names in cluster.wgsl and scene.wgsl.
+pub(crate) const CLUSTERS_X: u32 = 16;
+pub(crate) const CLUSTERS_Y: u32 = 9;
+pub(crate) const CLUSTERS_Z: u32 = 24;
+const NUM_CLUSTERS: u32 = CLUSTERS_X * CLUSTERS_Y * CLUSTERS_Z;
+/// Every cluster has room for this many light indices. Lights past that are left out of it.
+pub(crate) const MAX_LIGHTS_PER_CLUSTER: u32 = 64;
+/// The light buffer is allocated once at this size, so that the bind groups never change.
+pub(crate) const MAX_POINT_LIGHTS: usize = 1024;
+
+/// A small light shining in every direction, which only reaches as far as its `radius`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct PointLight {
+    pub(crate) position: [f32; 3],
+    pub(crate) radius: f32,
+    pub(crate) color: [f32; 3],
+    pub(crate) intensity: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ClusterParams {
+    view: [[f32; 4]; 4],
+    screen_size: [f32; 2],
+    near: f32,
+    far: f32,
+    tan_half_fovy: f32,
+    aspect: f32,
+    num_lights: u32,
+    _padding: u32,
+}
+
+/// # Clustered Forward Lighting
+/// Lighting every fragment with every light gets slow quickly: with hundreds of lights, each
+/// fragment would loop over all of them, even though only a handful are close enough to matter.
+/// Deferred shading (see [crate::deferred::GBuffer]) avoids drawing surfaces more than once, but
+/// can't light blended surfaces or keep more than one sample per pixel.
+///
+/// Clustered shading keeps the forward shaders, but tells them which lights to bother with. The
+/// view frustum is divided into a grid of clusters: tiles on the screen, each cut into slices along
+/// the view direction. The slices get thicker further from the camera, so that clusters keep
+/// roughly the same shape at every depth. Every frame, a compute pass tests each light's sphere of
+/// influence against the bounds of every cluster, and writes the indices of the lights that touch
+/// it into the cluster's list. The fragment shaders then work out which cluster they are in from
+/// their position on the screen and their depth, and only loop over that cluster's lights.
+///
+/// The light lists live in the [crate::scene_bind_group::SceneBindGroup], so every shader using
+/// that group can read them. [crate::debug_view::DebugMode::LightCount] colors fragments by the
+/// number of lights in their cluster instead of lighting them.
+pub(crate) struct ClusteredLights {
+    params: wgpu::Buffer,
+    lights: wgpu::Buffer,
+    /// The number of lights in every cluster.
+    light_counts: wgpu::Buffer,
+    /// `MAX_LIGHTS_PER_CLUSTER` light indices for every cluster.
+    light_indices: wgpu::Buffer,
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+}
+
+impl ClusteredLights {
+    const WORKGROUP_SIZE: (u32, u32, u32) = (4, 4, 4);
+
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let buffer = |label, size, usage| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let index_size = std::mem::size_of::<u32>() as u64;
+        let params = buffer(
+            "Cluster Params",
+            std::mem::size_of::<ClusterParams>() as u64,
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        );
+        let lights = buffer(
+            "Point Lights",
+            (std::mem::size_of::<PointLight>() * MAX_POINT_LIGHTS) as u64,
+            wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        );
+        let light_counts = buffer(
+            "Cluster Light Counts",
+            index_size * NUM_CLUSTERS as u64,
+            wgpu::BufferUsages::STORAGE,
+        );
+        let light_indices = buffer(
+            "Cluster Light Indices",
+            index_size * (NUM_CLUSTERS * MAX_LIGHTS_PER_CLUSTER) as u64,
+            wgpu::BufferUsages::STORAGE,
+        );
+
+        let entries = Self::layout_entries(0, wgpu::ShaderStages::COMPUTE, false);
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ClusteredLights::layout"),
+            entries: &entries,
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("ClusteredLights::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = device.create_shader_module(wgpu::include_wgsl!("shaders/cluster.wgsl"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("ClusteredLights::pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "assign_lights",
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("ClusteredLights::bind_group"),
+            layout: &layout,
+            entries: &Self::entries(0, [&params, &lights, &light_counts, &light_indices]),
+        });
+
+        Self {
+            params,
+            lights,
+            light_counts,
+            light_indices,
+            pipeline,
+            bind_group,
+        }
+    }
+
+    /// The layout of the cluster buffers, starting at `first_binding`: the parameters of the grid,
+    /// the lights, and the light counts and indices of the clusters. Shaders that only read the
+    /// lists want `read_only` storage, the compute pass that writes them doesn't.
+    pub(crate) fn layout_entries(
+        first_binding: u32,
+        visibility: wgpu::ShaderStages,
+        read_only: bool,
+    ) -> [wgpu::BindGroupLayoutEntry; 4] {
+        let entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding: first_binding + binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        [
+            entry(0, wgpu::BufferBindingType::Uniform),
+            entry(1, wgpu::BufferBindingType::Storage { read_only: true }),
+            entry(2, wgpu::BufferBindingType::Storage { read_only }),
+            entry(3, wgpu::BufferBindingType::Storage { read_only }),
+        ]
+    }
+
+    /// The cluster buffers, in the order of [Self::layout_entries].
+    pub(crate) fn bind_group_entries(&self, first_binding: u32) -> [wgpu::BindGroupEntry<'_>; 4] {
+        Self::entries(
+            first_binding,
+            [
+                &self.params,
+                &self.lights,
+                &self.light_counts,
+                &self.light_indices,
+            ],
+        )
+    }
+
+    fn entries(first_binding: u32, buffers: [&wgpu::Buffer; 4]) -> [wgpu::BindGroupEntry<'_>; 4] {
+        let mut binding = first_binding;
+        buffers.map(|buffer| {
+            let entry = wgpu::BindGroupEntry {
+                binding,
+                resource: buffer.as_entire_binding(),
+            };
+            binding += 1;
+            entry
+        })
+    }
+
+    /// Uploads the `lights` and the view they are binned for. Only the first [MAX_POINT_LIGHTS]
+    /// lights are used.
+    pub(crate) fn update(
+        &self,
+        queue: &wgpu::Queue,
+        camera: &CameraUniform,
+        projection: &Projection,
+        screen_size: (u32, u32),
+        lights: &[PointLight],
+    ) {
+        let lights = &lights[..lights.len().min(MAX_POINT_LIGHTS)];
+        let params = ClusterParams {
+            view: camera.view().into(),
+            screen_size: [screen_size.0 as f32, screen_size.1 as f32],
+            near: projection.znear(),
+            far: projection.zfar(),
+            tan_half_fovy: projection.tan_half_fovy(),
+            aspect: projection.aspect(),
+            num_lights: lights.len() as u32,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&[params]));
+        if !lights.is_empty() {
+            queue.write_buffer(&self.lights, 0, bytemuck::cast_slice(lights));
+        }
+    }
+
+    /// Records the pass that assigns the lights to the clusters. This needs to run before any
+    /// render pass that shades with them.
+    pub(crate) fn assign(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Light Assignment Pass"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        let (x, y, z) = Self::WORKGROUP_SIZE;
+        pass.dispatch_workgroups(
+            CLUSTERS_X.div_ceil(x),
+            CLUSTERS_Y.div_ceil(y),
+            CLUSTERS_Z.div_ceil(z),
+        );
+    }
+}
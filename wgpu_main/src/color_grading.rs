@@ -0,0 +1,409 @@
+use crate::post_process::{
+    create_fullscreen_pipeline, InputBindGroups, PostContext, PostEffect, PostTarget,
+};
+use crate::resources::CubeLut;
+use crate::texture::Texture;
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+/// The LUTs that come with the examples, which is all the web can go through, since it has no
+/// folder to look in.
+#[cfg(target_arch = "wasm32")]
+const LUT_FILES: [&str; 3] = [
+    "luts/warm.cube",
+    "luts/teal_orange.cube",
+    "luts/contrast.cube",
+];
+
+/// The adjustable parts of [ColorGrading].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct ColorGradingSettings {
+    /// How much of the graded image to use, from 0 (none of it) to 1 (all of it).
+    pub(crate) strength: f32,
+}
+
+impl Default for ColorGradingSettings {
+    fn default() -> Self {
+        Self { strength: 1.0 }
+    }
+}
+
+// This has to match `GradingUniform` in color_grading.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct GradingUniform {
+    domain_min: [f32; 3],
+    strength: f32,
+    domain_max: [f32; 3],
+    size: f32,
+}
+
+/// # Color Grading
+/// Grading gives the image its final look, like warmer highlights or a bleach bypass, by mapping
+/// every color to another one. Artists design these mappings in tools like DaVinci Resolve and
+/// export them as a [CubeLut], a lookup table that samples the mapping over a cube of colors.
+///
+/// The table is uploaded as a 3D texture, and the grading pass looks every pixel up in it, with
+/// the hardware interpolating between the entries around the color. 1D LUTs are turned into 3D
+/// ones when they are uploaded, so the pass only has to deal with one kind. The texture holds 10
+/// bits per channel, which keeps gradients from banding, but clamps the entries between 0 and 1.
+///
+/// LUTs are made for colors the way the screen shows them, so the pass runs after tonemapping, and
+/// encodes the colors first where the texture they come from is sRGB, which decodes them when it
+/// is read. A new LUT can be swapped in at any time with [Self::set_lut], and [LutLoader] loads
+/// them in the background while the application keeps running.
+pub(crate) struct ColorGrading {
+    pub(crate) settings: ColorGradingSettings,
+    uniform: wgpu::Buffer,
+    lut: Texture,
+    lut_size: u32,
+    domain_min: [f32; 3],
+    domain_max: [f32; 3],
+    layout: wgpu::BindGroupLayout,
+    /// The bind groups reading the images the effect has been handed, along with the LUT.
+    bind_groups: InputBindGroups,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    /// One for every format the effect has written so far, since it writes the format it reads.
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl ColorGrading {
+    pub(crate) const LABEL: &'static str = "Color Grading";
+    const LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgb10a2Unorm;
+    /// How many entries along every axis a 1D LUT gets when it is turned into a 3D one.
+    const SIZE_FROM_1D: u32 = 33;
+
+    /// Starts out with a LUT that leaves every color as it is.
+    pub(crate) fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Color Grading Uniform"),
+            size: std::mem::size_of::<GradingUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("color_grading_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D3,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Color Grading Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/color_grading.wgsl"));
+
+        // Two entries along every axis interpolate to exactly the color they are looked up with
+        let identity = (0..8)
+            .map(|i| [(i & 1) as f32, ((i >> 1) & 1) as f32, (i >> 2) as f32])
+            .collect::<Vec<_>>();
+
+        Self {
+            settings: ColorGradingSettings::default(),
+            uniform,
+            lut: Self::create_lut_texture(device, queue, 2, &identity),
+            lut_size: 2,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+            layout,
+            bind_groups: InputBindGroups::default(),
+            pipeline_layout,
+            shader,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    fn create_lut_texture(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        size: u32,
+        data: &[[f32; 3]],
+    ) -> Texture {
+        let lut = Texture::create_3d_texture(
+            device,
+            size,
+            Self::LUT_FORMAT,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            wgpu::FilterMode::Linear,
+            Some("color_grading_lut_texture"),
+        );
+        // Red, green and blue get 10 bits each from the bottom up, leaving 2 for alpha
+        let packed = data
+            .iter()
+            .map(|color| {
+                let [r, g, b] = color.map(|c| (c.clamp(0.0, 1.0) * 1023.0).round() as u32);
+                r | (g << 10) | (b << 20) | (3 << 30)
+            })
+            .collect::<Vec<_>>();
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &lut.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&packed),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(size * std::mem::size_of::<u32>() as u32),
+                rows_per_image: Some(size),
+            },
+            lut.size,
+        );
+        lut
+    }
+
+    /// Grades with `lut` from the next frame on.
+    pub(crate) fn set_lut(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, lut: &CubeLut) {
+        let (size, data) = lut.to_3d(Self::SIZE_FROM_1D);
+        self.lut = Self::create_lut_texture(device, queue, size, &data);
+        self.lut_size = size;
+        self.domain_min = lut.domain_min;
+        self.domain_max = lut.domain_max;
+        self.bind_groups.clear();
+    }
+}
+
+impl PostEffect for ColorGrading {
+    fn label(&self) -> &'static str {
+        Self::LABEL
+    }
+
+    fn invalidate(&mut self) {
+        self.bind_groups.clear();
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, _dt: instant::Duration) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[GradingUniform {
+                domain_min: self.domain_min,
+                strength: self.settings.strength,
+                domain_max: self.domain_max,
+                size: self.lut_size as f32,
+            }]),
+        );
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        let device = context.device;
+        let bind_group = self.bind_groups.get(input, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("color_grading_bind_group"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniform.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&input.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&self.lut.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&self.lut.sampler),
+                    },
+                ],
+            })
+        });
+        // The effect writes the format it reads, so the output tells whether the input is sRGB
+        let pipeline = self.pipelines.entry(output.format).or_insert_with(|| {
+            create_fullscreen_pipeline(
+                context.device,
+                &self.pipeline_layout,
+                &self.shader,
+                "Color Grading Pipeline",
+                if output.format.is_srgb() {
+                    "fs_srgb"
+                } else {
+                    "fs_main"
+                },
+                output.format,
+                wgpu::BlendState::REPLACE,
+            )
+        });
+
+        let mut render_pass = context
+            .encoder
+            .begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Color Grading Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: output.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+/// Loads LUTs in the background, so that they can be swapped while the application is running
+/// without waiting for the file. Every LUT that finished loading comes out of [Self::poll] along
+/// with the name it was loaded from.
+///
+/// Natively, the LUTs are the `.cube` files in the folder named by the `LUT_DIR` environment
+/// variable, or else in `models/luts` of the source tree, read as they are on disk rather than from
+/// the copies the build makes. The LUT loaded last is loaded again whenever its file changes, so a
+/// LUT can be tweaked in a grading tool while the application shows the result. The web has no
+/// folder to look through or files to watch, so it only goes through the LUTs that come with the
+/// examples.
+pub(crate) struct LutLoader {
+    sender: mpsc::Sender<(String, anyhow::Result<CubeLut>)>,
+    receiver: mpsc::Receiver<(String, anyhow::Result<CubeLut>)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    dir: std::path::PathBuf,
+    /// The file loaded last, and when it was modified then.
+    #[cfg(not(target_arch = "wasm32"))]
+    watched: Option<(String, Option<std::time::SystemTime>)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    last_check: instant::Instant,
+}
+
+impl LutLoader {
+    /// How often the watched file is checked for changes.
+    #[cfg(not(target_arch = "wasm32"))]
+    const CHECK_INTERVAL: instant::Duration = instant::Duration::from_millis(500);
+
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                Self { sender, receiver }
+            } else {
+                let dir = std::env::var_os("LUT_DIR").map_or_else(
+                    || std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("models/luts"),
+                    std::path::PathBuf::from,
+                );
+                Self {
+                    sender,
+                    receiver,
+                    dir,
+                    watched: None,
+                    last_check: instant::Instant::now(),
+                }
+            }
+        }
+    }
+
+    /// The names of the LUTs there are to load, in alphabetical order. The folder is looked
+    /// through again every time, so LUTs that were added in the meantime are in there.
+    pub(crate) fn files(&self) -> Vec<String> {
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                LUT_FILES.map(String::from).to_vec()
+            } else {
+                let entries = match std::fs::read_dir(&self.dir) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        log::error!("Couldn't look for LUTs in {}: {e}", self.dir.display());
+                        return Vec::new();
+                    }
+                };
+                let mut files = entries
+                    .filter_map(|entry| {
+                        let name = entry.ok()?.file_name().into_string().ok()?;
+                        name.ends_with(".cube").then_some(name)
+                    })
+                    .collect::<Vec<_>>();
+                files.sort();
+                files
+            }
+        }
+    }
+
+    /// Starts loading `file_name`, one of [Self::files], reading it again even if it was loaded
+    /// before, so that a file that was replaced in the meantime comes out as it is now.
+    pub(crate) fn load(&mut self, file_name: &str) {
+        let sender = self.sender.clone();
+        let file_name = file_name.to_string();
+        cfg_if::cfg_if! {
+            if #[cfg(target_arch = "wasm32")] {
+                wasm_bindgen_futures::spawn_local(async move {
+                    let lut = crate::resources::load_cube_lut(&file_name).await;
+                    // Nobody is waiting for it anymore if the loader is gone
+                    let _ = sender.send((file_name, lut));
+                });
+            } else {
+                let path = self.dir.join(&file_name);
+                self.watched = Some((file_name.clone(), Self::modified(&path)));
+                tokio::spawn(async move {
+                    let lut = crate::resources::read_cube_lut(&path).await;
+                    let _ = sender.send((file_name, lut));
+                });
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn modified(path: &std::path::Path) -> Option<std::time::SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// The next LUT that finished loading, if there is one. Also starts loading the watched LUT
+    /// again if its file changed.
+    pub(crate) fn poll(&mut self) -> Option<(String, anyhow::Result<CubeLut>)> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.last_check.elapsed() >= Self::CHECK_INTERVAL {
+            self.last_check = instant::Instant::now();
+            if let Some((file_name, modified)) = &self.watched {
+                if Self::modified(&self.dir.join(file_name)) != *modified {
+                    log::info!("{file_name} changed, loading it again");
+                    self.load(&file_name.clone());
+                }
+            }
+        }
+        self.receiver.try_recv().ok()
+    }
+}
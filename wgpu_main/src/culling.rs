@@ -0,0 +1,425 @@
+use cgmath::{
+    EuclideanSpace, InnerSpace, Matrix4, MetricSpace, Point3, Transform as _, Vector3, Vector4,
+};
+
+/// An axis-aligned bounding box: the smallest box with faces parallel to the coordinate planes
+/// that contains every vertex of a mesh.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Aabb {
+    pub(crate) min: Point3<f32>,
+    pub(crate) max: Point3<f32>,
+}
+
+impl Aabb {
+    /// An empty iterator gives a box of zero size at the origin.
+    pub(crate) fn from_points(points: impl IntoIterator<Item = [f32; 3]>) -> Self {
+        let mut points = points.into_iter().map(Point3::from);
+        let Some(first) = points.next() else {
+            return Self {
+                min: Point3::origin(),
+                max: Point3::origin(),
+            };
+        };
+        points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |aabb, p| Self {
+                min: Point3::new(
+                    aabb.min.x.min(p.x),
+                    aabb.min.y.min(p.y),
+                    aabb.min.z.min(p.z),
+                ),
+                max: Point3::new(
+                    aabb.max.x.max(p.x),
+                    aabb.max.y.max(p.y),
+                    aabb.max.z.max(p.z),
+                ),
+            },
+        )
+    }
+
+    pub(crate) fn union(&self, other: &Aabb) -> Self {
+        Self::from_points([
+            self.min.into(),
+            self.max.into(),
+            other.min.into(),
+            other.max.into(),
+        ])
+    }
+
+    pub(crate) fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    /// The box containing this box after it has been transformed by `matrix`. Rather than
+    /// transforming all eight corners, we project the half extents onto each world axis, which gives
+    /// the same result for a fraction of the work.
+    pub(crate) fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+        let center = matrix.transform_point(self.center());
+        let half = (self.max - self.min) * 0.5;
+        let extent = |row: usize| {
+            matrix.x[row].abs() * half.x
+                + matrix.y[row].abs() * half.y
+                + matrix.z[row].abs() * half.z
+        };
+        let extents = Vector3::new(extent(0), extent(1), extent(2));
+        Self {
+            min: center - extents,
+            max: center + extents,
+        }
+    }
+}
+
+/// A sphere containing every vertex of a mesh. Testing a sphere against a plane is a single dot
+/// product, so it makes for a cheap first test before the tighter [Aabb].
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct BoundingSphere {
+    pub(crate) center: Point3<f32>,
+    pub(crate) radius: f32,
+}
+
+impl BoundingSphere {
+    /// The sphere is centered on the bounding box of the points, which isn't the smallest sphere
+    /// possible, but it is close enough and cheap to compute.
+    pub(crate) fn from_points(points: &[[f32; 3]], aabb: &Aabb) -> Self {
+        let center = aabb.center();
+        let radius = points
+            .iter()
+            .map(|&p| center.distance(Point3::from(p)))
+            .fold(0.0, f32::max);
+        Self { center, radius }
+    }
+
+    /// The sphere containing this sphere after it has been transformed by `matrix`. With a
+    /// non-uniform scale the sphere becomes an ellipsoid, so we use the largest of the three scales.
+    pub(crate) fn transform(&self, matrix: &Matrix4<f32>) -> Self {
+        let scale = matrix
+            .x
+            .truncate()
+            .magnitude()
+            .max(matrix.y.truncate().magnitude())
+            .max(matrix.z.truncate().magnitude());
+        Self {
+            center: matrix.transform_point(self.center),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+/// # Bounding Volumes
+/// To decide whether an object can be seen at all, we don't want to look at every single one of its
+/// triangles. Instead we wrap the object in a simple shape that is guaranteed to contain all of it,
+/// and test that shape. If the shape is outside the view, so is the object.
+///
+/// Bounding volumes are computed once when a model is loaded, in the model's own space. Skinned and
+/// morphed meshes get the bounds of their rest pose, so animations that move vertices far away from
+/// it may get culled too early.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Bounds {
+    pub(crate) aabb: Aabb,
+    pub(crate) sphere: BoundingSphere,
+}
+
+impl Bounds {
+    pub(crate) fn from_positions(positions: &[[f32; 3]]) -> Self {
+        let aabb = Aabb::from_points(positions.iter().copied());
+        Self {
+            aabb,
+            sphere: BoundingSphere::from_points(positions, &aabb),
+        }
+    }
+
+    /// The bounds containing all of `bounds`, such as the meshes of a model.
+    pub(crate) fn union<'a>(bounds: impl IntoIterator<Item = &'a Bounds>) -> Self {
+        let bounds = bounds.into_iter().collect::<Vec<_>>();
+        let Some(aabb) = bounds.iter().map(|b| b.aabb).reduce(|a, b| a.union(&b)) else {
+            return Self::from_positions(&[]);
+        };
+        let center = aabb.center();
+        let radius = bounds
+            .iter()
+            .map(|b| center.distance(b.sphere.center) + b.sphere.radius)
+            .fold(0.0, f32::max);
+        Self {
+            aabb,
+            sphere: BoundingSphere { center, radius },
+        }
+    }
+}
+
+/// # Frustum Culling
+/// The camera can only see what is inside its view frustum: the truncated pyramid between the near
+/// and far planes. Anything outside of it gets clipped by the GPU anyway, but only after all of its
+/// vertices have gone through the vertex shader. Frustum culling skips those objects on the CPU,
+/// before they are ever submitted.
+///
+/// The six planes can be read straight out of the view projection matrix. A point is inside the
+/// frustum when its clip space coordinates satisfy `-w <= x <= w`, `-w <= y <= w` and `0 <= z <= w`
+/// (wgpu uses a depth range of 0 to 1). Each of those inequalities is a plane equation in terms of
+/// the rows of the matrix, e.g. `x <= w` becomes `(row3 - row0) · p >= 0`.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Frustum {
+    /// `(normal, distance)`, with the normals pointing into the frustum.
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub(crate) fn from_matrix(view_proj: Matrix4<f32>) -> Self {
+        // cgmath matrices are column major, so we need to gather the rows ourselves
+        let row = |i: usize| {
+            Vector4::new(
+                view_proj.x[i],
+                view_proj.y[i],
+                view_proj.z[i],
+                view_proj.w[i],
+            )
+        };
+        let (r0, r1, r2, r3) = (row(0), row(1), row(2), row(3));
+        let normalize = |plane: Vector4<f32>| plane / plane.truncate().magnitude();
+        Self {
+            planes: [
+                normalize(r3 + r0), // Left
+                normalize(r3 - r0), // Right
+                normalize(r3 + r1), // Bottom
+                normalize(r3 - r1), // Top
+                normalize(r2),      // Near
+                normalize(r3 - r2), // Far
+            ],
+        }
+    }
+
+    /// The planes as `(normal, distance)`, for uploading to the GPU.
+    pub(crate) fn planes(&self) -> [[f32; 4]; 6] {
+        self.planes.map(Into::into)
+    }
+
+    fn distance(plane: &Vector4<f32>, point: Point3<f32>) -> f32 {
+        plane.truncate().dot(point.to_vec()) + plane.w
+    }
+
+    pub(crate) fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| Self::distance(plane, sphere.center) >= -sphere.radius)
+    }
+
+    /// For every plane, we only need to test the corner of the box that is furthest along the plane's
+    /// normal. If even that corner is behind the plane, the whole box is.
+    pub(crate) fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = Point3::new(
+                if plane.x >= 0.0 {
+                    aabb.max.x
+                } else {
+                    aabb.min.x
+                },
+                if plane.y >= 0.0 {
+                    aabb.max.y
+                } else {
+                    aabb.min.y
+                },
+                if plane.z >= 0.0 {
+                    aabb.max.z
+                } else {
+                    aabb.min.z
+                },
+            );
+            Self::distance(plane, corner) >= 0.0
+        })
+    }
+
+    /// Whether an object with the given model space bounds, placed in the world by `model`, may be
+    /// visible. The sphere test rejects most objects cheaply; the box test catches the ones whose
+    /// sphere pokes into the frustum while the object itself doesn't.
+    pub(crate) fn is_visible(&self, bounds: &Bounds, model: &Matrix4<f32>) -> bool {
+        self.intersects_sphere(&bounds.sphere.transform(model))
+            && self.intersects_aabb(&bounds.aabb.transform(model))
+    }
+}
+
+/// How many instances the last culling pass kept and how many it threw away.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct CullStats {
+    pub(crate) visible: u32,
+    pub(crate) culled: u32,
+}
+
+impl CullStats {
+    pub(crate) fn record(&mut self, visible: bool) {
+        if visible {
+            self.visible += 1;
+        } else {
+            self.culled += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Matrix3, SquareMatrix};
+
+    /// A camera at the origin looking down -z, with a field of view of 90 degrees, so that the
+    /// frustum is as wide and as high as it is far away, from 1 to 100.
+    fn frustum() -> Frustum {
+        // From the depth range of OpenGL, -1 to 1, to the one of wgpu
+        let depth = Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.5))
+            * Matrix4::from_nonuniform_scale(1.0, 1.0, 0.5);
+        Frustum::from_matrix(depth * cgmath::perspective(Deg(90.0), 1.0, 1.0, 100.0))
+    }
+
+    fn aabb(min: [f32; 3], max: [f32; 3]) -> Aabb {
+        Aabb {
+            min: min.into(),
+            max: max.into(),
+        }
+    }
+
+    fn bounds(aabb: Aabb) -> Bounds {
+        let corners = (0..8).map(|i| {
+            let pick = |bit: u32, min: f32, max: f32| if i & bit == 0 { min } else { max };
+            [
+                pick(1, aabb.min.x, aabb.max.x),
+                pick(2, aabb.min.y, aabb.max.y),
+                pick(4, aabb.min.z, aabb.max.z),
+            ]
+        });
+        Bounds::from_positions(&corners.collect::<Vec<_>>())
+    }
+
+    fn assert_close(a: Point3<f32>, b: Point3<f32>) {
+        assert!(a.distance(b) < 1e-4, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn boxes_inside_are_visible() {
+        let frustum = frustum();
+        for aabb in [
+            aabb([-1.0, -1.0, -11.0], [1.0, 1.0, -9.0]),
+            aabb([-40.0, -40.0, -90.0], [40.0, 40.0, -50.0]),
+            aabb([4.0, 3.0, -6.0], [5.0, 4.0, -5.5]),
+        ] {
+            assert!(frustum.intersects_aabb(&aabb), "{aabb:?}");
+            assert!(frustum.is_visible(&bounds(aabb), &Matrix4::identity()));
+        }
+    }
+
+    #[test]
+    fn boxes_outside_are_culled() {
+        let frustum = frustum();
+        for aabb in [
+            // Behind the camera
+            aabb([-1.0, -1.0, 2.0], [1.0, 1.0, 4.0]),
+            // Between the camera and the near plane
+            aabb([-0.1, -0.1, -0.9], [0.1, 0.1, -0.5]),
+            // Past the far plane
+            aabb([-1.0, -1.0, -120.0], [1.0, 1.0, -101.0]),
+            // Off to the left, right, bottom and top
+            aabb([-20.0, -1.0, -11.0], [-12.0, 1.0, -9.0]),
+            aabb([12.0, -1.0, -11.0], [20.0, 1.0, -9.0]),
+            aabb([-1.0, -20.0, -11.0], [1.0, -12.0, -9.0]),
+            aabb([-1.0, 12.0, -11.0], [1.0, 20.0, -9.0]),
+        ] {
+            assert!(
+                !frustum.is_visible(&bounds(aabb), &Matrix4::identity()),
+                "{aabb:?}"
+            );
+            assert!(!frustum.intersects_aabb(&aabb), "{aabb:?}");
+        }
+    }
+
+    #[test]
+    fn boxes_straddling_a_plane_are_visible() {
+        let frustum = frustum();
+        for aabb in [
+            // Across the left and the right plane, which are 10 to the side at this distance
+            aabb([-12.0, -1.0, -11.0], [-8.0, 1.0, -9.0]),
+            aabb([8.0, -1.0, -11.0], [12.0, 1.0, -9.0]),
+            // Across the near and the far plane
+            aabb([-0.5, -0.5, -2.0], [0.5, 0.5, 2.0]),
+            aabb([-1.0, -1.0, -110.0], [1.0, 1.0, -90.0]),
+            // Around the whole frustum
+            aabb([-200.0, -200.0, -200.0], [200.0, 200.0, 200.0]),
+        ] {
+            assert!(frustum.intersects_aabb(&aabb), "{aabb:?}");
+            assert!(frustum.is_visible(&bounds(aabb), &Matrix4::identity()));
+        }
+    }
+
+    #[test]
+    fn the_sphere_alone_doesnt_cull_the_corners_of_the_frustum() {
+        // The sphere around this long, thin box pokes into the frustum, but the box doesn't
+        let aabb = aabb([-40.0, 11.0, -10.5], [9.0, 12.0, -9.5]);
+        let bounds = bounds(aabb);
+        let frustum = frustum();
+        assert!(frustum.intersects_sphere(&bounds.sphere));
+        assert!(!frustum.is_visible(&bounds, &Matrix4::identity()));
+    }
+
+    #[test]
+    fn transforming_with_a_rotation() {
+        let unit = aabb([-1.0, -1.0, -1.0], [1.0, 1.0, 1.0]);
+        // Turned by 45 degrees around y, the corners stick out by the diagonal
+        let rotated = unit.transform(&Matrix4::from_angle_y(Deg(45.0)));
+        let diagonal = 2.0f32.sqrt();
+        assert_close(rotated.min, Point3::new(-diagonal, -1.0, -diagonal));
+        assert_close(rotated.max, Point3::new(diagonal, 1.0, diagonal));
+
+        // A quarter turn of a box that isn't centered swaps the axes and moves the center along
+        let offset = aabb([1.0, 0.0, 0.0], [3.0, 1.0, 1.0]);
+        let rotated = offset.transform(&Matrix4::from_angle_z(Deg(90.0)));
+        assert_close(rotated.min, Point3::new(-1.0, 1.0, 0.0));
+        assert_close(rotated.max, Point3::new(0.0, 3.0, 1.0));
+    }
+
+    #[test]
+    fn transforming_with_a_scale_and_translation() {
+        let model = Matrix4::from_translation(Vector3::new(5.0, 0.0, -10.0))
+            * Matrix4::from_nonuniform_scale(2.0, 3.0, -4.0);
+        let transformed = aabb([-1.0, 0.0, 1.0], [1.0, 2.0, 2.0]).transform(&model);
+        // A negative scale mirrors the box, which swaps which of its ends is the minimum
+        assert_close(transformed.min, Point3::new(3.0, 0.0, -18.0));
+        assert_close(transformed.max, Point3::new(7.0, 6.0, -14.0));
+    }
+
+    #[test]
+    fn transforming_contains_every_transformed_corner() {
+        let aabb = aabb([-1.0, -2.0, 0.5], [3.0, 1.0, 2.0]);
+        let model = Matrix4::from_translation(Vector3::new(1.0, -2.0, 3.0))
+            * Matrix4::from(Matrix3::from_angle_x(Deg(30.0)) * Matrix3::from_angle_y(Deg(70.0)))
+            * Matrix4::from_nonuniform_scale(0.5, 2.0, 1.5);
+        let transformed = aabb.transform(&model);
+        let corners = bounds(aabb).aabb;
+        for i in 0..8 {
+            let pick = |bit: u32, min: f32, max: f32| if i & bit == 0 { min } else { max };
+            let corner = model.transform_point(Point3::new(
+                pick(1, corners.min.x, corners.max.x),
+                pick(2, corners.min.y, corners.max.y),
+                pick(4, corners.min.z, corners.max.z),
+            ));
+            for axis in 0..3 {
+                assert!(transformed.min[axis] <= corner[axis] + 1e-4, "{corner:?}");
+                assert!(transformed.max[axis] >= corner[axis] - 1e-4, "{corner:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn world_matrices_move_boxes_in_and_out_of_view() {
+        let frustum = frustum();
+        let bounds = bounds(aabb([-0.5, -0.5, -0.5], [0.5, 0.5, 0.5]));
+        let at = |x| Matrix4::from_translation(Vector3::new(x, 0.0, -10.0));
+        assert!(frustum.is_visible(&bounds, &at(0.0)));
+        assert!(!frustum.is_visible(&bounds, &at(-12.0)));
+        // Stretched along x, it reaches back into view
+        let stretched = at(-12.0) * Matrix4::from_nonuniform_scale(6.0, 1.0, 1.0);
+        assert!(frustum.is_visible(&bounds, &stretched));
+        // Turned on its side, it points up instead, out of view again
+        let turned = at(-12.0)
+            * Matrix4::from_angle_z(Deg(90.0))
+            * Matrix4::from_nonuniform_scale(6.0, 1.0, 1.0);
+        assert!(!frustum.is_visible(&bounds, &turned));
+    }
+}
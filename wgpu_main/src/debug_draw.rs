@@ -0,0 +1,376 @@
+use crate::culling::Aabb;
+use crate::texture::Texture;
+use cgmath::{EuclideanSpace, Matrix4, Point3, SquareMatrix, Transform, Vector3};
+use std::f32::consts::TAU;
+use std::mem;
+
+// This has to match `VertexInput` in debug_draw.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 3],
+}
+
+impl DebugVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<DebugVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+            ],
+        }
+    }
+}
+
+/// The lines [DebugDraw] draws in one way, gathered over a frame. Every shape is made of
+/// straight lines in world space, two vertices each.
+#[derive(Debug, Default)]
+pub(crate) struct DebugLines {
+    vertices: Vec<DebugVertex>,
+}
+
+impl DebugLines {
+    /// How many lines make up a circle of [Self::sphere].
+    const CIRCLE_SEGMENTS: usize = 32;
+
+    pub(crate) fn line(&mut self, a: Point3<f32>, b: Point3<f32>, color: [f32; 3]) {
+        self.vertices.push(DebugVertex {
+            position: a.into(),
+            color,
+        });
+        self.vertices.push(DebugVertex {
+            position: b.into(),
+            color,
+        });
+    }
+
+    /// The edges of a box in model space, such as [crate::culling::Bounds::aabb], placed in the
+    /// world by `model`; the identity for a box that is already in world space. It stays a box,
+    /// but rotates along with the model.
+    pub(crate) fn aabb(&mut self, aabb: &Aabb, model: &Matrix4<f32>, color: [f32; 3]) {
+        // Bit 0 of the index picks the x of the corner, bit 1 the y and bit 2 the z
+        let corners: [Point3<f32>; 8] = std::array::from_fn(|i| {
+            model.transform_point(Point3::new(
+                if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+                if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+                if i & 4 == 0 { aabb.min.z } else { aabb.max.z },
+            ))
+        });
+        self.box_edges(&corners, color);
+    }
+
+    /// Three circles around `center`, one in each of the planes between the axes.
+    pub(crate) fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 3]) {
+        let axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+        for i in 0..3 {
+            let (u, v) = (axes[i] * radius, axes[(i + 1) % 3] * radius);
+            let point = |segment: usize| {
+                let angle = segment as f32 / Self::CIRCLE_SEGMENTS as f32 * TAU;
+                center + u * angle.cos() + v * angle.sin()
+            };
+            for segment in 0..Self::CIRCLE_SEGMENTS {
+                self.line(point(segment), point(segment + 1), color);
+            }
+        }
+    }
+
+    /// The axes of `transform`, `length` long, with x in red, y in green and z in blue.
+    pub(crate) fn axes(&mut self, transform: &Matrix4<f32>, length: f32) {
+        let origin = transform.transform_point(Point3::origin());
+        let colors = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        for (axis, color) in [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()]
+            .into_iter()
+            .zip(colors)
+        {
+            let end = transform.transform_point(Point3::from_vec(axis * length));
+            self.line(origin, end, color);
+        }
+    }
+
+    /// The edges of the volume a camera with `view_proj` sees, from the near to the far plane.
+    pub(crate) fn frustum(&mut self, view_proj: &Matrix4<f32>, color: [f32; 3]) {
+        let Some(inv_view_proj) = view_proj.invert() else {
+            return;
+        };
+        // The corners of clip space, where depth goes from 0 at the near plane to 1 at the far one
+        let corners: [Point3<f32>; 8] = std::array::from_fn(|i| {
+            inv_view_proj.transform_point(Point3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { 0.0 } else { 1.0 },
+            ))
+        });
+        self.box_edges(&corners, color);
+    }
+
+    /// A square grid on the plane through `center` along x and z, `cells` cells of `spacing` world
+    /// units across.
+    pub(crate) fn grid(&mut self, center: Point3<f32>, cells: u32, spacing: f32, color: [f32; 3]) {
+        let half = cells as f32 * spacing * 0.5;
+        for i in 0..=cells {
+            let offset = i as f32 * spacing - half;
+            self.line(
+                center + Vector3::new(offset, 0.0, -half),
+                center + Vector3::new(offset, 0.0, half),
+                color,
+            );
+            self.line(
+                center + Vector3::new(-half, 0.0, offset),
+                center + Vector3::new(half, 0.0, offset),
+                color,
+            );
+        }
+    }
+
+    /// The 12 edges between `corners`, indexed the way [Self::aabb] does.
+    fn box_edges(&mut self, corners: &[Point3<f32>; 8], color: [f32; 3]) {
+        for i in 0..8 {
+            // Every edge joins two corners whose indices differ in a single bit
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.line(corners[i], corners[i | bit], color);
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> u32 {
+        self.vertices.len() as u32
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.vertices.clear();
+    }
+}
+
+/// # Debug Draw
+/// Light positions, bounding boxes or the frustum of a camera are hard to check by looking at the
+/// lit image alone. `DebugDraw` gives the rest of the application an immediate mode way to see
+/// them: anything can add shapes to [Self::depth_tested] or [Self::on_top] while updating, and
+/// [Self::render] draws them over the scene and throws them away, so that every frame only shows
+/// what was added for it.
+///
+/// The shapes are all made of lines, which are uploaded into a single vertex buffer that grows
+/// when a frame has more of them than it holds. The depth tested lines hide behind the surfaces in
+/// front of them; the others are drawn on top of everything. Both are drawn into the HDR target
+/// before post-processing, so that they get the same exposure as the scene. They don't write any
+/// velocity, so temporal anti-aliasing smears lines that move across the screen.
+pub(crate) struct DebugDraw {
+    /// Lines hidden by the surfaces in front of them.
+    pub(crate) depth_tested: DebugLines,
+    /// Lines drawn over everything, such as markers that must not get lost inside a mesh.
+    pub(crate) on_top: DebugLines,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    depth_tested_pipeline: wgpu::RenderPipeline,
+    on_top_pipeline: wgpu::RenderPipeline,
+}
+
+impl DebugDraw {
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        camera_layout: &wgpu::BindGroupLayout,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/debug_draw.wgsl"));
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug Draw Pipeline Layout"),
+            bind_group_layouts: &[camera_layout],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |label, depth_compare| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[DebugVertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    ..Default::default()
+                },
+                // The lines never hide anything themselves
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let depth_tested_pipeline = create_pipeline(
+            "Debug Draw Depth Tested Pipeline",
+            wgpu::CompareFunction::LessEqual,
+        );
+        let on_top_pipeline =
+            create_pipeline("Debug Draw On Top Pipeline", wgpu::CompareFunction::Always);
+
+        let capacity = 1024;
+        Self {
+            depth_tested: DebugLines::default(),
+            on_top: DebugLines::default(),
+            buffer: Self::create_buffer(device, capacity),
+            capacity,
+            depth_tested_pipeline,
+            on_top_pipeline,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Draw Vertex Buffer"),
+            size: (capacity * mem::size_of::<DebugVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Draws the lines gathered since the last call into `view`, testing them against `depth`
+    /// where they should be, and clears them for the next frame.
+    pub(crate) fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        let depth_tested = 0..self.depth_tested.len();
+        let on_top = depth_tested.end..depth_tested.end + self.on_top.len();
+        if on_top.end == 0 {
+            return;
+        }
+
+        // Both kinds share the buffer, one after the other
+        if on_top.end as usize > self.capacity {
+            self.capacity = (on_top.end as usize).next_power_of_two();
+            self.buffer = Self::create_buffer(device, self.capacity);
+        }
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&self.depth_tested.vertices),
+        );
+        queue.write_buffer(
+            &self.buffer,
+            (depth_tested.end as usize * mem::size_of::<DebugVertex>()) as wgpu::BufferAddress,
+            bytemuck::cast_slice(&self.on_top.vertices),
+        );
+        self.depth_tested.clear();
+        self.on_top.clear();
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Debug Draw Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        if !depth_tested.is_empty() {
+            render_pass.set_pipeline(&self.depth_tested_pipeline);
+            render_pass.draw(depth_tested, 0..1);
+        }
+        if !on_top.is_empty() {
+            render_pass.set_pipeline(&self.on_top_pipeline);
+            render_pass.draw(on_top, 0..1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Deg;
+
+    const WHITE: [f32; 3] = [1.0; 3];
+
+    #[test]
+    fn counts_two_vertices_per_line() {
+        let mut lines = DebugLines::default();
+        let aabb = Aabb {
+            min: Point3::new(-1.0, -1.0, -1.0),
+            max: Point3::new(1.0, 1.0, 1.0),
+        };
+        lines.aabb(&aabb, &Matrix4::identity(), WHITE);
+        assert_eq!(lines.len(), 24);
+
+        lines.clear();
+        lines.sphere(Point3::origin(), 1.0, WHITE);
+        assert_eq!(lines.len(), 2 * 3 * 32);
+
+        lines.clear();
+        lines.grid(Point3::origin(), 10, 1.0, WHITE);
+        assert_eq!(lines.len(), 4 * 11);
+    }
+
+    #[test]
+    fn frustum_corners_lie_on_the_near_and_far_planes() {
+        // A camera at the origin looking down -z, from 1 to 100, with the depth range of wgpu
+        let depth = Matrix4::from_translation(Vector3::new(0.0, 0.0, 0.5))
+            * Matrix4::from_nonuniform_scale(1.0, 1.0, 0.5);
+        let view_proj = depth * cgmath::perspective(Deg(90.0), 1.0, 1.0, 100.0);
+
+        let mut lines = DebugLines::default();
+        lines.frustum(&view_proj, WHITE);
+        assert_eq!(lines.len(), 24);
+
+        let (mut near, mut far) = (0, 0);
+        for vertex in &lines.vertices {
+            let [x, y, z] = vertex.position;
+            // With a field of view of 90 degrees the frustum is as wide as it is far away
+            assert!((x.abs() - -z).abs() < 1e-3 * -z, "{:?}", vertex.position);
+            assert!((y.abs() - -z).abs() < 1e-3 * -z, "{:?}", vertex.position);
+            if (z + 1.0).abs() < 1e-3 {
+                near += 1;
+            } else if (z + 100.0).abs() < 1e-1 {
+                far += 1;
+            } else {
+                panic!("{:?} is on neither plane", vertex.position);
+            }
+        }
+        // Four edges on each plane, and four joining them
+        assert_eq!((near, far), (12, 12));
+    }
+}
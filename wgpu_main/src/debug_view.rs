@@ -0,0 +1,133 @@
+use wgpu::util::DeviceExt;
+
+/// What the model shaders draw instead of the lit scene. The numbers have to match the `DEBUG_`
+/// constants in scene.wgsl.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DebugMode {
+    /// The lit scene.
+    #[default]
+    Off = 0,
+    /// The normals in world space, with the normal map applied, from -1..1 to 0..1.
+    WorldNormals = 1,
+    /// The normal map as it is sampled, in tangent space.
+    NormalMap = 2,
+    /// The tangents in world space.
+    Tangents = 3,
+    /// The bitangents in world space.
+    Bitangents = 4,
+    /// The texture coordinates in red and green, over a checker pattern of an eighth of the texture
+    /// that shows how it is stretched.
+    Uvs = 5,
+    /// The color of the material, without any light.
+    Albedo = 6,
+    /// The light that falls on the surface, as if the material was white.
+    Lighting = 7,
+    /// The distance from the camera, from black at the near plane to white at the far plane.
+    Depth = 8,
+    /// The mip level of the color texture the hardware would pick, whether or not the texture has
+    /// it. Blue is the full texture, going through green to red at a 256th of it.
+    MipLevel = 9,
+    /// How many surfaces were drawn over every pixel, whether they are visible or not, going from
+    /// dark red through orange to white. Drawn with [crate::material_pipelines::MaterialPass::Overdraw].
+    Overdraw = 10,
+    /// How many point lights every fragment's cluster holds, going from blue through green to red
+    /// at a quarter of what a cluster can hold. See [crate::clustered::ClusteredLights].
+    LightCount = 11,
+}
+
+impl DebugMode {
+    const ALL: [Self; 12] = [
+        Self::Off,
+        Self::WorldNormals,
+        Self::NormalMap,
+        Self::Tangents,
+        Self::Bitangents,
+        Self::Uvs,
+        Self::Albedo,
+        Self::Lighting,
+        Self::Depth,
+        Self::MipLevel,
+        Self::Overdraw,
+        Self::LightCount,
+    ];
+
+    pub(crate) fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    pub(crate) fn previous(self) -> Self {
+        Self::ALL[(self as usize + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+// This has to match `DebugUniform` in scene.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugUniform {
+    mode: u32,
+    _padding: [u32; 3],
+}
+
+/// # Debug Views
+/// When an asset looks wrong, the lit image rarely tells why: a flipped normal map, tangents that
+/// don't line up with the texture or stretched texture coordinates all just look like odd shading.
+/// The debug views replace the lit color of the model shaders with one of the inputs to the
+/// lighting, picked with a [DebugMode] the shaders read from a uniform next to the lights.
+///
+/// The G-buffer doesn't keep the texture coordinates or the tangents, so the opaque meshes are
+/// drawn forward while a debug view is on, whichever render path is picked.
+pub(crate) struct DebugView {
+    pub(crate) mode: DebugMode,
+    uniform: wgpu::Buffer,
+}
+
+impl DebugView {
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let mode = DebugMode::default();
+        let uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Debug View Uniform"),
+            contents: bytemuck::cast_slice(&[DebugUniform {
+                mode: mode as u32,
+                _padding: [0; 3],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        Self { mode, uniform }
+    }
+
+    /// The entry for the debug uniform in the layout of a bind group the shaders read it from.
+    pub(crate) fn layout_entry(
+        binding: u32,
+        visibility: wgpu::ShaderStages,
+    ) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }
+    }
+
+    /// The debug uniform, for [Self::layout_entry].
+    pub(crate) fn bind_group_entry(&self, binding: u32) -> wgpu::BindGroupEntry<'_> {
+        wgpu::BindGroupEntry {
+            binding,
+            resource: self.uniform.as_entire_binding(),
+        }
+    }
+
+    pub(crate) fn update(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[DebugUniform {
+                mode: self.mode as u32,
+                _padding: [0; 3],
+            }]),
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use crate::texture::Texture;
+
+/// Which renderer draws the opaque part of the scene.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum RenderPath {
+    /// Every surface is lit as it's drawn.
+    #[default]
+    Forward,
+    /// Surfaces are drawn into a [GBuffer] first, and lit afterwards.
+    Deferred,
+}
+
+impl RenderPath {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Self::Forward => Self::Deferred,
+            Self::Deferred => Self::Forward,
+        }
+    }
+}
+
+/// # Deferred Shading
+/// The forward shaders light every fragment they draw, for every light. When surfaces overlap, the
+/// fragments of the ones further back are lit for nothing, and with many lights that adds up fast.
+///
+/// Deferred shading splits drawing into two passes. The geometry pass draws the meshes with the
+/// `fs_gbuffer` entry point of their shaders, which only stores what lighting needs to know about the
+/// closest surface in each pixel in the G-buffer: its albedo, its normal in world space and the
+/// specular settings of its material. Where the surface is comes from the depth buffer. The lighting
+/// pass then draws a triangle covering the whole screen for every light, adding up the light each
+/// pixel receives into the HDR texture. This way every pixel on the screen is lit exactly once per
+/// light, no matter how much was drawn there. A pass each would be far too many for the point
+/// lights of [crate::clustered::ClusteredLights], so those are looked up by cluster instead, the same
+/// way the forward shaders do.
+///
+/// The G-buffer can only hold one surface per pixel, so blended surfaces still have to be drawn
+/// forward, after the lighting pass.
+pub(crate) struct GBuffer {
+    albedo: Texture,
+    normal: Texture,
+    material: Texture,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    lighting_pipeline: wgpu::RenderPipeline,
+}
+
+impl GBuffer {
+    pub(crate) const ALBEDO_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+    /// Normals need more precision than 8 bits, or smooth surfaces come out banded.
+    pub(crate) const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    /// The specular strength and shininess of the material.
+    pub(crate) const MATERIAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg8Unorm;
+
+    /// `depth` is the depth buffer of the geometry pass, `output_format` the format of the target
+    /// the lighting pass writes to.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        depth: &Texture,
+        camera_layout: &wgpu::BindGroupLayout,
+        scene_layout: &wgpu::BindGroupLayout,
+        output_format: wgpu::TextureFormat,
+    ) -> Self {
+        let (albedo, normal, material) =
+            Self::create_targets(device, depth.size.width, depth.size.height);
+
+        let texture_entry = |binding, sample_type| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let color = wgpu::TextureSampleType::Float { filterable: false };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("gbuffer_bind_group_layout"),
+            entries: &[
+                texture_entry(0, color),
+                texture_entry(1, color),
+                texture_entry(2, color),
+                texture_entry(3, wgpu::TextureSampleType::Depth),
+            ],
+        });
+        let bind_group =
+            Self::create_bind_group(device, &layout, [&albedo, &normal, &material, depth]);
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Deferred Lighting Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                concat!(
+                    include_str!("shaders/scene.wgsl"),
+                    include_str!("shaders/deferred.wgsl"),
+                )
+                .into(),
+            ),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Deferred Lighting Pipeline Layout"),
+            bind_group_layouts: &[&layout, camera_layout, scene_layout],
+            push_constant_ranges: &[],
+        });
+        // Every light adds to what the ones before it have lit
+        let additive = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let lighting_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Deferred Lighting Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                // The shader makes up a triangle covering the whole screen
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: output_format,
+                    blend: Some(wgpu::BlendState {
+                        color: additive,
+                        alpha: wgpu::BlendComponent::REPLACE,
+                    }),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            albedo,
+            normal,
+            material,
+            layout,
+            bind_group,
+            lighting_pipeline,
+        }
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+    ) -> (Texture, Texture, Texture) {
+        let create = |format, label| {
+            Texture::create_2d_texture(
+                device,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                wgpu::FilterMode::Nearest,
+                Some(label),
+            )
+        };
+        (
+            create(Self::ALBEDO_FORMAT, "gbuffer_albedo_texture"),
+            create(Self::NORMAL_FORMAT, "gbuffer_normal_texture"),
+            create(Self::MATERIAL_FORMAT, "gbuffer_material_texture"),
+        )
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        textures: [&Texture; 4],
+    ) -> wgpu::BindGroup {
+        let entries = textures
+            .iter()
+            .enumerate()
+            .map(|(binding, texture)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            })
+            .collect::<Vec<_>>();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("gbuffer_bind_group"),
+            layout,
+            entries: &entries,
+        })
+    }
+
+    /// Recreates the G-buffer at the size of the new `depth` buffer.
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, depth: &Texture) {
+        (self.albedo, self.normal, self.material) =
+            Self::create_targets(device, depth.size.width, depth.size.height);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.layout,
+            [&self.albedo, &self.normal, &self.material, depth],
+        );
+    }
+
+    /// Begins the geometry pass, clearing the G-buffer and the `depth` buffer.
+    pub(crate) fn begin_geometry_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth: &'a Texture,
+    ) -> wgpu::RenderPass<'a> {
+        let target = |texture: &'a Texture| {
+            Some(wgpu::RenderPassColorAttachment {
+                view: &texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })
+        };
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("G-Buffer Pass"),
+            color_attachments: &[
+                target(&self.albedo),
+                target(&self.normal),
+                target(&self.material),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        })
+    }
+
+    /// Clears `output` to `background`, then adds the light of every one of the `scenes` to it. Each
+    /// is a [crate::scene_bind_group::SceneBindGroup] with its own main light.
+    pub(crate) fn light(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        background: wgpu::Color,
+        camera_bind_group: &wgpu::BindGroup,
+        scenes: &[&wgpu::BindGroup],
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Deferred Lighting Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(background),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_pipeline(&self.lighting_pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_bind_group(1, camera_bind_group, &[]);
+        for scene in scenes {
+            render_pass.set_bind_group(2, scene, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use crate::camera::Projection;
+use crate::post_process::{
+    create_fullscreen_pipeline, InputBindGroups, PostContext, PostEffect, PostTarget,
+};
+use crate::texture::Texture;
+use std::collections::HashMap;
+use wgpu::util::DeviceExt;
+
+/// The lens [DepthOfField] simulates.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct DofSettings {
+    /// How far in front of the camera things are sharpest, in world units. Autofocus overrides
+    /// this.
+    pub(crate) focus_distance: f32,
+    /// The f-number: the focal length divided by the diameter of the aperture. Smaller values
+    /// open the aperture wider, which blurs everything out of focus more.
+    pub(crate) f_stop: f32,
+    /// In millimetres, taking world units as metres. Longer lenses blur more at the same f-stop.
+    pub(crate) focal_length: f32,
+    /// The blur never gets wider than this many pixels across.
+    pub(crate) max_coc: f32,
+    /// Whether to focus on whatever is in the middle of the screen.
+    pub(crate) autofocus: bool,
+    /// How quickly autofocus follows changes in distance.
+    pub(crate) autofocus_speed: f32,
+}
+
+impl Default for DofSettings {
+    fn default() -> Self {
+        Self {
+            focus_distance: 10.0,
+            f_stop: 1.4,
+            focal_length: 85.0,
+            max_coc: 32.0,
+            autofocus: false,
+            autofocus_speed: 4.0,
+        }
+    }
+}
+
+// This has to match `DofUniform` in depth_of_field.wgsl and autofocus.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DofUniform {
+    focus_distance: f32,
+    aperture: f32,
+    focal_length: f32,
+    sensor_height: f32,
+    znear: f32,
+    zfar: f32,
+    max_coc: f32,
+    autofocus: u32,
+    autofocus_speed: f32,
+    delta_time: f32,
+    _padding: [u32; 2],
+}
+
+/// # Depth of Field
+/// A real lens only focuses at one distance. A point closer or further away spreads out into a
+/// disc on the sensor, called the circle of confusion, which grows with the size of the aperture
+/// and how far the point is from the focus distance. The thin lens equation gives its diameter
+/// from the distance of every pixel, which comes from the depth buffer, undoing the projection
+/// with the near and far planes of the [Projection]. The [DofSettings] are the settings of a
+/// physical camera. The sensor is taken to be as tall as a sensor behind a lens of the focal
+/// length would have to be to see the field of view of the projection, so that the focal length
+/// only changes the amount of blur and not the image.
+///
+/// The blur is gathered at half the resolution, which is plenty for something blurry:
+/// 1. The image is shrunk to half its size, with the circle of confusion of every pixel alongside.
+/// 2. Every pixel gathers the samples on a disc around it whose circle of confusion is large
+///    enough to reach it, the bokeh. Things in front of the focus distance, the near field, blur out
+///    over their edges and cover whatever is behind them, even if that is in focus, so they are
+///    gathered separately from things behind it, the far field, along with how much they cover.
+/// 3. The full size image is blended with the far field by how blurry each pixel is, and then the
+///    near field is laid over it.
+///
+/// With autofocus, a compute pass moves the focus distance towards the depth in the middle of the
+/// screen a bit every frame, which the other passes read, so the distance never goes through the
+/// CPU. It runs on the HDR image, before bloom and tonemapping, so that bright highlights turn into
+/// bright discs.
+pub(crate) struct DepthOfField {
+    pub(crate) settings: DofSettings,
+    znear: f32,
+    zfar: f32,
+    tan_half_fovy: f32,
+    uniform: wgpu::Buffer,
+    /// The distance in focus, see [Self::settings].
+    focus: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+    layout: wgpu::BindGroupLayout,
+    /// The bind groups reading the images the effect has been handed.
+    bind_groups: InputBindGroups,
+    scene_layout: wgpu::BindGroupLayout,
+    autofocus_layout: wgpu::BindGroupLayout,
+    /// The image at half size with the circle of confusion in alpha.
+    prefiltered: Texture,
+    near: Texture,
+    far: Texture,
+    /// The depth with the near and far fields for the downsample and composite passes, and with
+    /// the prefiltered image for the bokeh pass. See [Self::set_scene].
+    scene_bind_groups: [wgpu::BindGroup; 2],
+    autofocus_bind_group: wgpu::BindGroup,
+    pipeline_layout: wgpu::PipelineLayout,
+    shader: wgpu::ShaderModule,
+    downsample_pipeline: wgpu::RenderPipeline,
+    bokeh_pipeline: wgpu::RenderPipeline,
+    /// One for every format the effect has written so far, since it writes the format it reads.
+    composite_pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+    autofocus_pipeline: wgpu::ComputePipeline,
+}
+
+impl DepthOfField {
+    pub(crate) const LABEL: &'static str = "Depth of Field";
+    /// The fields are as bright as the HDR image, and need a sign for the circle of confusion.
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    /// `depth` is what the scene was rendered with, through `projection`.
+    pub(crate) fn new(device: &wgpu::Device, projection: &Projection, depth: &Texture) -> Self {
+        let settings = DofSettings::default();
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Depth of Field Uniform"),
+            size: std::mem::size_of::<DofUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // Starts out at the focus distance, so autofocus doesn't have to come all the way from 0
+        let focus = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Depth of Field Focus"),
+            contents: bytemuck::cast_slice(&[settings.focus_distance]),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("dof_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let uniform_entry = |binding, visibility| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let storage_entry = |binding, visibility, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let texture = |binding, visibility, sample_type| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Texture {
+                sample_type,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let fragment = wgpu::ShaderStages::FRAGMENT;
+        let float = wgpu::TextureSampleType::Float { filterable: true };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("dof_bind_group_layout"),
+            entries: &[
+                uniform_entry(0, fragment),
+                storage_entry(1, fragment, true),
+                texture(2, fragment, float),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: fragment,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let scene_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("dof_scene_bind_group_layout"),
+            entries: &[
+                texture(0, fragment, wgpu::TextureSampleType::Depth),
+                texture(1, fragment, float),
+                texture(2, fragment, float),
+            ],
+        });
+        let compute = wgpu::ShaderStages::COMPUTE;
+        let autofocus_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("dof_autofocus_bind_group_layout"),
+            entries: &[
+                uniform_entry(0, compute),
+                storage_entry(1, compute, false),
+                texture(2, compute, wgpu::TextureSampleType::Depth),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Depth of Field Pipeline Layout"),
+            bind_group_layouts: &[&layout, &scene_layout],
+            push_constant_ranges: &[],
+        });
+        let shader =
+            device.create_shader_module(wgpu::include_wgsl!("shaders/depth_of_field.wgsl"));
+        let downsample_pipeline = create_fullscreen_pipeline(
+            device,
+            &pipeline_layout,
+            &shader,
+            "Depth of Field Downsample Pipeline",
+            "fs_downsample",
+            Self::FORMAT,
+            wgpu::BlendState::REPLACE,
+        );
+        let target = Some(wgpu::ColorTargetState {
+            format: Self::FORMAT,
+            blend: Some(wgpu::BlendState::REPLACE),
+            write_mask: wgpu::ColorWrites::ALL,
+        });
+        let bokeh_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Depth of Field Bokeh Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            // The near field, then the far field
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_bokeh",
+                targets: &[target.clone(), target],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        let autofocus_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Autofocus Pipeline Layout"),
+                bind_group_layouts: &[&autofocus_layout],
+                push_constant_ranges: &[],
+            });
+        let autofocus_shader =
+            device.create_shader_module(wgpu::include_wgsl!("shaders/autofocus.wgsl"));
+        let autofocus_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Autofocus Pipeline"),
+            layout: Some(&autofocus_pipeline_layout),
+            module: &autofocus_shader,
+            entry_point: "cs_main",
+        });
+
+        let [prefiltered, near, far] = Self::create_fields(device, depth);
+        let (scene_bind_groups, autofocus_bind_group) = Self::create_scene_bind_groups(
+            device,
+            [&scene_layout, &autofocus_layout],
+            &uniform,
+            &focus,
+            depth,
+            [&prefiltered, &near, &far],
+        );
+
+        Self {
+            settings,
+            // The projection only changes its aspect ratio when the window resizes, which the
+            // blur doesn't depend on
+            znear: projection.znear(),
+            zfar: projection.zfar(),
+            tan_half_fovy: projection.tan_half_fovy(),
+            uniform,
+            focus,
+            sampler,
+            layout,
+            bind_groups: InputBindGroups::default(),
+            scene_layout,
+            autofocus_layout,
+            prefiltered,
+            near,
+            far,
+            scene_bind_groups,
+            autofocus_bind_group,
+            pipeline_layout,
+            shader,
+            downsample_pipeline,
+            bokeh_pipeline,
+            composite_pipelines: HashMap::new(),
+            autofocus_pipeline,
+        }
+    }
+
+    /// The prefiltered image and the near and far fields, at half the size of `depth`.
+    fn create_fields(device: &wgpu::Device, depth: &Texture) -> [Texture; 3] {
+        [
+            "dof_prefiltered_texture",
+            "dof_near_texture",
+            "dof_far_texture",
+        ]
+        .map(|label| {
+            Texture::create_2d_texture(
+                device,
+                (depth.size.width / 2).max(1),
+                (depth.size.height / 2).max(1),
+                Self::FORMAT,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                wgpu::FilterMode::Linear,
+                Some(label),
+            )
+        })
+    }
+
+    fn create_scene_bind_groups(
+        device: &wgpu::Device,
+        [scene_layout, autofocus_layout]: [&wgpu::BindGroupLayout; 2],
+        uniform: &wgpu::Buffer,
+        focus: &wgpu::Buffer,
+        depth: &Texture,
+        [prefiltered, near, far]: [&Texture; 3],
+    ) -> ([wgpu::BindGroup; 2], wgpu::BindGroup) {
+        fn view(texture: &Texture) -> wgpu::BindingResource<'_> {
+            wgpu::BindingResource::TextureView(&texture.view)
+        }
+        // A pass can't read the texture it writes, so the downsample pass gets the fields even
+        // though it doesn't read them
+        let scene_bind_groups = [(near, far), (prefiltered, prefiltered)].map(|(a, b)| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("dof_scene_bind_group"),
+                layout: scene_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: view(depth),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: view(a),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: view(b),
+                    },
+                ],
+            })
+        });
+        let autofocus_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("dof_autofocus_bind_group"),
+            layout: autofocus_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: focus.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: view(depth),
+                },
+            ],
+        });
+        (scene_bind_groups, autofocus_bind_group)
+    }
+
+    /// Reads the depth from `depth` from now on, after it was recreated, and resizes the fields to
+    /// match it.
+    pub(crate) fn set_scene(&mut self, device: &wgpu::Device, depth: &Texture) {
+        [self.prefiltered, self.near, self.far] = Self::create_fields(device, depth);
+        (self.scene_bind_groups, self.autofocus_bind_group) = Self::create_scene_bind_groups(
+            device,
+            [&self.scene_layout, &self.autofocus_layout],
+            &self.uniform,
+            &self.focus,
+            depth,
+            [&self.prefiltered, &self.near, &self.far],
+        );
+    }
+
+    fn begin_pass<'a>(
+        encoder: &'a mut wgpu::CommandEncoder,
+        label: &str,
+        targets: &[&'a wgpu::TextureView],
+    ) -> wgpu::RenderPass<'a> {
+        let color_attachments = targets
+            .iter()
+            .map(|&view| {
+                Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &color_attachments,
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        })
+    }
+}
+
+impl PostEffect for DepthOfField {
+    fn label(&self) -> &'static str {
+        Self::LABEL
+    }
+
+    fn invalidate(&mut self) {
+        self.bind_groups.clear();
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, dt: instant::Duration) {
+        // World units are metres, and the focal length is in millimetres
+        let focal_length = self.settings.focal_length / 1000.0;
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[DofUniform {
+                focus_distance: self.settings.focus_distance,
+                aperture: focal_length / self.settings.f_stop,
+                focal_length,
+                sensor_height: 2.0 * focal_length * self.tan_half_fovy,
+                znear: self.znear,
+                zfar: self.zfar,
+                max_coc: self.settings.max_coc,
+                autofocus: self.settings.autofocus.into(),
+                autofocus_speed: self.settings.autofocus_speed,
+                delta_time: dt.as_secs_f32(),
+                _padding: [0; 2],
+            }]),
+        );
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        {
+            let mut compute_pass =
+                context
+                    .encoder
+                    .begin_compute_pass(&wgpu::ComputePassDescriptor {
+                        label: Some("Autofocus Pass"),
+                        timestamp_writes: None,
+                    });
+            compute_pass.set_pipeline(&self.autofocus_pipeline);
+            compute_pass.set_bind_group(0, &self.autofocus_bind_group, &[]);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        let device = context.device;
+        let bind_group = self.bind_groups.get(input, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("dof_bind_group"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: self.uniform.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: self.focus.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&input.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+            })
+        });
+        let passes = [
+            (
+                "Depth of Field Downsample Pass",
+                &self.downsample_pipeline,
+                &[&self.prefiltered.view][..],
+                &self.scene_bind_groups[0],
+            ),
+            (
+                "Depth of Field Bokeh Pass",
+                &self.bokeh_pipeline,
+                &[&self.near.view, &self.far.view][..],
+                &self.scene_bind_groups[1],
+            ),
+        ];
+        for (label, pipeline, targets, scene_bind_group) in passes {
+            let mut render_pass = Self::begin_pass(context.encoder, label, targets);
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.set_bind_group(1, scene_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        let pipeline = self
+            .composite_pipelines
+            .entry(output.format)
+            .or_insert_with(|| {
+                create_fullscreen_pipeline(
+                    context.device,
+                    &self.pipeline_layout,
+                    &self.shader,
+                    "Depth of Field Composite Pipeline",
+                    "fs_composite",
+                    output.format,
+                    wgpu::BlendState::REPLACE,
+                )
+            });
+        let mut render_pass = Self::begin_pass(
+            context.encoder,
+            "Depth of Field Composite Pass",
+            &[output.view],
+        );
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.set_bind_group(1, &self.scene_bind_groups[0], &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
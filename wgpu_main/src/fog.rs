@@ -0,0 +1,136 @@
+use wgpu::util::DeviceExt;
+
+/// The adjustable parts of [Fog].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct FogSettings {
+    /// The color surfaces fade into without a sky, the same as the background so that distant
+    /// objects disappear into it. With one, they fade into the sky behind them instead.
+    pub(crate) color: [f32; 3],
+    /// How much of the light is lost per world unit the view ray travels through the distance fog.
+    pub(crate) density: f32,
+    /// How far from the camera the fog starts, in world units, so that nearby surfaces stay clear.
+    pub(crate) start_distance: f32,
+    /// The height at which the height fog is as dense as [Self::height_density].
+    pub(crate) height: f32,
+    /// How much of the light is lost per world unit through the height fog at [Self::height].
+    pub(crate) height_density: f32,
+    /// How quickly the height fog thins out above [Self::height], and thickens below it.
+    pub(crate) height_falloff: f32,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        Self {
+            color: [0.1, 0.2, 0.3],
+            density: 0.02,
+            start_distance: 2.0,
+            height: 0.0,
+            height_density: 0.1,
+            height_falloff: 0.5,
+        }
+    }
+}
+
+// This has to match `Fog` in scene.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FogUniform {
+    color: [f32; 3],
+    density: f32,
+    start_distance: f32,
+    height: f32,
+    height_density: f32,
+    height_falloff: f32,
+    environment: f32,
+    _padding: [f32; 3],
+}
+
+impl FogUniform {
+    fn new(settings: FogSettings, enabled: bool, environment: bool) -> Self {
+        // Without any density the shaders leave every color as it is
+        let scale = if enabled { 1.0 } else { 0.0 };
+        Self {
+            color: settings.color,
+            density: settings.density * scale,
+            start_distance: settings.start_distance,
+            height: settings.height,
+            height_density: settings.height_density * scale,
+            height_falloff: settings.height_falloff,
+            environment: if environment { 1.0 } else { 0.0 },
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+/// # Fog
+/// Light traveling through the air gets scattered by the particles in it, so the further away a
+/// surface is, the less of its own color reaches the camera, and the more of the color of the air.
+/// Without that, distant objects look as crisp as close ones and large scenes look sterile.
+///
+/// The fraction of the light that makes it through falls exponentially with the optical depth, the
+/// density of the air summed up along the view ray. There are two kinds of fog that add to it:
+/// - Distance fog has the same density everywhere, so its optical depth is the density times the
+///   distance the ray travels through it, past [FogSettings::start_distance].
+/// - Height fog gathers near the ground, like mist in a valley. Its density falls exponentially
+///   with the height, which has a closed form when summed along the ray, so it doesn't have to be
+///   marched.
+///
+/// The shaders blend every lit surface towards the fog color by how much of the light is lost,
+/// transparent ones and the light markers included. With the sky on, the fog color comes from the
+/// sky cube along the view direction instead, so that surfaces fade into the sky right behind them.
+pub(crate) struct Fog {
+    pub(crate) settings: FogSettings,
+    pub(crate) enabled: bool,
+    uniform: wgpu::Buffer,
+}
+
+impl Fog {
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let settings = FogSettings::default();
+        let enabled = false;
+        let uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Fog Uniform"),
+            contents: bytemuck::cast_slice(&[FogUniform::new(settings, enabled, false)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        Self {
+            settings,
+            enabled,
+            uniform,
+        }
+    }
+
+    /// The entry for the fog uniform in the layout of a bind group the shaders read it from.
+    pub(crate) fn layout_entry(
+        binding: u32,
+        visibility: wgpu::ShaderStages,
+    ) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }
+    }
+
+    /// The fog uniform, for [Self::layout_entry].
+    pub(crate) fn bind_group_entry(&self, binding: u32) -> wgpu::BindGroupEntry<'_> {
+        wgpu::BindGroupEntry {
+            binding,
+            resource: self.uniform.as_entire_binding(),
+        }
+    }
+
+    /// Uploads the settings, with the fog taking its color from the sky if `environment` is set.
+    pub(crate) fn update(&self, queue: &wgpu::Queue, environment: bool) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[FogUniform::new(self.settings, self.enabled, environment)]),
+        );
+    }
+}
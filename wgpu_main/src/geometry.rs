@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use wgpu::util::DeviceExt;
+
+/// A vertex buffer and an index buffer shared by many meshes. Each mesh only knows where its
+/// vertices and indices start; see [GeometryArena].
+pub(crate) struct GeometryPage {
+    pub(crate) vertex_buffer: wgpu::Buffer,
+    pub(crate) index_buffer: wgpu::Buffer,
+}
+
+impl GeometryPage {
+    fn new(
+        device: &wgpu::Device,
+        label: &str,
+        vertex_size: wgpu::BufferAddress,
+        index_size: wgpu::BufferAddress,
+    ) -> Self {
+        Self {
+            vertex_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{label} vertex buffer")),
+                size: vertex_size,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            index_buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{label} index buffer")),
+                size: index_size,
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+        }
+    }
+
+    fn with_contents(
+        device: &wgpu::Device,
+        label: &str,
+        vertices: &[u8],
+        indices: &[u32],
+        vertex_usage: wgpu::BufferUsages,
+    ) -> Self {
+        Self {
+            vertex_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{label} vertex buffer")),
+                contents: vertices,
+                usage: wgpu::BufferUsages::VERTEX | vertex_usage,
+            }),
+            index_buffer: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{label} index buffer")),
+                contents: bytemuck::cast_slice(indices),
+                usage: wgpu::BufferUsages::INDEX,
+            }),
+        }
+    }
+}
+
+/// Where a mesh ended up in a [GeometryArena]. `base_vertex` is added to every index of the mesh,
+/// and its indices start at `first_index`.
+pub(crate) struct GeometryAllocation {
+    pub(crate) page: Arc<GeometryPage>,
+    pub(crate) base_vertex: i32,
+    pub(crate) first_index: u32,
+}
+
+struct OpenPage {
+    page: Arc<GeometryPage>,
+    vertex_bytes: wgpu::BufferAddress,
+    indices: wgpu::BufferAddress,
+}
+
+/// # Geometry Arena
+/// Giving every mesh its own vertex and index buffer means that every mesh we draw needs its own
+/// `set_vertex_buffer` and `set_index_buffer` calls. With thousands of small meshes, those calls
+/// add up. Instead, the arena hands out space in a few large [GeometryPage]s, and meshes that share
+/// a page can be drawn one after the other without binding anything in between: `draw_indexed`
+/// takes the index range and `base_vertex` of each mesh.
+///
+/// `base_vertex` counts whole vertices, so all the vertices in a page must have the same size. The
+/// arena keeps a separate page for every vertex size, which means [crate::model::ModelVertex] and
+/// [crate::model::SkinnedVertex] meshes never share a vertex buffer. When a page is full a new one
+/// is started, and a mesh larger than a whole page gets a page sized to fit it.
+///
+/// Pages stay alive as long as a mesh uses them, so the arena itself can be dropped once loading is
+/// done. Meshes loaded with another arena go into other pages.
+pub(crate) struct GeometryArena {
+    label: String,
+    /// The page currently being filled, for every vertex size.
+    open_pages: HashMap<wgpu::BufferAddress, OpenPage>,
+}
+
+impl GeometryArena {
+    const VERTEX_PAGE_SIZE: wgpu::BufferAddress = 4 << 20;
+    const INDEX_PAGE_SIZE: wgpu::BufferAddress = 1 << 20;
+
+    pub(crate) fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            open_pages: HashMap::new(),
+        }
+    }
+
+    /// Copies the vertices and indices of a mesh into the arena.
+    pub(crate) fn allocate<V: bytemuck::Pod>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        vertices: &[V],
+        indices: &[u32],
+    ) -> GeometryAllocation {
+        let vertex_stride = std::mem::size_of::<V>() as wgpu::BufferAddress;
+        let vertices: &[u8] = bytemuck::cast_slice(vertices);
+        let indices: &[u8] = bytemuck::cast_slice(indices);
+        let (vertex_size, index_size) = (vertices.len() as u64, indices.len() as u64);
+
+        let fits = |open: &OpenPage| {
+            open.vertex_bytes + vertex_size <= open.page.vertex_buffer.size()
+                && open.indices * 4 + index_size <= open.page.index_buffer.size()
+        };
+        if !self.open_pages.get(&vertex_stride).is_some_and(fits) {
+            let page = GeometryPage::new(
+                device,
+                &format!("{} page", self.label),
+                vertex_size.max(Self::VERTEX_PAGE_SIZE),
+                index_size.max(Self::INDEX_PAGE_SIZE),
+            );
+            self.open_pages.insert(
+                vertex_stride,
+                OpenPage {
+                    page: Arc::new(page),
+                    vertex_bytes: 0,
+                    indices: 0,
+                },
+            );
+        }
+
+        let open = self.open_pages.get_mut(&vertex_stride).unwrap();
+        if !vertices.is_empty() {
+            queue.write_buffer(&open.page.vertex_buffer, open.vertex_bytes, vertices);
+        }
+        if !indices.is_empty() {
+            queue.write_buffer(&open.page.index_buffer, open.indices * 4, indices);
+        }
+        let allocation = GeometryAllocation {
+            page: open.page.clone(),
+            base_vertex: (open.vertex_bytes / vertex_stride) as i32,
+            first_index: open.indices as u32,
+        };
+        open.vertex_bytes += vertex_size;
+        open.indices += index_size / 4;
+        allocation
+    }
+
+    /// Like [Self::allocate], but the mesh gets a page of its own, with its first vertex at the start
+    /// of the vertex buffer. This is for meshes whose vertices are also read by other passes, like the
+    /// base vertices of morph targets, which need the extra `vertex_usage`.
+    pub(crate) fn allocate_dedicated<V: bytemuck::Pod>(
+        &self,
+        device: &wgpu::Device,
+        vertices: &[V],
+        indices: &[u32],
+        vertex_usage: wgpu::BufferUsages,
+    ) -> GeometryAllocation {
+        GeometryAllocation {
+            page: Arc::new(GeometryPage::with_contents(
+                device,
+                &format!("{} dedicated page", self.label),
+                bytemuck::cast_slice(vertices),
+                indices,
+                vertex_usage,
+            )),
+            base_vertex: 0,
+            first_index: 0,
+        }
+    }
+}
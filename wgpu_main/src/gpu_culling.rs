@@ -0,0 +1,330 @@
+use crate::culling::Frustum;
+use crate::instance::InstanceRaw;
+use crate::lod::{LodSelector, MAX_LODS};
+use crate::model::Model;
+use cgmath::Point3;
+use wgpu::util::{DeviceExt, DrawIndexedIndirectArgs};
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullParams {
+    planes: [[f32; 4]; 6],
+    sphere: [f32; 4],
+    camera_position: [f32; 4],
+    lod_thresholds: [f32; 4],
+    num_instances: u32,
+    num_meshes: u32,
+    num_lods: u32,
+    tan_half_fovy: f32,
+    hysteresis: f32,
+    // Uniforms are padded to a multiple of 16 bytes
+    _padding: [u32; 3],
+}
+
+/// # GPU Culling
+/// [Frustum::is_visible] works well for a few hundred instances, but with tens of thousands the CPU
+/// spends most of the frame testing bounding spheres and uploading the survivors. The GPU is much
+/// better at doing the same small amount of work for lots of instances, so we move the culling there.
+///
+/// A compute pass tests every instance against the frustum, and copies the visible ones to the front
+/// of an output buffer, which then serves as the instance buffer. The catch is that the CPU doesn't
+/// know how many instances survived, so it can't pass the instance count to `draw_indexed`. Instead
+/// the compute pass also writes the arguments of the draw call into an *indirect buffer*, and we
+/// draw with `draw_indexed_indirect`, which reads them from there.
+///
+/// The same pass also picks each visible instance's level of detail with the rules of [LodSelector].
+/// Every level gets its own region of the output buffer and its own draw arguments, and remembers
+/// the level of each instance for the hysteresis in a buffer of its own.
+///
+/// Indirect draws and compute shaders aren't available everywhere (WebGL2 has neither), so callers
+/// should check [CullPipeline::is_supported] and keep culling on the CPU otherwise.
+pub(crate) struct CullPipeline {
+    pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+}
+
+impl CullPipeline {
+    const WORKGROUP_SIZE: u32 = 64;
+
+    pub(crate) fn is_supported(adapter: &wgpu::Adapter) -> bool {
+        adapter.get_downlevel_capabilities().flags.contains(
+            wgpu::DownlevelFlags::COMPUTE_SHADERS | wgpu::DownlevelFlags::INDIRECT_EXECUTION,
+        )
+    }
+
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("CullPipeline::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // All instances
+                storage(1, true),
+                // The visible instances
+                storage(2, false),
+                // The draw arguments
+                storage(3, false),
+                // The level of detail of every instance
+                storage(4, false),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("CullPipeline::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = device.create_shader_module(wgpu::include_wgsl!("shaders/cull.wgsl"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("CullPipeline::pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "cull_instances",
+        });
+
+        Self { pipeline, layout }
+    }
+
+    /// Records the culling pass for `instances`. This needs to run before the render pass that
+    /// draws them.
+    pub(crate) fn cull(&self, encoder: &mut wgpu::CommandEncoder, instances: &CulledInstances) {
+        // Start counting from zero again
+        encoder.copy_buffer_to_buffer(
+            &instances.initial_draws,
+            0,
+            &instances.draws,
+            0,
+            instances.draws.size(),
+        );
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Cull Pass"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &instances.bind_group, &[]);
+        pass.dispatch_workgroups(instances.num_instances.div_ceil(Self::WORKGROUP_SIZE), 1, 1);
+    }
+}
+
+/// The instances of a single model, culled on the GPU by a [CullPipeline].
+pub(crate) struct CulledInstances {
+    capacity: u32,
+    num_instances: u32,
+    num_meshes: u32,
+    num_lods: u32,
+    sphere: [f32; 4],
+    lod_thresholds: [f32; 4],
+    hysteresis: f32,
+    params: wgpu::Buffer,
+    instances: wgpu::Buffer,
+    /// One region of `capacity` instances per level of detail.
+    visible_instances: wgpu::Buffer,
+    /// One `DrawIndexedIndirectArgs` per mesh, in the same order as [Model::meshes], for every level
+    /// of detail.
+    draws: wgpu::Buffer,
+    /// The draw arguments with an instance count of zero, copied over `draws` before every pass.
+    initial_draws: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl CulledInstances {
+    /// Allocates the buffers for culling up to `capacity` instances of `model`. The levels of detail
+    /// are picked with the thresholds of `lod_selector`, of which only the first `MAX_LODS - 1` are
+    /// used.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        pipeline: &CullPipeline,
+        model: &Model,
+        capacity: u32,
+        lod_selector: &LodSelector,
+    ) -> Self {
+        let num_lods = model
+            .num_lods()
+            .min(lod_selector.thresholds.len() + 1)
+            .min(MAX_LODS);
+        let instance_size = std::mem::size_of::<InstanceRaw>() as u64;
+        let params = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Params"),
+            size: std::mem::size_of::<CullParams>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let instances = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Instances"),
+            size: instance_size * capacity as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let visible_instances = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Visible Instances"),
+            size: instance_size * capacity as u64 * num_lods as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let lod_state = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull LOD State"),
+            size: std::mem::size_of::<u32>() as u64 * capacity as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let initial_draws = (0..num_lods)
+            .flat_map(|lod| model.meshes.iter().map(move |mesh| mesh.lod(lod)))
+            .flat_map(|level| {
+                DrawIndexedIndirectArgs {
+                    index_count: level.indices.len() as u32,
+                    instance_count: 0,
+                    first_index: level.indices.start,
+                    base_vertex: level.base_vertex,
+                    first_instance: 0,
+                }
+                .as_bytes()
+                .to_vec()
+            })
+            .collect::<Vec<_>>();
+        let initial_draws = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Cull Initial Draws"),
+            contents: &initial_draws,
+            usage: wgpu::BufferUsages::COPY_SRC,
+        });
+        let draws = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Cull Draws"),
+            size: initial_draws.size(),
+            usage: wgpu::BufferUsages::INDIRECT
+                | wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Cull Bind Group"),
+            layout: &pipeline.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: instances.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: visible_instances.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: draws.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: lod_state.as_entire_binding(),
+                },
+            ],
+        });
+
+        let sphere = model.bounds.sphere;
+        let mut lod_thresholds = [0.0; 4];
+        for (threshold, &t) in lod_thresholds.iter_mut().zip(&lod_selector.thresholds) {
+            *threshold = t;
+        }
+        Self {
+            capacity,
+            num_instances: 0,
+            num_meshes: model.meshes.len() as u32,
+            num_lods: num_lods as u32,
+            sphere: [
+                sphere.center.x,
+                sphere.center.y,
+                sphere.center.z,
+                sphere.radius,
+            ],
+            lod_thresholds,
+            hysteresis: lod_selector.hysteresis,
+            params,
+            instances,
+            visible_instances,
+            draws,
+            initial_draws,
+            bind_group,
+        }
+    }
+
+    /// Replaces all instances. Instances past the capacity are dropped.
+    pub(crate) fn write_instances(&mut self, queue: &wgpu::Queue, instances: &[InstanceRaw]) {
+        let instances = &instances[..instances.len().min(self.capacity as usize)];
+        self.num_instances = instances.len() as u32;
+        if !instances.is_empty() {
+            queue.write_buffer(&self.instances, 0, bytemuck::cast_slice(instances));
+        }
+    }
+
+    /// Sets the view the next pass culls against and picks the levels of detail for.
+    pub(crate) fn set_view(
+        &self,
+        queue: &wgpu::Queue,
+        frustum: &Frustum,
+        camera_position: Point3<f32>,
+        tan_half_fovy: f32,
+    ) {
+        let params = CullParams {
+            planes: frustum.planes(),
+            sphere: self.sphere,
+            camera_position: camera_position.to_homogeneous().into(),
+            lod_thresholds: self.lod_thresholds,
+            num_instances: self.num_instances,
+            num_meshes: self.num_meshes,
+            num_lods: self.num_lods,
+            tan_half_fovy,
+            hysteresis: self.hysteresis,
+            _padding: [0; 3],
+        };
+        queue.write_buffer(&self.params, 0, bytemuck::cast_slice(&[params]));
+    }
+
+    pub(crate) fn num_lods(&self) -> usize {
+        self.num_lods as usize
+    }
+
+    /// The visible instances, to be bound as the instance buffer. Each level of detail has its own
+    /// region, starting at [Self::lod_offset].
+    pub(crate) fn instance_buffer(&self) -> &wgpu::Buffer {
+        &self.visible_instances
+    }
+
+    /// Where the visible instances that use the level of detail `lod` start in
+    /// [Self::instance_buffer].
+    pub(crate) fn lod_offset(&self, lod: usize) -> wgpu::BufferAddress {
+        (std::mem::size_of::<InstanceRaw>() * self.capacity as usize * lod) as u64
+    }
+
+    /// The draw arguments, one set per mesh, for [crate::batch::DrawArgs::Indirect].
+    pub(crate) fn indirect_buffer(&self) -> &wgpu::Buffer {
+        &self.draws
+    }
+
+    /// Where the draw arguments of the level of detail `lod` start in [Self::indirect_buffer].
+    pub(crate) fn indirect_offset(&self, lod: usize) -> wgpu::BufferAddress {
+        (std::mem::size_of::<DrawIndexedIndirectArgs>() * self.num_meshes as usize * lod) as u64
+    }
+}
@@ -1,6 +1,93 @@
+use crate::anti_aliasing::{AntiAliasing, Fxaa, Smaa, Taa};
+use crate::auto_exposure::AutoExposure;
+use crate::bloom::Bloom;
+use crate::camera::Projection;
+use crate::color_grading::ColorGrading;
+use crate::depth_of_field::DepthOfField;
+use crate::post_process::{InputBindGroups, PostContext, PostEffect, PostProcessStack, PostTarget};
 use crate::texture::Texture;
+use crate::velocity::VelocityBuffer;
+use cgmath::Vector2;
 use wgpu::Operations;
 
+/// The curves [Tonemapping] can tonemap with.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TonemapOperator {
+    /// Stephen Hill's fit of the ACES filmic curve, with its characteristic shift in hue.
+    #[default]
+    AcesFitted,
+    /// Reinhard's `x / (1 + x)` on the luminance, extended so that the white point maps to white.
+    ReinhardExtended,
+    /// John Hable's filmic curve from Uncharted 2.
+    Uncharted2,
+    /// Troy Sobotka's AgX, which desaturates highlights instead of skewing their hue.
+    Agx,
+    /// The Khronos PBR Neutral curve, which keeps base colors as close to true as possible.
+    KhronosPbrNeutral,
+    /// No curve at all, only clamping. Useful for debugging, since everything above 1 clips.
+    None,
+}
+
+impl TonemapOperator {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Self::AcesFitted => Self::ReinhardExtended,
+            Self::ReinhardExtended => Self::Uncharted2,
+            Self::Uncharted2 => Self::Agx,
+            Self::Agx => Self::KhronosPbrNeutral,
+            Self::KhronosPbrNeutral => Self::None,
+            Self::None => Self::AcesFitted,
+        }
+    }
+}
+
+/// How [Tonemapping] turns the HDR image into colors the screen can show.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct TonemappingSettings {
+    /// In EV: every step doubles the brightness of the image. With `auto_exposure`, this shifts the
+    /// exposure [AutoExposure] picks instead.
+    pub(crate) exposure: f32,
+    /// Whether to adapt the exposure to the brightness of the scene.
+    pub(crate) auto_exposure: bool,
+    pub(crate) operator: TonemapOperator,
+    /// The brightness that ends up as pure white, for the operators that have one (Reinhard
+    /// extended and Uncharted 2).
+    pub(crate) white_point: f32,
+}
+
+impl Default for TonemappingSettings {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            auto_exposure: false,
+            operator: TonemapOperator::default(),
+            white_point: 11.2,
+        }
+    }
+}
+
+// This has to match `TonemappingUniform` in hdr.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TonemappingUniform {
+    exposure: f32,
+    white_point: f32,
+    tonemapper: u32,
+    auto_exposure: u32,
+}
+
+impl From<TonemappingSettings> for TonemappingUniform {
+    fn from(settings: TonemappingSettings) -> Self {
+        Self {
+            // The shader only needs the factor to multiply the colors with
+            exposure: settings.exposure.exp2(),
+            white_point: settings.white_point,
+            tonemapper: settings.operator as u32,
+            auto_exposure: settings.auto_exposure.into(),
+        }
+    }
+}
+
 /// # High Dynamic Range Rendering
 /// When we use `TextureFormat::Bgra8UnormSrgb` for the surface texture, it means that we have 8 bits
 /// for each red, green, blue and alpha channel. While the channels are stored as integers between 0
@@ -25,18 +112,42 @@ use wgpu::Operations;
 /// our scene in an HDR format, then convert the values to a supported format such as
 /// `TextureFormat::Bgra8UnormSrgb` before displaying them on the screen, using a technique called
 /// tone mapping.
+///
+/// The HDR image goes through a [PostProcessStack] on its way to the surface: [DepthOfField] blurs
+/// what is out of focus when it is switched on, [Bloom] adds a glow around the brightest parts of
+/// the image, and [Tonemapping] converts it into the format of the surface, where [ColorGrading]
+/// gives it its final look. Optionally, [AntiAliasing] then smooths the edges of the tonemapped
+/// image.
+///
+/// ## Exposure and Tone Mapping Operators
+/// Like a camera, the image is exposed first: every color is multiplied by `2^EV`, so one more EV
+/// makes the scene twice as bright. Then a [TonemapOperator] squeezes the exposed colors into the
+/// range the screen can show. Each operator trades off contrast, saturation and how highlights fade
+/// to white differently, so they can be switched at runtime to compare them. They are all compiled
+/// into the same shader, which picks one with a uniform, so switching doesn't need a new pipeline.
+/// Instead of setting the exposure by hand, [AutoExposure] can adapt it to the scene. It measures
+/// the HDR image before any of the stages run, so that the glow of bloom doesn't count towards how
+/// bright the scene is.
 pub(crate) struct HdrPipeline {
-    pipeline: wgpu::RenderPipeline,
-    bind_group: wgpu::BindGroup,
     texture: Texture,
-    width: u32,
-    height: u32,
     format: wgpu::TextureFormat,
-    layout: wgpu::BindGroupLayout,
+    surface_format: wgpu::TextureFormat,
+    post_process: PostProcessStack,
+    anti_aliasing: AntiAliasing,
 }
 
 impl HdrPipeline {
-    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
+    /// `camera`, `velocity` and `depth` are what the scene gets rendered with, for temporal
+    /// anti-aliasing, and depth of field also needs the `projection`.
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        config: &wgpu::SurfaceConfiguration,
+        projection: &Projection,
+        camera: &wgpu::Buffer,
+        velocity: &VelocityBuffer,
+        depth: &Texture,
+    ) -> Self {
         let width = config.width;
         let height = config.height;
 
@@ -44,7 +155,39 @@ impl HdrPipeline {
         // rendering.
         let format = wgpu::TextureFormat::Rgba16Float;
 
-        let texture = Texture::create_2d_texture(
+        let texture = Self::create_texture(device, format, width, height);
+
+        let mut post_process = PostProcessStack::new(width, height);
+        post_process.push(Taa::new(device, camera, velocity, depth));
+        post_process.push(DepthOfField::new(device, projection, depth));
+        // Only wanted for some shots
+        post_process.set_enabled(DepthOfField::LABEL, false);
+        post_process.push(Bloom::new(device, width, height));
+        post_process.push(Tonemapping::new(device, config.format));
+        // Switched on once there is a LUT to grade with
+        post_process.push(ColorGrading::new(device, queue));
+        post_process.set_enabled(ColorGrading::LABEL, false);
+        post_process.push(Fxaa::new(device));
+        post_process.push(Smaa::new(device, queue, width, height));
+        let anti_aliasing = AntiAliasing::default();
+        anti_aliasing.apply(&mut post_process);
+
+        Self {
+            texture,
+            format,
+            surface_format: config.format,
+            post_process,
+            anti_aliasing,
+        }
+    }
+
+    fn create_texture(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Texture {
+        Texture::create_2d_texture(
             device,
             width,
             height,
@@ -52,8 +195,110 @@ impl HdrPipeline {
             wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
             wgpu::FilterMode::Nearest,
             Some("hdr_texture"),
+        )
+    }
+
+    /// Recreates the HDR texture at the size of the new `depth` buffer, which the scene gets
+    /// rendered with from now on, along with `velocity`.
+    pub fn resize(&mut self, device: &wgpu::Device, velocity: &VelocityBuffer, depth: &Texture) {
+        let (width, height) = (depth.size.width, depth.size.height);
+        self.texture = Self::create_texture(device, self.format, width, height);
+        self.post_process.resize(device, width, height);
+        if let Some(taa) = self.post_process.effect_mut::<Taa>() {
+            taa.set_scene(device, velocity, depth);
+        }
+        if let Some(dof) = self.post_process.effect_mut::<DepthOfField>() {
+            dof.set_scene(device, depth);
+        }
+    }
+
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.texture.view
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        self.format
+    }
+
+    /// The stages the HDR image goes through, for changing their settings or order.
+    pub(crate) fn post_process(&mut self) -> &mut PostProcessStack {
+        &mut self.post_process
+    }
+
+    pub(crate) fn anti_aliasing(&self) -> AntiAliasing {
+        self.anti_aliasing
+    }
+
+    pub(crate) fn set_anti_aliasing(&mut self, anti_aliasing: AntiAliasing) {
+        self.anti_aliasing = anti_aliasing;
+        anti_aliasing.apply(&mut self.post_process);
+        // Whatever is left in the history is from the last time TAA was on
+        self.reset_history();
+    }
+
+    /// How far to shift the projection for the next frame, in pixels. Only temporal anti-aliasing
+    /// needs the image to move around.
+    pub(crate) fn jitter(&mut self) -> Vector2<f32> {
+        match (self.anti_aliasing, self.post_process.effect_mut::<Taa>()) {
+            (AntiAliasing::Taa, Some(taa)) => taa.jitter(),
+            _ => Vector2::new(0.0, 0.0),
+        }
+    }
+
+    /// Throws away what the stages remember from earlier frames, for when the camera cuts
+    /// somewhere else.
+    pub(crate) fn reset_history(&mut self) {
+        if let Some(taa) = self.post_process.effect_mut::<Taa>() {
+            taa.reset_history();
+        }
+    }
+
+    /// Uploads the settings of the stages. `dt` is the time since the last frame, which auto
+    /// exposure adapts over.
+    pub(crate) fn update(&mut self, queue: &wgpu::Queue, dt: instant::Duration) {
+        self.post_process.update(queue, dt);
+    }
+
+    /// This measures the brightness of the internal HDR texture for auto exposure, then runs it
+    /// through the post-processing stages, which render it to the [TextureView] supplied as
+    /// parameter.
+    pub fn process(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        if let Some(tonemapping) = self.post_process.effect_mut::<Tonemapping>() {
+            tonemapping.measure(device, encoder, &self.texture);
+        }
+        self.post_process.process(
+            device,
+            encoder,
+            &self.texture,
+            PostTarget {
+                view: output,
+                format: self.surface_format,
+            },
         );
+    }
+}
+
+/// Exposes the HDR image and squeezes it into the format of the surface with a [TonemapOperator].
+/// This is usually the last stage of the [PostProcessStack], since it is the one that leaves HDR.
+pub(crate) struct Tonemapping {
+    pub(crate) settings: TonemappingSettings,
+    pub(crate) auto_exposure: AutoExposure,
+    buffer: wgpu::Buffer,
+    layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::RenderPipeline,
+    /// The bind groups reading the images the effect has been handed.
+    bind_groups: InputBindGroups,
+    output_format: wgpu::TextureFormat,
+}
 
+impl Tonemapping {
+    /// `output_format` is the format of the surface.
+    pub(crate) fn new(device: &wgpu::Device, output_format: wgpu::TextureFormat) -> Self {
         let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("hdr_bind_group_layout"),
             entries: &[
@@ -74,24 +319,38 @@ impl HdrPipeline {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
-            ],
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("hdr_bind_group"),
-            layout: &layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                // The tonemapping settings
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                // The luminance auto exposure adapted to
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
             ],
         });
 
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tonemapping Buffer"),
+            size: std::mem::size_of::<TonemappingUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
         let shader = wgpu::include_wgsl!("shaders/hdr.wgsl");
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: None,
@@ -102,7 +361,7 @@ impl HdrPipeline {
         let pipeline = crate::State::create_render_pipeline(
             device,
             &pipeline_layout,
-            config.format,
+            output_format,
             None,
             // We'll use some math to generate the vertex data in the shader, so we don't need any
             // vertex buffers
@@ -113,71 +372,98 @@ impl HdrPipeline {
         );
 
         Self {
-            pipeline,
-            bind_group,
-            texture,
-            width,
-            height,
-            format,
+            settings: TonemappingSettings::default(),
+            auto_exposure: AutoExposure::new(device),
+            buffer,
             layout,
+            bind_groups: InputBindGroups::default(),
+            pipeline,
+            output_format,
         }
     }
 
-    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
-        self.texture = Texture::create_2d_texture(
-            device,
-            width,
-            height,
-            wgpu::TextureFormat::Rgba16Float,
-            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            wgpu::FilterMode::Nearest,
-            Some("hdr_texture"),
-        );
-        self.bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("hdr_bind_group"),
-            layout: &self.layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&self.texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&self.texture.sampler),
-                },
-            ],
-        });
-        self.width = width;
-        self.height = height;
+    /// Lets auto exposure measure `source`, if it is switched on.
+    fn measure(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        source: &Texture,
+    ) {
+        if self.settings.auto_exposure {
+            self.auto_exposure.compute(device, encoder, source);
+        }
     }
+}
 
-    pub fn view(&self) -> &wgpu::TextureView {
-        &self.texture.view
+impl PostEffect for Tonemapping {
+    fn label(&self) -> &'static str {
+        "Tonemapping"
     }
 
-    pub fn format(&self) -> wgpu::TextureFormat {
-        self.format
+    fn invalidate(&mut self) {
+        self.bind_groups.clear();
+        self.auto_exposure.invalidate();
     }
 
-    /// This renders the internal HDR texture to the [TextureView] supplied as parameter.
-    pub fn process(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
-        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("hdr_render_pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: output,
-                resolve_target: None,
-                ops: Operations {
-                    load: wgpu::LoadOp::Load,
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: None,
-            timestamp_writes: None,
-            occlusion_query_set: None,
+    fn output_format(&self, _input: wgpu::TextureFormat) -> wgpu::TextureFormat {
+        self.output_format
+    }
+
+    fn update(&mut self, queue: &wgpu::Queue, dt: instant::Duration) {
+        queue.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&[TonemappingUniform::from(self.settings)]),
+        );
+        self.auto_exposure.update(queue, dt);
+    }
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget) {
+        let device = context.device;
+        let bind_group = self.bind_groups.get(input, || {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("hdr_bind_group"),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&input.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&input.sampler),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: self.buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: self.auto_exposure.adaptation_buffer().as_entire_binding(),
+                    },
+                ],
+            })
         });
 
+        let mut render_pass = context
+            .encoder
+            .begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("hdr_render_pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: output.view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: wgpu::LoadOp::Load,
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
         render_pass.set_pipeline(&self.pipeline);
-        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_bind_group(0, bind_group, &[]);
         render_pass.draw(0..3, 0..1);
     }
 }
@@ -1,7 +1,8 @@
 use crate::model;
-use cgmath::{prelude::*, Deg, Matrix4, Quaternion, Vector3};
-use wgpu::util::DeviceExt;
-use wgpu::Device;
+use crate::scene::Transform;
+use cgmath::{prelude::*, Deg, Matrix3, Matrix4, Quaternion, Vector3};
+use std::mem;
+use std::ops::Range;
 
 /// Instancing allows us to draw the same object multiple times with different properties (position,
 /// orientation, size, color, etc.). There are multiple ways of doing instancing. One way would be to
@@ -14,9 +15,17 @@ use wgpu::Device;
 /// A `Quaternion` is a mathematical structure often used to represent rotation. Using these values
 /// directly in the shader would be a pain, as quaternions don't have a WGSL analog. So, we'll convert
 /// the `Instance` data into a matrix and store it in a struct called `InstanceRaw`.
+///
+/// Besides its transform, an instance can be tinted with a color that the shader multiplies the
+/// diffuse color with, and it can be drawn with a different material than the one its meshes use.
 pub(crate) struct Instance {
-    position: Vector3<f32>,
-    rotation: Quaternion<f32>,
+    pub(crate) position: Vector3<f32>,
+    pub(crate) rotation: Quaternion<f32>,
+    pub(crate) scale: Vector3<f32>,
+    pub(crate) tint: [f32; 4],
+    /// Index into the materials of the model this instance is drawn with. `None` uses the materials
+    /// of the meshes. Neighbouring instances with different materials can't share a draw call.
+    pub(crate) material: Option<usize>,
 }
 
 /// This is the data that goes into wgpu::Buffer. We keep these separate so that we can update `Instance`
@@ -34,28 +43,56 @@ pub(crate) struct Instance {
 /// On top of that, computing the inverse of a matrix is actually really expensive, especially doing
 /// that computation for every vertex.
 ///
-/// Instead, we're going to add a `normal` matrix field to `InstanceRaw`. Instead of inverting the
-/// model matrix, we'll just use the instance's rotation to create a `Matrix3`.
+/// Instead, we're going to add a `normal` matrix field to `InstanceRaw`, which we compute once per
+/// instance on the CPU. As long as an instance is only rotated, the rotation matrix is all we need. A
+/// non-uniform scale however squashes the surface in one direction, which tilts the normals the
+/// *other* way: stretching a sphere along x makes its normals point closer to the y-z plane. The
+/// matrix that does this is the inverse transpose of the upper 3x3 part of the model matrix.
+///
+/// # Previous Model Matrix
+/// Temporal anti-aliasing needs to know how far every pixel moved since the last frame, which
+/// depends on where the instance was back then. The model matrix of the last frame comes along for
+/// that, but it doesn't fit into the vertex attributes of the main shaders next to everything else,
+/// so only the velocity pass reads it, with the layout of [InstanceRaw::velocity_desc].
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct InstanceRaw {
     model: [[f32; 4]; 4],
     normal: [[f32; 3]; 3],
+    tint: [f32; 4],
+    previous_model: [[f32; 4]; 4],
 }
 
 impl Instance {
+    pub(crate) fn new(position: Vector3<f32>, rotation: Quaternion<f32>) -> Self {
+        Self {
+            position,
+            rotation,
+            scale: Vector3::new(1.0, 1.0, 1.0),
+            tint: [1.0; 4],
+            material: None,
+        }
+    }
+
     pub(crate) fn to_raw(&self) -> InstanceRaw {
-        InstanceRaw {
-            model: (Matrix4::from_translation(self.position) * Matrix4::from(self.rotation)).into(),
-            normal: cgmath::Matrix3::from(self.rotation).into(),
+        InstanceRaw::from_matrix(self.transform().to_matrix(), self.tint)
+    }
+
+    /// The transform of this instance, for placing it in a [crate::scene::SceneGraph].
+    pub(crate) fn transform(&self) -> Transform {
+        Transform {
+            translation: self.position,
+            rotation: self.rotation,
+            scale: self.scale,
         }
     }
 
     const NUM_INSTANCES_PER_ROW: u32 = 10;
 
     const SPACE_BETWEEN: f32 = 3.0;
-    pub(crate) fn create_instances(device: &Device) -> (Vec<Instance>, wgpu::Buffer) {
-        let instances = (0..Self::NUM_INSTANCES_PER_ROW)
+    /// A 10x10 grid of instances, each one rotated by 45 degrees around the direction it is placed in.
+    pub(crate) fn create_grid() -> Vec<Instance> {
+        (0..Self::NUM_INSTANCES_PER_ROW)
             .flat_map(|z| {
                 (0..Self::NUM_INSTANCES_PER_ROW).map(move |x| {
                     let x =
@@ -70,25 +107,280 @@ impl Instance {
                     } else {
                         Quaternion::from_axis_angle(position.normalize(), Deg(45.0))
                     };
-                    Self { position, rotation }
+                    Self::new(position, rotation)
                 })
             })
-            .collect::<Vec<_>>();
+            .collect::<Vec<_>>()
+    }
+}
+
+impl InstanceRaw {
+    pub(crate) fn model_matrix(&self) -> Matrix4<f32> {
+        self.model.into()
+    }
+
+    /// Builds the instance data from an arbitrary world matrix, such as the ones computed by the
+    /// [crate::scene::SceneGraph]. See the [InstanceRaw] docs for why the normal matrix is the
+    /// inverse transpose. The instance hasn't moved since the last frame, unless
+    /// [Self::with_previous] says otherwise.
+    pub(crate) fn from_matrix(model: Matrix4<f32>, tint: [f32; 4]) -> Self {
+        let linear = Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+        let normal = linear
+            .invert()
+            .map(|inverse| inverse.transpose())
+            .unwrap_or(linear);
+        Self {
+            model: model.into(),
+            normal: normal.into(),
+            tint,
+            previous_model: model.into(),
+        }
+    }
+
+    /// Sets where the instance was in the last frame.
+    pub(crate) fn with_previous(mut self, previous_model: Matrix4<f32>) -> Self {
+        self.previous_model = previous_model.into();
+        self
+    }
+
+    /// The layout the velocity pass reads the instances with: the model matrix in the same place as
+    /// [model::Vertex::desc], and the previous model matrix in the locations of the normal matrix
+    /// and the tint, which the velocity pass doesn't need.
+    pub(crate) fn velocity_desc() -> wgpu::VertexBufferLayout<'static> {
+        const fn column(offset: usize, shader_location: u32) -> wgpu::VertexAttribute {
+            wgpu::VertexAttribute {
+                offset: (offset * mem::size_of::<f32>()) as wgpu::BufferAddress,
+                shader_location,
+                format: wgpu::VertexFormat::Float32x4,
+            }
+        }
+        const ATTRIBUTES: [wgpu::VertexAttribute; 8] = [
+            column(0, 5),
+            column(4, 6),
+            column(8, 7),
+            column(12, 8),
+            // The previous model matrix comes after the model matrix, the normal matrix and the tint
+            column(29, 9),
+            column(33, 10),
+            column(37, 11),
+            column(41, 14),
+        ];
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+/// A handle to an instance in an [InstanceSet]. It stays valid until the instance is removed, even
+/// if other instances are added or removed in the meantime.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct InstanceId(usize);
+
+/// # Dynamic Instances
+/// A set of instances that can be added, removed and changed at runtime, along with the GPU buffer
+/// holding their [InstanceRaw] data.
+///
+/// The instances are kept tightly packed, so that they can be drawn with a single range. Removing an
+/// instance moves the last one into its place, which is why instances are referred to by
+/// [InstanceId] rather than by their index.
+///
+/// Changes are only recorded on the CPU. [InstanceSet::upload] then writes the range of instances
+/// that changed since the last upload, rather than the whole buffer, and nothing at all if nothing
+/// changed. If the instances no longer fit, the buffer is recreated with twice the capacity, the
+/// same way a `Vec` grows.
+///
+/// Every uploaded instance also carries the matrix it had at the upload before, as its previous
+/// model matrix. Instances that moved are uploaded once more after they stop, so that they don't
+/// keep looking like they are moving.
+///
+/// # Material Overrides
+/// A draw call uses a single material, so instances with a different [Instance::material] than
+/// their neighbours in the buffer end up in draw calls of their own, see
+/// [InstanceSet::draw_ranges]. The order of the instances is up to the set, so with many different
+/// overrides mixed together, a separate set for each material keeps the number of draw calls down.
+pub(crate) struct InstanceSet {
+    label: String,
+    list: InstanceList,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+}
+
+impl InstanceSet {
+    pub(crate) fn new(device: &wgpu::Device, label: &str, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            label: label.to_string(),
+            list: InstanceList::default(),
+            buffer: Self::create_buffer(device, label, capacity),
+            capacity,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, label: &str, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (capacity * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub(crate) fn add(&mut self, instance: Instance) -> InstanceId {
+        self.list.add(instance)
+    }
+
+    /// Removes an instance, returning it if it was still in the set.
+    pub(crate) fn remove(&mut self, id: InstanceId) -> Option<Instance> {
+        self.list.remove(id)
+    }
+
+    /// Gives mutable access to an instance, which gets uploaded again by the next [Self::upload].
+    pub(crate) fn get_mut(&mut self, id: InstanceId) -> Option<&mut Instance> {
+        self.list.get_mut(id)
+    }
+
+    pub(crate) fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Writes all changes since the last upload into the instance buffer, growing it if needed.
+    pub(crate) fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let len = self.list.instances.len();
+        if len > self.capacity {
+            self.capacity = len.next_power_of_two();
+            self.buffer = Self::create_buffer(device, &self.label, self.capacity);
+            self.list.dirty = Some(0..len);
+        }
+
+        if let Some((range, data)) = self.list.take_changes() {
+            queue.write_buffer(
+                &self.buffer,
+                (range.start * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+                bytemuck::cast_slice(&data),
+            );
+        }
+    }
+
+    /// Splits the visible instances into consecutive ranges that use the same material override, so
+    /// that each range can be drawn with a single draw call. Culled instances end a range, since the
+    /// instances of a draw call have to sit next to each other in the buffer, and so does every
+    /// change of material between neighbours.
+    pub(crate) fn draw_ranges(
+        &self,
+        is_visible: impl FnMut(&Instance) -> bool,
+    ) -> Vec<(Option<usize>, Range<u32>)> {
+        self.list.draw_ranges(is_visible)
+    }
+}
+
+/// The instances of an [InstanceSet] and what changed about them, apart from the buffer they get
+/// uploaded to.
+#[derive(Default)]
+struct InstanceList {
+    instances: Vec<Instance>,
+    /// The id of the instance at each index.
+    ids: Vec<InstanceId>,
+    /// The index of each instance id, or `None` once the instance has been removed.
+    indices: Vec<Option<usize>>,
+    /// The indices that need to be uploaded.
+    dirty: Option<Range<usize>>,
+    /// The model matrix of each instance as of the last upload.
+    uploaded: Vec<Matrix4<f32>>,
+    /// The indices that changed in the last upload.
+    moved: Option<Range<usize>>,
+}
 
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
+impl InstanceList {
+    fn mark_dirty(&mut self, index: usize) {
+        self.dirty = Some(match self.dirty.take() {
+            Some(dirty) => dirty.start.min(index)..dirty.end.max(index + 1),
+            None => index..index + 1,
         });
+    }
+
+    fn add(&mut self, instance: Instance) -> InstanceId {
+        let id = InstanceId(self.indices.len());
+        self.indices.push(Some(self.instances.len()));
+        self.ids.push(id);
+        self.uploaded.push(instance.transform().to_matrix());
+        self.instances.push(instance);
+        self.mark_dirty(self.instances.len() - 1);
+        id
+    }
+
+    fn remove(&mut self, id: InstanceId) -> Option<Instance> {
+        let index = self.indices.get_mut(id.0)?.take()?;
+        let instance = self.instances.swap_remove(index);
+        self.ids.swap_remove(index);
+        self.uploaded.swap_remove(index);
+        // The last instance has moved into the gap
+        if let Some(&moved) = self.ids.get(index) {
+            self.indices[moved.0] = Some(index);
+            self.mark_dirty(index);
+        }
+        Some(instance)
+    }
 
-        (instances, instance_buffer)
+    fn get_mut(&mut self, id: InstanceId) -> Option<&mut Instance> {
+        let index = (*self.indices.get(id.0)?)?;
+        self.mark_dirty(index);
+        Some(&mut self.instances[index])
+    }
+
+    /// The range of instances that has to be uploaded, along with their data, or `None` if the
+    /// buffer is up to date.
+    fn take_changes(&mut self) -> Option<(Range<usize>, Vec<InstanceRaw>)> {
+        // What moved in the last upload is uploaded once more, now that it was where it is in the
+        // frame before too
+        let moved = self.moved.take();
+        self.moved = self.dirty.take();
+        let range = match (self.moved.clone(), moved) {
+            (Some(dirty), Some(moved)) => dirty.start.min(moved.start)..dirty.end.max(moved.end),
+            (Some(range), None) | (None, Some(range)) => range,
+            (None, None) => return None,
+        };
+        // Removing instances can leave the range pointing past the end
+        let range = range.start.min(self.instances.len())..range.end.min(self.instances.len());
+        if range.is_empty() {
+            return None;
+        }
+        let data = self.instances[range.clone()]
+            .iter()
+            .zip(&mut self.uploaded[range.clone()])
+            .map(|(instance, uploaded)| {
+                let raw = instance.to_raw().with_previous(*uploaded);
+                *uploaded = instance.transform().to_matrix();
+                raw
+            })
+            .collect();
+        Some((range, data))
+    }
+
+    fn draw_ranges(
+        &self,
+        mut is_visible: impl FnMut(&Instance) -> bool,
+    ) -> Vec<(Option<usize>, Range<u32>)> {
+        let mut ranges: Vec<(Option<usize>, Range<u32>)> = Vec::new();
+        for (i, instance) in self.instances.iter().enumerate() {
+            if !is_visible(instance) {
+                continue;
+            }
+            let i = i as u32;
+            match ranges.last_mut() {
+                Some((material, range)) if *material == instance.material && range.end == i => {
+                    range.end = i + 1
+                }
+                _ => ranges.push((instance.material, i..i + 1)),
+            }
+        }
+        ranges
     }
 }
 
 impl model::Vertex for InstanceRaw {
     fn desc() -> wgpu::VertexBufferLayout<'static> {
-        use std::mem;
         wgpu::VertexBufferLayout {
             array_stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
             // We need to switch from using a step mode of Vertex to Instance. This means that our
@@ -133,7 +425,130 @@ impl model::Vertex for InstanceRaw {
                     shader_location: 11,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                // Locations 12 and 13 are taken by the joints and weights of a SkinnedVertex.
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 25]>() as wgpu::BufferAddress,
+                    shader_location: 14,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Instance {
+        Instance::new(Vector3::new(x, 0.0, 0.0), Quaternion::one())
+    }
+
+    /// The x of every instance in the order they sit in the buffer.
+    fn positions(list: &InstanceList) -> Vec<f32> {
+        list.instances.iter().map(|i| i.position.x).collect()
+    }
+
+    fn uploaded_range(list: &mut InstanceList) -> Option<Range<usize>> {
+        list.take_changes().map(|(range, _)| range)
+    }
+
+    #[test]
+    fn adding_uploads_the_new_instances() {
+        let mut list = InstanceList::default();
+        list.add(at(0.0));
+        list.add(at(1.0));
+        assert_eq!(uploaded_range(&mut list), Some(0..2));
+        list.add(at(2.0));
+        assert_eq!(uploaded_range(&mut list), Some(0..3));
+        assert_eq!(positions(&list), [0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn nothing_is_uploaded_without_changes() {
+        let mut list = InstanceList::default();
+        list.add(at(0.0));
+        assert!(list.take_changes().is_some());
+        // Once more, for the previous model matrix
+        assert!(list.take_changes().is_some());
+        assert!(list.take_changes().is_none());
+        assert!(list.take_changes().is_none());
+    }
+
+    #[test]
+    fn updating_uploads_only_that_instance() {
+        let mut list = InstanceList::default();
+        let ids = (0..4).map(|x| list.add(at(x as f32))).collect::<Vec<_>>();
+        list.take_changes();
+        list.take_changes();
+
+        list.get_mut(ids[2]).unwrap().position.y = 5.0;
+        let (range, data) = list.take_changes().unwrap();
+        assert_eq!(range, 2..3);
+        let model = data[0].model_matrix();
+        assert_eq!(model.w.truncate(), Vector3::new(2.0, 5.0, 0.0));
+        assert_eq!(
+            Matrix4::from(data[0].previous_model).w.truncate(),
+            Vector3::new(2.0, 0.0, 0.0)
+        );
+
+        // The frame after, it hasn't moved anymore
+        let (range, data) = list.take_changes().unwrap();
+        assert_eq!(range, 2..3);
+        assert_eq!(data[0].model, data[0].previous_model);
+        assert!(list.take_changes().is_none());
+    }
+
+    #[test]
+    fn removing_moves_the_last_instance_into_the_gap() {
+        let mut list = InstanceList::default();
+        let ids = (0..4).map(|x| list.add(at(x as f32))).collect::<Vec<_>>();
+        list.take_changes();
+        list.take_changes();
+
+        assert_eq!(list.remove(ids[1]).unwrap().position.x, 1.0);
+        assert!(list.remove(ids[1]).is_none());
+        assert_eq!(positions(&list), [0.0, 3.0, 2.0]);
+        assert_eq!(uploaded_range(&mut list), Some(1..2));
+
+        // The ids of the other instances still find them
+        assert_eq!(list.get_mut(ids[3]).unwrap().position.x, 3.0);
+        assert_eq!(list.get_mut(ids[2]).unwrap().position.x, 2.0);
+        assert!(list.get_mut(ids[1]).is_none());
+    }
+
+    #[test]
+    fn removing_the_last_instance_uploads_nothing() {
+        let mut list = InstanceList::default();
+        list.add(at(0.0));
+        let last = list.add(at(1.0));
+        list.take_changes();
+        list.take_changes();
+
+        list.remove(last);
+        assert!(list.take_changes().is_none());
+        assert_eq!(positions(&list), [0.0]);
+    }
+
+    #[test]
+    fn draw_ranges_split_at_culled_instances_and_material_overrides() {
+        let mut list = InstanceList::default();
+        for (x, material) in [None, None, Some(1), Some(1), None, None]
+            .into_iter()
+            .enumerate()
+        {
+            list.add(Instance {
+                material,
+                ..at(x as f32)
+            });
+        }
+        assert_eq!(
+            list.draw_ranges(|_| true),
+            [(None, 0..2), (Some(1), 2..4), (None, 4..6)]
+        );
+        assert_eq!(
+            list.draw_ranges(|instance| instance.position.x != 1.0),
+            [(None, 0..1), (Some(1), 2..4), (None, 4..6)]
+        );
+    }
+}
@@ -1,11 +1,39 @@
+mod animation;
+mod anti_aliasing;
+mod auto_exposure;
+mod batch;
+mod bloom;
 mod camera;
+mod clustered;
+mod color_grading;
+mod culling;
+mod debug_draw;
+mod debug_view;
+mod deferred;
+mod depth_of_field;
+mod fog;
+mod geometry;
+mod gpu_culling;
 mod hdr;
 mod instance;
 mod light;
+mod lod;
+mod material_pipelines;
 mod model;
+mod morph;
+mod oit;
+mod post_process;
 mod resources;
+mod scene;
+mod scene_bind_group;
+mod sky;
+mod ssao;
 mod state;
 mod texture;
+mod transparency;
+mod velocity;
+#[cfg(test)]
+mod headless;
 
 use state::State;
 use winit::{
@@ -1,8 +1,4 @@
 use wgpu::util::DeviceExt;
-use wgpu::{
-    BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, Buffer, BufferBindingType,
-    Device,
-};
 
 /// In the real world, a light source emits photons that bounce around until they enter our eyes.
 /// The color we see is the light's original color minus whatever energy it lost while bouncing around.
@@ -17,7 +13,9 @@ use wgpu::{
 /// ambient lighting, diffuse lighting, and specular lighting. The `Blinn-Phong model` is a modification
 /// of the `Phong reflection model`, which cheats a bit at the specular calculation to speed things up.
 ///
-/// The `LightUniform` represents a colored point in space. It is used to represent the light source
+/// The `LightUniform` represents a colored point in space. It is used to represent the light source.
+/// A light far enough away that its rays are practically parallel, like the sun, is a directional
+/// light instead, which only has a direction.
 ///
 /// # Ambient Lighting
 /// Light has a tendency to bounce around and fill in the shadows. This is called `ambient lighting`.
@@ -47,8 +45,10 @@ use wgpu::{
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub(crate) struct LightUniform {
     pub(crate) position: [f32; 3],
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    _padding: u32,
+    /// 1 for a point light at `position`, 0 for a directional light shining from the direction
+    /// `position` points in, like the w of homogeneous coordinates. It also pads the position to
+    /// the 16 bytes uniforms require.
+    w: f32,
     pub(crate) color: [f32; 3],
     // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
     _padding2: u32,
@@ -58,44 +58,30 @@ impl LightUniform {
     pub fn new(position: [f32; 3], color: [f32; 3]) -> Self {
         LightUniform {
             position,
-            _padding: 0,
+            w: 1.0,
+            color,
+            _padding2: 0,
+        }
+    }
+
+    /// A light shining from `direction` everywhere, with no position.
+    pub(crate) fn directional(direction: [f32; 3], color: [f32; 3]) -> Self {
+        LightUniform {
+            position: direction,
+            w: 0.0,
             color,
             _padding2: 0,
         }
     }
 
-    pub fn create_bind_group(device: &Device) -> (Buffer, BindGroupLayout, BindGroup) {
-        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+    /// A uniform buffer holding the default light, for the
+    /// [crate::scene_bind_group::SceneBindGroup].
+    pub(crate) fn create_buffer(device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Light Buffer"),
             contents: bytemuck::cast_slice(&[LightUniform::default()]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
-
-        let light_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: None,
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-            });
-
-        let light_bind_group = device.create_bind_group(&BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-            label: None,
-        });
-
-        (light_buffer, light_bind_group_layout, light_bind_group)
+        })
     }
 }
 
@@ -103,7 +89,7 @@ impl Default for LightUniform {
     fn default() -> LightUniform {
         LightUniform {
             position: [2.0, 2.0, 2.0],
-            _padding: 0,
+            w: 1.0,
             color: [1.0, 1.0, 1.0],
             _padding2: 0,
         }
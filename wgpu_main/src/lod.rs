@@ -0,0 +1,541 @@
+use cgmath::{InnerSpace, Vector3};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// A single level of detail of a [crate::model::Mesh]. All levels share the mesh's vertex and index
+/// buffers: `indices` is the range of the index buffer holding this level's triangles, and
+/// `base_vertex` is added to every index, so that levels with their own vertices (such as the ones
+/// loaded from artist-supplied LOD files) can live in the same vertex buffer.
+#[derive(Debug, Clone)]
+pub(crate) struct LodLevel {
+    pub(crate) indices: Range<u32>,
+    pub(crate) base_vertex: i32,
+}
+
+/// # Level of Detail
+/// A mesh that covers a handful of pixels doesn't need hundreds of triangles. A LOD chain is a list
+/// of versions of the same mesh with fewer and fewer triangles, and every frame each instance picks
+/// the coarsest version that still looks the same at its size on screen.
+///
+/// The size on screen is the projected radius of the instance's bounding sphere, as a fraction of
+/// half the viewport height. It is compared against one threshold per transition: an instance smaller
+/// than `thresholds[0]` uses LOD 1, smaller than `thresholds[1]` uses LOD 2 and so on.
+///
+/// # Hysteresis
+/// An instance sitting right at a threshold would flip between two levels every time the camera
+/// moves a tiny bit, which shows up as popping. To avoid that, an instance only switches to a
+/// coarser level once it is `hysteresis` (as a fraction) below the threshold, and back to the finer
+/// level once it is that much above it.
+pub(crate) struct LodSelector {
+    pub(crate) thresholds: Vec<f32>,
+    pub(crate) hysteresis: f32,
+}
+
+impl Default for LodSelector {
+    fn default() -> Self {
+        Self {
+            thresholds: vec![0.3, 0.15, 0.075],
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl LodSelector {
+    /// The projected size of a sphere at `distance` from the camera. `tan_half_fovy` is the tangent
+    /// of half the vertical field of view.
+    pub(crate) fn screen_size(radius: f32, distance: f32, tan_half_fovy: f32) -> f32 {
+        radius / (distance.max(radius) * tan_half_fovy)
+    }
+
+    /// Picks the level for an instance of the given screen size that used `current` last frame.
+    /// `num_lods` is the number of levels the mesh actually has.
+    pub(crate) fn select(&self, current: usize, screen_size: f32, num_lods: usize) -> usize {
+        let max_lod = num_lods.min(self.thresholds.len() + 1).saturating_sub(1);
+        let mut lod = current.min(max_lod);
+        while lod < max_lod && screen_size < self.thresholds[lod] * (1.0 - self.hysteresis) {
+            lod += 1;
+        }
+        while lod > 0 && screen_size > self.thresholds[lod - 1] * (1.0 + self.hysteresis) {
+            lod -= 1;
+        }
+        lod
+    }
+}
+
+/// The most levels [generate_lods] creates, including the original mesh.
+pub(crate) const MAX_LODS: usize = 4;
+
+/// Builds the LOD chain for a mesh by repeatedly halving its triangle count with [simplify]. The
+/// first entry is the original index list. The chain ends early once simplifying stops paying off,
+/// which happens when most of the remaining vertices sit on seams or borders.
+pub(crate) fn generate_lods(
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    indices: &[u32],
+) -> Vec<Vec<u32>> {
+    let mut lods = vec![indices.to_vec()];
+    while lods.len() < MAX_LODS {
+        let previous = &lods[lods.len() - 1];
+        let simplified = simplify(positions, normals, previous, previous.len() / 2);
+        if simplified.len() * 5 > previous.len() * 4 {
+            break;
+        }
+        lods.push(simplified);
+    }
+    lods
+}
+
+/// The error quadric of a vertex: the sum of the squared distances to the planes of the triangles
+/// around it, stored as the 10 unique coefficients of a symmetric 4x4 matrix.
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(n: Vector3<f64>, d: f64, weight: f64) -> Self {
+        let (a, b, c) = (n.x, n.y, n.z);
+        Self(
+            [
+                a * a,
+                a * b,
+                a * c,
+                a * d,
+                b * b,
+                b * c,
+                b * d,
+                c * c,
+                c * d,
+                d * d,
+            ]
+            .map(|q| q * weight),
+        )
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (q, o) in self.0.iter_mut().zip(other.0) {
+            *q += o;
+        }
+    }
+
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let [aa, ab, ac, ad, bb, bc, bd, cc, cd, dd] = self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        aa * x * x
+            + 2.0 * ab * x * y
+            + 2.0 * ac * x * z
+            + 2.0 * ad * x
+            + bb * y * y
+            + 2.0 * bc * y * z
+            + 2.0 * bd * y
+            + cc * z * z
+            + 2.0 * cd * z
+            + dd
+    }
+}
+
+/// Collapses whose vertex normals are further apart than this (about 45 degrees) would smear the
+/// shading, so they are skipped.
+const MIN_NORMAL_DOT: f32 = 0.7;
+/// Collapses that turn a triangle by more than about 60 degrees would change the silhouette too much.
+const MIN_FACE_DOT: f64 = 0.5;
+
+/// # Mesh Simplification
+/// Reduces `indices` to about `target_index_count` indices with quadric error metrics (Garland and
+/// Heckbert). Every vertex gets a quadric that measures the squared distance to the planes of its
+/// original triangles. Collapsing an edge moves one vertex onto the other, and the cost of doing so
+/// is the quadric error at the new position. Cheap collapses remove triangles that barely contribute
+/// to the shape, such as those on flat areas.
+///
+/// Vertices are only ever moved onto existing vertices, so the vertex buffer stays the same and only
+/// the index list changes.
+///
+/// # Seams
+/// Vertices that share a position but differ in their other attributes (a UV seam, or a hard edge
+/// with split normals) are collapsed together, as a group. A group may only collapse into another
+/// group if every one of its vertices is connected to a vertex of the target group, so each side of
+/// a seam stays connected to its own texture coordinates and normals. Vertices on the border of an
+/// open mesh are never moved.
+pub(crate) fn simplify(
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    indices: &[u32],
+    target_index_count: usize,
+) -> Vec<u32> {
+    // Group vertices by position
+    let mut groups_by_position = HashMap::new();
+    let group = positions
+        .iter()
+        .map(|p| {
+            let next = groups_by_position.len();
+            *groups_by_position
+                .entry(p.map(f32::to_bits))
+                .or_insert(next)
+        })
+        .collect::<Vec<_>>();
+    let num_groups = groups_by_position.len();
+    let position = |v: u32| Vector3::from(positions[v as usize]).cast::<f64>().unwrap();
+
+    // Accumulate the quadrics, and find the border edges, which are only used by a single triangle
+    let mut quadrics = vec![Quadric::default(); num_groups];
+    let mut edge_uses = HashMap::new();
+    for tri in indices.chunks_exact(3) {
+        let (p0, p1, p2) = (position(tri[0]), position(tri[1]), position(tri[2]));
+        let n = (p1 - p0).cross(p2 - p0);
+        let area = n.magnitude();
+        if area > 0.0 {
+            let n = n / area;
+            let quadric = Quadric::from_plane(n, -n.dot(p0), area * 0.5);
+            for &v in tri {
+                quadrics[group[v as usize]].add(&quadric);
+            }
+        }
+        for (a, b) in [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])] {
+            let (a, b) = (group[a as usize], group[b as usize]);
+            *edge_uses.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    let mut locked = vec![false; num_groups];
+    for (&(a, b), &uses) in &edge_uses {
+        if uses == 1 {
+            locked[a] = true;
+            locked[b] = true;
+        }
+    }
+
+    // Every vertex points to the vertex it was collapsed into, or to itself
+    let mut remap = (0..positions.len() as u32).collect::<Vec<_>>();
+    fn resolve(remap: &mut [u32], mut v: u32) -> u32 {
+        while remap[v as usize] != v {
+            let next = remap[remap[v as usize] as usize];
+            remap[v as usize] = next;
+            v = next;
+        }
+        v
+    }
+
+    let mut indices = indices.to_vec();
+    loop {
+        let num_triangles = indices.len() / 3;
+        if indices.len() <= target_index_count {
+            break;
+        }
+
+        // The triangles around every group, and the candidate collapses
+        let mut triangles = vec![Vec::new(); num_groups];
+        let mut candidates = Vec::new();
+        for (t, tri) in indices.chunks_exact(3).enumerate() {
+            for (i, &v) in tri.iter().enumerate() {
+                let g = group[v as usize];
+                triangles[g].push(t);
+                let next = group[tri[(i + 1) % 3] as usize];
+                for (from, to) in [(g, next), (next, g)] {
+                    if !locked[from] {
+                        let mut quadric = quadrics[from];
+                        quadric.add(&quadrics[to]);
+                        let target = positions[tri
+                            .iter()
+                            .find(|&&w| group[w as usize] == to)
+                            .copied()
+                            .unwrap_or(v) as usize];
+                        let cost = quadric.error(Vector3::from(target).cast::<f64>().unwrap());
+                        candidates.push((cost, from, to));
+                    }
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        candidates.dedup_by(|a, b| a.1 == b.1 && a.2 == b.2);
+
+        let mut touched = vec![false; num_groups];
+        let mut removed = 0;
+        for (_, from, to) in candidates {
+            if touched[from] || touched[to] {
+                continue;
+            }
+            if num_triangles - removed <= target_index_count / 3 {
+                break;
+            }
+
+            // Find the vertex of `to` every vertex of `from` moves onto
+            let mut moves: Vec<(u32, u32)> = Vec::new();
+            let mut stranded = Vec::new();
+            for &t in &triangles[from] {
+                let tri = &indices[t * 3..t * 3 + 3];
+                for &u in tri.iter().filter(|&&u| group[u as usize] == from) {
+                    match tri.iter().find(|&&v| group[v as usize] == to) {
+                        Some(&v) => {
+                            if !moves.iter().any(|&(m, _)| m == u) {
+                                moves.push((u, v));
+                            }
+                        }
+                        None => stranded.push(u),
+                    }
+                }
+            }
+            let valid = stranded.iter().all(|u| moves.iter().any(|&(m, _)| m == *u))
+                && moves.iter().all(|&(u, v)| {
+                    let (nu, nv) = (
+                        Vector3::from(normals[u as usize]),
+                        Vector3::from(normals[v as usize]),
+                    );
+                    nu.dot(nv) >= MIN_NORMAL_DOT * nu.magnitude() * nv.magnitude()
+                });
+            if !valid {
+                continue;
+            }
+
+            // Make sure none of the remaining triangles turns too far, or flips over
+            let target = position(moves[0].1);
+            let mut collapsed = 0;
+            let keeps_shape = triangles[from].iter().all(|&t| {
+                let tri = &indices[t * 3..t * 3 + 3];
+                if tri.iter().any(|&v| group[v as usize] == to) {
+                    collapsed += 1;
+                    return true;
+                }
+                let old = tri.iter().map(|&v| position(v)).collect::<Vec<_>>();
+                let new = tri
+                    .iter()
+                    .map(|&v| {
+                        if group[v as usize] == from {
+                            target
+                        } else {
+                            position(v)
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let old_normal = (old[1] - old[0]).cross(old[2] - old[0]);
+                let new_normal = (new[1] - new[0]).cross(new[2] - new[0]);
+                // Strictly, so that triangles collapsing into a line don't pass either
+                old_normal.dot(new_normal)
+                    > MIN_FACE_DOT * old_normal.magnitude() * new_normal.magnitude()
+            });
+            if !keeps_shape {
+                continue;
+            }
+
+            for (u, v) in moves {
+                remap[u as usize] = v;
+            }
+            let from_quadric = quadrics[from];
+            quadrics[to].add(&from_quadric);
+            // All triangles around both groups have changed, so they have to wait for the next pass
+            for &t in triangles[from].iter().chain(&triangles[to]) {
+                for &v in &indices[t * 3..t * 3 + 3] {
+                    touched[group[v as usize]] = true;
+                }
+            }
+            removed += collapsed;
+        }
+
+        if removed == 0 {
+            break;
+        }
+
+        // Apply the collapses and drop the triangles that have lost their area
+        let mut simplified = Vec::with_capacity(indices.len());
+        for tri in indices.chunks_exact(3) {
+            let tri = tri
+                .iter()
+                .map(|&v| resolve(&mut remap, v))
+                .collect::<Vec<_>>();
+            let (a, b, c) = (
+                group[tri[0] as usize],
+                group[tri[1] as usize],
+                group[tri[2] as usize],
+            );
+            if a != b && b != c && c != a {
+                simplified.extend(tri);
+            }
+        }
+        indices = simplified;
+    }
+
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// A flat grid of `n` by `n` quads in the xy plane, facing +z, with every quad split in two.
+    /// `height` lifts the vertices off the plane.
+    fn grid(n: u32, height: impl Fn(f32, f32) -> f32) -> (Vec<[f32; 3]>, Vec<[f32; 3]>, Vec<u32>) {
+        let positions = (0..=n)
+            .flat_map(|y| (0..=n).map(move |x| (x as f32, y as f32)))
+            .map(|(x, y)| [x, y, height(x, y)])
+            .collect::<Vec<_>>();
+        let normals = vec![[0.0, 0.0, 1.0]; positions.len()];
+        let mut indices = Vec::new();
+        for y in 0..n {
+            for x in 0..n {
+                let i = y * (n + 1) + x;
+                indices.extend([i, i + 1, i + n + 2, i, i + n + 2, i + n + 1]);
+            }
+        }
+        (positions, normals, indices)
+    }
+
+    fn face_normal(positions: &[[f32; 3]], tri: &[u32]) -> Vector3<f32> {
+        let [a, b, c] = [0, 1, 2].map(|i| Vector3::from(positions[tri[i] as usize]));
+        (b - a).cross(c - a)
+    }
+
+    #[test]
+    fn simplify_reaches_the_target() {
+        let (positions, normals, indices) = grid(16, |_, _| 0.0);
+        let target = indices.len() / 2;
+        let simplified = simplify(&positions, &normals, &indices, target);
+        // Every collapse on a flat grid removes two triangles at once
+        assert!(
+            simplified.len() <= target,
+            "{} > {target}",
+            simplified.len()
+        );
+        assert!(
+            simplified.len() >= target - 6,
+            "{} < {target}",
+            simplified.len()
+        );
+    }
+
+    #[test]
+    fn simplify_keeps_the_border() {
+        let n = 16;
+        let (positions, normals, indices) = grid(n, |_, _| 0.0);
+        let simplified = simplify(&positions, &normals, &indices, 0);
+        assert!(simplified.len() < indices.len() / 4);
+        let used = simplified.iter().copied().collect::<HashSet<_>>();
+        for (v, p) in positions.iter().enumerate() {
+            let on_border = p[0] == 0.0 || p[1] == 0.0 || p[0] == n as f32 || p[1] == n as f32;
+            assert!(
+                !on_border || used.contains(&(v as u32)),
+                "vertex {v} at {p:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn simplify_keeps_seams_closed() {
+        // A UV seam down the middle of the grid: the vertices on it are copied, and the triangles
+        // right of it use the copies
+        let n = 16;
+        let seam = n / 2;
+        let (mut positions, mut normals, mut indices) = grid(n, |_, _| 0.0);
+        let mut copies = HashMap::new();
+        for y in 0..=n {
+            let v = y * (n + 1) + seam;
+            copies.insert(v, positions.len() as u32);
+            positions.push(positions[v as usize]);
+            normals.push(normals[v as usize]);
+        }
+        for tri in indices.chunks_exact_mut(3) {
+            let right = tri.iter().any(|&v| v % (n + 1) > seam);
+            for v in tri.iter_mut().filter(|_| right) {
+                *v = copies.get(v).copied().unwrap_or(*v);
+            }
+        }
+        let is_right = |v: u32| v as usize >= (n as usize + 1).pow(2) || v % (n + 1) > seam;
+
+        let simplified = simplify(&positions, &normals, &indices, indices.len() / 4);
+        assert!(simplified.len() < indices.len() / 2);
+        for tri in simplified.chunks_exact(3) {
+            let right = is_right(tri[0]);
+            assert!(
+                tri.iter().all(|&v| is_right(v) == right),
+                "{tri:?} crosses the seam"
+            );
+        }
+        // Both sides of the seam still meet at the same vertices, so there is no crack
+        let used = simplified.iter().copied().collect::<HashSet<_>>();
+        for (&left, &right) in &copies {
+            assert_eq!(
+                used.contains(&left),
+                used.contains(&right),
+                "{left} and {right}"
+            );
+        }
+    }
+
+    #[test]
+    fn simplify_doesnt_flip_triangles() {
+        let (positions, normals, indices) = grid(16, |x, y| (x * 0.7).sin() * (y * 0.5).cos());
+        for target in [indices.len() / 2, indices.len() / 4, 0] {
+            let simplified = simplify(&positions, &normals, &indices, target);
+            for tri in simplified.chunks_exact(3) {
+                assert!(face_normal(&positions, tri).z > 0.0, "{tri:?} flipped");
+            }
+        }
+    }
+
+    #[test]
+    fn the_cube_gets_levels_of_detail() {
+        let (models, _) = tobj::load_obj(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/models/cube.obj"),
+            &tobj::LoadOptions {
+                triangulate: true,
+                single_index: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mesh = &models[0].mesh;
+        let positions = mesh
+            .positions
+            .chunks_exact(3)
+            .map(|p| [p[0], p[1], p[2]])
+            .collect::<Vec<_>>();
+        let normals = mesh
+            .normals
+            .chunks_exact(3)
+            .map(|n| [n[0], n[1], n[2]])
+            .collect::<Vec<_>>();
+        let lods = generate_lods(&positions, &normals, &mesh.indices);
+        assert_eq!(lods.len(), MAX_LODS);
+        for pair in lods.windows(2) {
+            assert!(pair[1].len() < pair[0].len());
+        }
+    }
+
+    #[test]
+    fn select_waits_past_the_threshold() {
+        let selector = LodSelector::default();
+        // Just below the first threshold isn't far enough to switch
+        assert_eq!(selector.select(0, 0.28, 4), 0);
+        assert_eq!(selector.select(0, 0.26, 4), 1);
+        // And just above it isn't far enough to switch back
+        assert_eq!(selector.select(1, 0.32, 4), 1);
+        assert_eq!(selector.select(1, 0.34, 4), 0);
+        // In between, either level stays where it is
+        for size in [0.28, 0.3, 0.32] {
+            assert_eq!(selector.select(0, size, 4), 0);
+            assert_eq!(selector.select(1, size, 4), 1);
+        }
+    }
+
+    #[test]
+    fn select_skips_levels() {
+        let selector = LodSelector::default();
+        assert_eq!(selector.select(0, 0.01, 4), 3);
+        assert_eq!(selector.select(3, 1.0, 4), 0);
+        assert_eq!(selector.select(0, 0.1, 4), 2);
+    }
+
+    #[test]
+    fn select_stays_within_the_mesh() {
+        let selector = LodSelector::default();
+        assert_eq!(selector.select(0, 0.01, 2), 1);
+        assert_eq!(selector.select(3, 0.01, 2), 1);
+        assert_eq!(selector.select(2, 0.01, 1), 0);
+    }
+
+    #[test]
+    fn screen_size_shrinks_with_distance() {
+        let near = LodSelector::screen_size(1.0, 2.0, 1.0);
+        let far = LodSelector::screen_size(1.0, 4.0, 1.0);
+        assert_eq!(near, 0.5);
+        assert_eq!(far, 0.25);
+        // From inside the sphere, it fills the view
+        assert_eq!(LodSelector::screen_size(1.0, 0.5, 1.0), 1.0);
+    }
+}
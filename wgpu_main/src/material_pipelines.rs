@@ -0,0 +1,307 @@
+use crate::deferred::GBuffer;
+use crate::model::{AlphaMode, Material};
+use crate::oit::WeightedBlendedOit;
+use crate::velocity::VelocityBuffer;
+use std::collections::HashMap;
+
+/// Everything the pipelines of a [MaterialPipelines] share.
+pub(crate) struct MaterialPipelinesDescriptor<'a> {
+    pub(crate) label: &'a str,
+    pub(crate) layout: &'a wgpu::PipelineLayout,
+    pub(crate) shader: wgpu::ShaderModuleDescriptor<'a>,
+    pub(crate) vertex_layouts: &'a [wgpu::VertexBufferLayout<'a>],
+    /// The layouts `vs_velocity` reads the vertices and instances with.
+    pub(crate) velocity_vertex_layouts: &'a [wgpu::VertexBufferLayout<'a>],
+    pub(crate) color_format: wgpu::TextureFormat,
+    pub(crate) depth_format: wgpu::TextureFormat,
+}
+
+/// The passes a material can be drawn in, each of which writes to different targets with its own
+/// entry point of the shader.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum MaterialPass {
+    /// Lit right away, into the color target, with `fs_main`.
+    Forward,
+    /// Into the targets of a [WeightedBlendedOit], with `fs_oit`. Only for blended materials.
+    WeightedBlended,
+    /// Into the targets of a [GBuffer], with `fs_gbuffer`. Only for opaque and masked materials.
+    GBuffer,
+    /// Into a [VelocityBuffer], with `vs_velocity` and `fs_velocity`, only where the depth is equal
+    /// to what was drawn before. Only for opaque and masked materials.
+    Velocity,
+    /// Only into the depth buffer, with `vs_velocity` and no fragment shader, so that the depth is
+    /// ready before a forward pass. Only for opaque materials, since masked ones would need their
+    /// fragment shader to discard what is cut out.
+    DepthPrepass,
+    /// Adding up every surface drawn over a pixel into the color target, with `fs_overdraw` and no
+    /// depth test, for [crate::debug_view::DebugMode::Overdraw].
+    Overdraw,
+}
+
+/// # Material Pipelines
+/// Blending, depth writes and face culling are all baked into a render pipeline, so a shader that
+/// draws materials with different [AlphaMode]s, or both single and double-sided ones, needs one
+/// pipeline for every combination. `MaterialPipelines` builds all of them up front from the same
+/// shader, and [MaterialPipelines::get] picks the one a material needs:
+/// - Opaque and masked materials replace what is behind them and write depth. Masked ones discard
+///   the fragments below their cutoff in the shader.
+/// - Blended materials are mixed with what is behind them using their alpha, and don't write depth.
+/// - Double-sided materials don't cull back faces. The shader flips the normal of back faces so
+///   that they are lit as if they were facing the camera.
+///
+/// On top of that, every [MaterialPass] needs pipelines of its own for the materials it draws.
+pub(crate) struct MaterialPipelines {
+    pipelines: HashMap<(MaterialPass, AlphaMode, bool), wgpu::RenderPipeline>,
+}
+
+impl MaterialPipelines {
+    pub(crate) fn new(device: &wgpu::Device, desc: MaterialPipelinesDescriptor) -> Self {
+        let shader = device.create_shader_module(desc.shader);
+        let create = |pass: MaterialPass,
+                      label: &str,
+                      fragment: Option<wgpu::FragmentState>,
+                      double_sided: bool,
+                      depth_write_enabled: bool| {
+            let label = format!(
+                "{} ({label}{})",
+                desc.label,
+                if double_sided { ", double-sided" } else { "" }
+            );
+            // `vs_velocity` and `vs_main` compute the same positions, so the other passes also draw
+            // where the depth prepass already left their own depth
+            let (entry_point, buffers, depth_compare) = match pass {
+                MaterialPass::Velocity => (
+                    "vs_velocity",
+                    desc.velocity_vertex_layouts,
+                    wgpu::CompareFunction::Equal,
+                ),
+                MaterialPass::DepthPrepass => (
+                    "vs_velocity",
+                    desc.velocity_vertex_layouts,
+                    wgpu::CompareFunction::Less,
+                ),
+                MaterialPass::Overdraw => (
+                    "vs_main",
+                    desc.vertex_layouts,
+                    wgpu::CompareFunction::Always,
+                ),
+                _ => (
+                    "vs_main",
+                    desc.vertex_layouts,
+                    wgpu::CompareFunction::LessEqual,
+                ),
+            };
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(&label),
+                layout: Some(desc.layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point,
+                    buffers,
+                },
+                fragment,
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: (!double_sided).then_some(wgpu::Face::Back),
+                    unclipped_depth: false,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: desc.depth_format,
+                    depth_write_enabled,
+                    depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+
+        let mut pipelines = HashMap::new();
+        for double_sided in [false, true] {
+            for alpha_mode in [AlphaMode::Opaque, AlphaMode::Mask, AlphaMode::Blend] {
+                let blend = match alpha_mode {
+                    AlphaMode::Opaque | AlphaMode::Mask => wgpu::BlendState::REPLACE,
+                    AlphaMode::Blend => wgpu::BlendState::ALPHA_BLENDING,
+                };
+                let fragment = wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: desc.color_format,
+                        blend: Some(blend),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                };
+                let pipeline = create(
+                    MaterialPass::Forward,
+                    &format!("{alpha_mode:?}"),
+                    Some(fragment),
+                    double_sided,
+                    alpha_mode != AlphaMode::Blend,
+                );
+                pipelines.insert((MaterialPass::Forward, alpha_mode, double_sided), pipeline);
+            }
+
+            // The colors are added up, while the revealage is multiplied by one minus each alpha
+            let fragment = wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_oit",
+                targets: &[
+                    Some(wgpu::ColorTargetState {
+                        format: WeightedBlendedOit::ACCUM_FORMAT,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent {
+                                src_factor: wgpu::BlendFactor::One,
+                                dst_factor: wgpu::BlendFactor::One,
+                                operation: wgpu::BlendOperation::Add,
+                            },
+                            alpha: wgpu::BlendComponent {
+                                src_factor: wgpu::BlendFactor::One,
+                                dst_factor: wgpu::BlendFactor::One,
+                                operation: wgpu::BlendOperation::Add,
+                            },
+                        }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    }),
+                    Some(wgpu::ColorTargetState {
+                        format: WeightedBlendedOit::REVEALAGE_FORMAT,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent {
+                                src_factor: wgpu::BlendFactor::Zero,
+                                dst_factor: wgpu::BlendFactor::OneMinusSrc,
+                                operation: wgpu::BlendOperation::Add,
+                            },
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }),
+                        write_mask: wgpu::ColorWrites::RED,
+                    }),
+                ],
+            };
+            let pipeline = create(
+                MaterialPass::WeightedBlended,
+                "Weighted Blended",
+                Some(fragment),
+                double_sided,
+                false,
+            );
+            pipelines.insert(
+                (
+                    MaterialPass::WeightedBlended,
+                    AlphaMode::Blend,
+                    double_sided,
+                ),
+                pipeline,
+            );
+
+            // The G-buffer only holds the closest surface, so there is nothing to blend
+            for alpha_mode in [AlphaMode::Opaque, AlphaMode::Mask] {
+                let target = |format| {
+                    Some(wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })
+                };
+                let fragment = wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_gbuffer",
+                    targets: &[
+                        target(GBuffer::ALBEDO_FORMAT),
+                        target(GBuffer::NORMAL_FORMAT),
+                        target(GBuffer::MATERIAL_FORMAT),
+                    ],
+                };
+                let pipeline = create(
+                    MaterialPass::GBuffer,
+                    &format!("G-Buffer {alpha_mode:?}"),
+                    Some(fragment),
+                    double_sided,
+                    true,
+                );
+                pipelines.insert((MaterialPass::GBuffer, alpha_mode, double_sided), pipeline);
+
+                // Masked fragments that were discarded before fail the depth test, so there is no
+                // need to discard them again
+                let fragment = wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_velocity",
+                    targets: &[target(VelocityBuffer::FORMAT)],
+                };
+                let pipeline = create(
+                    MaterialPass::Velocity,
+                    &format!("Velocity {alpha_mode:?}"),
+                    Some(fragment),
+                    double_sided,
+                    false,
+                );
+                pipelines.insert((MaterialPass::Velocity, alpha_mode, double_sided), pipeline);
+            }
+
+            let pipeline = create(
+                MaterialPass::DepthPrepass,
+                "Depth Prepass",
+                None,
+                double_sided,
+                true,
+            );
+            pipelines.insert(
+                (MaterialPass::DepthPrepass, AlphaMode::Opaque, double_sided),
+                pipeline,
+            );
+
+            // Every surface counts, whatever it blends with or hides
+            for alpha_mode in [AlphaMode::Opaque, AlphaMode::Mask, AlphaMode::Blend] {
+                let additive = wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                };
+                let fragment = wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_overdraw",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: desc.color_format,
+                        blend: Some(wgpu::BlendState {
+                            color: additive,
+                            alpha: additive,
+                        }),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                };
+                let pipeline = create(
+                    MaterialPass::Overdraw,
+                    &format!("Overdraw {alpha_mode:?}"),
+                    Some(fragment),
+                    double_sided,
+                    false,
+                );
+                pipelines.insert((MaterialPass::Overdraw, alpha_mode, double_sided), pipeline);
+            }
+        }
+        Self { pipelines }
+    }
+
+    /// The pipeline to draw meshes with `material` with in `pass`.
+    ///
+    /// # Panics
+    /// If `pass` doesn't draw materials with the material's [AlphaMode].
+    pub(crate) fn get(&self, material: &Material, pass: MaterialPass) -> &wgpu::RenderPipeline {
+        self.pipelines
+            .get(&(pass, material.alpha_mode, material.double_sided))
+            .unwrap_or_else(|| {
+                panic!(
+                    "{pass:?} pass can't draw {:?} material {}",
+                    material.alpha_mode, material.name
+                )
+            })
+    }
+}
@@ -1,5 +1,12 @@
+use crate::animation::{AnimationClip, Skeleton};
+use crate::culling::Bounds;
+use crate::geometry::GeometryPage;
+use crate::lod::LodLevel;
+use crate::morph::MorphTargets;
 use crate::texture::Texture;
 use std::ops::Range;
+use std::sync::Arc;
+use wgpu::util::DeviceExt;
 use wgpu::BindGroup;
 
 /// Making `Vertex` a trait will allow us to abstract out the `VertexBufferLayout` creation code to
@@ -98,9 +105,158 @@ impl Vertex for ModelVertex {
     }
 }
 
+/// A `SkinnedVertex` is a [ModelVertex] that is also bound to up to four joints of a [Skeleton].
+/// The `joints` index into the joint palette, and the `weights` say how much each of those joints
+/// influences the vertex. The weights of a vertex should sum to 1.0.
+///
+/// The joint and weight attributes use shader locations 12 and 13, since locations 5 to 11 are taken
+/// by the instance data.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkinnedVertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
+    pub joints: [u16; 4],
+    pub weights: [f32; 4],
+}
+
+impl Vertex for SkinnedVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<SkinnedVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                // Joint indices and weights
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 14]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Uint16x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: (mem::size_of::<[f32; 14]>() + mem::size_of::<[u16; 4]>())
+                        as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// A `Model` is a set of meshes and the materials they use. Models loaded from glTF files may also
+/// carry a [Skeleton] and the animation clips that drive it; the meshes bound to it then use
+/// [SkinnedVertex] instead of [ModelVertex], see [Mesh::skinned].
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    /// The bounds of all the meshes together, used to cull instances of the model.
+    pub(crate) bounds: Bounds,
+    pub(crate) skeleton: Option<Skeleton>,
+    pub(crate) animations: Vec<AnimationClip>,
+}
+
+impl Model {
+    /// The number of levels of detail of the mesh with the most of them.
+    pub(crate) fn num_lods(&self) -> usize {
+        self.meshes
+            .iter()
+            .map(|mesh| mesh.lods.len())
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Returns the index of the animation clip called `name`, if the model has one.
+    pub(crate) fn find_animation(&self, name: &str) -> Option<usize> {
+        self.animations.iter().position(|clip| clip.name == name)
+    }
+}
+
+/// How the alpha of a material's surface is used.
+///
+/// # Transparency
+/// Opaque surfaces ignore their alpha. Masked surfaces are either there or not: fragments with an
+/// alpha below the material's cutoff are thrown away, which is how foliage and fences are usually
+/// drawn. Both write depth, so they can be drawn in any order.
+///
+/// Blended surfaces are mixed with whatever is behind them, like glass. That only works if whatever
+/// is behind them has already been drawn, so blended surfaces are drawn after everything opaque,
+/// sorted from back to front. They don't write depth either, since that would hide the blended
+/// surfaces behind them.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum AlphaMode {
+    #[default]
+    Opaque,
+    Mask,
+    Blend,
+}
+
+/// The settings of a [Material] besides its textures.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct MaterialOptions {
+    pub(crate) alpha_mode: AlphaMode,
+    /// Masked fragments with an alpha below this are discarded.
+    pub(crate) alpha_cutoff: f32,
+    /// Multiplies the alpha of the diffuse texture, like the dissolve (`d`) value of MTL files.
+    pub(crate) opacity: f32,
+    /// Double-sided surfaces are drawn from behind too, instead of being culled.
+    pub(crate) double_sided: bool,
+    /// How strong the specular highlights are.
+    pub(crate) specular: f32,
+    /// The exponent of the specular highlights. Higher values make for smaller, sharper highlights.
+    pub(crate) shininess: f32,
+}
+
+impl Default for MaterialOptions {
+    fn default() -> Self {
+        Self {
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            opacity: 1.0,
+            double_sided: false,
+            specular: 1.0,
+            shininess: 32.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialUniform {
+    opacity: f32,
+    // Zero for materials that aren't masked, which keeps every fragment
+    alpha_cutoff: f32,
+    specular: f32,
+    shininess: f32,
 }
 
 pub struct Material {
@@ -110,6 +266,8 @@ pub struct Material {
     // All z values should be positive. That's why the normal map has a bluish tint.
     pub normal_texture: Texture,
     pub bind_group: wgpu::BindGroup,
+    pub(crate) alpha_mode: AlphaMode,
+    pub(crate) double_sided: bool,
 }
 
 impl Material {
@@ -120,6 +278,39 @@ impl Material {
         normal_texture: Texture,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
+        Self::with_options(
+            device,
+            name,
+            diffuse_texture,
+            normal_texture,
+            MaterialOptions::default(),
+            layout,
+        )
+    }
+
+    pub(crate) fn with_options(
+        device: &wgpu::Device,
+        name: &str,
+        diffuse_texture: Texture,
+        normal_texture: Texture,
+        options: MaterialOptions,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let uniform = MaterialUniform {
+            opacity: options.opacity,
+            alpha_cutoff: match options.alpha_mode {
+                AlphaMode::Mask => options.alpha_cutoff,
+                AlphaMode::Opaque | AlphaMode::Blend => 0.0,
+            },
+            specular: options.specular,
+            shininess: options.shininess,
+        };
+        // The bind group keeps the buffer alive
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{name} material uniform")),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
             entries: &[
@@ -139,6 +330,10 @@ impl Material {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
             ],
             label: None,
         });
@@ -147,17 +342,40 @@ impl Material {
             diffuse_texture,
             normal_texture,
             bind_group,
+            alpha_mode: options.alpha_mode,
+            double_sided: options.double_sided,
         }
     }
 }
-/// `Mesh` holds a vertex buffer, an index buffer, and the number of indices in the mesh. We're
-/// using a `usize` for the material. This `usize` will index the `materials` list when it is time to draw.
+/// `Mesh` refers to the vertex and index buffers holding its vertices, and to the range of those
+/// buffers it uses. The buffers are shared with other meshes, see [GeometryArena]. We're using a
+/// `usize` for the material. This `usize` will index the `materials` list when it is time to draw.
+///
+/// Meshes loaded from glTF files can also have [MorphTargets]. Once those have been prepared, the
+/// mesh is drawn from the deformed vertex buffer instead of its own.
+///
+/// A mesh can also have several levels of detail, which all live in the same buffers. The full
+/// detail mesh always comes first.
+///
+/// [GeometryArena]: crate::geometry::GeometryArena
 pub struct Mesh {
     pub name: String,
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
-    pub num_elements: u32,
+    pub(crate) geometry: Arc<GeometryPage>,
     pub material: usize,
+    pub(crate) bounds: Bounds,
+    /// From the full detail mesh to the coarsest one. There is always at least one level.
+    pub(crate) lods: Vec<LodLevel>,
+    pub(crate) morph_targets: Option<MorphTargets>,
+    /// Whether the vertices are [SkinnedVertex]es bound to the [Skeleton] of the model, rather than
+    /// [ModelVertex]es.
+    pub(crate) skinned: bool,
+}
+
+impl Mesh {
+    /// The level of detail `lod`, or the coarsest level if the mesh doesn't have that many.
+    pub(crate) fn lod(&self, lod: usize) -> &LodLevel {
+        &self.lods[lod.min(self.lods.len() - 1)]
+    }
 }
 
 pub trait DrawModel<'a> {
@@ -166,30 +384,67 @@ pub trait DrawModel<'a> {
         mesh: &'a Mesh,
         material: &'a Material,
         camera_bind_group: &'a BindGroup,
-        light_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
     );
     fn draw_mesh_instanced(
         &mut self,
         mesh: &'a Mesh,
         material: &'a Material,
         camera_bind_group: &'a BindGroup,
-        light_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        instances: Range<u32>,
+    );
+    /// Draws the `instances` of the level of detail `lod` of `mesh`, with `material`.
+    fn draw_mesh_lod(
+        &mut self,
+        mesh: &'a Mesh,
+        material: &'a Material,
+        camera_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        lod: usize,
         instances: Range<u32>,
     );
 
+    /// Like [DrawModel::draw_mesh_instanced], but the instance count (and the rest of the draw
+    /// arguments) are read from `indirect_buffer` at `indirect_offset`, as a
+    /// `wgpu::util::DrawIndexedIndirectArgs`. Morphed meshes are drawn with their base vertices.
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'a Mesh,
+        material: &'a Material,
+        camera_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        indirect_buffer: &'a wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+    );
+
     fn draw_model(
         &mut self,
         model: &'a Model,
         camera_bind_group: &'a BindGroup,
-        light_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
     );
 
+    /// Draws every mesh of `model` once per level of detail, with the instances in
+    /// `lod_ranges[lod]`. The instance buffer has to hold the instances sorted by their level of
+    /// detail, so that each bucket is a contiguous range. A single range draws every instance at
+    /// full detail.
     fn draw_model_instanced(
         &mut self,
         model: &'a Model,
         camera_bind_group: &'a BindGroup,
-        light_bind_group: &'a BindGroup,
-        instances: Range<u32>,
+        scene_bind_group: &'a BindGroup,
+        lod_ranges: &[Range<u32>],
+    );
+
+    /// Draws every mesh of `model` with the draw arguments from `indirect_buffer`, which holds one
+    /// `wgpu::util::DrawIndexedIndirectArgs` per mesh.
+    fn draw_model_indirect(
+        &mut self,
+        model: &'a Model,
+        camera_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        indirect_buffer: &'a wgpu::Buffer,
     );
 }
 
@@ -202,9 +457,9 @@ where
         mesh: &'b Mesh,
         material: &'b Material,
         camera_bind_group: &'b BindGroup,
-        light_bind_group: &'b BindGroup,
+        scene_bind_group: &'b BindGroup,
     ) {
-        self.draw_mesh_instanced(mesh, material, camera_bind_group, light_bind_group, 0..1);
+        self.draw_mesh_instanced(mesh, material, camera_bind_group, scene_bind_group, 0..1);
     }
 
     fn draw_mesh_instanced(
@@ -212,44 +467,136 @@ where
         mesh: &'b Mesh,
         material: &'a Material,
         camera_bind_group: &'a BindGroup,
-        light_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
         instances: Range<u32>,
     ) {
-        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        self.draw_mesh_lod(
+            mesh,
+            material,
+            camera_bind_group,
+            scene_bind_group,
+            0,
+            instances,
+        );
+    }
+
+    fn draw_mesh_lod(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'a Material,
+        camera_bind_group: &'a BindGroup,
+        scene_bind_group: &'a BindGroup,
+        lod: usize,
+        instances: Range<u32>,
+    ) {
+        let level = mesh.lod(lod);
+        self.set_index_buffer(
+            mesh.geometry.index_buffer.slice(..),
+            wgpu::IndexFormat::Uint32,
+        );
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
-        self.set_bind_group(2, light_bind_group, &[]);
+        self.set_bind_group(2, scene_bind_group, &[]);
+
+        // Every instance of a morphed mesh has its own copy of the deformed vertices, so we draw the
+        // instances one by one, offsetting the indices into that instance's copy.
+        if let Some((morph, deformed)) = mesh
+            .morph_targets
+            .as_ref()
+            .and_then(|m| Some((m, m.deformed_buffer()?)))
+        {
+            self.set_vertex_buffer(0, deformed.slice(..));
+            for instance in instances.start..instances.end.min(morph.capacity()) {
+                let base_vertex = (instance * morph.num_vertices()) as i32 + level.base_vertex;
+                self.draw_indexed(level.indices.clone(), base_vertex, instance..instance + 1);
+            }
+            return;
+        }
+
+        self.set_vertex_buffer(0, mesh.geometry.vertex_buffer.slice(..));
         // When using an index buffer, you need to use draw_indexed. The draw method ignores the
         // index buffer. Also, make sure you use the number of indices, not vertices, as your
         // model will either draw wrong or the method will panic because there are not enough indices.
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(level.indices.clone(), level.base_vertex, instances);
+    }
+
+    fn draw_mesh_indirect(
+        &mut self,
+        mesh: &'b Mesh,
+        material: &'b Material,
+        camera_bind_group: &'b BindGroup,
+        scene_bind_group: &'b BindGroup,
+        indirect_buffer: &'b wgpu::Buffer,
+        indirect_offset: wgpu::BufferAddress,
+    ) {
+        self.set_vertex_buffer(0, mesh.geometry.vertex_buffer.slice(..));
+        self.set_index_buffer(
+            mesh.geometry.index_buffer.slice(..),
+            wgpu::IndexFormat::Uint32,
+        );
+        self.set_bind_group(0, &material.bind_group, &[]);
+        self.set_bind_group(1, camera_bind_group, &[]);
+        self.set_bind_group(2, scene_bind_group, &[]);
+        self.draw_indexed_indirect(indirect_buffer, indirect_offset);
     }
 
     fn draw_model(
         &mut self,
         model: &'b Model,
         camera_bind_group: &'b BindGroup,
-        light_bind_group: &'b BindGroup,
+        scene_bind_group: &'b BindGroup,
     ) {
-        self.draw_model_instanced(model, camera_bind_group, light_bind_group, 0..1);
+        let instances = 0..1;
+        self.draw_model_instanced(
+            model,
+            camera_bind_group,
+            scene_bind_group,
+            std::slice::from_ref(&instances),
+        );
     }
 
     fn draw_model_instanced(
         &mut self,
         model: &'b Model,
         camera_bind_group: &'b BindGroup,
-        light_bind_group: &'b BindGroup,
-        instances: Range<u32>,
+        scene_bind_group: &'b BindGroup,
+        lod_ranges: &[Range<u32>],
     ) {
-        for mesh in &model.meshes {
+        for (lod, instances) in lod_ranges.iter().enumerate() {
+            if instances.is_empty() {
+                continue;
+            }
+            for mesh in &model.meshes {
+                let material = &model.materials[mesh.material];
+                self.draw_mesh_lod(
+                    mesh,
+                    material,
+                    camera_bind_group,
+                    scene_bind_group,
+                    lod,
+                    instances.clone(),
+                );
+            }
+        }
+    }
+
+    fn draw_model_indirect(
+        &mut self,
+        model: &'b Model,
+        camera_bind_group: &'b BindGroup,
+        scene_bind_group: &'b BindGroup,
+        indirect_buffer: &'b wgpu::Buffer,
+    ) {
+        let args_size = std::mem::size_of::<wgpu::util::DrawIndexedIndirectArgs>();
+        for (i, mesh) in model.meshes.iter().enumerate() {
             let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(
+            self.draw_mesh_indirect(
                 mesh,
                 material,
                 camera_bind_group,
-                light_bind_group,
-                instances.clone(),
+                scene_bind_group,
+                indirect_buffer,
+                (i * args_size) as wgpu::BufferAddress,
             );
         }
     }
@@ -304,11 +651,15 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
     ) {
-        self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
-        self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        let level = mesh.lod(0);
+        self.set_vertex_buffer(0, mesh.geometry.vertex_buffer.slice(..));
+        self.set_index_buffer(
+            mesh.geometry.index_buffer.slice(..),
+            wgpu::IndexFormat::Uint32,
+        );
         self.set_bind_group(0, camera_bind_group, &[]);
         self.set_bind_group(1, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(level.indices.clone(), level.base_vertex, instances);
     }
 
     fn draw_light_model(
@@ -0,0 +1,289 @@
+use crate::model::{Mesh, Model};
+use wgpu::util::DeviceExt;
+
+/// # Morph Targets
+/// Morph targets (also known as blend shapes) deform a mesh by adding a weighted sum of per-vertex
+/// displacements to it. A face mesh might have one target for a smile and another for raised
+/// eyebrows; setting their weights to 0.5 and 1.0 blends both expressions on top of the base mesh:
+///
+/// `position = base_position + Σ weight[t] * position_delta[t]`
+///
+/// and the same for the normals and tangents. The displacements of all targets live in a single
+/// storage buffer, laid out target by target.
+///
+/// Rather than applying the weights in every vertex shader that may draw a morphed mesh, we run a
+/// compute pre-pass (see [MorphPipeline]) that writes the deformed vertices into a separate vertex
+/// buffer, one copy per instance. The deformed buffer has exactly the same layout as the mesh's own
+/// vertex buffer, so the regular model and skinning pipelines can draw it as is.
+pub(crate) struct MorphTargets {
+    num_targets: u32,
+    num_vertices: u32,
+    /// The size of a single vertex, in bytes. Morphing only touches the position, normal and tangent
+    /// of a vertex; everything else (like the joints of a [crate::model::SkinnedVertex]) is copied.
+    vertex_stride: u32,
+    deltas: wgpu::Buffer,
+    /// The weights from the model file, used for instances that never had their weights set.
+    default_weights: Vec<f32>,
+    instances: Option<MorphInstances>,
+}
+
+/// The per-instance part of [MorphTargets], created by [MorphPipeline::prepare].
+struct MorphInstances {
+    capacity: u32,
+    weights: wgpu::Buffer,
+    deformed: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MorphParams {
+    num_vertices: u32,
+    num_targets: u32,
+    vertex_stride: u32,
+    num_instances: u32,
+}
+
+impl MorphTargets {
+    /// `deltas` holds one `[position, normal, tangent]` displacement per vertex for each target,
+    /// target by target.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        label: &str,
+        deltas: &[[[f32; 3]; 3]],
+        num_vertices: u32,
+        vertex_stride: u32,
+        default_weights: Vec<f32>,
+    ) -> Self {
+        let num_targets = deltas.len() as u32 / num_vertices.max(1);
+        let deltas = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{label} morph target deltas")),
+            contents: bytemuck::cast_slice(deltas),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let mut default_weights = default_weights;
+        default_weights.resize(num_targets as usize, 0.0);
+
+        Self {
+            num_targets,
+            num_vertices,
+            vertex_stride,
+            deltas,
+            default_weights,
+            instances: None,
+        }
+    }
+
+    pub(crate) fn num_targets(&self) -> u32 {
+        self.num_targets
+    }
+
+    pub(crate) fn num_vertices(&self) -> u32 {
+        self.num_vertices
+    }
+
+    /// How many instances [MorphPipeline::prepare] allocated deformed vertices for.
+    pub(crate) fn capacity(&self) -> u32 {
+        self.instances.as_ref().map_or(0, |i| i.capacity)
+    }
+
+    /// The buffer holding the deformed vertices, if [MorphPipeline::prepare] has been called.
+    pub(crate) fn deformed_buffer(&self) -> Option<&wgpu::Buffer> {
+        self.instances.as_ref().map(|i| &i.deformed)
+    }
+
+    /// Sets the weights of a single instance. Missing weights are treated as 0.0, and instances past
+    /// the capacity given to [MorphPipeline::prepare] are ignored.
+    pub(crate) fn set_weights(&self, queue: &wgpu::Queue, instance: u32, weights: &[f32]) {
+        let Some(instances) = &self.instances else {
+            return;
+        };
+        if instance >= instances.capacity {
+            return;
+        }
+        let mut data = weights.to_vec();
+        data.resize(self.num_targets as usize, 0.0);
+        queue.write_buffer(
+            &instances.weights,
+            (instance * self.num_targets) as u64 * std::mem::size_of::<f32>() as u64,
+            bytemuck::cast_slice(&data),
+        );
+    }
+}
+
+/// Owns the compute pipeline that applies morph target weights.
+pub(crate) struct MorphPipeline {
+    pipeline: wgpu::ComputePipeline,
+    layout: wgpu::BindGroupLayout,
+}
+
+impl MorphPipeline {
+    const WORKGROUP_SIZE: u32 = 64;
+
+    pub(crate) fn new(device: &wgpu::Device) -> Self {
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("MorphPipeline::layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // The base vertices
+                storage(1, true),
+                // The deltas
+                storage(2, true),
+                // The weights
+                storage(3, true),
+                // The deformed vertices
+                storage(4, false),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("MorphPipeline::pipeline_layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let module = device.create_shader_module(wgpu::include_wgsl!("shaders/morph.wgsl"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("MorphPipeline::pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "apply_morph_targets",
+        });
+
+        Self { pipeline, layout }
+    }
+
+    /// Allocates the weights and the deformed vertex buffers of every morphed mesh in `model` for up
+    /// to `capacity` instances. All instances start out with the default weights from the model file.
+    ///
+    /// The meshes' vertex buffers need `BufferUsages::STORAGE`, since the compute pass reads the base
+    /// vertices from them, starting at the first vertex of the buffer. See
+    /// [crate::geometry::GeometryArena::allocate_dedicated].
+    pub(crate) fn prepare(&self, device: &wgpu::Device, model: &mut Model, capacity: u32) {
+        for mesh in &mut model.meshes {
+            let Mesh {
+                name,
+                geometry,
+                morph_targets: Some(morph),
+                ..
+            } = mesh
+            else {
+                continue;
+            };
+
+            let params = MorphParams {
+                num_vertices: morph.num_vertices,
+                num_targets: morph.num_targets,
+                vertex_stride: morph.vertex_stride / 4,
+                num_instances: capacity,
+            };
+            let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{name} morph params")),
+                contents: bytemuck::cast_slice(&[params]),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let weights = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{name} morph weights")),
+                contents: bytemuck::cast_slice(&morph.default_weights.repeat(capacity as usize)),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            });
+            let deformed = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{name} deformed vertex buffer")),
+                size: morph.vertex_stride as u64 * morph.num_vertices as u64 * capacity as u64,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            });
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(&format!("{name} morph bind group")),
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: geometry.vertex_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: morph.deltas.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: weights.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: deformed.as_entire_binding(),
+                    },
+                ],
+            });
+
+            morph.instances = Some(MorphInstances {
+                capacity,
+                weights,
+                deformed,
+                bind_group,
+            });
+        }
+    }
+
+    /// Writes the deformed vertices of every prepared mesh in `model`. This needs to run before the
+    /// render pass that draws the model.
+    pub(crate) fn deform(&self, encoder: &mut wgpu::CommandEncoder, model: &Model) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Morph Pass"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        for morph in model.meshes.iter().filter_map(|m| m.morph_targets.as_ref()) {
+            let Some(instances) = &morph.instances else {
+                continue;
+            };
+            pass.set_bind_group(0, &instances.bind_group, &[]);
+            pass.dispatch_workgroups(
+                morph.num_vertices.div_ceil(Self::WORKGROUP_SIZE),
+                instances.capacity,
+                1,
+            );
+        }
+    }
+}
+
+/// Packs the displacements of a single target into the layout [MorphTargets::new] expects.
+pub(crate) fn pack_deltas(
+    num_vertices: usize,
+    positions: Option<Vec<[f32; 3]>>,
+    normals: Option<Vec<[f32; 3]>>,
+    tangents: Option<Vec<[f32; 3]>>,
+) -> Vec<[[f32; 3]; 3]> {
+    let get = |deltas: &Option<Vec<[f32; 3]>>, i: usize| {
+        deltas
+            .as_ref()
+            .and_then(|d| d.get(i).copied())
+            .unwrap_or([0.0; 3])
+    };
+    (0..num_vertices)
+        .map(|i| [get(&positions, i), get(&normals, i), get(&tangents, i)])
+        .collect()
+}
@@ -0,0 +1,208 @@
+use crate::texture::Texture;
+
+/// # Weighted Blended Order-Independent Transparency
+/// Sorting blended instances back to front (see [crate::transparency::SortedInstances]) breaks down
+/// when they intersect, since then neither one is entirely in front of the other. Weighted blended
+/// order-independent transparency gives up on getting the order exactly right, in exchange for not
+/// needing any order at all.
+///
+/// Blended surfaces are drawn into two targets of their own. The accumulation target adds up the
+/// premultiplied colors of all the surfaces covering a pixel, each weighted by how close and how
+/// opaque it is. The revealage target multiplies together the transparency of all the surfaces,
+/// which is how much of the scene behind them still shows. Since both addition and multiplication
+/// don't care about order, the surfaces can be drawn in any order. Finally, a composite pass blends
+/// the weighted average color over the HDR target, before it gets tonemapped.
+///
+/// The result is an approximation: surfaces that are close together blend into a mix rather than
+/// one covering the other, and a very opaque surface lets a bit more through than it should.
+pub(crate) struct WeightedBlendedOit {
+    accum: Texture,
+    revealage: Texture,
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl WeightedBlendedOit {
+    /// `Rgba16Float` keeps the weighted sums from overflowing or losing precision.
+    pub(crate) const ACCUM_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub(crate) const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+    /// `output_format` is the format of the target the transparent surfaces are composited onto.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        output_format: wgpu::TextureFormat,
+    ) -> Self {
+        let (accum, revealage) = Self::create_targets(device, width, height);
+
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("oit_bind_group_layout"),
+            entries: &[texture_entry(0), texture_entry(1)],
+        });
+        let bind_group = Self::create_bind_group(device, &layout, &accum, &revealage);
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/oit.wgsl"));
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("OIT Composite Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("OIT Composite Pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                // The shader makes up a triangle covering the whole screen
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: output_format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            accum,
+            revealage,
+            layout,
+            bind_group,
+            pipeline,
+        }
+    }
+
+    fn create_targets(device: &wgpu::Device, width: u32, height: u32) -> (Texture, Texture) {
+        let usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT;
+        let accum = Texture::create_2d_texture(
+            device,
+            width,
+            height,
+            Self::ACCUM_FORMAT,
+            usage,
+            wgpu::FilterMode::Nearest,
+            Some("oit_accum_texture"),
+        );
+        let revealage = Texture::create_2d_texture(
+            device,
+            width,
+            height,
+            Self::REVEALAGE_FORMAT,
+            usage,
+            wgpu::FilterMode::Nearest,
+            Some("oit_revealage_texture"),
+        );
+        (accum, revealage)
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        accum: &Texture,
+        revealage: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("oit_bind_group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accum.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+            ],
+        })
+    }
+
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.accum, self.revealage) = Self::create_targets(device, width, height);
+        self.bind_group =
+            Self::create_bind_group(device, &self.layout, &self.accum, &self.revealage);
+    }
+
+    /// Begins the pass that draws the blended surfaces into the accumulation and revealage targets,
+    /// testing against the depth of the opaque scene without writing to it.
+    pub(crate) fn begin_accumulate<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth_view: &'a wgpu::TextureView,
+    ) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("OIT Accumulation Pass"),
+            color_attachments: &[
+                Some(wgpu::RenderPassColorAttachment {
+                    view: &self.accum.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: wgpu::StoreOp::Store,
+                    },
+                }),
+                Some(wgpu::RenderPassColorAttachment {
+                    view: &self.revealage.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        // Nothing covers the scene yet, so all of it shows
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: wgpu::StoreOp::Store,
+                    },
+                }),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        })
+    }
+
+    /// Blends the accumulated surfaces over `output`.
+    pub(crate) fn composite(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("OIT Composite Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
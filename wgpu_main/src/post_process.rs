@@ -0,0 +1,341 @@
+use crate::texture::Texture;
+use std::any::Any;
+use std::collections::HashMap;
+
+/// What a [PostEffect] records its passes with.
+pub(crate) struct PostContext<'a> {
+    pub(crate) device: &'a wgpu::Device,
+    pub(crate) encoder: &'a mut wgpu::CommandEncoder,
+}
+
+/// The image a [PostEffect] writes to.
+pub(crate) struct PostTarget<'a> {
+    pub(crate) view: &'a wgpu::TextureView,
+    pub(crate) format: wgpu::TextureFormat,
+}
+
+/// A single stage of a [PostProcessStack]. It reads the image the stage before it wrote, and
+/// writes a new one with one or more full-screen or compute passes.
+///
+/// The image it reads changes whenever stages are added, reordered or switched on or off, so
+/// effects keep the bind groups that read it in [InputBindGroups], which get emptied by
+/// [Self::invalidate] when that happens.
+pub(crate) trait PostEffect: Any {
+    /// The name of the stage, for looking it up and for logging.
+    fn label(&self) -> &'static str;
+
+    /// The format of the image the effect writes, when it reads one in `input`.
+    fn output_format(&self, input: wgpu::TextureFormat) -> wgpu::TextureFormat {
+        input
+    }
+
+    /// Called with the new size of the images when the window resizes.
+    fn resize(&mut self, _device: &wgpu::Device, _width: u32, _height: u32) {}
+
+    /// Called when the images the effect reads may have changed, after the stages were changed
+    /// or the window resized, to drop the bind groups that read them.
+    fn invalidate(&mut self) {}
+
+    /// Called once a frame, before [Self::render], to upload the settings. `dt` is the time since
+    /// the last frame.
+    fn update(&mut self, _queue: &wgpu::Queue, _dt: instant::Duration) {}
+
+    fn render(&mut self, context: &mut PostContext, input: &Texture, output: PostTarget);
+}
+
+/// The bind groups a [PostEffect] reads its input with, for every image it has been handed. The
+/// stack hands out the same few images every frame, so these only get created the first time an
+/// image comes along after [PostEffect::invalidate].
+pub(crate) struct InputBindGroups<T = wgpu::BindGroup> {
+    bind_groups: HashMap<wgpu::Id<wgpu::TextureView>, T>,
+}
+
+impl<T> Default for InputBindGroups<T> {
+    fn default() -> Self {
+        Self {
+            bind_groups: HashMap::new(),
+        }
+    }
+}
+
+impl<T> InputBindGroups<T> {
+    /// The bind groups for `input`, created with `create` if there aren't any yet.
+    pub(crate) fn get(&mut self, input: &Texture, create: impl FnOnce() -> T) -> &T {
+        self.bind_groups
+            .entry(input.view.global_id())
+            .or_insert_with(create)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bind_groups.clear();
+    }
+}
+
+/// A pipeline for a full-screen pass of a [PostEffect]. `shader` has to make up a triangle
+/// covering the screen in `vs_main`, and `entry_point` writes `format`.
+pub(crate) fn create_fullscreen_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    label: &str,
+    entry_point: &str,
+    format: wgpu::TextureFormat,
+    blend: wgpu::BlendState,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            // The shader makes up a triangle covering the whole screen
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point,
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+struct PostStage {
+    effect: Box<dyn PostEffect>,
+    enabled: bool,
+}
+
+/// # Post-Processing Stack
+/// Most of what happens to an image after it has been rendered, like bloom, tonemapping or
+/// anti-aliasing, takes the whole image as input and produces a whole new image. The
+/// `PostProcessStack` runs a list of such [PostEffect]s in order, each reading what the previous one
+/// wrote, with the last one writing to the output, usually the surface.
+///
+/// Rather than every effect allocating an image of its own, the stack keeps a pool of intermediate
+/// images and hands them out as needed. Consecutive effects writing the same format take turns
+/// writing to one of a pair of images while reading the other, which is known as ping-ponging.
+/// The pool is emptied when the window resizes, and refilled at the new size as the effects run.
+///
+/// Stages can be added, moved and switched off while the application is running, for example to
+/// compare the image with and without an effect. The last stage that is switched on has to write
+/// the format of the output.
+pub(crate) struct PostProcessStack {
+    stages: Vec<PostStage>,
+    /// A pair of images for every format the stages write.
+    targets: HashMap<wgpu::TextureFormat, [Texture; 2]>,
+    width: u32,
+    height: u32,
+    /// Set when the last stage doesn't write the format of the output, so that it only gets
+    /// reported once.
+    reported_format: Option<wgpu::TextureFormat>,
+}
+
+impl PostProcessStack {
+    pub(crate) fn new(width: u32, height: u32) -> Self {
+        Self {
+            stages: Vec::new(),
+            targets: HashMap::new(),
+            width,
+            height,
+            reported_format: None,
+        }
+    }
+
+    /// Adds `effect` as the last stage.
+    pub(crate) fn push(&mut self, effect: impl PostEffect) {
+        self.insert(self.stages.len(), effect);
+    }
+
+    /// Adds `effect` as the stage at `index`, moving the stages after it along.
+    pub(crate) fn insert(&mut self, index: usize, effect: impl PostEffect) {
+        self.stages.insert(
+            index.min(self.stages.len()),
+            PostStage {
+                effect: Box::new(effect),
+                enabled: true,
+            },
+        );
+        self.changed();
+    }
+
+    /// The index of the stage called `label`.
+    pub(crate) fn position(&self, label: &str) -> Option<usize> {
+        self.stages
+            .iter()
+            .position(|stage| stage.effect.label() == label)
+    }
+
+    /// Moves the stage called `label` to `index`.
+    pub(crate) fn move_stage(&mut self, label: &str, index: usize) {
+        if let Some(from) = self.position(label) {
+            let stage = self.stages.remove(from);
+            self.stages.insert(index.min(self.stages.len()), stage);
+            self.changed();
+        }
+    }
+
+    /// Switches the stage called `label` on or off, returning whether it is on now.
+    pub(crate) fn toggle(&mut self, label: &str) -> Option<bool> {
+        let index = self.position(label)?;
+        let enabled = !self.stages[index].enabled;
+        self.set_enabled(label, enabled);
+        Some(enabled)
+    }
+
+    /// Switches the stage called `label` on if `enabled` is true, and off otherwise.
+    pub(crate) fn set_enabled(&mut self, label: &str, enabled: bool) {
+        if let Some(index) = self.position(label) {
+            self.stages[index].enabled = enabled;
+            self.changed();
+        }
+    }
+
+    /// The labels of the stages in order, with the ones that are switched off in parentheses.
+    pub(crate) fn describe(&self) -> String {
+        self.stages
+            .iter()
+            .map(|stage| match stage.enabled {
+                true => stage.effect.label().to_string(),
+                false => format!("({})", stage.effect.label()),
+            })
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    /// The first stage of type `T`, for changing its settings.
+    pub(crate) fn effect_mut<T: PostEffect>(&mut self) -> Option<&mut T> {
+        self.stages.iter_mut().find_map(|stage| {
+            let effect: &mut dyn Any = stage.effect.as_mut();
+            effect.downcast_mut::<T>()
+        })
+    }
+
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.targets.clear();
+        for stage in &mut self.stages {
+            stage.effect.resize(device, width, height);
+            stage.effect.invalidate();
+        }
+    }
+
+    /// The stages read different images after they were changed, and the last one may write a
+    /// different format.
+    fn changed(&mut self) {
+        self.reported_format = None;
+        for stage in &mut self.stages {
+            stage.effect.invalidate();
+        }
+    }
+
+    pub(crate) fn update(&mut self, queue: &wgpu::Queue, dt: instant::Duration) {
+        for stage in self.stages.iter_mut().filter(|stage| stage.enabled) {
+            stage.effect.update(queue, dt);
+        }
+    }
+
+    fn create_target(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> Texture {
+        let mut usage =
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT;
+        // Lets compute passes write the image too, where the format allows it
+        if format
+            .guaranteed_format_features(device.features())
+            .allowed_usages
+            .contains(wgpu::TextureUsages::STORAGE_BINDING)
+        {
+            usage |= wgpu::TextureUsages::STORAGE_BINDING;
+        }
+        Texture::create_2d_texture(
+            device,
+            width,
+            height,
+            format,
+            usage,
+            wgpu::FilterMode::Linear,
+            Some("post_process_texture"),
+        )
+    }
+
+    /// Runs every stage that is switched on, starting from `input`, with the last one writing to
+    /// `output`.
+    pub(crate) fn process(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        input: &Texture,
+        output: PostTarget,
+    ) {
+        let enabled = self
+            .stages
+            .iter()
+            .enumerate()
+            .filter(|(_, stage)| stage.enabled)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let Some(&last) = enabled.last() else {
+            return;
+        };
+
+        // Make sure there are images for all the formats the stages write before any of them get
+        // borrowed
+        let mut format = input.texture.format();
+        for &i in &enabled {
+            format = self.stages[i].effect.output_format(format);
+            if i != last && !self.targets.contains_key(&format) {
+                let create = || Self::create_target(device, format, self.width, self.height);
+                self.targets.insert(format, [create(), create()]);
+            }
+        }
+        if format != output.format {
+            if self.reported_format != Some(format) {
+                log::error!(
+                    "Post-processing ends in {format:?}, but the output is {:?}: {}",
+                    output.format,
+                    self.describe()
+                );
+                self.reported_format = Some(format);
+            }
+            return;
+        }
+
+        let mut context = PostContext { device, encoder };
+        let mut input = input;
+        for &i in &enabled {
+            let effect = &mut self.stages[i].effect;
+            let format = effect.output_format(input.texture.format());
+            if i == last {
+                effect.render(&mut context, input, output);
+                break;
+            }
+            // Write to whichever image of the pair isn't being read
+            let pair = &self.targets[&format];
+            let target = if std::ptr::eq(input, &pair[0]) {
+                &pair[1]
+            } else {
+                &pair[0]
+            };
+            effect.render(
+                &mut context,
+                input,
+                PostTarget {
+                    view: &target.view,
+                    format,
+                },
+            );
+            input = target;
+        }
+    }
+}
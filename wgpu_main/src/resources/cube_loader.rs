@@ -0,0 +1,294 @@
+use anyhow::{anyhow, bail, Context};
+
+/// Whether a [CubeLut] maps every channel on its own, or colors as a whole.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum LutDimension {
+    OneD,
+    ThreeD,
+}
+
+/// # Cube LUTs
+/// A lookup table in the `.cube` format of Adobe and DaVinci Resolve, which is what color grading
+/// usually gets delivered as. It is a text file with a few keywords at the top, followed by one
+/// line of three numbers, red, green and blue, for every entry of the table:
+/// - `TITLE "..."` names the LUT.
+/// - `LUT_1D_SIZE n` makes it a 1D LUT with `n` entries, which maps each channel on its own.
+/// - `LUT_3D_SIZE n` makes it a 3D LUT with `n` entries along every axis of an RGB cube, `n³` in
+///   total, with red changing fastest and blue slowest.
+/// - `DOMAIN_MIN r g b` and `DOMAIN_MAX r g b` are the input colors the first and last entries
+///   along each axis are for, 0 and 1 unless given. Resolve writes `LUT_1D_INPUT_RANGE min max` and
+///   `LUT_3D_INPUT_RANGE min max` for the same on all channels.
+///
+/// Lines starting with `#` are comments. Keywords this doesn't know are skipped, since some tools
+/// add their own.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CubeLut {
+    pub(crate) title: Option<String>,
+    pub(crate) dimension: LutDimension,
+    /// The number of entries along each axis.
+    pub(crate) size: u32,
+    pub(crate) domain_min: [f32; 3],
+    pub(crate) domain_max: [f32; 3],
+    /// `size` entries for a 1D LUT, `size³` for a 3D LUT.
+    pub(crate) data: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    /// The sizes the format allows.
+    const MAX_1D_SIZE: u32 = 65536;
+    const MAX_3D_SIZE: u32 = 256;
+
+    pub(crate) fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut title = None;
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut data = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let context = || format!("line {}: {line}", number + 1);
+            let mut words = line.split_whitespace();
+            let Some(keyword) = words.next() else {
+                continue;
+            };
+
+            // Every line that doesn't start with a keyword is an entry of the table
+            if !keyword.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                if size.is_none() {
+                    bail!("{}: entries before the size of the LUT", context());
+                }
+                data.push(parse_floats(line).with_context(context)?);
+                continue;
+            }
+            if !data.is_empty() {
+                bail!("{}: keywords after the entries of the LUT", context());
+            }
+            let rest = line[keyword.len()..].trim();
+            match keyword {
+                "TITLE" => title = Some(rest.trim_matches('"').to_string()),
+                "LUT_1D_SIZE" | "LUT_3D_SIZE" => {
+                    let (dimension, max) = match keyword {
+                        "LUT_1D_SIZE" => (LutDimension::OneD, Self::MAX_1D_SIZE),
+                        _ => (LutDimension::ThreeD, Self::MAX_3D_SIZE),
+                    };
+                    let n = rest.parse::<u32>().with_context(context)?;
+                    if !(2..=max).contains(&n) {
+                        bail!("{}: the size has to be between 2 and {max}", context());
+                    }
+                    if size.replace((dimension, n)).is_some() {
+                        bail!("{}: the LUT has more than one size", context());
+                    }
+                }
+                "DOMAIN_MIN" => domain_min = parse_floats(rest).with_context(context)?,
+                "DOMAIN_MAX" => domain_max = parse_floats(rest).with_context(context)?,
+                "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => {
+                    let [min, max] = parse_floats(rest).with_context(context)?;
+                    domain_min = [min; 3];
+                    domain_max = [max; 3];
+                }
+                _ => log::warn!("Skipping unknown .cube keyword on {}", context()),
+            }
+        }
+
+        let (dimension, size) = size.ok_or_else(|| anyhow!("the LUT has no size"))?;
+        let expected = match dimension {
+            LutDimension::OneD => size as usize,
+            LutDimension::ThreeD => (size as usize).pow(3),
+        };
+        if data.len() != expected {
+            bail!(
+                "a {dimension:?} LUT of size {size} needs {expected} entries, but has {}",
+                data.len()
+            );
+        }
+        if (0..3).any(|i| domain_max[i] <= domain_min[i]) {
+            bail!("the domain from {domain_min:?} to {domain_max:?} is empty");
+        }
+
+        Ok(Self {
+            title,
+            dimension,
+            size,
+            domain_min,
+            domain_max,
+            data,
+        })
+    }
+
+    /// A 3D LUT with the same effect, turning a 1D LUT into a cube of `size_1d` entries along every
+    /// axis. Each entry looks up its channels in the 1D LUT, in between entries where they fall
+    /// between them, so the result is only as exact as the cube is fine.
+    pub(crate) fn to_3d(&self, size_1d: u32) -> (u32, Vec<[f32; 3]>) {
+        if self.dimension == LutDimension::ThreeD {
+            return (self.size, self.data.clone());
+        }
+
+        let last = (self.size - 1) as f32;
+        let lookup = |channel: usize, t: f32| {
+            let x = t * last;
+            let i = (x.floor() as usize).min(self.size as usize - 2);
+            let a = self.data[i][channel];
+            let b = self.data[i + 1][channel];
+            a + (b - a) * (x - i as f32)
+        };
+        let step = |i: u32| i as f32 / (size_1d - 1) as f32;
+        let mut data = Vec::with_capacity((size_1d as usize).pow(3));
+        for b in 0..size_1d {
+            for g in 0..size_1d {
+                for r in 0..size_1d {
+                    data.push([lookup(0, step(r)), lookup(1, step(g)), lookup(2, step(b))]);
+                }
+            }
+        }
+        (size_1d, data)
+    }
+}
+
+fn parse_floats<const N: usize>(text: &str) -> anyhow::Result<[f32; N]> {
+    let values = text
+        .split_whitespace()
+        .map(str::parse::<f32>)
+        .collect::<Result<Vec<_>, _>>()?;
+    values
+        .try_into()
+        .map_err(|values: Vec<_>| anyhow!("expected {N} numbers, found {}", values.len()))
+}
+
+/// Loads and parses the `.cube` LUT `file_name`, the same way as the models.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn load_cube_lut(file_name: &str) -> anyhow::Result<CubeLut> {
+    let text = super::load_string(file_name).await?;
+    CubeLut::parse(&text).with_context(|| format!("Couldn't parse {file_name}"))
+}
+
+/// Reads and parses the `.cube` LUT at `path`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn read_cube_lut(path: &std::path::Path) -> anyhow::Result<CubeLut> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Couldn't read {}", path.display()))?;
+    CubeLut::parse(&text).with_context(|| format!("Couldn't parse {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3D LUT of size 2 that swaps red and blue, as a .cube file with `header` on top.
+    fn swap_red_blue(header: &str) -> String {
+        let mut text = header.to_string();
+        for b in 0..2 {
+            for g in 0..2 {
+                for r in 0..2 {
+                    text += &format!("{b} {g} {r}\n");
+                }
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn parses_a_3d_lut() {
+        let lut = CubeLut::parse(&swap_red_blue("TITLE \"Swap\"\nLUT_3D_SIZE 2\n")).unwrap();
+        assert_eq!(lut.title.as_deref(), Some("Swap"));
+        assert_eq!(lut.dimension, LutDimension::ThreeD);
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.domain_min, [0.0; 3]);
+        assert_eq!(lut.domain_max, [1.0; 3]);
+        // Red changes fastest
+        assert_eq!(lut.data[1], [0.0, 0.0, 1.0]);
+        assert_eq!(lut.data[4], [1.0, 0.0, 0.0]);
+        assert_eq!(lut.data.len(), 8);
+    }
+
+    #[test]
+    fn parses_the_domain() {
+        let header = "LUT_3D_SIZE 2\nDOMAIN_MIN -0.5 0 0.25\nDOMAIN_MAX 2 1 4\n";
+        let lut = CubeLut::parse(&swap_red_blue(header)).unwrap();
+        assert_eq!(lut.domain_min, [-0.5, 0.0, 0.25]);
+        assert_eq!(lut.domain_max, [2.0, 1.0, 4.0]);
+
+        let lut =
+            CubeLut::parse(&swap_red_blue("LUT_3D_INPUT_RANGE 0 4\nLUT_3D_SIZE 2\n")).unwrap();
+        assert_eq!(lut.domain_min, [0.0; 3]);
+        assert_eq!(lut.domain_max, [4.0; 3]);
+
+        let empty = "LUT_3D_SIZE 2\nDOMAIN_MIN 0 1 0\nDOMAIN_MAX 1 1 1\n";
+        assert!(CubeLut::parse(&swap_red_blue(empty)).is_err());
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let text = swap_red_blue("# Made by hand\n\n  \nLUT_3D_SIZE 2\n# The entries\n\n");
+        let text = text.replacen("0 0 1\n", "0 0 1\n\n# In between\n", 1);
+        let lut = CubeLut::parse(&text).unwrap();
+        assert_eq!(lut.title, None);
+        assert_eq!(lut.data.len(), 8);
+        assert_eq!(lut.data[1], [0.0, 0.0, 1.0]);
+        assert_eq!(lut.data[2], [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_entries() {
+        let text = swap_red_blue("LUT_3D_SIZE 2\n");
+        let missing = text.lines().take(8).collect::<Vec<_>>().join("\n");
+        assert!(CubeLut::parse(&missing).is_err());
+        assert!(CubeLut::parse(&(text.clone() + "1 1 1\n")).is_err());
+        assert!(CubeLut::parse(&text.replacen("0 0 0", "0 0", 1)).is_err());
+        assert!(CubeLut::parse("0 0 0\nLUT_3D_SIZE 2\n").is_err());
+        assert!(CubeLut::parse("LUT_3D_SIZE 1\n0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn turns_a_1d_lut_into_a_3d_one() {
+        // Inverts red, halves green and leaves blue as it is
+        let text = "LUT_1D_SIZE 3\n1 0 0\n0.5 0.25 0.5\n0 0.5 1\n";
+        let lut = CubeLut::parse(text).unwrap();
+        assert_eq!(lut.dimension, LutDimension::OneD);
+        assert_eq!(lut.data.len(), 3);
+
+        let (size, data) = lut.to_3d(5);
+        assert_eq!(size, 5);
+        assert_eq!(data.len(), 125);
+        let at = |r: usize, g: usize, b: usize| data[r + g * 5 + b * 25];
+        assert_eq!(at(0, 0, 0), [1.0, 0.0, 0.0]);
+        assert_eq!(at(4, 4, 4), [0.0, 0.5, 1.0]);
+        // A quarter of the way is in between the first two entries
+        assert_eq!(at(1, 1, 1), [0.75, 0.125, 0.25]);
+        assert_eq!(at(3, 2, 0), [0.25, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn keeps_a_3d_lut_as_it_is() {
+        let lut = CubeLut::parse(&swap_red_blue("LUT_3D_SIZE 2\n")).unwrap();
+        assert_eq!(lut.to_3d(33), (2, lut.data.clone()));
+    }
+
+    #[test]
+    fn rejects_broken_1d_luts() {
+        // The entries of a 3D LUT of the same size
+        let cube = swap_red_blue("LUT_1D_SIZE 2\n");
+        assert!(CubeLut::parse(&cube).is_err());
+        assert!(CubeLut::parse("LUT_1D_SIZE 2\n0 0 0\n").is_err());
+        assert!(CubeLut::parse("LUT_1D_SIZE 65537\n0 0 0\n").is_err());
+        assert!(CubeLut::parse("LUT_1D_SIZE 2\n0 0 0\n1 1\n").is_err());
+    }
+
+    #[test]
+    fn rejects_a_lut_with_both_sizes() {
+        let text = "LUT_1D_SIZE 2\nLUT_3D_SIZE 2\n0 0 0\n1 1 1\n";
+        assert!(CubeLut::parse(text).is_err());
+    }
+
+    #[tokio::test]
+    async fn the_example_luts_parse() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("models/luts");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            read_cube_lut(&entry.unwrap().path()).await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use super::{compute_tangents, load_binary};
+use crate::animation::{
+    AnimationClip, Channel, ChannelTarget, ChannelValues, Interpolation, Joint, Skeleton,
+    MAX_JOINTS,
+};
+use crate::culling::Bounds;
+use crate::geometry::{GeometryAllocation, GeometryArena};
+use crate::lod::LodLevel;
+use crate::model::{AlphaMode, Material, MaterialOptions, Mesh, Model, ModelVertex, SkinnedVertex};
+use crate::morph::{pack_deltas, MorphTargets};
+use crate::scene::Transform;
+use crate::texture::Texture;
+use anyhow::{bail, Context};
+use cgmath::{Matrix4, Quaternion, SquareMatrix, Vector3};
+use gltf::animation::util::ReadOutputs;
+use std::collections::{HashMap, HashSet};
+use wgpu::{BindGroupLayout, Device, Queue};
+
+/// glTF URIs are relative to the file that references them, while [load_binary] expects paths
+/// relative to the models directory.
+fn resolve_uri(file_name: &str, uri: &str) -> String {
+    match std::path::Path::new(file_name).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.join(uri).to_string_lossy().into_owned(),
+        _ => uri.to_string(),
+    }
+}
+
+async fn load_buffers(file_name: &str, gltf: &gltf::Gltf) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut buffers = Vec::new();
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf
+                .blob
+                .clone()
+                .context("glTF buffer refers to a missing BIN chunk")?,
+            gltf::buffer::Source::Uri(uri) if uri.starts_with("data:") => {
+                bail!(
+                    "{file_name}: embedded data URIs are not supported, use a .glb or a .bin file"
+                )
+            }
+            gltf::buffer::Source::Uri(uri) => load_binary(&resolve_uri(file_name, uri)).await?,
+        };
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+
+async fn load_image(
+    file_name: &str,
+    texture: gltf::Texture<'_>,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<Vec<u8>> {
+    match texture.source().source() {
+        gltf::image::Source::View { view, .. } => {
+            let buffer = &buffers[view.buffer().index()];
+            Ok(buffer[view.offset()..view.offset() + view.length()].to_vec())
+        }
+        gltf::image::Source::Uri { uri, .. } if uri.starts_with("data:") => {
+            bail!("{file_name}: embedded data URIs are not supported, use a .glb or an image file")
+        }
+        gltf::image::Source::Uri { uri, .. } => load_binary(&resolve_uri(file_name, uri)).await,
+    }
+}
+
+async fn load_material(
+    file_name: &str,
+    material: &gltf::Material<'_>,
+    buffers: &[Vec<u8>],
+    device: &Device,
+    queue: &Queue,
+    layout: &BindGroupLayout,
+) -> anyhow::Result<Material> {
+    let name = material.name().unwrap_or(file_name);
+
+    let diffuse_texture = match material.pbr_metallic_roughness().base_color_texture() {
+        Some(info) => {
+            let bytes = load_image(file_name, info.texture(), buffers).await?;
+            Texture::from_bytes(device, queue, &bytes, name, false)?
+        }
+        None => {
+            let color = material
+                .pbr_metallic_roughness()
+                .base_color_factor()
+                .map(|c| (c.clamp(0.0, 1.0) * 255.0) as u8);
+            Texture::from_color(device, queue, color, name, false)?
+        }
+    };
+
+    let normal_texture = match material.normal_texture() {
+        Some(info) => {
+            let bytes = load_image(file_name, info.texture(), buffers).await?;
+            Texture::from_bytes(device, queue, &bytes, name, true)?
+        }
+        // A flat normal pointing straight out of the surface
+        None => Texture::from_color(device, queue, [128, 128, 255, 255], name, true)?,
+    };
+
+    let options = MaterialOptions {
+        alpha_mode: match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        },
+        alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+        // Without a texture, the alpha of the base color is already part of the plain diffuse texture
+        opacity: match material.pbr_metallic_roughness().base_color_texture() {
+            Some(_) => material.pbr_metallic_roughness().base_color_factor()[3],
+            None => 1.0,
+        },
+        double_sided: material.double_sided(),
+        ..Default::default()
+    };
+    Ok(Material::with_options(
+        device,
+        name,
+        diffuse_texture,
+        normal_texture,
+        options,
+        layout,
+    ))
+}
+
+fn node_matrix(node: &gltf::Node) -> Matrix4<f32> {
+    Matrix4::from(node.transform().matrix())
+}
+
+fn load_skeleton(skin: &gltf::Skin, gltf: &gltf::Gltf, buffers: &[Vec<u8>]) -> Skeleton {
+    let mut parents = HashMap::new();
+    for node in gltf.nodes() {
+        for child in node.children() {
+            parents.insert(child.index(), node.index());
+        }
+    }
+
+    let node_to_joint = skin
+        .joints()
+        .enumerate()
+        .map(|(joint, node)| (node.index(), joint))
+        .collect::<HashMap<_, _>>();
+
+    let inverse_binds = skin
+        .reader(|b| Some(&buffers[b.index()]))
+        .read_inverse_bind_matrices()
+        .map(|m| m.map(Matrix4::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    // Non-joint nodes above the root joints (such as an armature node) still contribute their
+    // transform to every joint.
+    let mut root_transform = Matrix4::identity();
+    if let Some(root) = skin
+        .joints()
+        .find(|j| !matches!(parents.get(&j.index()), Some(p) if node_to_joint.contains_key(p)))
+    {
+        let mut parent = parents.get(&root.index()).copied();
+        while let Some(index) = parent {
+            let node = gltf.nodes().nth(index).unwrap();
+            root_transform = node_matrix(&node) * root_transform;
+            parent = parents.get(&index).copied();
+        }
+    }
+
+    let joints = skin
+        .joints()
+        .enumerate()
+        .map(|(i, node)| {
+            let (translation, rotation, scale) = node.transform().decomposed();
+            Joint {
+                parent: parents
+                    .get(&node.index())
+                    .and_then(|p| node_to_joint.get(p))
+                    .copied(),
+                rest: Transform {
+                    translation: translation.into(),
+                    rotation: Quaternion::from(rotation),
+                    scale: scale.into(),
+                },
+                inverse_bind: inverse_binds.get(i).copied().unwrap_or(Matrix4::identity()),
+            }
+        })
+        .collect();
+
+    // The palette only has room for so many joints, and vertices bound to the ones past it would
+    // read whatever the shader makes of an index out of bounds
+    let joint_count = skin.joints().len();
+    if joint_count > MAX_JOINTS {
+        log::warn!(
+            "Skin {} has {joint_count} joints, but only the first {MAX_JOINTS} can be animated",
+            skin.name().unwrap_or("without a name")
+        );
+    }
+
+    Skeleton::new(joints, root_transform)
+}
+
+/// `primitive_meshes` maps every glTF mesh to the [Mesh]es its primitives were loaded into, since
+/// morph weight channels target a whole glTF mesh.
+fn load_animations(
+    gltf: &gltf::Gltf,
+    skin: Option<&gltf::Skin>,
+    primitive_meshes: &HashMap<usize, Vec<usize>>,
+    buffers: &[Vec<u8>],
+) -> Vec<AnimationClip> {
+    let node_to_joint = skin
+        .map(|skin| {
+            skin.joints()
+                .enumerate()
+                .map(|(joint, node)| (node.index(), joint))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    gltf.animations()
+        .map(|animation| {
+            let mut channels = Vec::new();
+            for channel in animation.channels() {
+                let node = channel.target().node();
+                let reader = channel.reader(|b| Some(&buffers[b.index()]));
+                // Channels without keyframes have nothing to sample
+                let Some(times) = reader
+                    .read_inputs()
+                    .map(|t| t.collect::<Vec<_>>())
+                    .filter(|times| !times.is_empty())
+                else {
+                    continue;
+                };
+                let interpolation = match channel.sampler().interpolation() {
+                    gltf::animation::Interpolation::Step => Interpolation::Step,
+                    gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                    gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+                };
+                let joint = node_to_joint.get(&node.index()).copied();
+                // Sampling indexes the values by keyframe, so channels with too few or too many of
+                // them are broken files rather than something we can play back
+                let keyframe_values = times.len() * interpolation.stride();
+                let warn_incomplete = |count: usize| {
+                    log::warn!(
+                        "Skipping channel {} of animation {}: {count} values for {} keyframes",
+                        channel.index(),
+                        animation.name().unwrap_or("without a name"),
+                        times.len()
+                    );
+                };
+                let values = match (reader.read_outputs(), joint) {
+                    (Some(ReadOutputs::Translations(t)), Some(_)) => {
+                        ChannelValues::Translations(t.map(Vector3::from).collect())
+                    }
+                    (Some(ReadOutputs::Rotations(r)), Some(_)) => {
+                        ChannelValues::Rotations(r.into_f32().map(Quaternion::from).collect())
+                    }
+                    (Some(ReadOutputs::Scales(s)), Some(_)) => {
+                        ChannelValues::Scales(s.map(Vector3::from).collect())
+                    }
+                    (Some(ReadOutputs::MorphTargetWeights(w)), _) => {
+                        let weights = w.into_f32().collect::<Vec<_>>();
+                        // One weight per morph target and keyframe value
+                        if weights.is_empty() || weights.len() % keyframe_values != 0 {
+                            warn_incomplete(weights.len());
+                            continue;
+                        }
+                        // One channel per primitive, since each of them is a separate mesh for us
+                        let meshes = node
+                            .mesh()
+                            .and_then(|m| primitive_meshes.get(&m.index()))
+                            .cloned()
+                            .unwrap_or_default();
+                        for mesh in meshes {
+                            channels.push(Channel {
+                                target: ChannelTarget::MorphWeights(mesh),
+                                interpolation,
+                                times: times.clone(),
+                                values: ChannelValues::MorphWeights(weights.clone()),
+                            });
+                        }
+                        continue;
+                    }
+                    // Only joints and morph weights can be animated for now
+                    _ => continue,
+                };
+                if values.len() != keyframe_values {
+                    warn_incomplete(values.len());
+                    continue;
+                }
+                channels.push(Channel {
+                    target: ChannelTarget::Joint(joint.unwrap()),
+                    interpolation,
+                    times,
+                    values,
+                });
+            }
+
+            AnimationClip::new(
+                animation
+                    .name()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("animation {}", animation.index())),
+                channels,
+            )
+        })
+        .collect()
+}
+
+/// Morphed meshes get buffers of their own, since the morph pre-pass reads their base vertices as a
+/// storage buffer.
+fn allocate<V: bytemuck::Pod>(
+    geometry: &mut GeometryArena,
+    device: &Device,
+    queue: &Queue,
+    vertices: &[V],
+    indices: &[u32],
+    morphed: bool,
+) -> GeometryAllocation {
+    if morphed {
+        geometry.allocate_dedicated(device, vertices, indices, wgpu::BufferUsages::STORAGE)
+    } else {
+        geometry.allocate(device, queue, vertices, indices)
+    }
+}
+
+/// Loads a glTF 2.0 file (`.gltf` with external buffers and images, or `.glb`). If the file contains
+/// a skin, the first skin becomes the model's [Skeleton], and the meshes of the nodes using that skin
+/// are built from [SkinnedVertex]es. Every other mesh uses [ModelVertex] just like
+/// [super::load_model], so that props sitting next to a rig aren't bent by its joints.
+///
+/// Primitives with morph targets get [MorphTargets], and animations targeting joints or morph
+/// weights are loaded as clips.
+///
+/// Node transforms of non-skinned meshes are not applied; each primitive becomes one [Mesh].
+pub(crate) async fn load_gltf_model(
+    file_name: &str,
+    device: &Device,
+    queue: &Queue,
+    layout: &BindGroupLayout,
+    geometry: &mut GeometryArena,
+) -> anyhow::Result<Model> {
+    let data = load_binary(file_name).await?;
+    let gltf = gltf::Gltf::from_slice(&data)?;
+    let buffers = load_buffers(file_name, &gltf).await?;
+
+    let mut materials = Vec::new();
+    for material in gltf.materials() {
+        materials.push(load_material(file_name, &material, &buffers, device, queue, layout).await?);
+    }
+    // Primitives without a material use the glTF default material, which is plain white.
+    let default_material = materials.len();
+    materials.push(Material::new(
+        device,
+        "default",
+        Texture::from_color(device, queue, [255; 4], "default", false)?,
+        Texture::from_color(device, queue, [128, 128, 255, 255], "default", true)?,
+        layout,
+    ));
+
+    let skin = gltf.skins().next();
+    let skeleton = skin.as_ref().map(|s| load_skeleton(s, &gltf, &buffers));
+    let skinned_meshes = gltf
+        .nodes()
+        .filter(|node| {
+            node.skin().map(|s| s.index()) == skin.as_ref().map(|s| s.index()) && skin.is_some()
+        })
+        .filter_map(|node| node.mesh().map(|mesh| mesh.index()))
+        .collect::<HashSet<_>>();
+
+    let mut meshes = Vec::new();
+    let mut primitive_meshes = HashMap::new();
+    for mesh in gltf.meshes() {
+        for primitive in mesh.primitives() {
+            primitive_meshes
+                .entry(mesh.index())
+                .or_insert_with(Vec::new)
+                .push(meshes.len());
+
+            let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+            let positions = reader
+                .read_positions()
+                .with_context(|| format!("{file_name}: primitive without positions"))?
+                .collect::<Vec<_>>();
+            let normals = reader
+                .read_normals()
+                .map(|n| n.collect::<Vec<_>>())
+                .unwrap_or_else(|| vec![[0.0; 3]; positions.len()]);
+            let tex_coords = reader
+                .read_tex_coords(0)
+                .map(|t| t.into_f32().collect::<Vec<_>>())
+                .unwrap_or_else(|| vec![[0.0; 2]; positions.len()]);
+            let indices = reader
+                .read_indices()
+                .map(|i| i.into_u32().collect::<Vec<_>>())
+                .unwrap_or_else(|| (0..positions.len() as u32).collect());
+
+            let mut vertices = positions
+                .iter()
+                .zip(&normals)
+                .zip(&tex_coords)
+                .map(|((&position, &normal), &tex_coords)| ModelVertex {
+                    position,
+                    tex_coords,
+                    normal,
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                })
+                .collect::<Vec<_>>();
+            compute_tangents(&mut vertices, &indices);
+
+            let name = mesh.name().unwrap_or(file_name);
+            let morph_deltas = reader
+                .read_morph_targets()
+                .flat_map(|(position_deltas, normal_deltas, tangent_deltas)| {
+                    pack_deltas(
+                        positions.len(),
+                        position_deltas.map(|p| p.collect()),
+                        normal_deltas.map(|n| n.collect()),
+                        tangent_deltas.map(|t| t.collect()),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let morphed = !morph_deltas.is_empty();
+
+            let skinned = skinned_meshes.contains(&mesh.index());
+            let (allocation, vertex_stride) = if skinned {
+                let joints = reader
+                    .read_joints(0)
+                    .map(|j| j.into_u16().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[0; 4]; positions.len()]);
+                let weights = reader
+                    .read_weights(0)
+                    .map(|w| w.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_else(|| vec![[1.0, 0.0, 0.0, 0.0]; positions.len()]);
+
+                let vertices = vertices
+                    .iter()
+                    .zip(joints.iter().zip(&weights))
+                    .map(|(v, (&joints, &weights))| {
+                        // Exporters don't always normalize the weights
+                        let sum = weights.iter().sum::<f32>();
+                        let weights = if sum > 0.0 {
+                            weights.map(|w| w / sum)
+                        } else {
+                            [1.0, 0.0, 0.0, 0.0]
+                        };
+                        SkinnedVertex {
+                            position: v.position,
+                            tex_coords: v.tex_coords,
+                            normal: v.normal,
+                            tangent: v.tangent,
+                            bitangent: v.bitangent,
+                            joints,
+                            weights,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                (
+                    allocate(geometry, device, queue, &vertices, &indices, morphed),
+                    std::mem::size_of::<SkinnedVertex>(),
+                )
+            } else {
+                (
+                    allocate(geometry, device, queue, &vertices, &indices, morphed),
+                    std::mem::size_of::<ModelVertex>(),
+                )
+            };
+
+            meshes.push(Mesh {
+                name: name.to_string(),
+                geometry: allocation.page,
+                material: primitive.material().index().unwrap_or(default_material),
+                bounds: Bounds::from_positions(&positions),
+                // glTF meshes are often skinned or morphed, and simplifying their rest pose could
+                // collapse parts that pull apart once animated, so they keep their full detail.
+                lods: vec![LodLevel {
+                    indices: allocation.first_index..allocation.first_index + indices.len() as u32,
+                    base_vertex: allocation.base_vertex,
+                }],
+                morph_targets: morphed.then(|| {
+                    MorphTargets::new(
+                        device,
+                        name,
+                        &morph_deltas,
+                        positions.len() as u32,
+                        vertex_stride as u32,
+                        mesh.weights().map(<[f32]>::to_vec).unwrap_or_default(),
+                    )
+                }),
+                skinned,
+            });
+        }
+    }
+
+    let animations = load_animations(&gltf, skin.as_ref(), &primitive_meshes, &buffers);
+
+    Ok(Model {
+        bounds: Bounds::union(meshes.iter().map(|m| &m.bounds)),
+        meshes,
+        materials,
+        skeleton,
+        animations,
+    })
+}
@@ -1,5 +1,9 @@
+mod cube_loader;
+mod gltf_loader;
 mod hdr_loader;
 mod resources;
 
+pub(crate) use cube_loader::*;
+pub(crate) use gltf_loader::*;
 pub use hdr_loader::*;
 pub use resources::*;
@@ -1,10 +1,12 @@
-use crate::model::{Material, Mesh, Model, ModelVertex};
+use crate::culling::Bounds;
+use crate::geometry::GeometryArena;
+use crate::lod::{self, LodLevel};
+use crate::model::{AlphaMode, Material, MaterialOptions, Mesh, Model, ModelVertex};
 use crate::texture::Texture;
 use cfg_if::cfg_if;
 /// By design, you can't access files on a user's filesystem in Web Assembly. Instead, we'll serve
 /// those files up using a web serve and then load those files into our code using an http request.
 use std::io::{BufReader, Cursor};
-use wgpu::util::DeviceExt;
 use wgpu::{BindGroupLayout, Device, Queue};
 
 #[cfg(target_arch = "wasm32")]
@@ -56,12 +58,17 @@ pub(crate) async fn load_model(
     device: &Device,
     queue: &Queue,
     layout: &BindGroupLayout,
+    geometry: &mut GeometryArena,
 ) -> anyhow::Result<Model> {
+    load_model_with_lods(file_name, &[], device, queue, layout, geometry).await
+}
+
+async fn load_obj(file_name: &str) -> anyhow::Result<tobj::LoadResult> {
     let obj_text = load_string(file_name).await?;
     let obj_cursor = Cursor::new(obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
 
-    let (models, obj_materials) = tobj::load_obj_buf_async(
+    Ok(tobj::load_obj_buf_async(
         &mut obj_reader,
         &tobj::LoadOptions {
             triangulate: true,
@@ -73,131 +80,276 @@ pub(crate) async fn load_model(
             tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
         },
     )
-    .await?;
+    .await)
+}
+
+fn obj_vertices(mesh: &tobj::Mesh) -> Vec<ModelVertex> {
+    let mut vertices = (0..mesh.positions.len() / 3)
+        .map(|i| ModelVertex {
+            position: [
+                mesh.positions[i * 3],
+                mesh.positions[i * 3 + 1],
+                mesh.positions[i * 3 + 2],
+            ],
+            tex_coords: [mesh.texcoords[i * 2], mesh.texcoords[i * 2 + 1]],
+            normal: if mesh.normals.is_empty() {
+                [0.0; 3]
+            } else {
+                [
+                    mesh.normals[i * 3],
+                    mesh.normals[i * 3 + 1],
+                    mesh.normals[i * 3 + 2],
+                ]
+            },
+            // We'll calculate tangents later
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
+        })
+        .collect::<Vec<_>>();
+
+    compute_tangents(&mut vertices, &mesh.indices);
+    vertices
+}
+
+/// MTL files have no alpha modes, so we guess one from the dissolve (`d`) settings. A dissolve
+/// texture (`map_d`) usually cuts out leaves or fences, so it becomes a mask, drawn on both sides
+/// since a cutout is usually a single flat card. [load_diffuse_texture] puts it into the alpha
+/// the mask is cut out with. A dissolve below 1 on
+/// its own makes the whole surface see-through, like glass.
+fn mtl_options(material: &tobj::Material) -> MaterialOptions {
+    let dissolve = material.dissolve.unwrap_or(1.0);
+    if material.dissolve_texture.is_some() {
+        MaterialOptions {
+            alpha_mode: AlphaMode::Mask,
+            opacity: dissolve,
+            double_sided: true,
+            ..Default::default()
+        }
+    } else if dissolve < 1.0 {
+        MaterialOptions {
+            alpha_mode: AlphaMode::Blend,
+            opacity: dissolve,
+            ..Default::default()
+        }
+    } else {
+        MaterialOptions::default()
+    }
+}
+
+/// Loads the diffuse texture of an MTL `material`, with its dissolve texture, if it has one, in the
+/// alpha. The dissolve texture is stretched to the size of the diffuse texture if they differ.
+async fn load_diffuse_texture(
+    material: &tobj::Material,
+    device: &Device,
+    queue: &Queue,
+) -> anyhow::Result<Texture> {
+    let diffuse_file = material.diffuse_texture.as_deref().unwrap();
+    let Some(dissolve_file) = material.dissolve_texture.as_deref() else {
+        return Texture::load_texture(diffuse_file, device, queue, false).await;
+    };
+    let mut diffuse = image::load_from_memory(&load_binary(diffuse_file).await?)?.to_rgba8();
+    let mut dissolve = image::load_from_memory(&load_binary(dissolve_file).await?)?.to_luma8();
+    if dissolve.dimensions() != diffuse.dimensions() {
+        dissolve = image::imageops::resize(
+            &dissolve,
+            diffuse.width(),
+            diffuse.height(),
+            image::imageops::FilterType::Triangle,
+        );
+    }
+    for (pixel, alpha) in diffuse.pixels_mut().zip(dissolve.pixels()) {
+        pixel[3] = alpha[0];
+    }
+    Texture::from_image(
+        device,
+        queue,
+        &image::DynamicImage::ImageRgba8(diffuse),
+        Some(diffuse_file),
+        false,
+    )
+}
+
+/// Loads an OBJ model together with its levels of detail. `lod_files` are OBJ files with simplified
+/// versions of the model, from the most detailed to the coarsest, each with the same meshes in the
+/// same order as `file_name`. Their materials are ignored.
+///
+/// Without any `lod_files`, the levels are generated with [lod::generate_lods] instead.
+///
+/// The vertices and indices of all meshes are copied into `geometry`.
+pub(crate) async fn load_model_with_lods(
+    file_name: &str,
+    lod_files: &[&str],
+    device: &Device,
+    queue: &Queue,
+    layout: &BindGroupLayout,
+    geometry: &mut GeometryArena,
+) -> anyhow::Result<Model> {
+    let (models, obj_materials) = load_obj(file_name).await??;
+    let mut lod_models = Vec::new();
+    for lod_file in lod_files {
+        let (lod_model, _) = load_obj(lod_file).await??;
+        if lod_model.len() != models.len() {
+            anyhow::bail!(
+                "{lod_file} has {} meshes, but {file_name} has {}",
+                lod_model.len(),
+                models.len()
+            );
+        }
+        lod_models.push(lod_model);
+    }
 
     let mut materials = Vec::new();
     for m in obj_materials? {
-        let diffuse_texture =
-            Texture::load_texture(&m.diffuse_texture.unwrap(), device, queue, false).await?;
+        let options = mtl_options(&m);
+        let diffuse_texture = load_diffuse_texture(&m, device, queue).await?;
         let normal_texture =
             Texture::load_texture(&m.normal_texture.unwrap(), device, queue, true).await?;
 
-        materials.push(Material::new(
+        materials.push(Material::with_options(
             device,
             &m.name,
             diffuse_texture,
             normal_texture,
+            options,
             layout,
         ));
     }
 
     let meshes = models
         .iter()
-        .map(|m| {
-            let mut vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: if m.mesh.normals.is_empty() {
-                        [0.0; 3]
-                    } else {
-                        [
-                            m.mesh.normals[i * 3],
-                            m.mesh.normals[i * 3 + 1],
-                            m.mesh.normals[i * 3 + 2],
-                        ]
-                    },
-                    // We'll calculate tangents later
-                    tangent: [0.0; 3],
-                    bitangent: [0.0; 3],
-                })
-                .collect::<Vec<_>>();
-
-            let indices = &m.mesh.indices;
-            let mut triangles_included = vec![0; vertices.len()];
-
-            // Calculate tangents and bitangents. We're going to use the triangles, so we need to
-            // loop through the indices in chunks of 3.
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0: cgmath::Vector3<_> = v0.position.into();
-                let pos1: cgmath::Vector3<_> = v1.position.into();
-                let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                // We flip the bitangent to enable right-handed normal maps with wgpu texture coordinate system
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
-
-                // Used to average the tangets/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
-            }
+        .enumerate()
+        .map(|(i, m)| {
+            let mut vertices = obj_vertices(&m.mesh);
+            let bounds =
+                Bounds::from_positions(&vertices.iter().map(|v| v.position).collect::<Vec<_>>());
 
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.iter().enumerate() {
-                let denom = 1.0 / *n as f32;
-                let v = &mut vertices[i];
-                v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
-                v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
+            // Every level's indices go right after the previous level's. Artist supplied levels
+            // bring their own vertices, which go after the previous level's vertices.
+            let mut indices = Vec::new();
+            let mut lods = Vec::new();
+            let mut push_lod = |level_indices: &[u32], base_vertex: usize| {
+                let start = indices.len() as u32;
+                indices.extend_from_slice(level_indices);
+                lods.push(LodLevel {
+                    indices: start..indices.len() as u32,
+                    base_vertex: base_vertex as i32,
+                });
+            };
+            push_lod(&m.mesh.indices, 0);
+            if lod_models.is_empty() {
+                let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+                let normals = vertices.iter().map(|v| v.normal).collect::<Vec<_>>();
+                for level in lod::generate_lods(&positions, &normals, &m.mesh.indices)
+                    .iter()
+                    .skip(1)
+                {
+                    push_lod(level, 0);
+                }
+            }
+            for lod_model in &lod_models {
+                let base_vertex = vertices.len();
+                vertices.extend(obj_vertices(&lod_model[i].mesh));
+                push_lod(&lod_model[i].mesh.indices, base_vertex);
             }
 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{file_name} vertex buffer")),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{file_name} index buffer")),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+            log::info!(
+                "{file_name}: {} has levels of detail with {:?} triangles",
+                m.name,
+                lods.iter()
+                    .map(|level| level.indices.len() / 3)
+                    .collect::<Vec<_>>()
+            );
+
+            // The levels were laid out from zero, move them to where the mesh ended up
+            let allocation = geometry.allocate(device, queue, &vertices, &indices);
+            for level in &mut lods {
+                level.indices = level.indices.start + allocation.first_index
+                    ..level.indices.end + allocation.first_index;
+                level.base_vertex += allocation.base_vertex;
+            }
             Mesh {
                 name: m.name.clone(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
+                geometry: allocation.page,
                 material: m.mesh.material_id.unwrap_or(0),
+                bounds,
+                lods,
+                morph_targets: None,
+                skinned: false,
             }
         })
-        .collect();
+        .collect::<Vec<_>>();
+
+    Ok(Model {
+        bounds: Bounds::union(meshes.iter().map(|m| &m.bounds)),
+        meshes,
+        materials,
+        skeleton: None,
+        animations: Vec::new(),
+    })
+}
 
-    Ok(Model { meshes, materials })
+/// OBJ files don't carry tangents and bitangents, and neither do most glTF exports, so we derive them
+/// from the triangle edges and texture coordinates. The results are accumulated per vertex and then
+/// averaged over the triangles sharing it.
+pub(crate) fn compute_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut triangles_included = vec![0; vertices.len()];
+
+    // Calculate tangents and bitangents. We're going to use the triangles, so we need to
+    // loop through the indices in chunks of 3.
+    for c in indices.chunks(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        let pos0: cgmath::Vector3<_> = v0.position.into();
+        let pos1: cgmath::Vector3<_> = v1.position.into();
+        let pos2: cgmath::Vector3<_> = v2.position.into();
+
+        let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
+        let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
+        let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
+
+        // Calculate the edges of the triangle
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+
+        // This will give us a direction to calculate the tangent and bitangent
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        // Solving the following system of equations will give us the tangent and bitangent.
+        //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        // We flip the bitangent to enable right-handed normal maps with wgpu texture coordinate system
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+        // We'll use the same tangent/bitangent for each vertex in the triangle
+        vertices[c[0] as usize].tangent =
+            (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
+        vertices[c[1] as usize].tangent =
+            (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
+        vertices[c[2] as usize].tangent =
+            (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
+        vertices[c[0] as usize].bitangent =
+            (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
+        vertices[c[1] as usize].bitangent =
+            (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
+        vertices[c[2] as usize].bitangent =
+            (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
+
+        // Used to average the tangets/bitangents
+        triangles_included[c[0] as usize] += 1;
+        triangles_included[c[1] as usize] += 1;
+        triangles_included[c[2] as usize] += 1;
+    }
+
+    // Average the tangents/bitangents
+    for (i, n) in triangles_included.iter().enumerate() {
+        let denom = 1.0 / *n as f32;
+        let v = &mut vertices[i];
+        v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
+        v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
+    }
 }
@@ -0,0 +1,5 @@
+mod scene_graph;
+mod transform;
+
+pub(crate) use scene_graph::{Attachment, NodeId, SceneGraph};
+pub(crate) use transform::Transform;
@@ -0,0 +1,244 @@
+use super::Transform;
+use crate::instance::InstanceRaw;
+use crate::transparency::TransparencyMode;
+use cgmath::{Matrix4, SquareMatrix, Vector3};
+
+/// A handle to a node in a [SceneGraph]. Nodes are never removed, so a handle stays valid for as
+/// long as the graph it came from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct NodeId(usize);
+
+/// Something that takes its position in the world from the node it is attached to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Attachment {
+    /// An instance of a model. The index is chosen by the renderer and refers to its own list of
+    /// models; the scene graph only uses it to group instances, see [SceneGraph::model_instances].
+    Model(usize),
+    /// The light source. Only its position is taken from the node.
+    Light,
+    /// The camera. The camera's own position and orientation become relative to the node.
+    Camera,
+}
+
+struct SceneNode {
+    local: Transform,
+    children: Vec<NodeId>,
+    attachments: Vec<Attachment>,
+    /// `parent.world * local`, only valid while `dirty` is false.
+    world: Matrix4<f32>,
+    /// `world` as of the update before the last one.
+    previous_world: Matrix4<f32>,
+    dirty: bool,
+}
+
+/// # Scene Graph
+/// So far every object in the scene has been placed directly in world space. That works for a grid of
+/// cubes, but as soon as objects start carrying other objects around (a lamp standing on a cart, a
+/// camera mounted on a vehicle) we would have to redo the math for every passenger whenever the
+/// carrier moves.
+///
+/// A scene graph solves this by arranging the objects in a tree. Every node stores its transform
+/// relative to its parent, and its world transform is the product of all the local transforms on the
+/// path from the root down to it:
+///
+/// `world = parent.world * local`
+///
+/// Moving a node then implicitly moves its whole subtree.
+///
+/// # Dirty Flags
+/// Recomputing every world matrix each frame would be wasteful when most of the scene is static, so
+/// the world matrices are cached. Changing a node's local transform marks it as dirty, and
+/// [SceneGraph::update_world_matrices] walks the tree from the roots, recomputing the world matrix of
+/// every dirty node and everything below it. Nodes whose subtree didn't change are skipped.
+///
+/// The world matrices of the update before are kept as well, so that the instance data can say how
+/// far every instance moved since the last frame.
+///
+/// # Scene Settings
+/// How a scene looks best depends on what is in it. A scene with a few panes of glass is drawn
+/// exactly by sorting them, while one full of intersecting smoke or foliage would rather not have
+/// them pop as their order changes. The renderer reads those choices from the scene it draws.
+pub(crate) struct SceneGraph {
+    nodes: Vec<SceneNode>,
+    roots: Vec<NodeId>,
+    /// Whether any world matrix changed in the last update.
+    moved: bool,
+    /// How the blended surfaces of the scene are drawn.
+    pub(crate) transparency: TransparencyMode,
+}
+
+impl SceneGraph {
+    pub(crate) fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+            moved: false,
+            transparency: TransparencyMode::default(),
+        }
+    }
+
+    /// Adds a node below `parent`, or as a new root if `parent` is `None`.
+    pub(crate) fn add_node(&mut self, local: Transform, parent: Option<NodeId>) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(SceneNode {
+            local,
+            children: Vec::new(),
+            attachments: Vec::new(),
+            world: Matrix4::identity(),
+            previous_world: Matrix4::identity(),
+            dirty: true,
+        });
+        match parent {
+            Some(parent) => self.nodes[parent.0].children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    pub(crate) fn attach(&mut self, node: NodeId, attachment: Attachment) {
+        self.nodes[node.0].attachments.push(attachment);
+    }
+
+    /// Gives mutable access to the local transform of `node` and marks it as dirty.
+    pub(crate) fn local_mut(&mut self, node: NodeId) -> &mut Transform {
+        let node = &mut self.nodes[node.0];
+        node.dirty = true;
+        &mut node.local
+    }
+
+    /// The cached world matrix of `node`, as of the last call to [Self::update_world_matrices].
+    pub(crate) fn world_matrix(&self, node: NodeId) -> Matrix4<f32> {
+        self.nodes[node.0].world
+    }
+
+    /// The position of `node` in world space, as of the last call to [Self::update_world_matrices].
+    pub(crate) fn world_position(&self, node: NodeId) -> Vector3<f32> {
+        self.nodes[node.0].world.w.truncate()
+    }
+
+    /// Recomputes the world matrices of all dirty nodes and their descendants. Returns whether the
+    /// instance data changed, so callers know when it needs to be uploaded again. That includes the
+    /// update after the one that last moved something, since the previous world matrices caught up
+    /// with it.
+    pub(crate) fn update_world_matrices(&mut self) -> bool {
+        let mut changed = false;
+        let mut stack = self
+            .roots
+            .iter()
+            .map(|&root| (root, Matrix4::identity(), false))
+            .collect::<Vec<_>>();
+
+        while let Some((id, parent_world, parent_changed)) = stack.pop() {
+            let node = &mut self.nodes[id.0];
+            node.previous_world = node.world;
+            let dirty = node.dirty || parent_changed;
+            if dirty {
+                node.world = parent_world * node.local.to_matrix();
+                node.dirty = false;
+                changed = true;
+            }
+            let world = node.world;
+            stack.extend(node.children.iter().map(|&child| (child, world, dirty)));
+        }
+
+        let moved = std::mem::replace(&mut self.moved, changed);
+        changed || moved
+    }
+
+    /// All nodes carrying `attachment`, in the order they were added.
+    pub(crate) fn nodes_with(&self, attachment: Attachment) -> impl Iterator<Item = NodeId> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(move |(_, node)| node.attachments.contains(&attachment))
+            .map(|(i, _)| NodeId(i))
+    }
+
+    /// Builds the instance data for every node that has `model` attached, in the order the nodes
+    /// were added. This only reads the cached world matrices, so call [Self::update_world_matrices]
+    /// first.
+    pub(crate) fn model_instances(&self, model: usize) -> Vec<InstanceRaw> {
+        self.nodes_with(Attachment::Model(model))
+            .map(|node| {
+                InstanceRaw::from_matrix(self.world_matrix(node), [1.0; 4])
+                    .with_previous(self.nodes[node.0].previous_world)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32, y: f32, z: f32) -> Transform {
+        Transform {
+            translation: Vector3::new(x, y, z),
+            ..Transform::default()
+        }
+    }
+
+    #[test]
+    fn moving_a_parent_moves_its_children() {
+        let mut graph = SceneGraph::new();
+        let cart = graph.add_node(at(1.0, 0.0, 0.0), None);
+        let lamp = graph.add_node(at(0.0, 2.0, 0.0), Some(cart));
+        graph.update_world_matrices();
+        assert_eq!(graph.world_position(lamp), Vector3::new(1.0, 2.0, 0.0));
+
+        graph.local_mut(cart).translation.x = 5.0;
+        graph.update_world_matrices();
+        assert_eq!(graph.world_position(cart), Vector3::new(5.0, 0.0, 0.0));
+        assert_eq!(graph.world_position(lamp), Vector3::new(5.0, 2.0, 0.0));
+    }
+
+    #[test]
+    fn clean_siblings_are_not_recomputed() {
+        let mut graph = SceneGraph::new();
+        let parent = graph.add_node(at(0.0, 0.0, 0.0), None);
+        let moved = graph.add_node(at(1.0, 0.0, 0.0), Some(parent));
+        let clean = graph.add_node(at(-1.0, 0.0, 0.0), Some(parent));
+        graph.update_world_matrices();
+
+        // Changing the local transform behind the graph's back leaves the node clean, so its stale
+        // world matrix shows whether it was recomputed
+        graph.nodes[clean.0].local = at(-3.0, 0.0, 0.0);
+        graph.local_mut(moved).translation.x = 2.0;
+        graph.update_world_matrices();
+        assert_eq!(graph.world_position(moved), Vector3::new(2.0, 0.0, 0.0));
+        assert_eq!(graph.world_position(clean), Vector3::new(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn previous_world_matrices_trail_by_one_update() {
+        let mut graph = SceneGraph::new();
+        let node = graph.add_node(at(1.0, 0.0, 0.0), None);
+        graph.update_world_matrices();
+        let first = graph.world_matrix(node);
+
+        graph.local_mut(node).translation.x = 2.0;
+        graph.update_world_matrices();
+        assert_eq!(graph.nodes[node.0].previous_world, first);
+        let second = graph.world_matrix(node);
+
+        // Without any change, the previous matrix catches up with the current one
+        graph.update_world_matrices();
+        assert_eq!(graph.nodes[node.0].previous_world, second);
+        assert_eq!(graph.world_matrix(node), second);
+    }
+
+    #[test]
+    fn reports_changes_for_one_more_update() {
+        let mut graph = SceneGraph::new();
+        let node = graph.add_node(at(0.0, 0.0, 0.0), None);
+        assert!(graph.update_world_matrices());
+        // The previous world matrices still have to catch up
+        assert!(graph.update_world_matrices());
+        assert!(!graph.update_world_matrices());
+
+        graph.local_mut(node).translation.y = 1.0;
+        assert!(graph.update_world_matrices());
+        assert!(graph.update_world_matrices());
+        assert!(!graph.update_world_matrices());
+    }
+}
@@ -0,0 +1,37 @@
+use cgmath::{Matrix4, Quaternion, Vector3};
+
+/// A transform relative to some parent, split into translation, rotation and scale. Animation
+/// clips write these three channels independently, and scene nodes are usually moved by changing
+/// only one of them, so we keep them apart and only combine them into a matrix when needed.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Transform {
+    pub(crate) translation: Vector3<f32>,
+    pub(crate) rotation: Quaternion<f32>,
+    pub(crate) scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub(crate) fn new(translation: Vector3<f32>, rotation: Quaternion<f32>) -> Self {
+        Self {
+            translation,
+            rotation,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn to_matrix(self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::new(0.0, 0.0, 0.0),
+            rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::clustered::ClusteredLights;
+use crate::debug_view::DebugView;
+use crate::fog::Fog;
+use crate::sky::Sky;
+use crate::ssao::Ssao;
+
+/// Everything in a [SceneBindGroup].
+#[derive(Copy, Clone)]
+pub(crate) struct SceneResources<'a> {
+    /// Holds a [crate::light::LightUniform].
+    pub(crate) light: &'a wgpu::Buffer,
+    pub(crate) clusters: &'a ClusteredLights,
+    pub(crate) ssao: &'a Ssao,
+    pub(crate) fog: &'a Fog,
+    pub(crate) sky: &'a Sky,
+    pub(crate) debug_view: &'a DebugView,
+}
+
+/// # Scene Bind Group
+/// The lit shaders read a lot about the scene besides the surface they draw: the main light, the
+/// point lights of the clusters, the ambient occlusion, the fog, the sky and the debug view. All of
+/// it changes at most once per frame, so it goes into a single bind group that is bound once for
+/// every pass, and the shaders declare it once in `scene.wgsl` at `SCENE_GROUP`.
+///
+/// The binding of every resource is only spelled out here and in `scene.wgsl`. A new resource is
+/// added to [SceneResources], given the next free binding below, and declared in `scene.wgsl`.
+///
+/// The main light shares the group with the rest rather than getting one of its own, since the
+/// downlevel limits only guarantee 4 bind groups, and the skinned shaders already use all of them:
+/// the material, the camera, this group and the joints.
+pub(crate) struct SceneBindGroup {
+    layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+}
+
+impl SceneBindGroup {
+    const LIGHT: u32 = 0;
+    /// The cluster parameters, the point lights and the light lists of every cluster take 4.
+    const CLUSTERS: u32 = 1;
+    const AMBIENT_OCCLUSION: u32 = 5;
+    const FOG: u32 = 6;
+    /// The sky cube and its sampler take 2.
+    const SKY: u32 = 7;
+    const DEBUG_VIEW: u32 = 9;
+
+    pub(crate) fn new(device: &wgpu::Device, resources: SceneResources) -> Self {
+        // The light moves the tangent space positions in the vertex shaders, while only the fragment
+        // shaders look up the rest
+        let fragment = wgpu::ShaderStages::FRAGMENT;
+        let mut entries = vec![wgpu::BindGroupLayoutEntry {
+            binding: Self::LIGHT,
+            visibility: wgpu::ShaderStages::VERTEX | fragment,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }];
+        entries.extend(ClusteredLights::layout_entries(
+            Self::CLUSTERS,
+            fragment,
+            true,
+        ));
+        entries.push(Ssao::layout_entry(Self::AMBIENT_OCCLUSION, fragment));
+        entries.push(Fog::layout_entry(Self::FOG, fragment));
+        entries.extend(Sky::layout_entries(Self::SKY, fragment));
+        entries.push(DebugView::layout_entry(Self::DEBUG_VIEW, fragment));
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("scene_bind_group_layout"),
+            entries: &entries,
+        });
+        let bind_group = Self::create_bind_group(device, &layout, resources);
+        Self { layout, bind_group }
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        resources: SceneResources,
+    ) -> wgpu::BindGroup {
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: Self::LIGHT,
+            resource: resources.light.as_entire_binding(),
+        }];
+        entries.extend(resources.clusters.bind_group_entries(Self::CLUSTERS));
+        entries.push(resources.ssao.bind_group_entry(Self::AMBIENT_OCCLUSION));
+        entries.push(resources.fog.bind_group_entry(Self::FOG));
+        entries.extend(resources.sky.bind_group_entries(Self::SKY));
+        entries.push(resources.debug_view.bind_group_entry(Self::DEBUG_VIEW));
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("scene_bind_group"),
+            layout,
+            entries: &entries,
+        })
+    }
+
+    /// Binds the `resources` again, for when one of them was recreated, like the occlusion after a
+    /// resize.
+    pub(crate) fn rebind(&mut self, device: &wgpu::Device, resources: SceneResources) {
+        self.bind_group = Self::create_bind_group(device, &self.layout, resources);
+    }
+
+    pub(crate) fn layout(&self) -> &wgpu::BindGroupLayout {
+        &self.layout
+    }
+
+    pub(crate) fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
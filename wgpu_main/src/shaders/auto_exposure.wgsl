@@ -0,0 +1,111 @@
+// This has to match `AutoExposure::NUM_BINS` in auto_exposure.rs
+const NUM_BINS: u32 = 256u;
+
+struct AutoExposureParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    low_percentile: f32,
+    high_percentile: f32,
+    speed_brighten: f32,
+    speed_darken: f32,
+    // In seconds
+    delta_time: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: AutoExposureParams;
+
+@group(0) @binding(1)
+var hdr_image: texture_2d<f32>;
+
+// How many pixels fall into each range of log luminance. The first bin holds the pixels that are (almost) black.
+@group(0) @binding(2)
+var<storage, read_write> histogram: array<atomic<u32>, NUM_BINS>;
+
+// The log2 luminance the exposure is currently adapted to
+@group(0) @binding(3)
+var<storage, read_write> adapted_log_luminance: f32;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn bin_of(color: vec3<f32>) -> u32 {
+    let l = luminance(color);
+    if l < 0.00001 {
+        return 0u;
+    }
+    let t = saturate((log2(l) - params.min_log_luminance) / params.log_luminance_range);
+    return u32(t * f32(NUM_BINS - 2u)) + 1u;
+}
+
+// Counting into workgroup memory first means the global histogram only gets one atomic add per bin and workgroup,
+// instead of one for every pixel.
+var<workgroup> local_histogram: array<atomic<u32>, NUM_BINS>;
+
+@compute
+@workgroup_size(16, 16)
+fn build_histogram(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_index) local_index: u32,
+) {
+    atomicStore(&local_histogram[local_index], 0u);
+    workgroupBarrier();
+
+    let size = textureDimensions(hdr_image);
+    if all(global_id.xy < size) {
+        let color = textureLoad(hdr_image, vec2<i32>(global_id.xy), 0).rgb;
+        atomicAdd(&local_histogram[bin_of(color)], 1u);
+    }
+    workgroupBarrier();
+
+    atomicAdd(&histogram[local_index], atomicLoad(&local_histogram[local_index]));
+}
+
+var<workgroup> bins: array<u32, NUM_BINS>;
+
+@compute
+@workgroup_size(256)
+fn average_histogram(@builtin(local_invocation_index) local_index: u32) {
+    // Take the counts, leaving the histogram empty for the next frame
+    bins[local_index] = atomicExchange(&histogram[local_index], 0u);
+    workgroupBarrier();
+
+    if local_index != 0u {
+        return;
+    }
+
+    // Black pixels are left out, they are mostly background
+    var total = 0u;
+    for (var i = 1u; i < NUM_BINS; i += 1u) {
+        total += bins[i];
+    }
+    let low = f32(total) * params.low_percentile;
+    let high = f32(total) * params.high_percentile;
+
+    // Average the log luminance of the pixels between the two percentiles. A bin straddling a cutoff only counts
+    // with the pixels on the inside.
+    var seen = 0.0;
+    var sum = 0.0;
+    var weight = 0.0;
+    for (var i = 1u; i < NUM_BINS; i += 1u) {
+        let count = f32(bins[i]);
+        let inside = max(min(seen + count, high) - max(seen, low), 0.0);
+        seen += count;
+        let log_luminance = params.min_log_luminance
+            + (f32(i) - 0.5) / f32(NUM_BINS - 2u) * params.log_luminance_range;
+        sum += inside * log_luminance;
+        weight += inside;
+    }
+    if weight <= 0.0 {
+        return;
+    }
+    let target_log_luminance = sum / weight;
+
+    // Move towards the new average by a fraction that depends on the frame time, so adaptation takes as long at any
+    // frame rate
+    let current = adapted_log_luminance;
+    let speed = select(params.speed_darken, params.speed_brighten, target_log_luminance > current);
+    let blend = 1.0 - exp(-params.delta_time * speed);
+    adapted_log_luminance = current + (target_log_luminance - current) * blend;
+}
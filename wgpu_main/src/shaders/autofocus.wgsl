@@ -0,0 +1,54 @@
+// Moves the focus of depth of field towards what is in the middle of the screen with autofocus, and straight to the
+// focus distance without it. The other passes of depth_of_field.wgsl read the focus this leaves behind.
+
+// This has to match `DofUniform` in depth_of_field.rs
+struct DofUniform {
+    focus_distance: f32,
+    aperture: f32,
+    focal_length: f32,
+    sensor_height: f32,
+    znear: f32,
+    zfar: f32,
+    max_coc: f32,
+    autofocus: u32,
+    autofocus_speed: f32,
+    delta_time: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> dof: DofUniform;
+// Carried over from frame to frame, so that autofocus can ease into the new distance
+@group(0) @binding(1)
+var<storage, read_write> focus: f32;
+@group(0) @binding(2)
+var depth_texture: texture_depth_2d;
+
+fn linearize_depth(depth: f32) -> f32 {
+    return dof.znear * dof.zfar / (dof.zfar - depth * (dof.zfar - dof.znear));
+}
+
+@compute
+@workgroup_size(1)
+fn cs_main() {
+    if dof.autofocus == 0u {
+        focus = dof.focus_distance;
+        return;
+    }
+
+    // A few pixels around the middle, so that a single one on an edge doesn't make the focus jump
+    let size = vec2<i32>(textureDimensions(depth_texture));
+    let center = size / 2;
+    var offsets = array<vec2<i32>, 5>(
+        vec2<i32>(0, 0),
+        vec2<i32>(-8, 0),
+        vec2<i32>(8, 0),
+        vec2<i32>(0, -8),
+        vec2<i32>(0, 8),
+    );
+    var target_distance = 0.0;
+    for (var i = 0; i < 5; i += 1) {
+        let pixel = clamp(center + offsets[i], vec2<i32>(0), size - 1);
+        target_distance += linearize_depth(textureLoad(depth_texture, pixel, 0)) / 5.0;
+    }
+    focus = mix(focus, target_distance, 1.0 - exp(-dof.delta_time * dof.autofocus_speed));
+}
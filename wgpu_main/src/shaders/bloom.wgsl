@@ -0,0 +1,151 @@
+// Bloom, following https://www.iryoku.com/next-generation-post-processing-in-call-of-duty-advanced-warfare
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+struct BloomUniform {
+    threshold: f32,
+    // As a fraction of the threshold
+    soft_knee: f32,
+    // The distance between the samples of the upsample filter, in texels
+    radius: f32,
+    // How much of the glow the composite pass adds
+    intensity: f32,
+}
+
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+@group(0) @binding(1)
+var source_sampler: sampler;
+@group(0) @binding(2)
+var<uniform> bloom: BloomUniform;
+// The first texture of the chain, which only the composite pass reads. `source` is the input image then.
+@group(0) @binding(3)
+var bloom_texture: texture_2d<f32>;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn sample_texture(t: texture_2d<f32>, uv: vec2<f32>, offset: vec2<f32>) -> vec3<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(t));
+    return textureSample(t, source_sampler, uv + offset * texel).rgb;
+}
+
+fn sample(uv: vec2<f32>, offset: vec2<f32>) -> vec3<f32> {
+    return sample_texture(source, uv, offset);
+}
+
+// The 13 samples of the downsample filter, in texels of the source. The outer ones fall between four texels each, so
+// bilinear filtering averages 36 texels in all:
+//   a . b . c
+//   . j . k .
+//   d . e . f
+//   . l . m .
+//   g . h . i
+struct Taps {
+    a: vec3<f32>, b: vec3<f32>, c: vec3<f32>,
+    d: vec3<f32>, e: vec3<f32>, f: vec3<f32>,
+    g: vec3<f32>, h: vec3<f32>, i: vec3<f32>,
+    j: vec3<f32>, k: vec3<f32>, l: vec3<f32>, m: vec3<f32>,
+}
+
+fn taps(uv: vec2<f32>) -> Taps {
+    return Taps(
+        sample(uv, vec2<f32>(-2.0, -2.0)), sample(uv, vec2<f32>(0.0, -2.0)), sample(uv, vec2<f32>(2.0, -2.0)),
+        sample(uv, vec2<f32>(-2.0, 0.0)), sample(uv, vec2<f32>(0.0, 0.0)), sample(uv, vec2<f32>(2.0, 0.0)),
+        sample(uv, vec2<f32>(-2.0, 2.0)), sample(uv, vec2<f32>(0.0, 2.0)), sample(uv, vec2<f32>(2.0, 2.0)),
+        sample(uv, vec2<f32>(-1.0, -1.0)), sample(uv, vec2<f32>(1.0, -1.0)),
+        sample(uv, vec2<f32>(-1.0, 1.0)), sample(uv, vec2<f32>(1.0, 1.0)),
+    );
+}
+
+// The taps make up five overlapping boxes of four samples each: the inner one counts for half of the result, the four
+// corner ones for an eighth each.
+fn downsample(uv: vec2<f32>) -> vec3<f32> {
+    let t = taps(uv);
+    return t.e * 0.125
+        + (t.a + t.c + t.g + t.i) * 0.03125
+        + (t.b + t.d + t.f + t.h) * 0.0625
+        + (t.j + t.k + t.l + t.m) * 0.125;
+}
+
+// Karis average: the boxes are weighted by the inverse of their brightness, so a single very bright pixel can't
+// dominate the result and flicker as it moves from one texel to the next.
+fn karis_box(box: vec3<f32>, weight: f32) -> vec4<f32> {
+    let w = weight / (1.0 + luminance(box));
+    return vec4<f32>(box * w, w);
+}
+
+fn downsample_karis(uv: vec2<f32>) -> vec3<f32> {
+    let t = taps(uv);
+    let sum = karis_box((t.j + t.k + t.l + t.m) * 0.25, 0.5)
+        + karis_box((t.a + t.b + t.d + t.e) * 0.25, 0.125)
+        + karis_box((t.b + t.c + t.e + t.f) * 0.25, 0.125)
+        + karis_box((t.d + t.e + t.g + t.h) * 0.25, 0.125)
+        + karis_box((t.e + t.f + t.h + t.i) * 0.25, 0.125);
+    return sum.rgb / sum.a;
+}
+
+// Keeps the light above the threshold, fading in along a quadratic curve over the knee below it
+fn soft_threshold(color: vec3<f32>) -> vec3<f32> {
+    let brightness = max(color.r, max(color.g, color.b));
+    let knee = bloom.threshold * bloom.soft_knee;
+    var soft = clamp(brightness - bloom.threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee + 0.00001);
+    let contribution = max(soft, brightness - bloom.threshold) / max(brightness, 0.00001);
+    return color * contribution;
+}
+
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(soft_threshold(downsample_karis(in.uv)), 1.0);
+}
+
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(downsample(in.uv), 1.0);
+}
+
+// A 3x3 tent filter, spread out by the radius:
+//   1 2 1
+//   2 4 2  / 16
+//   1 2 1
+fn tent(t: texture_2d<f32>, uv: vec2<f32>) -> vec3<f32> {
+    let r = bloom.radius;
+    let color = sample_texture(t, uv, vec2<f32>(0.0, 0.0)) * 4.0
+        + (sample_texture(t, uv, vec2<f32>(0.0, -r)) + sample_texture(t, uv, vec2<f32>(-r, 0.0))
+            + sample_texture(t, uv, vec2<f32>(r, 0.0)) + sample_texture(t, uv, vec2<f32>(0.0, r))) * 2.0
+        + sample_texture(t, uv, vec2<f32>(-r, -r)) + sample_texture(t, uv, vec2<f32>(r, -r))
+        + sample_texture(t, uv, vec2<f32>(-r, r)) + sample_texture(t, uv, vec2<f32>(r, r));
+    return color / 16.0;
+}
+
+@fragment
+fn fs_upsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(tent(source, in.uv), 1.0);
+}
+
+// Copies the input, adding the glow on top
+@fragment
+fn fs_composite(in: VertexOutput) -> @location(0) vec4<f32> {
+    let scene = textureSample(source, source_sampler, in.uv);
+    return vec4<f32>(scene.rgb + tent(bloom_texture, in.uv) * bloom.intensity, scene.a);
+}
@@ -0,0 +1,84 @@
+// These have to match the constants of the same names in clustered.rs
+const CLUSTERS_X: u32 = 16u;
+const CLUSTERS_Y: u32 = 9u;
+const CLUSTERS_Z: u32 = 24u;
+const MAX_LIGHTS_PER_CLUSTER: u32 = 64u;
+
+struct ClusterParams {
+    view: mat4x4<f32>,
+    screen_size: vec2<f32>,
+    near: f32,
+    far: f32,
+    // The tangent of half the vertical field of view
+    tan_half_fovy: f32,
+    aspect: f32,
+    num_lights: u32,
+}
+
+struct PointLight {
+    position: vec3<f32>,
+    radius: f32,
+    color: vec3<f32>,
+    intensity: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: ClusterParams;
+
+@group(0) @binding(1)
+var<storage, read> lights: array<PointLight>;
+
+@group(0) @binding(2)
+var<storage, read_write> light_counts: array<u32>;
+
+// `MAX_LIGHTS_PER_CLUSTER` indices into `lights` for every cluster
+@group(0) @binding(3)
+var<storage, read_write> light_indices: array<u32>;
+
+// The distance from the camera at which depth slice `slice` starts. Every slice is the same factor thicker than the one
+// in front of it, so that clusters stay about as deep as they are wide.
+fn slice_depth(slice: u32) -> f32 {
+    return params.near * pow(params.far / params.near, f32(slice) / f32(CLUSTERS_Z));
+}
+
+@compute
+@workgroup_size(4, 4, 4)
+fn assign_lights(@builtin(global_invocation_id) cluster: vec3<u32>) {
+    if any(cluster >= vec3<u32>(CLUSTERS_X, CLUSTERS_Y, CLUSTERS_Z)) {
+        return;
+    }
+    let index = cluster.x + cluster.y * CLUSTERS_X + cluster.z * CLUSTERS_X * CLUSTERS_Y;
+
+    // The tile of the screen this cluster covers, in normalized device coordinates. Tiles are counted from the top of
+    // the screen, but y points up in NDC.
+    let grid = vec2<f32>(f32(CLUSTERS_X), f32(CLUSTERS_Y));
+    let tile_min = vec2<f32>(f32(cluster.x), f32(cluster.y + 1u)) / grid;
+    let tile_max = vec2<f32>(f32(cluster.x + 1u), f32(cluster.y)) / grid;
+    let ndc_min = vec2<f32>(tile_min.x * 2.0 - 1.0, 1.0 - tile_min.y * 2.0);
+    let ndc_max = vec2<f32>(tile_max.x * 2.0 - 1.0, 1.0 - tile_max.y * 2.0);
+
+    // The tile widens with the distance from the camera, so the bounding box of the cluster in view space has to hold
+    // its corners at both the near and the far end of its slice. The camera looks down -z.
+    let near = slice_depth(cluster.z);
+    let far = slice_depth(cluster.z + 1u);
+    let scale = vec2<f32>(params.tan_half_fovy * params.aspect, params.tan_half_fovy);
+    let near_min = ndc_min * scale * near;
+    let near_max = ndc_max * scale * near;
+    let far_min = ndc_min * scale * far;
+    let far_max = ndc_max * scale * far;
+    let box_min = vec3<f32>(min(min(near_min, near_max), min(far_min, far_max)), -far);
+    let box_max = vec3<f32>(max(max(near_min, near_max), max(far_min, far_max)), -near);
+
+    // A light touches the cluster if the point of the box closest to it is within its radius
+    var count = 0u;
+    for (var i = 0u; i < params.num_lights && count < MAX_LIGHTS_PER_CLUSTER; i += 1u) {
+        let light = lights[i];
+        let center = (params.view * vec4<f32>(light.position, 1.0)).xyz;
+        let offset = clamp(center, box_min, box_max) - center;
+        if dot(offset, offset) <= light.radius * light.radius {
+            light_indices[index * MAX_LIGHTS_PER_CLUSTER + count] = i;
+            count += 1u;
+        }
+    }
+    light_counts[index] = count;
+}
@@ -0,0 +1,75 @@
+// Color grading through a 3D lookup table, after tonemapping
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+struct GradingUniform {
+    // The input colors the first and last entries of the LUT are for
+    domain_min: vec3<f32>,
+    // How much of the graded color to use, from 0 (none) to 1 (all of it)
+    strength: f32,
+    domain_max: vec3<f32>,
+    // The number of entries along every axis of the LUT
+    size: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> grading: GradingUniform;
+@group(0) @binding(1)
+var input_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var lut_texture: texture_3d<f32>;
+@group(0) @binding(3)
+var lut_sampler: sampler;
+
+fn grade(color: vec3<f32>) -> vec3<f32> {
+    let t = saturate((color - grading.domain_min) / (grading.domain_max - grading.domain_min));
+    // The entries sit in the middle of the texels, so the ends of the domain are half a texel in from the edges
+    let coords = (t * (grading.size - 1.0) + 0.5) / grading.size;
+    let graded = textureSampleLevel(lut_texture, lut_sampler, coords, 0.0).rgb;
+    return mix(color, graded, grading.strength);
+}
+
+// LUTs are made for colors the way the screen shows them, so the input has to be encoded like that
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureLoad(input_texture, vec2<i32>(in.clip_position.xy), 0);
+    return vec4<f32>(grade(color.rgb), color.a);
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+// sRGB textures decode to linear colors when they are read and encode them again when they are written, so the colors
+// are encoded for the lookup and decoded again afterwards
+@fragment
+fn fs_srgb(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureLoad(input_texture, vec2<i32>(in.clip_position.xy), 0);
+    return vec4<f32>(srgb_to_linear(grade(linear_to_srgb(color.rgb))), color.a);
+}
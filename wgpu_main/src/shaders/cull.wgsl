@@ -0,0 +1,111 @@
+struct CullParams {
+    // The frustum planes as (normal, distance), with the normals pointing inwards
+    planes: array<vec4<f32>, 6>,
+    // The bounding sphere of the model in model space: center in xyz, radius in w
+    sphere: vec4<f32>,
+    camera_position: vec4<f32>,
+    // The screen sizes below which instances switch to the next coarser level of detail
+    lod_thresholds: vec4<f32>,
+    num_instances: u32,
+    num_meshes: u32,
+    num_lods: u32,
+    // The tangent of half the vertical field of view
+    tan_half_fovy: f32,
+    hysteresis: f32,
+}
+
+// Matches `DrawIndexedIndirectArgs`. Only the instance count is written by this shader.
+struct DrawArgs {
+    index_count: u32,
+    instance_count: atomic<u32>,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: CullParams;
+
+// `InstanceRaw` is 45 floats (model matrix, normal matrix, tint and previous model matrix) without any padding, which
+// doesn't match the alignment WGSL would give a struct holding a mat4x4, so the instances are bound as raw words.
+const INSTANCE_SIZE: u32 = 45u;
+
+@group(0) @binding(1)
+var<storage, read> instances: array<u32>;
+
+@group(0) @binding(2)
+var<storage, read_write> visible_instances: array<u32>;
+
+// One set of arguments per mesh of the model and level of detail, grouped by level
+@group(0) @binding(3)
+var<storage, read_write> draws: array<DrawArgs>;
+
+// The level of detail every instance used the last time it was visible
+@group(0) @binding(4)
+var<storage, read_write> lod_state: array<u32>;
+
+fn load_column(base: u32) -> vec4<f32> {
+    return vec4<f32>(
+        bitcast<f32>(instances[base]),
+        bitcast<f32>(instances[base + 1u]),
+        bitcast<f32>(instances[base + 2u]),
+        bitcast<f32>(instances[base + 3u]),
+    );
+}
+
+@compute
+@workgroup_size(64)
+fn cull_instances(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let instance = global_id.x;
+    if instance >= params.num_instances {
+        return;
+    }
+
+    let base = instance * INSTANCE_SIZE;
+    let model_matrix = mat4x4<f32>(
+        load_column(base),
+        load_column(base + 4u),
+        load_column(base + 8u),
+        load_column(base + 12u),
+    );
+
+    // Move the bounding sphere into world space. A non-uniform scale would turn it into an ellipsoid, so we grow it
+    // by the largest scale instead.
+    let center = (model_matrix * vec4<f32>(params.sphere.xyz, 1.0)).xyz;
+    let scale = max(length(model_matrix[0].xyz), max(length(model_matrix[1].xyz), length(model_matrix[2].xyz)));
+    let radius = params.sphere.w * scale;
+
+    for (var i = 0u; i < 6u; i += 1u) {
+        let plane = params.planes[i];
+        if dot(plane.xyz, center) + plane.w < -radius {
+            return;
+        }
+    }
+
+    // Pick the level of detail from the size on screen, see `LodSelector::select`
+    let distance = max(length(center - params.camera_position.xyz), radius);
+    let screen_size = radius / (distance * params.tan_half_fovy);
+    let max_lod = params.num_lods - 1u;
+    var lod = min(lod_state[instance], max_lod);
+    while lod < max_lod && screen_size < params.lod_thresholds[lod] * (1.0 - params.hysteresis) {
+        lod += 1u;
+    }
+    while lod > 0u && screen_size > params.lod_thresholds[lod - 1u] * (1.0 + params.hysteresis) {
+        lod -= 1u;
+    }
+    lod_state[instance] = lod;
+
+    // Every mesh of the model draws the same instances, so they all get the same count. The first mesh's count
+    // doubles as the allocator for the slots in the level's region of the output buffer.
+    let first_draw = lod * params.num_meshes;
+    let slot = atomicAdd(&draws[first_draw].instance_count, 1u);
+    for (var mesh = 1u; mesh < params.num_meshes; mesh += 1u) {
+        atomicAdd(&draws[first_draw + mesh].instance_count, 1u);
+    }
+
+    // Each level's region has room for every instance, which is as many as there are entries in `lod_state`
+    let out_base = (lod * arrayLength(&lod_state) + slot) * INSTANCE_SIZE;
+    for (var word = 0u; word < INSTANCE_SIZE; word += 1u) {
+        visible_instances[out_base + word] = instances[base + word];
+    }
+}
@@ -0,0 +1,80 @@
+// Lights the surfaces stored in the G-buffer, one full-screen pass per light, put together with `scene.wgsl` in front.
+// The point lights are added in the pass of the light whose bind group holds them, looking them up by cluster just like
+// the forward shaders do.
+struct VertexOutput {
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    let uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@group(0) @binding(0)
+var t_albedo: texture_2d<f32>;
+@group(0) @binding(1)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(2)
+var t_material: texture_2d<f32>;
+@group(0) @binding(3)
+var t_depth: texture_depth_2d;
+
+const CAMERA_GROUP: u32 = 1u;
+const SCENE_GROUP: u32 = 2u;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let coords = vec2<i32>(in.clip_position.xy);
+    let depth = textureLoad(t_depth, coords, 0);
+    // Nothing was drawn here, so the background shows
+    if depth >= 1.0 {
+        discard;
+    }
+
+    // Undo the projection to find where the surface is in the world. The y axis points down on the
+    // screen, but up in clip space.
+    let size = vec2<f32>(textureDimensions(t_depth));
+    let uv = in.clip_position.xy / size;
+    let clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let homogeneous_position = camera.inv_view_proj * clip_position;
+    let world_position = homogeneous_position.xyz / homogeneous_position.w;
+
+    let albedo = textureLoad(t_albedo, coords, 0).rgb;
+    let normal = normalize(textureLoad(t_normal, coords, 0).xyz);
+    let material = textureLoad(t_material, coords, 0);
+    let specular = material.r;
+    let shininess = material.g * 256.0;
+
+    // The same Blinn-Phong lighting as the forward shader, only in world space
+    let ambient_color = light.color * 0.1 * textureLoad(t_ambient_occlusion, coords, 0).r;
+
+    let light_dir = normalize(light.position - world_position * light.w);
+    let view_dir = normalize(camera.view_pos.xyz - world_position);
+
+    let diffuse_strength = max(dot(normal, light_dir), 0.0);
+    let diffuse_color = light.color * diffuse_strength;
+
+    let halfway_dir = normalize(light_dir + view_dir);
+    let specular_strength = pow(max(dot(normal, halfway_dir), 0.0), shininess);
+    let specular_color = specular * specular_strength * light.color;
+
+    var result = (ambient_color + diffuse_color + specular_color) * albedo;
+
+    let cluster = cluster_index(in.clip_position.xy, world_position);
+    let count = cluster_light_counts[cluster];
+    for (var i = 0u; i < count; i += 1u) {
+        let point = point_lights[cluster_light_indices[cluster * MAX_LIGHTS_PER_CLUSTER + i]];
+        result += point_light(point, world_position, normal, view_dir, specular, shininess) * albedo;
+    }
+    // The passes of all the lights are added together, so this is only right as long as there is a single one
+    return vec4<f32>(apply_fog(result, world_position), 1.0);
+}
@@ -0,0 +1,178 @@
+// Depth of field, blurring everything in front of and behind the focus distance by how far it is out of focus
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+// This has to match `DofUniform` in depth_of_field.rs
+struct DofUniform {
+    // In world units, ignored with autofocus
+    focus_distance: f32,
+    // The diameter of the aperture, in world units
+    aperture: f32,
+    focal_length: f32,
+    // The height of the sensor that gives the field of view of the projection with the focal length
+    sensor_height: f32,
+    znear: f32,
+    zfar: f32,
+    // The largest circle of confusion, in pixels of the full image
+    max_coc: f32,
+    autofocus: u32,
+    autofocus_speed: f32,
+    delta_time: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> dof: DofUniform;
+// The distance that is in focus, see autofocus.wgsl
+@group(0) @binding(1)
+var<storage, read> focus: f32;
+@group(0) @binding(2)
+var input_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var linear_sampler: sampler;
+
+@group(1) @binding(0)
+var depth_texture: texture_depth_2d;
+// What the pass reads of the half resolution images: the prefiltered image for the bokeh pass, the near and far fields
+// for the composite pass
+@group(1) @binding(1)
+var source_a: texture_2d<f32>;
+@group(1) @binding(2)
+var source_b: texture_2d<f32>;
+
+// The distance to the camera along the view direction, from a value in the depth buffer
+fn linearize_depth(depth: f32) -> f32 {
+    return dof.znear * dof.zfar / (dof.zfar - depth * (dof.zfar - dof.znear));
+}
+
+// The diameter of the circle a point at `distance` blurs into, in pixels of the full image. It is negative in front of
+// the focus distance, where the point belongs to the near field, and positive behind it, in the far field.
+fn circle_of_confusion(distance: f32) -> f32 {
+    // A thin lens can't focus closer than its focal length
+    let focus_distance = max(focus, dof.focal_length * 2.0);
+    let coc = dof.aperture * dof.focal_length * (distance - focus_distance)
+        / (distance * (focus_distance - dof.focal_length));
+    let height = f32(textureDimensions(depth_texture).y);
+    return clamp(coc / dof.sensor_height * height, -dof.max_coc, dof.max_coc);
+}
+
+// Shrinks the image to half its size, with the circle of confusion of every pixel in alpha, in pixels of the half size
+// image
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    // A bilinear sample in the middle of the four pixels averages them
+    let color = textureSampleLevel(input_texture, linear_sampler, in.uv, 0.0).rgb;
+
+    // The closest of the four pixels decides, so the edges of blurry things in front stay on top
+    let pixel = vec2<i32>(in.clip_position.xy) * 2;
+    let size = vec2<i32>(textureDimensions(depth_texture)) - 1;
+    var depth = 1.0;
+    for (var i = 0; i < 4; i += 1) {
+        let offset = vec2<i32>(i & 1, i >> 1u);
+        depth = min(depth, textureLoad(depth_texture, min(pixel + offset, size), 0));
+    }
+    return vec4<f32>(color, circle_of_confusion(linearize_depth(depth)) * 0.5);
+}
+
+// The samples of the bokeh lie on rings around the pixel, with eight more on every ring further out, which spreads them
+// about evenly over the disc
+const RINGS: i32 = 4;
+
+struct BokehOutput {
+    // The blurry things in front of the focus distance, with how much they cover the pixel in alpha
+    @location(0) near: vec4<f32>,
+    // The blurry things behind the focus distance, with whether any of them reached the pixel in alpha
+    @location(1) far: vec4<f32>,
+}
+
+// Gathers the bokeh of the pixels around, taking every sample whose circle of confusion is large enough to reach this
+// pixel. The near field is kept apart from the far field, since things in front blur out over what is behind them
+// even where that is in focus.
+@fragment
+fn fs_bokeh(in: VertexOutput) -> BokehOutput {
+    let size = vec2<f32>(textureDimensions(source_a));
+    let max_radius = dof.max_coc * 0.25;
+
+    var near = vec4<f32>(0.0);
+    var far = vec4<f32>(0.0);
+    // How many samples of every ring there are, and how many of them are in the near field and reach this pixel
+    var ring_samples = array<f32, RINGS>();
+    var ring_near = array<f32, RINGS>();
+    var widest_near = -1;
+    for (var ring = 0; ring < RINGS; ring += 1) {
+        let count = max(ring * 8, 1);
+        let distance = max_radius * f32(ring) / f32(RINGS - 1);
+        for (var i = 0; i < count; i += 1) {
+            // Every other ring is turned by half a step, so the samples don't line up into spokes
+            let angle = 6.2831853 * (f32(i) + 0.5 * f32(ring & 1)) / f32(count);
+            let offset = vec2<f32>(cos(angle), sin(angle)) * distance;
+            let sample = textureSampleLevel(source_a, linear_sampler, in.uv + offset / size, 0.0);
+            // The radius is half the diameter, and a sample reaches a bit further than its radius to soften the edge
+            let radius = abs(sample.a) * 0.5;
+            let reach = saturate(radius - distance + 1.0);
+
+            ring_samples[ring] += 1.0;
+            if sample.a < 0.0 {
+                near += vec4<f32>(sample.rgb, 1.0) * reach;
+                ring_near[ring] += reach;
+                if reach > 0.0 {
+                    widest_near = max(widest_near, i32(ceil(radius / max_radius * f32(RINGS - 1))));
+                }
+            } else {
+                far += vec4<f32>(sample.rgb, 1.0) * reach;
+            }
+        }
+    }
+
+    var out: BokehOutput;
+    if far.a > 0.0 {
+        out.far = vec4<f32>(far.rgb / far.a, 1.0);
+    }
+    if near.a > 0.0 {
+        // The near field covers the pixel as much as the samples within the widest blur around reach it, which fades
+        // it out past the edges of what is in front
+        var reached = 0.0;
+        var total = 0.0;
+        for (var ring = 0; ring <= min(widest_near, RINGS - 1); ring += 1) {
+            reached += ring_near[ring];
+            total += ring_samples[ring];
+        }
+        out.near = vec4<f32>(near.rgb / near.a, saturate(2.0 * reached / total));
+    }
+    return out;
+}
+
+// Puts the blurred fields back together with the sharp image, at its full size
+@fragment
+fn fs_composite(in: VertexOutput) -> @location(0) vec4<f32> {
+    let sharp = textureLoad(input_texture, vec2<i32>(in.clip_position.xy), 0);
+    let depth = textureLoad(depth_texture, vec2<i32>(in.clip_position.xy), 0);
+    let coc = circle_of_confusion(linearize_depth(depth));
+
+    var color = sharp.rgb;
+    // Behind the focus distance, the blur takes over from the sharp image as the circle of confusion grows past a pixel
+    let far = textureSampleLevel(source_b, linear_sampler, in.uv, 0.0);
+    if far.a > 0.0 {
+        color = mix(color, far.rgb, smoothstep(1.0, 4.0, coc));
+    }
+    let near = textureSampleLevel(source_a, linear_sampler, in.uv, 0.0);
+    color = mix(color, near.rgb, near.a);
+    return vec4<f32>(color, sharp.a);
+}
@@ -0,0 +1,175 @@
+// FXAA 3.11, following Timothy Lottes' reference implementation with the default quality preset 12
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+struct FxaaUniform {
+    // How much single pixel features get blended away, from 0 (not at all) to 1
+    subpix: f32,
+    // How much contrast relative to the brightest neighbour a pixel needs to count as being on an edge
+    edge_threshold: f32,
+    // Darker pixels than this are left alone
+    edge_threshold_min: f32,
+}
+
+@group(0) @binding(0)
+var<uniform> fxaa: FxaaUniform;
+@group(0) @binding(1)
+var input_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var input_sampler: sampler;
+
+// How far each step of the search along an edge goes, in pixels. The last one is a guess beyond the end of the search.
+const NUM_STEPS: i32 = 5;
+const STEPS = array<f32, 5>(1.0, 1.5, 2.0, 4.0, 12.0);
+
+// FXAA compares perceived brightness, and the image is linear once read, so this takes the square root as a cheap
+// approximation of gamma
+fn luma(color: vec3<f32>) -> f32 {
+    return sqrt(dot(color, vec3<f32>(0.2126, 0.7152, 0.0722)));
+}
+
+fn luma_at(uv: vec2<f32>) -> f32 {
+    return luma(textureSampleLevel(input_texture, input_sampler, uv, 0.0).rgb);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(input_texture));
+    var pos = in.uv;
+    let color = textureSampleLevel(input_texture, input_sampler, pos, 0.0);
+    let luma_m = luma(color.rgb);
+    var luma_s = luma_at(pos + vec2<f32>(0.0, texel.y));
+    let luma_e = luma_at(pos + vec2<f32>(texel.x, 0.0));
+    var luma_n = luma_at(pos - vec2<f32>(0.0, texel.y));
+    let luma_w = luma_at(pos - vec2<f32>(texel.x, 0.0));
+
+    // Leave pixels without enough contrast to be on an edge alone
+    let range_max = max(max(luma_n, luma_w), max(luma_e, max(luma_s, luma_m)));
+    let range_min = min(min(luma_n, luma_w), min(luma_e, min(luma_s, luma_m)));
+    let range = range_max - range_min;
+    if range < max(fxaa.edge_threshold_min, range_max * fxaa.edge_threshold) {
+        return color;
+    }
+
+    let luma_nw = luma_at(pos + vec2<f32>(-texel.x, -texel.y));
+    let luma_se = luma_at(pos + vec2<f32>(texel.x, texel.y));
+    let luma_ne = luma_at(pos + vec2<f32>(texel.x, -texel.y));
+    let luma_sw = luma_at(pos + vec2<f32>(-texel.x, texel.y));
+
+    // Whether the edge runs horizontally or vertically
+    let luma_ns = luma_n + luma_s;
+    let luma_we = luma_w + luma_e;
+    let luma_nese = luma_ne + luma_se;
+    let luma_nwne = luma_nw + luma_ne;
+    let luma_nwsw = luma_nw + luma_sw;
+    let luma_swse = luma_sw + luma_se;
+    let edge_horz = abs(-2.0 * luma_w + luma_nwsw) + abs(-2.0 * luma_m + luma_ns) * 2.0
+        + abs(-2.0 * luma_e + luma_nese);
+    let edge_vert = abs(-2.0 * luma_s + luma_swse) + abs(-2.0 * luma_m + luma_we) * 2.0
+        + abs(-2.0 * luma_n + luma_nwne);
+    let horz_span = edge_horz >= edge_vert;
+
+    // How much the pixel stands out from its neighbourhood, for blending away features smaller than a pixel
+    let subpix_a = (luma_ns + luma_we) * 2.0 + luma_nwsw + luma_nese;
+    let subpix_c = saturate(abs(subpix_a / 12.0 - luma_m) / range);
+
+    // Pick the side of the pixel the edge is on
+    var length_sign = texel.x;
+    if horz_span {
+        length_sign = texel.y;
+    } else {
+        luma_n = luma_w;
+        luma_s = luma_e;
+    }
+    let gradient_n = luma_n - luma_m;
+    let gradient_s = luma_s - luma_m;
+    let pair_n = abs(gradient_n) >= abs(gradient_s);
+    let gradient_scaled = max(abs(gradient_n), abs(gradient_s)) / 4.0;
+    if pair_n {
+        length_sign = -length_sign;
+    }
+    let luma_nn = select(luma_s, luma_n, pair_n) + luma_m;
+    let luma_mm = luma_m - luma_nn * 0.5;
+
+    // Step half a pixel onto the edge, then search along it in both directions until the luma changes enough to
+    // mark its ends
+    var pos_b = pos;
+    var off_np = vec2<f32>(0.0, texel.y);
+    if horz_span {
+        pos_b.y += length_sign * 0.5;
+        off_np = vec2<f32>(texel.x, 0.0);
+    } else {
+        pos_b.x += length_sign * 0.5;
+    }
+    var steps = STEPS;
+    var pos_n = pos_b - off_np * steps[0];
+    var pos_p = pos_b + off_np * steps[0];
+    var luma_end_n = luma_at(pos_n) - luma_nn * 0.5;
+    var luma_end_p = luma_at(pos_p) - luma_nn * 0.5;
+    var done_n = abs(luma_end_n) >= gradient_scaled;
+    var done_p = abs(luma_end_p) >= gradient_scaled;
+    if !done_n {
+        pos_n -= off_np * steps[1];
+    }
+    if !done_p {
+        pos_p += off_np * steps[1];
+    }
+    for (var i = 2; i < NUM_STEPS && !(done_n && done_p); i += 1) {
+        if !done_n {
+            luma_end_n = luma_at(pos_n) - luma_nn * 0.5;
+            done_n = abs(luma_end_n) >= gradient_scaled;
+        }
+        if !done_p {
+            luma_end_p = luma_at(pos_p) - luma_nn * 0.5;
+            done_p = abs(luma_end_p) >= gradient_scaled;
+        }
+        if !done_n {
+            pos_n -= off_np * steps[i];
+        }
+        if !done_p {
+            pos_p += off_np * steps[i];
+        }
+    }
+
+    var dst_n = pos.y - pos_n.y;
+    var dst_p = pos_p.y - pos.y;
+    if horz_span {
+        dst_n = pos.x - pos_n.x;
+        dst_p = pos_p.x - pos.x;
+    }
+
+    // Only blend across the edge if the luma at the nearer end moves the way the pixel does
+    let direction_n = dst_n < dst_p;
+    let end_luma = select(luma_end_p, luma_end_n, direction_n);
+    let good_span = (end_luma < 0.0) != (luma_mm < 0.0);
+    let pixel_offset = 0.5 - min(dst_n, dst_p) / (dst_n + dst_p);
+
+    let subpix_f = (3.0 - 2.0 * subpix_c) * subpix_c * subpix_c;
+    let subpix_h = subpix_f * subpix_f * fxaa.subpix;
+
+    let offset = max(select(0.0, pixel_offset, good_span), subpix_h);
+    if horz_span {
+        pos.y += offset * length_sign;
+    } else {
+        pos.x += offset * length_sign;
+    }
+    return vec4<f32>(textureSampleLevel(input_texture, input_sampler, pos, 0.0).rgb, color.a);
+}
@@ -23,6 +23,135 @@ fn aces_tone_map(hdr: vec3<f32>) -> vec3<f32> {
     return clamp(m2 * (a / b), vec3(0.0), vec3(1.0));
 }
 
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Reinhard's operator maps luminance L to L / (1 + L), which only reaches white at infinity. The extended version
+// reaches it at the white point instead. Working on the luminance rather than each channel keeps the hue.
+// Based on https://www.cs.utah.edu/docs/techreports/2002/pdf/UUCS-02-001.pdf
+fn reinhard_extended_tone_map(hdr: vec3<f32>, white_point: f32) -> vec3<f32> {
+    let l_in = luminance(hdr);
+    let l_out = l_in * (1.0 + l_in / (white_point * white_point)) / (1.0 + l_in);
+    return clamp(hdr * (l_out / max(l_in, 0.00001)), vec3(0.0), vec3(1.0));
+}
+
+fn uncharted2_curve(x: vec3<f32>) -> vec3<f32> {
+    let a = 0.15; // Shoulder strength
+    let b = 0.50; // Linear strength
+    let c = 0.10; // Linear angle
+    let d = 0.20; // Toe strength
+    let e = 0.02; // Toe numerator
+    let f = 0.30; // Toe denominator
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+// John Hable's filmic curve, scaled so that the white point ends up white
+// Based on http://filmicworlds.com/blog/filmic-tonemapping-operators/
+fn uncharted2_tone_map(hdr: vec3<f32>, white_point: f32) -> vec3<f32> {
+    let white_scale = 1.0 / uncharted2_curve(vec3(white_point));
+    return clamp(uncharted2_curve(hdr) * white_scale, vec3(0.0), vec3(1.0));
+}
+
+// AgX works in log space after a slight inset of the primaries, so that very bright colors move towards white
+// instead of clipping to a more saturated hue. This is the polynomial approximation of the default look.
+// Based on https://iolite-engine.com/blog_posts/minimal_agx_implementation
+fn agx_tone_map(hdr: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+    );
+    let outset = mat3x3(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    var x = log2(max(inset * hdr, vec3(1e-10)));
+    x = (clamp(x, vec3(min_ev), vec3(max_ev)) - min_ev) / (max_ev - min_ev);
+    // The sigmoid contrast curve
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    x = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+    // The curve produces display-encoded values, but the surface expects linear ones
+    let display = clamp(outset * x, vec3(0.0), vec3(1.0));
+    return pow(display, vec3(2.2));
+}
+
+// Leaves colors alone up to a point, then compresses the highlights and desaturates them towards white.
+// Based on https://github.com/KhronosGroup/ToneMapping/tree/main/PBR_Neutral
+fn khronos_pbr_neutral_tone_map(hdr: vec3<f32>) -> vec3<f32> {
+    let start_compression = 0.8 - 0.04;
+    let desaturation = 0.15;
+
+    var color = hdr;
+    let x = min(color.r, min(color.g, color.b));
+    let offset = select(0.04, x - 6.25 * x * x, x < 0.08);
+    color -= offset;
+
+    let peak = max(color.r, max(color.g, color.b));
+    if peak < start_compression {
+        return color;
+    }
+
+    let d = 1.0 - start_compression;
+    let new_peak = 1.0 - d * d / (peak + d - start_compression);
+    color *= new_peak / peak;
+
+    let g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    return mix(color, vec3(new_peak), g);
+}
+
+// This has to match `TonemapOperator` in hdr.rs
+const OPERATOR_ACES_FITTED: u32 = 0u;
+const OPERATOR_REINHARD_EXTENDED: u32 = 1u;
+const OPERATOR_UNCHARTED2: u32 = 2u;
+const OPERATOR_AGX: u32 = 3u;
+const OPERATOR_KHRONOS_PBR_NEUTRAL: u32 = 4u;
+
+struct TonemappingUniform {
+    // The factor the colors are multiplied with, 2^EV
+    exposure: f32,
+    white_point: f32,
+    tonemapper: u32,
+    // Whether to expose for the adapted luminance, with `exposure` shifting the result
+    auto_exposure: u32,
+}
+
+// Auto exposure exposes the scene so that its average luminance comes out as middle gray
+const MIDDLE_GRAY: f32 = 0.18;
+
+fn tone_map(hdr: vec3<f32>) -> vec3<f32> {
+    var exposure = tonemapping.exposure;
+    if tonemapping.auto_exposure != 0u {
+        exposure *= MIDDLE_GRAY / exp2(adapted_log_luminance);
+    }
+    let exposed = hdr * exposure;
+    switch tonemapping.tonemapper {
+        case OPERATOR_ACES_FITTED: {
+            return aces_tone_map(exposed);
+        }
+        case OPERATOR_REINHARD_EXTENDED: {
+            return reinhard_extended_tone_map(exposed, tonemapping.white_point);
+        }
+        case OPERATOR_UNCHARTED2: {
+            return uncharted2_tone_map(exposed, tonemapping.white_point);
+        }
+        case OPERATOR_AGX: {
+            return agx_tone_map(exposed);
+        }
+        case OPERATOR_KHRONOS_PBR_NEUTRAL: {
+            return khronos_pbr_neutral_tone_map(exposed);
+        }
+        default: {
+            return clamp(exposed, vec3(0.0), vec3(1.0));
+        }
+    }
+}
+
 struct VertexOutput {
      @location(0) uv: vec2<f32>,
      @builtin(position) clip_position: vec4<f32>,
@@ -53,9 +182,18 @@ var hdr_image: texture_2d<f32>;
 @binding(1)
 var hdr_sampler: sampler;
 
+@group(0)
+@binding(2)
+var<uniform> tonemapping: TonemappingUniform;
+
+// Written by the auto exposure passes in auto_exposure.wgsl
+@group(0)
+@binding(3)
+var<storage, read> adapted_log_luminance: f32;
+
 @fragment
 fn fs_main(vs: VertexOutput) -> @location(0) vec4<f32> {
     let hdr = textureSample(hdr_image, hdr_sampler, vs.uv);
-    let sdr = aces_tone_map(hdr.rgb);
+    let sdr = tone_map(hdr.rgb);
     return vec4(sdr, hdr.a);
 }
@@ -1,18 +1,7 @@
-struct Camera {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-}
-
-@group(0) @binding(0)
-var<uniform> camera: Camera;
+// The marker drawn where the light is, put together with `scene.wgsl` in front
 
-struct Light {
-    // Swapping the order resulted in light changing the color of the cube instead of position.
-    position: vec3<f32>,
-    color: vec3<f32>,
-}
-@group(1) @binding(0)
-var<uniform> light: Light;
+const CAMERA_GROUP: u32 = 0u;
+const SCENE_GROUP: u32 = 1u;
 
 struct VertexInput {
     @location(0) pos: vec3<f32>,
@@ -21,13 +10,15 @@ struct VertexInput {
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec3<f32>,
+    @location(1) world_position: vec3<f32>,
 };
 
 @vertex
 fn vs_main(model: VertexInput) -> VertexOutput {
     let scale = 0.25;
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * vec4<f32>(model.pos * scale + light.position, 1.0);
+    out.world_position = model.pos * scale + light.position;
+    out.clip_position = camera.view_proj * vec4<f32>(out.world_position, 1.0);
     out.color = light.color;
 
     return out;
@@ -35,5 +26,5 @@ fn vs_main(model: VertexInput) -> VertexOutput {
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(in.color, 1.0);
+    return vec4<f32>(apply_fog(in.color, in.world_position), 1.0);
 }
\ No newline at end of file
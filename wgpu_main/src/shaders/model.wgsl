@@ -0,0 +1,306 @@
+// The fragment stage and the vertex layouts every model shader shares, put together with `scene.wgsl` in front and
+// the vertex stage of the kind of mesh behind. See `shader_instances.wgsl` and `shader_skinned.wgsl`.
+
+const CAMERA_GROUP: u32 = 1u;
+const SCENE_GROUP: u32 = 2u;
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+
+    @location(14) tint: vec4<f32>,
+};
+
+// The instance data as the velocity pass reads it, see `InstanceRaw::velocity_desc`
+struct VelocityInstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+
+    @location(9) previous_model_matrix_0: vec4<f32>,
+    @location(10) previous_model_matrix_1: vec4<f32>,
+    @location(11) previous_model_matrix_2: vec4<f32>,
+    @location(14) previous_model_matrix_3: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) @invariant clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+//    @location(1) world_normal: vec3<f32>,
+//    @location(2) world_position: vec3<f32>,
+    @location(1) tangent_position: vec3<f32>,
+    @location(2) tangent_light_position: vec3<f32>,
+    @location(3) tangent_view_position: vec3<f32>,
+    @location(4) tint: vec4<f32>,
+    // The tangent frame in world space, for writing world space normals into the G-buffer
+    @location(5) world_tangent: vec3<f32>,
+    @location(6) world_bitangent: vec3<f32>,
+    @location(7) world_normal: vec3<f32>,
+    // For finding the cluster of the fragment and lighting it with the point lights in there
+    @location(8) world_position: vec3<f32>,
+};
+
+struct VelocityOutput {
+    // The velocity pass only draws where the depth is equal to what the other passes left there, so the position has
+    // to come out exactly the same as in `vs_main`
+    @builtin(position) @invariant clip_position: vec4<f32>,
+    @location(0) current_position: vec4<f32>,
+    @location(1) previous_position: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var s_normal: sampler;
+
+struct MaterialUniform {
+    opacity: f32,
+    // Zero unless the material is alpha-masked
+    alpha_cutoff: f32,
+    specular: f32,
+    shininess: f32,
+}
+
+@group(0) @binding(4)
+var<uniform> material: MaterialUniform;
+
+struct Surface {
+    color: vec4<f32>,
+    // In tangent space
+    normal: vec3<f32>,
+}
+
+// Samples the material at the fragment, throwing the fragment away if it is masked out
+fn surface(in: VertexOutput, front_facing: bool) -> Surface {
+    var object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords) * in.tint;
+    object_color.a *= material.opacity;
+    if material.alpha_cutoff > 0.0 {
+        // Sharpen the edge around the cutoff to about a pixel wide, so that masks stay crisp when
+        // the texture is magnified
+        object_color.a = (object_color.a - material.alpha_cutoff) / max(fwidth(object_color.a), 0.0001) + 0.5;
+        if object_color.a < 0.5 {
+            discard;
+        }
+        // Whatever is left is fully there
+        object_color.a = 1.0;
+    }
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
+    var tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    // The back of a double-sided surface faces the other way
+    if !front_facing {
+        tangent_normal.z = -tangent_normal.z;
+    }
+    return Surface(object_color, tangent_normal);
+}
+
+fn world_normal(in: VertexOutput, tangent_normal: vec3<f32>) -> vec3<f32> {
+    let tangent_to_world = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    return normalize(tangent_to_world * tangent_normal);
+}
+
+// The ambient occlusion was worked out from the depth prepass, which only has the opaque meshes in it, so it doesn't
+// apply to anything else
+fn ambient_occlusion(in: VertexOutput, object_color: vec4<f32>) -> f32 {
+    if material.alpha_cutoff > 0.0 || object_color.a < 1.0 {
+        return 1.0;
+    }
+    return textureLoad(t_ambient_occlusion, vec2<i32>(in.clip_position.xy), 0).r;
+}
+
+// What the debug view shows for the fragment, for all of them but the lighting and the overdraw
+fn debug_color(in: VertexOutput, object: Surface) -> vec3<f32> {
+    switch debug.mode {
+        case DEBUG_WORLD_NORMALS: {
+            return world_normal(in, object.normal) * 0.5 + 0.5;
+        }
+        case DEBUG_NORMAL_MAP: {
+            return object.normal * 0.5 + 0.5;
+        }
+        case DEBUG_TANGENTS: {
+            return normalize(in.world_tangent) * 0.5 + 0.5;
+        }
+        case DEBUG_BITANGENTS: {
+            return normalize(in.world_bitangent) * 0.5 + 0.5;
+        }
+        case DEBUG_UVS: {
+            // Cells of an eighth of the texture, which stay square unless the texture is stretched
+            let cells = floor(in.tex_coords * 8.0);
+            let checker = fract((cells.x + cells.y) * 0.5) * 2.0;
+            return vec3<f32>(fract(in.tex_coords), 0.0) * (0.5 + 0.5 * checker);
+        }
+        case DEBUG_ALBEDO: {
+            return object.color.rgb;
+        }
+        case DEBUG_DEPTH: {
+            let depth = -(camera.view * vec4<f32>(in.world_position, 1.0)).z;
+            return vec3<f32>(saturate((depth - clusters.near) / (clusters.far - clusters.near)));
+        }
+        case DEBUG_MIP_LEVEL: {
+            // The hardware picks the level by how many texels a pixel covers
+            let texels = in.tex_coords * vec2<f32>(textureDimensions(t_diffuse));
+            let dx = dpdx(texels);
+            let dy = dpdy(texels);
+            let level = 0.5 * log2(max(dot(dx, dx), dot(dy, dy)));
+            let t = saturate(level / 8.0);
+            let cold = mix(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), saturate(t * 2.0));
+            return mix(cold, vec3<f32>(1.0, 0.0, 0.0), saturate(t * 2.0 - 1.0));
+        }
+        case DEBUG_LIGHT_COUNT: {
+            return light_count_color(cluster_light_counts[cluster_index(in.clip_position.xy, in.world_position)]);
+        }
+        default: {
+            return vec3<f32>(1.0, 0.0, 1.0);
+        }
+    }
+}
+
+fn shade(in: VertexOutput, front_facing: bool) -> vec4<f32> {
+    let object = surface(in, front_facing);
+    let object_color = object.color;
+    let tangent_normal = object.normal;
+    if debug.mode != DEBUG_OFF && debug.mode != DEBUG_LIGHTING {
+        return vec4<f32>(debug_color(in, object), object_color.a);
+    }
+
+    // We don't need (or want) much ambient light, so 0.1 is fine
+    let ambient_strength = 0.1;
+    let ambient_color = light.color * ambient_strength * ambient_occlusion(in, object_color);
+
+    // Create the lighting vectors
+    // A directional light has no position, only the direction to it
+    let light_dir = normalize(in.tangent_light_position - in.tangent_position * light.w);
+    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
+
+    // Diffuse lighting
+    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
+    let diffuse_color = light.color * diffuse_strength;
+
+    // Specular lighting
+    // The Blinn part of the Blinn-Phong comes from the realization that if you add the `view_dir` and `light_dir` vectors
+    // together, and normalize the result, and use the dot product of that with the normal, you get the roughly the same
+    // results without the issues that using `reflect_dir` had.
+//    let reflect_dir = reflect(-light_dir, in.world_normal);
+    let halfway_dir = normalize(light_dir + view_dir);
+//    let specular_strength = pow(max(dot(view_dir, reflect_dir), 0.0), material.shininess);
+    let specular_strength = pow(max(dot(tangent_normal, halfway_dir), 0.0), material.shininess);
+    let specular_color = material.specular * specular_strength * light.color;
+
+
+    // Combine the lighting
+    var lighting = ambient_color + diffuse_color + specular_color;
+
+    // Add the point lights, but only the ones assigned to the fragment's cluster
+    let cluster = cluster_index(in.clip_position.xy, in.world_position);
+    let count = cluster_light_counts[cluster];
+    let normal = world_normal(in, tangent_normal);
+    let world_view_dir = normalize(camera.view_pos.xyz - in.world_position);
+    for (var i = 0u; i < count; i += 1u) {
+        let point = point_lights[cluster_light_indices[cluster * MAX_LIGHTS_PER_CLUSTER + i]];
+        let point_color = point_light(
+            point,
+            in.world_position,
+            normal,
+            world_view_dir,
+            material.specular,
+            material.shininess,
+        );
+        lighting += point_color;
+    }
+    if debug.mode == DEBUG_LIGHTING {
+        return vec4<f32>(lighting, object_color.a);
+    }
+    let result = lighting * object_color.xyz;
+
+    // Blended surfaces are fogged the same way, since whatever they are blended over was fogged already
+    return vec4<f32>(apply_fog(result, in.world_position), object_color.a);
+}
+
+@fragment
+fn fs_main(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> @location(0) vec4<f32> {
+    return shade(in, front_facing);
+}
+
+// Every surface drawn over a pixel adds a bit of heat, with additive blending and no depth test, so that it goes from
+// dark red through orange to white the more surfaces cover it. The vertex outputs go unused, but downlevel backends
+// want every one of them to be consumed.
+@fragment
+fn fs_overdraw(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(0.1, 0.04, 0.01, 1.0);
+}
+
+struct OitOutput {
+    @location(0) accum: vec4<f32>,
+    @location(1) revealage: f32,
+}
+
+// Weighted Blended Order-Independent Transparency
+// Instead of blending each surface over the ones behind it, which depends on the order they are drawn in, every
+// surface adds its premultiplied color to a running sum, weighted so that closer and more opaque surfaces count for
+// more. The revealage target multiplies together how much of the background each surface lets through. Both
+// operations give the same result in any order, and the composite pass turns them into a weighted average.
+// Based on https://jcgt.org/published/0002/02/09/
+@fragment
+fn fs_oit(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> OitOutput {
+    let color = shade(in, front_facing);
+    // The tangent matrix only rotates, so distances in tangent space are the same as in world space
+    let distance = length(in.tangent_view_position - in.tangent_position);
+    let weight = color.a * clamp(
+        10.0 / (0.00001 + pow(distance / 5.0, 2.0) + pow(distance / 200.0, 6.0)),
+        0.01,
+        3000.0,
+    );
+
+    var out: OitOutput;
+    out.accum = vec4<f32>(color.rgb * color.a, color.a) * weight;
+    out.revealage = color.a;
+    return out;
+}
+
+struct GBufferOutput {
+    @location(0) albedo: vec4<f32>,
+    @location(1) normal: vec4<f32>,
+    @location(2) material: vec4<f32>,
+}
+
+// Deferred Shading
+// Instead of lighting the surface right away, the geometry pass only stores what the lighting needs to know about it in
+// the G-buffer: its color, its normal in world space and the material's specular settings. The lighting pass then
+// lights every pixel on the screen once, no matter how many surfaces were drawn on top of each other there.
+@fragment
+fn fs_gbuffer(in: VertexOutput, @builtin(front_facing) front_facing: bool) -> GBufferOutput {
+    let object = surface(in, front_facing);
+
+    var out: GBufferOutput;
+    out.albedo = vec4<f32>(object.color.rgb, 1.0);
+    out.normal = vec4<f32>(world_normal(in, object.normal), 0.0);
+    // Shininess rarely goes above 256, which lets it fit into a normalized channel
+    out.material = vec4<f32>(material.specular, material.shininess / 256.0, 0.0, 0.0);
+    return out;
+}
+
+// Motion Vectors
+// Temporal anti-aliasing looks up where every pixel was in the last frame, so the velocity pass writes how far the
+// surface in each pixel moved on the screen since then. Where it is now and where it was are both worked out with the
+// projection without the jitter, or the velocity would jitter along with it.
+@fragment
+fn fs_velocity(in: VelocityOutput) -> @location(0) vec2<f32> {
+    let current = in.current_position.xy / in.current_position.w;
+    let previous = in.previous_position.xy / in.previous_position.w;
+    // From normalized device coordinates to texture coordinates, in which y points down
+    return (current - previous) * vec2<f32>(0.5, -0.5);
+}
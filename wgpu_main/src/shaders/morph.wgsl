@@ -0,0 +1,91 @@
+struct MorphParams {
+    num_vertices: u32,
+    num_targets: u32,
+    // The size of a vertex in 4 byte words
+    vertex_stride: u32,
+    num_instances: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: MorphParams;
+
+// The vertex buffers are bound as raw words, so the same shader works for `ModelVertex` and `SkinnedVertex`. Both
+// start with position (words 0-2), tex_coords (3-4), normal (5-7), tangent (8-10) and bitangent (11-13). Any words
+// after those are copied untouched. We use u32 rather than f32 so the copy can't alter bit patterns, such as the
+// packed joint indices of a skinned vertex.
+@group(0) @binding(1)
+var<storage, read> base_vertices: array<u32>;
+
+// 9 floats per vertex per target: position, normal and tangent displacement.
+@group(0) @binding(2)
+var<storage, read> deltas: array<f32>;
+
+// `num_targets` weights per instance
+@group(0) @binding(3)
+var<storage, read> weights: array<f32>;
+
+// `num_vertices` vertices per instance
+@group(0) @binding(4)
+var<storage, read_write> deformed_vertices: array<u32>;
+
+fn load_vec3(base: u32) -> vec3<f32> {
+    return vec3<f32>(
+        bitcast<f32>(base_vertices[base]),
+        bitcast<f32>(base_vertices[base + 1u]),
+        bitcast<f32>(base_vertices[base + 2u]),
+    );
+}
+
+fn store_vec3(base: u32, v: vec3<f32>) {
+    deformed_vertices[base] = bitcast<u32>(v.x);
+    deformed_vertices[base + 1u] = bitcast<u32>(v.y);
+    deformed_vertices[base + 2u] = bitcast<u32>(v.z);
+}
+
+fn load_delta(base: u32) -> vec3<f32> {
+    return vec3<f32>(deltas[base], deltas[base + 1u], deltas[base + 2u]);
+}
+
+// One invocation per vertex along x, one row of workgroups per instance along y.
+@compute
+@workgroup_size(64, 1, 1)
+fn apply_morph_targets(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let vertex = global_id.x;
+    let instance = global_id.y;
+    if vertex >= params.num_vertices || instance >= params.num_instances {
+        return;
+    }
+
+    let src = vertex * params.vertex_stride;
+    let dst = (instance * params.num_vertices + vertex) * params.vertex_stride;
+
+    var position = load_vec3(src);
+    var normal = load_vec3(src + 5u);
+    var tangent = load_vec3(src + 8u);
+    let bitangent = load_vec3(src + 11u);
+
+    for (var t = 0u; t < params.num_targets; t++) {
+        let weight = weights[instance * params.num_targets + t];
+        if weight == 0.0 {
+            continue;
+        }
+        let delta = (t * params.num_vertices + vertex) * 9u;
+        position += weight * load_delta(delta);
+        normal += weight * load_delta(delta + 3u);
+        tangent += weight * load_delta(delta + 6u);
+    }
+
+    // There are no bitangent deltas, so we rebuild the bitangent from the morphed normal and tangent, keeping the
+    // handedness of the original tangent frame.
+    let handedness = select(-1.0, 1.0, dot(cross(load_vec3(src + 5u), load_vec3(src + 8u)), bitangent) >= 0.0);
+    let morphed_bitangent = cross(normal, tangent) * handedness;
+
+    // Copy the whole vertex first so attributes we don't morph (tex_coords, joints, weights) come along.
+    for (var i = 0u; i < params.vertex_stride; i++) {
+        deformed_vertices[dst + i] = base_vertices[src + i];
+    }
+    store_vec3(dst, position);
+    store_vec3(dst + 5u, normal);
+    store_vec3(dst + 8u, tangent);
+    store_vec3(dst + 11u, morphed_bitangent);
+}
@@ -0,0 +1,43 @@
+// Composites the weighted blended transparent surfaces over the opaque scene.
+struct VertexOutput {
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    let uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@group(0)
+@binding(0)
+var accum_texture: texture_2d<f32>;
+
+@group(0)
+@binding(1)
+var revealage_texture: texture_2d<f32>;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Both targets are the size of the screen, so we can read them pixel for pixel
+    let coords = vec2<i32>(in.clip_position.xy);
+    let revealage = textureLoad(revealage_texture, coords, 0).r;
+    // Nothing transparent covers this pixel
+    if revealage >= 1.0 {
+        discard;
+    }
+
+    let accum = textureLoad(accum_texture, coords, 0);
+    // The weighted average of the colors of all the surfaces, which is blended over the scene by however
+    // much of it they cover together
+    let average_color = accum.rgb / max(accum.a, 0.00001);
+    return vec4<f32>(average_color, 1.0 - revealage);
+}
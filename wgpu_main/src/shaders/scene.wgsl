@@ -0,0 +1,166 @@
+// What every lit shader reads about the scene: the camera, the light, the point lights of the clusters, the ambient
+// occlusion, the fog, the sky and the debug view, along with the functions that light and fog a surface with them.
+// Shaders that include this define `CAMERA_GROUP` and `SCENE_GROUP`, the bind groups they put the camera and the rest
+// at.
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    unjittered_view_proj: mat4x4<f32>,
+    previous_view_proj: mat4x4<f32>,
+};
+
+@group(CAMERA_GROUP) @binding(0)
+var<uniform> camera: CameraUniform;
+
+// With w at 0, this is a directional light shining from the direction `position` points in, see light.rs
+struct Light {
+    position: vec3<f32>,
+    w: f32,
+    color: vec3<f32>,
+}
+
+@group(SCENE_GROUP) @binding(0)
+var<uniform> light: Light;
+
+// Clustered lighting, see `cluster.wgsl` for how the lights are assigned to the clusters. These have to match the
+// constants of the same names in clustered.rs.
+const CLUSTERS_X: u32 = 16u;
+const CLUSTERS_Y: u32 = 9u;
+const CLUSTERS_Z: u32 = 24u;
+const MAX_LIGHTS_PER_CLUSTER: u32 = 64u;
+
+struct ClusterParams {
+    view: mat4x4<f32>,
+    screen_size: vec2<f32>,
+    near: f32,
+    far: f32,
+    tan_half_fovy: f32,
+    aspect: f32,
+    num_lights: u32,
+}
+
+struct PointLight {
+    position: vec3<f32>,
+    radius: f32,
+    color: vec3<f32>,
+    intensity: f32,
+}
+
+@group(SCENE_GROUP) @binding(1)
+var<uniform> clusters: ClusterParams;
+@group(SCENE_GROUP) @binding(2)
+var<storage, read> point_lights: array<PointLight>;
+@group(SCENE_GROUP) @binding(3)
+var<storage, read> cluster_light_counts: array<u32>;
+@group(SCENE_GROUP) @binding(4)
+var<storage, read> cluster_light_indices: array<u32>;
+// How much of the ambient light reaches every pixel, from the SSAO pass
+@group(SCENE_GROUP) @binding(5)
+var t_ambient_occlusion: texture_2d<f32>;
+
+// Distance and height fog, see fog.rs
+struct Fog {
+    color: vec3<f32>,
+    density: f32,
+    start_distance: f32,
+    height: f32,
+    height_density: f32,
+    height_falloff: f32,
+    // 1 to take the color from the sky along the view ray instead
+    environment: f32,
+}
+
+@group(SCENE_GROUP) @binding(6)
+var<uniform> fog: Fog;
+@group(SCENE_GROUP) @binding(7)
+var t_environment: texture_cube<f32>;
+@group(SCENE_GROUP) @binding(8)
+var s_environment: sampler;
+
+// The debug view to draw instead of the lit color, see debug_view.rs. These have to match `DebugMode`.
+const DEBUG_OFF: u32 = 0u;
+const DEBUG_WORLD_NORMALS: u32 = 1u;
+const DEBUG_NORMAL_MAP: u32 = 2u;
+const DEBUG_TANGENTS: u32 = 3u;
+const DEBUG_BITANGENTS: u32 = 4u;
+const DEBUG_UVS: u32 = 5u;
+const DEBUG_ALBEDO: u32 = 6u;
+const DEBUG_LIGHTING: u32 = 7u;
+const DEBUG_DEPTH: u32 = 8u;
+const DEBUG_MIP_LEVEL: u32 = 9u;
+const DEBUG_LIGHT_COUNT: u32 = 11u;
+
+struct DebugUniform {
+    mode: u32,
+}
+
+@group(SCENE_GROUP) @binding(9)
+var<uniform> debug: DebugUniform;
+
+// Blends `color` towards the fog color by how much of the light from `world_position` is lost on the way to the camera
+fn apply_fog(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+    let to_surface = world_position - camera.view_pos.xyz;
+    let distance = length(to_surface);
+    // Only the part of the view ray past the start distance goes through the fog
+    let fog_distance = distance - fog.start_distance;
+    if fog_distance <= 0.0 {
+        return color;
+    }
+    let ray_dir = to_surface / distance;
+
+    // The distance fog is equally dense everywhere along the ray
+    var optical_depth = fog.density * fog_distance;
+    // The height fog thins out exponentially with the height, which sums up along the ray to the density where the
+    // fog starts, times the distance, times how much the density changes between the ends of the ray on average
+    let start_height = camera.view_pos.y + ray_dir.y * fog.start_distance - fog.height;
+    let falloff = fog.height_falloff * ray_dir.y * fog_distance;
+    let average = select((1.0 - exp(-falloff)) / falloff, 1.0, abs(falloff) < 0.0001);
+    optical_depth += fog.height_density * exp(-fog.height_falloff * start_height) * fog_distance * average;
+
+    let sky_color = textureSampleLevel(t_environment, s_environment, ray_dir, 0.0).rgb;
+    let fog_color = mix(fog.color, sky_color, fog.environment);
+    return mix(color, fog_color, 1.0 - exp(-optical_depth));
+}
+
+// The cluster a fragment at `frag_coord` on the screen and `world_position` in the world falls into
+fn cluster_index(frag_coord: vec2<f32>, world_position: vec3<f32>) -> u32 {
+    let grid = vec2<f32>(f32(CLUSTERS_X), f32(CLUSTERS_Y));
+    let tile = min(vec2<u32>(frag_coord / clusters.screen_size * grid), vec2<u32>(CLUSTERS_X, CLUSTERS_Y) - 1u);
+    // The depth slices grow exponentially, so the slice is found with a logarithm
+    let depth = -(clusters.view * vec4<f32>(world_position, 1.0)).z;
+    let slice = log(max(depth, clusters.near) / clusters.near) / log(clusters.far / clusters.near) * f32(CLUSTERS_Z);
+    let z = min(u32(slice), CLUSTERS_Z - 1u);
+    return tile.x + tile.y * CLUSTERS_X + z * CLUSTERS_X * CLUSTERS_Y;
+}
+
+// Blinn-Phong for a point light, in world space. The light falls off with the square of the distance, smoothly brought
+// down to zero at its radius so that it doesn't end abruptly at the edge of the clusters it was assigned to.
+fn point_light(
+    light: PointLight,
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    view_dir: vec3<f32>,
+    specular: f32,
+    shininess: f32,
+) -> vec3<f32> {
+    let to_light = light.position - position;
+    let distance = length(to_light);
+    let light_dir = to_light / max(distance, 0.0001);
+    let window = saturate(1.0 - pow(distance / light.radius, 4.0));
+    let attenuation = window * window / (distance * distance + 1.0);
+
+    let diffuse_strength = max(dot(normal, light_dir), 0.0);
+    let halfway_dir = normalize(light_dir + view_dir);
+    let specular_strength = specular * pow(max(dot(normal, halfway_dir), 0.0), shininess);
+    return light.color * light.intensity * attenuation * (diffuse_strength + specular_strength);
+}
+
+// A heat map from blue through green to red, reaching red at a quarter of the lights a cluster can hold
+fn light_count_color(count: u32) -> vec3<f32> {
+    let t = f32(count) / f32(MAX_LIGHTS_PER_CLUSTER / 4u);
+    let cold = mix(vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 0.0), saturate(t * 2.0));
+    return mix(cold, vec3<f32>(1.0, 0.0, 0.0), saturate(t * 2.0 - 1.0));
+}
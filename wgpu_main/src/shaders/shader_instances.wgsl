@@ -1,21 +1,4 @@
-struct InstanceInput {
-    @location(5) model_matrix_0: vec4<f32>,
-    @location(6) model_matrix_1: vec4<f32>,
-    @location(7) model_matrix_2: vec4<f32>,
-    @location(8) model_matrix_3: vec4<f32>,
-
-    @location(9) normal_matrix_0: vec3<f32>,
-    @location(10) normal_matrix_1: vec3<f32>,
-    @location(11) normal_matrix_2: vec3<f32>,
-};
-
-struct CameraUniform {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-};
-
-@group(1) @binding(0)
-var<uniform> camera: CameraUniform;
+// The vertex stage of meshes drawn as they are, put together with `scene.wgsl` and `model.wgsl` into a whole shader
 
 // World Space to Tangent Space
 // Since the normal map, by default is in tangent space, we need to transform all the other variables used in that
@@ -28,16 +11,6 @@ struct VertexInput {
     @location(4) bitangent: vec3<f32>,
 };
 
-struct VertexOutput {
-    @builtin(position) clip_position: vec4<f32>,
-    @location(0) tex_coords: vec2<f32>,
-//    @location(1) world_normal: vec3<f32>,
-//    @location(2) world_position: vec3<f32>,
-    @location(1) tangent_position: vec3<f32>,
-    @location(2) tangent_light_position: vec3<f32>,
-    @location(3) tangent_view_position: vec3<f32>,
-};
-
 @vertex
 fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
     let model_matrix = mat4x4<f32>(
@@ -70,58 +43,35 @@ fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
     out.tangent_position = tangent_matrix * world_position.xyz;
     out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
     out.tangent_light_position = tangent_matrix * light.position;
+    out.tint = instance.tint;
+    out.world_tangent = world_tangent;
+    out.world_bitangent = world_bitangent;
+    out.world_normal = world_normal;
+    out.world_position = world_position.xyz;
 
     return out;
 }
 
-@group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
-@group(0) @binding(1)
-var s_diffuse: sampler;
-@group(0) @binding(2)
-var t_normal: texture_2d<f32>;
-@group(0) @binding(3)
-var s_normal: sampler;
+@vertex
+fn vs_velocity(model: VertexInput, instance: VelocityInstanceInput) -> VelocityOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let previous_model_matrix = mat4x4<f32>(
+        instance.previous_model_matrix_0,
+        instance.previous_model_matrix_1,
+        instance.previous_model_matrix_2,
+        instance.previous_model_matrix_3,
+    );
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    let previous_world_position = previous_model_matrix * vec4<f32>(model.position, 1.0);
 
-struct Light {
-    position: vec3<f32>,
-    color: vec3<f32>,
+    var out: VelocityOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.current_position = camera.unjittered_view_proj * world_position;
+    out.previous_position = camera.previous_view_proj * previous_world_position;
+    return out;
 }
-
-@group(2) @binding(0)
-var<uniform> light: Light;
-
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
-
-    // We don't need (or want) much ambient light, so 0.1 is fine
-    let ambient_strength = 0.1;
-    let ambient_color = light.color * ambient_strength;
-
-    // Create the lighting vectors
-    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
-    let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    let view_dir = normalize(in.tangent_view_position - in.tangent_position);
-
-    // Diffuse lighting
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0);
-    let diffuse_color = light.color * diffuse_strength;
-
-    // Specular lighting
-    // The Blinn part of the Blinn-Phong comes from the realization that if you add the `view_dir` and `light_dir` vectors
-    // together, and normalize the result, and use the dot product of that with the normal, you get the roughly the same
-    // results without the issues that using `reflect_dir` had.
-//    let reflect_dir = reflect(-light_dir, in.world_normal);
-    let halfway_dir = normalize(light_dir + view_dir);
-//    let specular_strength = pow(max(dot(view_dir, reflect_dir), 0.0), 32.0);
-    let specular_strength = pow(max(dot(tangent_normal, halfway_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color;
-
-
-    // Combine the lighting
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-
-    return vec4<f32>(result, object_color.a);
-}
\ No newline at end of file
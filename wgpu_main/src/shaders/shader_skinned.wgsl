@@ -0,0 +1,100 @@
+// The vertex stage of skinned meshes, put together with `scene.wgsl` and `model.wgsl` into a whole shader
+
+// This has to match `MAX_JOINTS` in skeleton.rs
+const MAX_JOINTS: u32 = 128u;
+
+// The joint palette. Each matrix takes a vertex from model space in the bind pose to model space in the current pose.
+@group(3) @binding(0)
+var<uniform> joints: array<mat4x4<f32>, MAX_JOINTS>;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec3<f32>,
+    @location(4) bitangent: vec3<f32>,
+    @location(12) joint_indices: vec4<u32>,
+    @location(13) joint_weights: vec4<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    // Linear blend skinning: the vertex is transformed by every joint it's bound to, and the results are blended
+    // using the weights. Blending the matrices first is equivalent and saves us three matrix-vector products.
+    let skin_matrix = joints[model.joint_indices.x] * model.joint_weights.x
+        + joints[model.joint_indices.y] * model.joint_weights.y
+        + joints[model.joint_indices.z] * model.joint_weights.z
+        + joints[model.joint_indices.w] * model.joint_weights.w;
+    // Skeletons rarely use non-uniform scale, so the upper 3x3 of the skin matrix is good enough for directions.
+    let skin_normal_matrix = mat3x3<f32>(skin_matrix[0].xyz, skin_matrix[1].xyz, skin_matrix[2].xyz);
+
+    let skinned_position = skin_matrix * vec4<f32>(model.position, 1.0);
+
+    // Construct the tangent matrix from the skinned tangent frame
+    let world_normal = normalize(normal_matrix * (skin_normal_matrix * model.normal));
+    let world_tangent = normalize(normal_matrix * (skin_normal_matrix * model.tangent));
+    let world_bitangent = normalize(normal_matrix * (skin_normal_matrix * model.bitangent));
+    let tangent_matrix = transpose(mat3x3<f32>(
+        world_tangent,
+        world_bitangent,
+        world_normal,
+    ));
+    let world_position = model_matrix * skinned_position;
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.tex_coords = model.tex_coords;
+    out.tangent_position = tangent_matrix * world_position.xyz;
+    out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
+    out.tangent_light_position = tangent_matrix * light.position;
+    out.tint = instance.tint;
+    out.world_tangent = world_tangent;
+    out.world_bitangent = world_bitangent;
+    out.world_normal = world_normal;
+    out.world_position = world_position.xyz;
+
+    return out;
+}
+
+// Only the instance moving is taken into account, since the joint palette of the last frame is gone by now
+@vertex
+fn vs_velocity(model: VertexInput, instance: VelocityInstanceInput) -> VelocityOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let previous_model_matrix = mat4x4<f32>(
+        instance.previous_model_matrix_0,
+        instance.previous_model_matrix_1,
+        instance.previous_model_matrix_2,
+        instance.previous_model_matrix_3,
+    );
+    let skin_matrix = joints[model.joint_indices.x] * model.joint_weights.x
+        + joints[model.joint_indices.y] * model.joint_weights.y
+        + joints[model.joint_indices.z] * model.joint_weights.z
+        + joints[model.joint_indices.w] * model.joint_weights.w;
+    let skinned_position = skin_matrix * vec4<f32>(model.position, 1.0);
+    let world_position = model_matrix * skinned_position;
+    let previous_world_position = previous_model_matrix * skinned_position;
+
+    var out: VelocityOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.current_position = camera.unjittered_view_proj * world_position;
+    out.previous_position = camera.previous_view_proj * previous_world_position;
+    return out;
+}
@@ -0,0 +1,121 @@
+// The Preetham sky, written into a cube by a compute pass and drawn behind everything as a skybox
+
+// This has to match `SkyUniform` in sky.rs
+struct SkyUniform {
+    // Towards the sun
+    sun_direction: vec3<f32>,
+    // The cosine of the angle between the middle and the edge of the sun disk
+    sun_cos_radius: f32,
+    sun_radiance: vec3<f32>,
+    // Brings the luminance of the model, in thousands of candela per square meter, in line with the scene
+    luminance_scale: f32,
+    // What is left of the sky once the sun is gone
+    night_color: vec3<f32>,
+    // The luminance and chromaticity at the zenith, each over what the Perez distribution is there
+    zenith: vec3<f32>,
+    // The coefficients A to E of the Perez distribution, for the luminance and the chromaticity in xyz
+    perez: array<vec4<f32>, 5>,
+}
+
+@group(0) @binding(0)
+var<uniform> sky: SkyUniform;
+// The faces of the cube, as layers
+@group(0) @binding(1)
+var faces: texture_storage_2d_array<rgba16float, write>;
+
+// How the brightness and color of the sky are spread out, for the angle `theta` between the view direction and the
+// zenith, and `gamma` between the view direction and the sun
+fn perez(cos_theta: f32, gamma: f32, cos_gamma: f32) -> vec3<f32> {
+    let a = sky.perez[0].xyz;
+    let b = sky.perez[1].xyz;
+    let c = sky.perez[2].xyz;
+    let d = sky.perez[3].xyz;
+    let e = sky.perez[4].xyz;
+    return (1.0 + a * exp(b / cos_theta)) * (1.0 + c * exp(d * gamma) + e * cos_gamma * cos_gamma);
+}
+
+fn sky_color(direction: vec3<f32>) -> vec3<f32> {
+    // The model doesn't go below the horizon, so the ground takes on a darker version of the horizon
+    let cos_theta = max(direction.y, 0.001);
+    let above = normalize(vec3<f32>(direction.x, max(direction.y, 0.0), direction.z));
+    let cos_gamma = dot(above, sky.sun_direction);
+    let gamma = acos(clamp(cos_gamma, -1.0, 1.0));
+    let yxy = sky.zenith * perez(cos_theta, gamma, cos_gamma);
+
+    // From the luminance Y and the chromaticity xy to XYZ, and from there to linear sRGB
+    let luminance = yxy.x * sky.luminance_scale;
+    let xyz = vec3<f32>(yxy.y / yxy.z * luminance, luminance, (1.0 - yxy.y - yxy.z) / yxy.z * luminance);
+    let xyz_to_rgb = mat3x3<f32>(
+        vec3<f32>(3.2406, -0.9689, 0.0557),
+        vec3<f32>(-1.5372, 1.8758, -0.2040),
+        vec3<f32>(-0.4986, 0.0415, 1.0570),
+    );
+    var color = max(xyz_to_rgb * xyz, vec3<f32>(0.0)) + sky.night_color;
+
+    // The disk of the sun, with a soft edge so that it doesn't flicker between texels
+    let sun = smoothstep(sky.sun_cos_radius - 0.0001, sky.sun_cos_radius + 0.0001, dot(direction, sky.sun_direction));
+    color += sky.sun_radiance * sun;
+
+    let ground = smoothstep(0.0, -0.05, direction.y);
+    return mix(color, color * 0.3, ground);
+}
+
+// The direction through the middle of texel `uv`, from -1 to 1 across the face, of `face` in the order and orientation
+// the cube is sampled in
+fn face_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    switch face {
+        case 0u: { return vec3<f32>(1.0, -uv.y, -uv.x); }
+        case 1u: { return vec3<f32>(-1.0, -uv.y, uv.x); }
+        case 2u: { return vec3<f32>(uv.x, 1.0, uv.y); }
+        case 3u: { return vec3<f32>(uv.x, -1.0, -uv.y); }
+        case 4u: { return vec3<f32>(uv.x, -uv.y, 1.0); }
+        default: { return vec3<f32>(-uv.x, -uv.y, -1.0); }
+    }
+}
+
+@compute
+@workgroup_size(16, 16, 1)
+fn cs_generate(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = textureDimensions(faces);
+    if any(id.xy >= size) {
+        return;
+    }
+    let uv = (vec2<f32>(id.xy) + 0.5) / vec2<f32>(size) * 2.0 - 1.0;
+    let direction = normalize(face_direction(id.z, uv));
+    textureStore(faces, id.xy, id.z, vec4<f32>(sky_color(direction), 1.0));
+}
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+@group(1) @binding(0)
+var t_sky: texture_cube<f32>;
+@group(1) @binding(1)
+var s_sky: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+@vertex
+fn vs_skybox(@builtin(vertex_index) vi: u32) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen, on the far plane
+    out.ndc = vec2<f32>(f32((vi << 1u) & 2u), f32(vi & 2u)) * 2.0 - 1.0;
+    out.clip_position = vec4<f32>(out.ndc, 1.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_skybox(in: VertexOutput) -> @location(0) vec4<f32> {
+    // Undo the projection to find the direction the pixel looks in
+    let far = camera.inv_view_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    let direction = normalize(far.xyz / far.w - camera.view_pos.xyz);
+    return vec4<f32>(textureSample(t_sky, s_sky, direction).rgb, 1.0);
+}
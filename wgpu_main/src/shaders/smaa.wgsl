@@ -0,0 +1,269 @@
+// SMAA 1x, following http://www.iryoku.com/smaa/ and its reference implementation, without diagonal edge detection
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+struct SmaaUniform {
+    // How much the luma of neighbouring pixels has to differ for an edge between them
+    threshold: f32,
+    // How many steps of two pixels the searches for the ends of an edge take at most
+    max_search_steps: f32,
+    // How much sharp corners are kept from being smoothed over, from 0 to 1
+    corner_rounding: f32,
+}
+
+// Every pass only declares the textures it reads, so the bindings don't overlap
+@group(0) @binding(0)
+var<uniform> smaa: SmaaUniform;
+@group(0) @binding(1)
+var linear_sampler: sampler;
+@group(0) @binding(2)
+var color_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var edges_texture: texture_2d<f32>;
+@group(0) @binding(4)
+var area_texture: texture_2d<f32>;
+@group(0) @binding(5)
+var search_texture: texture_2d<f32>;
+@group(0) @binding(6)
+var blend_texture: texture_2d<f32>;
+
+// These have to match smaa_textures.rs
+const AREA_MAX_DISTANCE: f32 = 16.0;
+const AREA_SIZE: vec2<f32> = vec2<f32>(80.0, 80.0);
+const SEARCH_RIGHT_OFFSET: i32 = 33;
+
+// An edge is only kept if it has at least half the contrast of the strongest edge around it, so that a strong edge
+// doesn't also mark the weaker ones next to it
+const LOCAL_CONTRAST_ADAPTATION: f32 = 2.0;
+
+// Edge Detection Pass
+
+// The thresholds are meant for gamma corrected luma, and the image is linear once read
+fn luma(uv: vec2<f32>) -> f32 {
+    let color = textureSampleLevel(color_texture, linear_sampler, uv, 0.0).rgb;
+    return dot(pow(color, vec3<f32>(1.0 / 2.2)), vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Marks edges to the left of the pixel in red and above it in green
+@fragment
+fn fs_edge_detection(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(color_texture));
+    let l = luma(in.uv);
+    let l_left = luma(in.uv - vec2<f32>(texel.x, 0.0));
+    let l_top = luma(in.uv - vec2<f32>(0.0, texel.y));
+    let delta = abs(l - vec2<f32>(l_left, l_top));
+    var edges = step(vec2<f32>(smaa.threshold), delta);
+    if dot(edges, vec2<f32>(1.0)) == 0.0 {
+        discard;
+    }
+
+    let l_right = luma(in.uv + vec2<f32>(texel.x, 0.0));
+    let l_bottom = luma(in.uv + vec2<f32>(0.0, texel.y));
+    let l_left_left = luma(in.uv - vec2<f32>(2.0 * texel.x, 0.0));
+    let l_top_top = luma(in.uv - vec2<f32>(0.0, 2.0 * texel.y));
+    let max_delta = max(
+        max(delta, abs(l - vec2<f32>(l_right, l_bottom))),
+        abs(vec2<f32>(l_left, l_top) - vec2<f32>(l_left_left, l_top_top)),
+    );
+    edges *= step(vec2<f32>(max(max_delta.x, max_delta.y)), LOCAL_CONTRAST_ADAPTATION * delta);
+    return vec4<f32>(edges, 0.0, 1.0);
+}
+
+// Blending Weight Calculation Pass
+
+fn edges_at(uv: vec2<f32>) -> vec2<f32> {
+    return textureSampleLevel(edges_texture, linear_sampler, uv, 0.0).rg;
+}
+
+// The searches fetch between four pixels at once, so bilinear filtering mixes their edges into a single value. The
+// search texture turns that value back into how many more pixels the edge goes on for.
+fn search_length(e: vec2<f32>, offset: i32) -> f32 {
+    let coords = vec2<i32>(round(e * 32.0)) + vec2<i32>(offset, 0);
+    return textureLoad(search_texture, coords, 0).r;
+}
+
+// The search texture stores distances scaled by 127
+const SEARCH_SCALE: f32 = 255.0 / 127.0;
+
+fn search_x_left(start: vec2<f32>, end: f32, texel: vec2<f32>) -> f32 {
+    var uv = start;
+    // Go on while there are edges above both pixels, and none crossing
+    var e = vec2<f32>(0.0, 1.0);
+    while uv.x > end && e.g > 0.8281 && e.r == 0.0 {
+        e = edges_at(uv);
+        uv.x -= 2.0 * texel.x;
+    }
+    let offset = 3.25 - SEARCH_SCALE * search_length(e, 0);
+    return uv.x + texel.x * offset;
+}
+
+fn search_x_right(start: vec2<f32>, end: f32, texel: vec2<f32>) -> f32 {
+    var uv = start;
+    var e = vec2<f32>(0.0, 1.0);
+    while uv.x < end && e.g > 0.8281 && e.r == 0.0 {
+        e = edges_at(uv);
+        uv.x += 2.0 * texel.x;
+    }
+    let offset = 3.25 - SEARCH_SCALE * search_length(e, SEARCH_RIGHT_OFFSET);
+    return uv.x - texel.x * offset;
+}
+
+fn search_y_up(start: vec2<f32>, end: f32, texel: vec2<f32>) -> f32 {
+    var uv = start;
+    var e = vec2<f32>(1.0, 0.0);
+    while uv.y > end && e.r > 0.8281 && e.g == 0.0 {
+        e = edges_at(uv);
+        uv.y -= 2.0 * texel.y;
+    }
+    let offset = 3.25 - SEARCH_SCALE * search_length(e.gr, 0);
+    return uv.y + texel.y * offset;
+}
+
+fn search_y_down(start: vec2<f32>, end: f32, texel: vec2<f32>) -> f32 {
+    var uv = start;
+    var e = vec2<f32>(1.0, 0.0);
+    while uv.y < end && e.r > 0.8281 && e.g == 0.0 {
+        e = edges_at(uv);
+        uv.y += 2.0 * texel.y;
+    }
+    let offset = 3.25 - SEARCH_SCALE * search_length(e.gr, SEARCH_RIGHT_OFFSET);
+    return uv.y - texel.y * offset;
+}
+
+// Looks up how much of the pixel the revectorized edge covers, from the distances to both ends of the edge and the
+// crossing edges found there. The distances are stored quadratically, hence the square root.
+fn area(d: vec2<f32>, e1: f32, e2: f32) -> vec2<f32> {
+    // Rounding prevents precision errors of bilinear filtering
+    let texel = AREA_MAX_DISTANCE * round(4.0 * vec2<f32>(e1, e2)) + sqrt(d);
+    return textureSampleLevel(area_texture, linear_sampler, (texel + 0.5) / AREA_SIZE, 0.0).rg;
+}
+
+// Keeps corners sharp, by reducing the blending near the end of an edge where another edge meets it at a right angle
+fn corner_rounding(d: vec2<f32>) -> vec2<f32> {
+    let left_right = step(d, d.yx);
+    return (1.0 - smaa.corner_rounding) * left_right / (left_right.x + left_right.y);
+}
+
+fn horizontal_corners(weights: vec2<f32>, coords: vec4<f32>, d: vec2<f32>) -> vec2<f32> {
+    let rounding = corner_rounding(d);
+    var factor = vec2<f32>(1.0);
+    factor.x -= rounding.x * textureSampleLevel(edges_texture, linear_sampler, coords.xy, 0.0, vec2<i32>(0, 1)).r;
+    factor.x -= rounding.y * textureSampleLevel(edges_texture, linear_sampler, coords.zw, 0.0, vec2<i32>(1, 1)).r;
+    factor.y -= rounding.x * textureSampleLevel(edges_texture, linear_sampler, coords.xy, 0.0, vec2<i32>(0, -2)).r;
+    factor.y -= rounding.y * textureSampleLevel(edges_texture, linear_sampler, coords.zw, 0.0, vec2<i32>(1, -2)).r;
+    return weights * saturate(factor);
+}
+
+fn vertical_corners(weights: vec2<f32>, coords: vec4<f32>, d: vec2<f32>) -> vec2<f32> {
+    let rounding = corner_rounding(d);
+    var factor = vec2<f32>(1.0);
+    factor.x -= rounding.x * textureSampleLevel(edges_texture, linear_sampler, coords.xy, 0.0, vec2<i32>(1, 0)).g;
+    factor.x -= rounding.y * textureSampleLevel(edges_texture, linear_sampler, coords.zw, 0.0, vec2<i32>(1, 1)).g;
+    factor.y -= rounding.x * textureSampleLevel(edges_texture, linear_sampler, coords.xy, 0.0, vec2<i32>(-2, 0)).g;
+    factor.y -= rounding.y * textureSampleLevel(edges_texture, linear_sampler, coords.zw, 0.0, vec2<i32>(-2, 1)).g;
+    return weights * saturate(factor);
+}
+
+// Finds the shape of the edges around the pixel, and how much it should blend with its neighbours to smooth them.
+// The weights for the edge above the pixel end up in red and green, the ones for the edge to its left in blue and
+// alpha.
+@fragment
+fn fs_blend_weights(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<f32>(textureDimensions(edges_texture));
+    let texel = 1.0 / size;
+    let pixel = in.uv * size;
+    // The searches sample between pixels, to read two of them at once. Being a quarter pixel off the middle lets the
+    // bilinear weights tell the pixels apart.
+    let offset0 = in.uv.xyxy + texel.xyxy * vec4<f32>(-0.25, -0.125, 1.25, -0.125);
+    let offset1 = in.uv.xyxy + texel.xyxy * vec4<f32>(-0.125, -0.25, -0.125, 1.25);
+    let offset2 = vec4<f32>(offset0.xz, offset1.yw)
+        + texel.xxyy * vec4<f32>(-2.0, 2.0, -2.0, 2.0) * smaa.max_search_steps;
+
+    var weights = vec4<f32>(0.0);
+    let e = edges_at(in.uv);
+
+    // Edge above the pixel
+    if e.g > 0.0 {
+        let left = search_x_left(offset0.xy, offset2.x, texel);
+        let right = search_x_right(offset0.zw, offset2.y, texel);
+        let d = abs(round(size.x * vec2<f32>(left, right) - pixel.x));
+        // The crossing edges at both ends, read a quarter pixel up so that the bilinear weights tell apart the ones
+        // going up from the ones going down
+        let e1 = edges_at(vec2<f32>(left, offset1.y)).r;
+        let e2 = textureSampleLevel(edges_texture, linear_sampler, vec2<f32>(right, offset1.y), 0.0, vec2<i32>(1, 0)).r;
+        weights = vec4<f32>(
+            horizontal_corners(area(d, e1, e2), vec4<f32>(left, in.uv.y, right, in.uv.y), d),
+            weights.ba,
+        );
+    }
+
+    // Edge to the left of the pixel
+    if e.r > 0.0 {
+        let up = search_y_up(offset1.xy, offset2.z, texel);
+        let down = search_y_down(offset1.zw, offset2.w, texel);
+        let d = abs(round(size.y * vec2<f32>(up, down) - pixel.y));
+        let e1 = edges_at(vec2<f32>(offset0.x, up)).g;
+        let e2 = textureSampleLevel(edges_texture, linear_sampler, vec2<f32>(offset0.x, down), 0.0, vec2<i32>(0, 1)).g;
+        weights = vec4<f32>(
+            weights.rg,
+            vertical_corners(area(d, e1, e2), vec4<f32>(in.uv.x, up, in.uv.x, down), d),
+        );
+    }
+
+    return weights;
+}
+
+// Neighborhood Blending Pass
+
+fn color_at(uv: vec2<f32>) -> vec4<f32> {
+    return textureSampleLevel(color_texture, linear_sampler, uv, 0.0);
+}
+
+// Blends every pixel with the neighbour across its strongest edge. The weights of the edges below and to the right of
+// the pixel are stored with the pixels there.
+@fragment
+fn fs_neighborhood_blending(in: VertexOutput) -> @location(0) vec4<f32> {
+    let texel = 1.0 / vec2<f32>(textureDimensions(color_texture));
+    let here = textureSampleLevel(blend_texture, linear_sampler, in.uv, 0.0);
+    // Right, bottom, left and top
+    let a = vec4<f32>(
+        textureSampleLevel(blend_texture, linear_sampler, in.uv + vec2<f32>(texel.x, 0.0), 0.0).a,
+        textureSampleLevel(blend_texture, linear_sampler, in.uv + vec2<f32>(0.0, texel.y), 0.0).g,
+        here.z,
+        here.x,
+    );
+    if dot(a, vec4<f32>(1.0)) < 1e-5 {
+        return color_at(in.uv);
+    }
+
+    // Blend along whichever direction has the stronger edge. Bilinear filtering does the blending, by sampling
+    // between the pixel and its neighbours.
+    let horizontal = max(a.x, a.z) > max(a.y, a.w);
+    var offset = vec4<f32>(0.0, a.y, 0.0, a.w);
+    var weight = a.yw;
+    if horizontal {
+        offset = vec4<f32>(a.x, 0.0, a.z, 0.0);
+        weight = a.xz;
+    }
+    weight /= dot(weight, vec2<f32>(1.0));
+    let coords = in.uv.xyxy + offset * vec4<f32>(texel, -texel);
+    return weight.x * color_at(coords.xy) + weight.y * color_at(coords.zw);
+}
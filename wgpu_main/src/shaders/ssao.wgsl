@@ -0,0 +1,170 @@
+// Screen-space ambient occlusion, estimating how much of the ambient light reaches every pixel from the depth buffer
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+// These have to match the constants of the same names in ssao.rs
+const MAX_SAMPLES: u32 = 64u;
+const NOISE_SIZE: i32 = 4;
+
+struct SsaoUniform {
+    // Points in the hemisphere above the surface, with z along the normal
+    kernel: array<vec4<f32>, MAX_SAMPLES>,
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> ssao: SsaoUniform;
+@group(0) @binding(1)
+var depth_texture: texture_depth_2d;
+// The cosine and sine of the angle to rotate the kernel by, tiled over the screen
+@group(0) @binding(2)
+var noise_texture: texture_2d<f32>;
+// The occlusion to blur
+@group(0) @binding(3)
+var source_texture: texture_2d<f32>;
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+};
+
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+fn load_depth(pixel: vec2<i32>) -> f32 {
+    let size = vec2<i32>(textureDimensions(depth_texture));
+    return textureLoad(depth_texture, clamp(pixel, vec2<i32>(0), size - 1), 0);
+}
+
+// The world position of the surface in the middle of `pixel`, which has `depth` in the depth buffer
+fn world_position(pixel: vec2<i32>, depth: f32) -> vec3<f32> {
+    let size = vec2<f32>(textureDimensions(depth_texture));
+    let uv = (vec2<f32>(pixel) + 0.5) / size;
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let world = camera.inv_view_proj * ndc;
+    return world.xyz / world.w;
+}
+
+// How far in front of the camera `position` is
+fn view_depth(position: vec3<f32>) -> f32 {
+    return -(camera.view * vec4<f32>(position, 1.0)).z;
+}
+
+// The surface next to `pixel` in the direction of `offset`, or in the opposite one, whichever continues the surface at
+// `center` better. Taking the closer one keeps the normals of pixels at the edge of an object from bending towards
+// whatever is behind it.
+fn neighbour_offset(pixel: vec2<i32>, offset: vec2<i32>, center: vec3<f32>) -> vec3<f32> {
+    let center_depth = view_depth(center);
+    let forward = world_position(pixel + offset, load_depth(pixel + offset));
+    let backward = world_position(pixel - offset, load_depth(pixel - offset));
+    if abs(view_depth(forward) - center_depth) < abs(view_depth(backward) - center_depth) {
+        return forward - center;
+    }
+    return center - backward;
+}
+
+@fragment
+fn fs_ssao(in: VertexOutput) -> @location(0) vec4<f32> {
+    let pixel = vec2<i32>(in.clip_position.xy);
+    let depth = load_depth(pixel);
+    // Nothing was drawn here
+    if depth >= 1.0 {
+        return vec4<f32>(1.0);
+    }
+
+    let position = world_position(pixel, depth);
+    let normal = normalize(cross(
+        neighbour_offset(pixel, vec2<i32>(0, 1), position),
+        neighbour_offset(pixel, vec2<i32>(1, 0), position),
+    ));
+
+    // Any tangent will do, as long as it is turned around the normal by the angle from the noise
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if abs(normal.y) > 0.99 {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent0 = normalize(cross(up, normal));
+    let bitangent0 = cross(normal, tangent0);
+    let rotation = textureLoad(noise_texture, pixel % NOISE_SIZE, 0).xy;
+    let tangent = tangent0 * rotation.x + bitangent0 * rotation.y;
+    let bitangent = cross(normal, tangent);
+    let tbn = mat3x3<f32>(tangent, bitangent, normal);
+
+    let size = vec2<f32>(textureDimensions(depth_texture));
+    let center_depth = view_depth(position);
+    var occlusion = 0.0;
+    for (var i = 0u; i < ssao.sample_count; i += 1u) {
+        let sample_position = position + tbn * ssao.kernel[i].xyz * ssao.radius;
+
+        let clip = camera.view_proj * vec4<f32>(sample_position, 1.0);
+        let ndc = clip.xy / clip.w;
+        let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+        // Whatever is off the screen can't be known, so it doesn't occlude anything
+        if any(uv < vec2<f32>(0.0)) || any(uv >= vec2<f32>(1.0)) {
+            continue;
+        }
+        let sample_pixel = vec2<i32>(uv * size);
+        let scene_depth = view_depth(world_position(sample_pixel, load_depth(sample_pixel)));
+
+        if scene_depth <= view_depth(sample_position) - ssao.bias {
+            // Surfaces much further in front than the radius are separate objects, which shouldn't darken the edges of
+            // what is behind them
+            occlusion += smoothstep(0.0, 1.0, ssao.radius / abs(center_depth - scene_depth));
+        }
+    }
+
+    let visibility = 1.0 - occlusion / f32(ssao.sample_count);
+    return vec4<f32>(pow(visibility, ssao.intensity));
+}
+
+// The blur averages a whole tile of the noise in each direction, which is what cancels it out, but only over pixels
+// at about the same depth as the middle one, so the occlusion stays on its own side of the edges of objects
+fn blur(pixel: vec2<i32>, direction: vec2<i32>) -> vec4<f32> {
+    let center_depth = view_depth(world_position(pixel, load_depth(pixel)));
+    let size = vec2<i32>(textureDimensions(source_texture));
+
+    var sum = 0.0;
+    var total_weight = 0.0;
+    for (var i = -NOISE_SIZE / 2; i < NOISE_SIZE / 2; i += 1) {
+        let neighbour = clamp(pixel + direction * i, vec2<i32>(0), size - 1);
+        let depth = view_depth(world_position(neighbour, load_depth(neighbour)));
+        let weight = max(0.0, 1.0 - abs(depth - center_depth) / (0.05 * center_depth));
+        sum += textureLoad(source_texture, neighbour, 0).r * weight;
+        total_weight += weight;
+    }
+    // The middle pixel always has a weight of one
+    return vec4<f32>(sum / total_weight);
+}
+
+@fragment
+fn fs_blur_x(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(vec2<i32>(in.clip_position.xy), vec2<i32>(1, 0));
+}
+
+@fragment
+fn fs_blur_y(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(vec2<i32>(in.clip_position.xy), vec2<i32>(0, 1));
+}
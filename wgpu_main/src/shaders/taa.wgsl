@@ -0,0 +1,191 @@
+// Temporal anti-aliasing, resolving the jittered frame against the history of the frames before it
+struct VertexOutput {
+     @location(0) uv: vec2<f32>,
+     @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vi: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // Generate a triangle that covers the whole screen
+    out.uv = vec2<f32>(
+        f32((vi << 1u) & 2u),
+        f32(vi & 2u),
+    );
+    out.clip_position = vec4<f32>(out.uv * 2.0 - 1.0, 0.0, 1.0);
+    // We need to invert the y coordinate so the image is not upside down
+    out.uv.y = 1.0 - out.uv.y;
+    return out;
+}
+
+struct TaaUniform {
+    // How much of the current frame goes into the result, the rest comes from the history
+    current_weight: f32,
+    // Zero when the history doesn't show the scene from anywhere near the current camera, like right after a resize or
+    // a camera cut
+    history_valid: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> taa: TaaUniform;
+@group(0) @binding(1)
+var input_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var history_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var history_sampler: sampler;
+
+// What the scene was rendered with, which only changes when the window resizes
+@group(1) @binding(0)
+var velocity_texture: texture_2d<f32>;
+@group(1) @binding(1)
+var depth_texture: texture_depth_2d;
+
+// This has to match `CameraUniform` in camera.rs
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    unjittered_view_proj: mat4x4<f32>,
+    previous_view_proj: mat4x4<f32>,
+};
+
+@group(2) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct TaaOutput {
+    @location(0) color: vec4<f32>,
+    // The same color again, for the next frame to read as its history
+    @location(1) history: vec4<f32>,
+}
+
+// The neighbourhood is compared in YCoCg, where the box around the colors fits them more tightly than in RGB, since
+// the brightness gets an axis of its own
+fn rgb_to_ycocg(rgb: vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(
+        dot(rgb, vec3<f32>(0.25, 0.5, 0.25)),
+        dot(rgb, vec3<f32>(0.5, 0.0, -0.5)),
+        dot(rgb, vec3<f32>(-0.25, 0.5, -0.25)),
+    );
+}
+
+fn ycocg_to_rgb(ycocg: vec3<f32>) -> vec3<f32> {
+    let y = ycocg.x;
+    let co = ycocg.y;
+    let cg = ycocg.z;
+    return vec3<f32>(y + co - cg, y + cg, y - co - cg);
+}
+
+// A few very bright pixels would otherwise outweigh everything around them, which makes them flicker as the jitter
+// moves them in and out of a pixel. Weighting every color by the inverse of its brightness before blending tames
+// them, as in Brian Karis' "High Quality Temporal Supersampling".
+fn brightness_weight(color: vec3<f32>) -> f32 {
+    return 1.0 / (1.0 + dot(color, vec3<f32>(0.2126, 0.7152, 0.0722)));
+}
+
+fn load_input(pixel: vec2<i32>) -> vec3<f32> {
+    let size = vec2<i32>(textureDimensions(input_texture));
+    return textureLoad(input_texture, clamp(pixel, vec2<i32>(0), size - 1), 0).rgb;
+}
+
+// Bilinear filtering would blur the history a bit more every frame it gets reprojected, so it is read with a
+// Catmull-Rom filter instead, which stays sharp. The 16 texels it needs are read with 9 bilinear samples, by sampling
+// the middle two texels of every row and column in between them, at the ratio of their weights.
+fn sample_history(uv: vec2<f32>) -> vec3<f32> {
+    let size = vec2<f32>(textureDimensions(history_texture));
+    let position = uv * size;
+    let center = floor(position - 0.5) + 0.5;
+    let f = position - center;
+
+    let w0 = f * (-0.5 + f * (1.0 - 0.5 * f));
+    let w1 = 1.0 + f * f * (-2.5 + 1.5 * f);
+    let w2 = f * (0.5 + f * (2.0 - 1.5 * f));
+    let w3 = f * f * (-0.5 + 0.5 * f);
+    let w12 = w1 + w2;
+
+    var uvs = array<vec2<f32>, 3>(
+        (center - 1.0) / size,
+        (center + w2 / w12) / size,
+        (center + 2.0) / size,
+    );
+    var weights = array<vec2<f32>, 3>(w0, w12, w3);
+
+    var result = vec3<f32>(0.0);
+    for (var y = 0; y < 3; y += 1) {
+        for (var x = 0; x < 3; x += 1) {
+            let sample_uv = vec2<f32>(uvs[x].x, uvs[y].y);
+            let weight = weights[x].x * weights[y].y;
+            result += textureSampleLevel(history_texture, history_sampler, sample_uv, 0.0).rgb * weight;
+        }
+    }
+    // The negative lobes of the filter can overshoot below zero next to bright pixels
+    return max(result, vec3<f32>(0.0));
+}
+
+// The velocity pass only draws meshes, so wherever the depth is still at the far plane, the velocity was left at zero.
+// The sky there still moves across the screen as the camera turns, so its velocity is worked out from the camera
+// alone, the same way the velocity pass does for surfaces.
+fn camera_velocity(uv: vec2<f32>, depth: f32) -> vec2<f32> {
+    let clip_position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let world_position = camera.inv_view_proj * clip_position;
+    let current = camera.unjittered_view_proj * world_position;
+    let previous = camera.previous_view_proj * world_position;
+    return (current.xy / current.w - previous.xy / previous.w) * vec2<f32>(0.5, -0.5);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> TaaOutput {
+    let pixel = vec2<i32>(in.clip_position.xy);
+    let current = load_input(pixel);
+
+    // The box the colors of the neighbourhood fit in, and which of the neighbours is closest to the camera
+    var box_min = rgb_to_ycocg(current);
+    var box_max = box_min;
+    var closest = pixel;
+    var closest_depth = textureLoad(depth_texture, pixel, 0);
+    let depth_size = vec2<i32>(textureDimensions(depth_texture));
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let neighbour = pixel + vec2<i32>(x, y);
+            let color = rgb_to_ycocg(load_input(neighbour));
+            box_min = min(box_min, color);
+            box_max = max(box_max, color);
+
+            let depth = textureLoad(depth_texture, clamp(neighbour, vec2<i32>(0), depth_size - 1), 0);
+            if depth < closest_depth {
+                closest = neighbour;
+                closest_depth = depth;
+            }
+        }
+    }
+
+    // Edges are where aliasing shows the most, and the pixels just outside an object would otherwise follow the
+    // background instead of the object, leaving a trail behind it. Taking the velocity of the closest neighbour grows
+    // moving objects by a pixel, so that their edges come along.
+    let velocity_size = vec2<i32>(textureDimensions(velocity_texture));
+    var velocity = textureLoad(velocity_texture, clamp(closest, vec2<i32>(0), velocity_size - 1), 0).xy;
+    if closest_depth >= 1.0 {
+        velocity = camera_velocity(in.uv, closest_depth);
+    }
+    let previous_uv = in.uv - velocity;
+
+    var result = current;
+    let on_screen = all(previous_uv >= vec2<f32>(0.0)) && all(previous_uv <= vec2<f32>(1.0));
+    if taa.history_valid != 0u && on_screen {
+        // Whatever the history has in this pixel that doesn't look like anything around it here is left over from
+        // something that moved away or was uncovered, so it is pulled into the box
+        let history = ycocg_to_rgb(clamp(rgb_to_ycocg(sample_history(previous_uv)), box_min, box_max));
+
+        let current_weight = taa.current_weight * brightness_weight(current);
+        let history_weight = (1.0 - taa.current_weight) * brightness_weight(history);
+        result = (current * current_weight + history * history_weight) / (current_weight + history_weight);
+    }
+
+    var out: TaaOutput;
+    out.color = vec4<f32>(result, 1.0);
+    out.history = out.color;
+    return out;
+}
@@ -0,0 +1,451 @@
+use crate::texture::{CubeTexture, Texture};
+use std::f32::consts::PI;
+use wgpu::util::DeviceExt;
+
+/// The adjustable parts of [Sky].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct SkySettings {
+    /// The hour of the day, from 0 to 24, which places the sun. It rises at 6 and sets at 18.
+    pub(crate) time_of_day: f32,
+    /// How many hours pass every second, 0 to stop the sun where it is.
+    pub(crate) time_scale: f32,
+    /// How hazy the air is, from 2 (a clear day) to 10 (a hazy one). Haze makes the sky paler and the
+    /// sun more orange.
+    pub(crate) turbidity: f32,
+    /// The luminance of the sky model is in thousands of candela per square meter, which this
+    /// brings in line with the lights of the scene.
+    pub(crate) intensity: f32,
+}
+
+impl Default for SkySettings {
+    fn default() -> Self {
+        Self {
+            time_of_day: 10.0,
+            time_scale: 0.0,
+            turbidity: 3.0,
+            intensity: 0.04,
+        }
+    }
+}
+
+impl SkySettings {
+    /// How far the path of the sun leans away from straight overhead, towards -z.
+    const SUN_PATH_TILT: f32 = 0.6;
+
+    /// The direction towards the sun. It rises along +x, is highest at noon and sets along -x.
+    pub(crate) fn sun_direction(&self) -> cgmath::Vector3<f32> {
+        let angle = (self.time_of_day - 6.0) / 12.0 * PI;
+        let (tilt_sin, tilt_cos) = Self::SUN_PATH_TILT.sin_cos();
+        cgmath::Vector3::new(angle.cos(), angle.sin() * tilt_cos, -angle.sin() * tilt_sin)
+    }
+
+    /// How much of the sunlight of every channel makes it through the atmosphere, which gets less
+    /// and redder the lower the sun is and the more air the light passes through on the way.
+    fn sun_transmittance(&self) -> [f32; 3] {
+        // Rayleigh scattering by the air and aerosol scattering by the haze, straight up, at
+        // 680, 550 and 440 nm
+        const RAYLEIGH: [f32; 3] = [0.040, 0.097, 0.236];
+        const AEROSOL: [f32; 3] = [1.65, 2.17, 2.91];
+        let haze = 0.04608 * self.turbidity - 0.04586;
+        // Kasten and Young's air mass, relative to the sun straight overhead
+        let elevation = self.sun_direction().y.max(0.0).asin();
+        let air_mass =
+            1.0 / (elevation.sin() + 0.50572 * (elevation.to_degrees() + 6.07995).powf(-1.6364));
+        std::array::from_fn(|i| (-(RAYLEIGH[i] + haze * AEROSOL[i]) * air_mass).exp())
+    }
+}
+
+// This has to match `SkyUniform` in sky.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyUniform {
+    sun_direction: [f32; 3],
+    sun_cos_radius: f32,
+    sun_radiance: [f32; 3],
+    luminance_scale: f32,
+    night_color: [f32; 3],
+    _padding: f32,
+    zenith: [f32; 3],
+    _padding2: f32,
+    /// The coefficients A to E of the Perez distribution, for the luminance and the two
+    /// chromaticities.
+    perez: [[f32; 4]; 5],
+}
+
+impl From<SkySettings> for SkyUniform {
+    fn from(settings: SkySettings) -> Self {
+        let t = settings.turbidity;
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+                0.0,
+            ],
+            [
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+                0.0,
+            ],
+            [
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+                0.0,
+            ],
+            [
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+                0.0,
+            ],
+            [
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+                0.0,
+            ],
+        ];
+
+        // The model only holds for the sun above the horizon, so below it the sky is the one of
+        // a setting sun, fading out as it gets darker
+        let sun_direction = settings.sun_direction();
+        let theta = sun_direction.y.clamp(0.0, 1.0).acos();
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let chromaticity = |c: [[f32; 4]; 3]| {
+            let theta = [theta.powi(3), theta.powi(2), theta, 1.0];
+            let row = |r: [f32; 4]| r.iter().zip(theta).map(|(a, b)| a * b).sum::<f32>();
+            t * t * row(c[0]) + t * row(c[1]) + row(c[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        // The shader scales the distribution by the zenith over what the distribution is there
+        let zenith = [zenith_luminance, zenith_x, zenith_y];
+        let zenith = std::array::from_fn(|i| {
+            let [a, b, c, d, e] = perez.map(|coefficients| coefficients[i]);
+            let at_zenith =
+                (1.0 + a * b.exp()) * (1.0 + c * (d * theta).exp() + e * theta.cos().powi(2));
+            zenith[i] / at_zenith
+        });
+
+        let day = smoothstep(-0.1, 0.05, sun_direction.y);
+        let sun = settings.sun_transmittance();
+        Self {
+            sun_direction: sun_direction.into(),
+            sun_cos_radius: Sky::SUN_ANGULAR_RADIUS.cos(),
+            sun_radiance: sun
+                .map(|c| c * Sky::SUN_RADIANCE * smoothstep(-0.01, 0.01, sun_direction.y)),
+            luminance_scale: settings.intensity * day,
+            night_color: Sky::NIGHT_COLOR,
+            _padding: 0.0,
+            zenith,
+            _padding2: 0.0,
+            perez,
+        }
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// # Procedural Sky
+/// A fixed clear color or a photo of the sky can't follow the sun around. Instead, the sky is
+/// worked out from the Preetham model ("A Practical Analytic Model for Daylight", 1999), which
+/// fits the brightness and color of a clear sky to a formula by Perez et al. The formula depends on
+/// the angle between the view direction and the zenith and the one between the view direction and
+/// the sun, with coefficients that change with the turbidity of the air. It gives the blue overhead,
+/// the bright glow around the sun and the pale horizon, and turns orange towards sunset.
+///
+/// A compute pass writes the sky into a [CubeTexture] whenever the sun moves or the settings
+/// change, so that drawing it costs a single lookup per pixel. The cube is drawn behind everything
+/// as a skybox, and bound next to the lights with [Self::layout_entries] so that the lit shaders
+/// can use it as an environment, such as the fog taking on the color of the sky behind a surface.
+///
+/// The time of day also sets the main light through [Self::main_light]: the sun, dimmed and
+/// reddened by the air it passes through, or a faint moon at night.
+pub(crate) struct Sky {
+    pub(crate) settings: SkySettings,
+    pub(crate) enabled: bool,
+    uniform: wgpu::Buffer,
+    cube: CubeTexture,
+    /// The settings the uniform was last written with, so that the cube is only generated again
+    /// once they change.
+    uploaded: Option<SkySettings>,
+    stale: bool,
+    generate_bind_group: wgpu::BindGroup,
+    generate_pipeline: wgpu::ComputePipeline,
+    skybox_bind_group: wgpu::BindGroup,
+    skybox_pipeline: wgpu::RenderPipeline,
+}
+
+impl Sky {
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    /// The texels along each side of every face of the cube.
+    const SIZE: u32 = 256;
+    /// Larger than the real sun, so that it covers a few texels of the cube.
+    const SUN_ANGULAR_RADIUS: f32 = 0.02;
+    /// How much brighter the disk of the sun is than the sky overhead.
+    const SUN_RADIANCE: f32 = 40.0;
+    const NIGHT_COLOR: [f32; 3] = [0.002, 0.003, 0.006];
+    /// The light of a full moon.
+    const MOONLIGHT: [f32; 3] = [0.04, 0.05, 0.08];
+
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        camera_layout: &wgpu::BindGroupLayout,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let settings = SkySettings::default();
+        let uniform = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Sky Uniform"),
+            contents: bytemuck::cast_slice(&[SkyUniform::from(settings)]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let cube = CubeTexture::create_2d(
+            device,
+            Self::SIZE,
+            Self::SIZE,
+            Self::FORMAT,
+            1,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            wgpu::FilterMode::Linear,
+            Some("sky_cube_texture"),
+        );
+        // Storage textures can't be cubes, so the compute pass writes the faces as layers
+        let faces = cube.texture().create_view(&wgpu::TextureViewDescriptor {
+            label: Some("sky_faces_view"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let generate_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("sky_generate_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: Self::FORMAT,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let generate_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("sky_generate_bind_group"),
+            layout: &generate_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&faces),
+                },
+            ],
+        });
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/sky.wgsl"));
+        let generate_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Sky Generate Pipeline Layout"),
+                bind_group_layouts: &[&generate_layout],
+                push_constant_ranges: &[],
+            });
+        let generate_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Sky Generate Pipeline"),
+            layout: Some(&generate_pipeline_layout),
+            module: &shader,
+            entry_point: "cs_generate",
+        });
+
+        let skybox_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("skybox_bind_group_layout"),
+            entries: &Self::layout_entries(0, wgpu::ShaderStages::FRAGMENT),
+        });
+        let skybox_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("skybox_bind_group"),
+            layout: &skybox_layout,
+            entries: &Self::bind_group_entries_for(&cube, 0),
+        });
+        let skybox_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Skybox Pipeline Layout"),
+                bind_group_layouts: &[camera_layout, &skybox_layout],
+                push_constant_ranges: &[],
+            });
+        let skybox_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox Pipeline"),
+            layout: Some(&skybox_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_skybox",
+                // The shader makes up a triangle covering the whole screen
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_skybox",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            // The sky sits on the far plane, so it only shows where nothing was drawn
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Self {
+            settings,
+            enabled: true,
+            uniform,
+            cube,
+            uploaded: None,
+            stale: true,
+            generate_bind_group,
+            generate_pipeline,
+            skybox_bind_group,
+            skybox_pipeline,
+        }
+    }
+
+    /// The entries for the sky cube and its sampler, at `binding` and the one after it, in the
+    /// layout of a bind group the shaders sample the sky from.
+    pub(crate) fn layout_entries(
+        binding: u32,
+        visibility: wgpu::ShaderStages,
+    ) -> [wgpu::BindGroupLayoutEntry; 2] {
+        [
+            wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::Cube,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: binding + 1,
+                visibility,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ]
+    }
+
+    /// The sky cube and its sampler, for [Self::layout_entries].
+    pub(crate) fn bind_group_entries(&self, binding: u32) -> [wgpu::BindGroupEntry<'_>; 2] {
+        Self::bind_group_entries_for(&self.cube, binding)
+    }
+
+    fn bind_group_entries_for(cube: &CubeTexture, binding: u32) -> [wgpu::BindGroupEntry<'_>; 2] {
+        [
+            wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(cube.view()),
+            },
+            wgpu::BindGroupEntry {
+                binding: binding + 1,
+                resource: wgpu::BindingResource::Sampler(cube.sampler()),
+            },
+        ]
+    }
+
+    /// The direction towards the light that lights the scene, the sun by day and the moon by
+    /// night, and its color. Both fade out around the horizon, so that the light doesn't jump
+    /// when they swap.
+    pub(crate) fn main_light(&self) -> (cgmath::Vector3<f32>, [f32; 3]) {
+        let sun_direction = self.settings.sun_direction();
+        if sun_direction.y >= 0.0 {
+            let fade = smoothstep(0.0, 0.1, sun_direction.y);
+            let color = self.settings.sun_transmittance().map(|c| c * fade);
+            (sun_direction, color)
+        } else {
+            let fade = smoothstep(0.0, 0.1, -sun_direction.y);
+            (-sun_direction, Self::MOONLIGHT.map(|c| c * fade))
+        }
+    }
+
+    /// Moves the sun along by `dt`, and uploads the settings if they changed since the last time.
+    pub(crate) fn update(&mut self, queue: &wgpu::Queue, dt: instant::Duration) {
+        let settings = &mut self.settings;
+        settings.time_of_day =
+            (settings.time_of_day + settings.time_scale * dt.as_secs_f32()).rem_euclid(24.0);
+        if self.uploaded != Some(self.settings) {
+            queue.write_buffer(
+                &self.uniform,
+                0,
+                bytemuck::cast_slice(&[SkyUniform::from(self.settings)]),
+            );
+            self.uploaded = Some(self.settings);
+            self.stale = true;
+        }
+    }
+
+    /// Writes the sky into the cube again, if the settings changed since the last time.
+    pub(crate) fn generate(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if !self.enabled || !self.stale {
+            return;
+        }
+        self.stale = false;
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Sky Generate Pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_pipeline(&self.generate_pipeline);
+        compute_pass.set_bind_group(0, &self.generate_bind_group, &[]);
+        // One invocation for every texel, with a layer of workgroups for every face
+        let workgroups = Self::SIZE.div_ceil(16);
+        compute_pass.dispatch_workgroups(workgroups, workgroups, 6);
+    }
+
+    /// Fills everything the depth buffer has nothing in with the sky.
+    pub(crate) fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        render_pass.set_pipeline(&self.skybox_pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.skybox_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
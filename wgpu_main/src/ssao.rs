@@ -0,0 +1,411 @@
+use crate::texture::Texture;
+use std::f32::consts::TAU;
+
+/// The adjustable parts of [Ssao].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct SsaoSettings {
+    /// How far around every surface to look for occluders, in world units.
+    pub(crate) radius: f32,
+    /// The occlusion is raised to this power, so higher values darken occluded corners more.
+    pub(crate) intensity: f32,
+    /// How many points around every surface get tested, up to [Ssao::MAX_SAMPLES].
+    pub(crate) sample_count: u32,
+    /// How far a point has to be behind the depth buffer to count as occluded, which keeps flat
+    /// surfaces from occluding themselves through the limited precision of the depth.
+    pub(crate) bias: f32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            intensity: 1.5,
+            sample_count: 16,
+            bias: 0.025,
+        }
+    }
+}
+
+// This has to match `SsaoUniform` in ssao.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniform {
+    kernel: [[f32; 4]; Ssao::MAX_SAMPLES as usize],
+    radius: f32,
+    intensity: f32,
+    bias: f32,
+    sample_count: u32,
+}
+
+impl From<SsaoSettings> for SsaoUniform {
+    fn from(settings: SsaoSettings) -> Self {
+        let sample_count = settings.sample_count.clamp(1, Ssao::MAX_SAMPLES);
+        let mut kernel = [[0.0; 4]; Ssao::MAX_SAMPLES as usize];
+        for (i, sample) in kernel.iter_mut().take(sample_count as usize).enumerate() {
+            *sample = Ssao::kernel_sample(i as u32, sample_count);
+        }
+        Self {
+            kernel,
+            radius: settings.radius,
+            intensity: settings.intensity,
+            bias: settings.bias,
+            sample_count,
+        }
+    }
+}
+
+/// # Screen-Space Ambient Occlusion
+/// The ambient term lights every surface the same, as if light reached it from everywhere around
+/// it equally. Creases, corners and the gaps between objects that touch get less of that light in
+/// reality, since the surfaces around them block most directions. Without that, they look flat and
+/// washed out.
+///
+/// SSAO estimates how much is blocked from the depth buffer alone. For every pixel, it works out
+/// the position and normal of the surface there, and tests a kernel of points spread out over the
+/// hemisphere above the surface. Every point that ends up behind what the depth buffer holds at its
+/// place on the screen is inside something, so it counts as occluded. The kernel is rotated by a
+/// different angle in each pixel of a small tiling noise texture, which trades the banding of a few
+/// samples for noise, and a blur then smooths that away. The blur is bilateral: it only mixes pixels
+/// at a similar depth, so the occlusion doesn't bleed across the edges of objects.
+///
+/// The normals are worked out from the depth buffer too, which works the same in both render
+/// paths. In the forward path, the depth buffer has to be ready before anything gets lit, so the
+/// opaque meshes are drawn into it in a depth prepass first. Masked meshes are left out of that,
+/// since discarding their fragments would need their materials.
+///
+/// The result scales the ambient light in the lighting shaders, which read it from the light bind
+/// group, see [Self::layout_entry].
+pub(crate) struct Ssao {
+    pub(crate) settings: SsaoSettings,
+    /// Switched off, the occlusion is cleared to none.
+    pub(crate) enabled: bool,
+    uniform: wgpu::Buffer,
+    noise: Texture,
+    /// The blurred occlusion, which the lighting reads.
+    occlusion: Texture,
+    /// The occlusion between the two directions of the blur.
+    scratch: Texture,
+    layout: wgpu::BindGroupLayout,
+    /// Reads the depth into `occlusion`, then blurs it into `scratch` and back.
+    bind_groups: [wgpu::BindGroup; 3],
+    occlusion_pipeline: wgpu::RenderPipeline,
+    horizontal_blur_pipeline: wgpu::RenderPipeline,
+    vertical_blur_pipeline: wgpu::RenderPipeline,
+}
+
+impl Ssao {
+    /// This has to match `MAX_SAMPLES` in ssao.wgsl.
+    pub(crate) const MAX_SAMPLES: u32 = 64;
+    /// The noise texture is this many pixels across and down. This has to match `NOISE_SIZE` in
+    /// ssao.wgsl.
+    const NOISE_SIZE: u32 = 4;
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+    /// `depth` is the depth buffer of the scene, and `camera_layout` the layout of the camera it
+    /// was rendered with.
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera_layout: &wgpu::BindGroupLayout,
+        depth: &Texture,
+    ) -> Self {
+        let uniform = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("SSAO Uniform"),
+            size: std::mem::size_of::<SsaoUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let noise = Self::create_noise_texture(device, queue);
+        let (occlusion, scratch) = Self::create_targets(device, depth);
+
+        let texture = |binding, sample_type| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("ssao_bind_group_layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                texture(1, wgpu::TextureSampleType::Depth),
+                texture(2, wgpu::TextureSampleType::Float { filterable: false }),
+                // The occlusion to blur
+                texture(3, wgpu::TextureSampleType::Float { filterable: true }),
+            ],
+        });
+        let bind_groups = Self::create_bind_groups(
+            device,
+            &layout,
+            &uniform,
+            depth,
+            &noise,
+            [&occlusion, &scratch],
+        );
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("SSAO Pipeline Layout"),
+            bind_group_layouts: &[&layout, camera_layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shaders/ssao.wgsl"));
+        let pipeline = |label, entry_point| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: Self::FORMAT,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+
+        Self {
+            settings: SsaoSettings::default(),
+            enabled: true,
+            uniform,
+            noise,
+            occlusion,
+            scratch,
+            layout,
+            bind_groups,
+            occlusion_pipeline: pipeline("SSAO Pipeline", "fs_ssao"),
+            horizontal_blur_pipeline: pipeline("SSAO Horizontal Blur Pipeline", "fs_blur_x"),
+            vertical_blur_pipeline: pipeline("SSAO Vertical Blur Pipeline", "fs_blur_y"),
+        }
+    }
+
+    /// The `i`th of `count` points of the kernel, in the tangent space of the surface with z along
+    /// the normal. They spiral out from the normal down to the surface by the golden angle, which
+    /// covers the hemisphere evenly for any count, and they get closer to the surface towards the
+    /// start, since nearby occluders matter the most.
+    fn kernel_sample(i: u32, count: u32) -> [f32; 4] {
+        const GOLDEN_ANGLE: f32 = 2.399_963;
+        let t = (i as f32 + 0.5) / count as f32;
+        let z = 1.0 - t;
+        let r = (1.0 - z * z).sqrt();
+        let phi = i as f32 * GOLDEN_ANGLE;
+        let scale = 0.1 + 0.9 * t * t;
+        [r * phi.cos() * scale, r * phi.sin() * scale, z * scale, 0.0]
+    }
+
+    /// The cosine and sine of the angle to rotate the kernel by in every pixel of the noise tile.
+    /// The angles go around the circle in the order of a Bayer matrix, so that neighbouring pixels
+    /// get angles far apart, which the blur averages out best.
+    fn create_noise_texture(device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
+        const BAYER: [u32; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
+        let data = BAYER
+            .iter()
+            .flat_map(|&n| {
+                let angle = TAU * n as f32 / BAYER.len() as f32;
+                [angle.cos(), angle.sin()]
+            })
+            .collect::<Vec<_>>();
+
+        let noise = Texture::create_2d_texture(
+            device,
+            Self::NOISE_SIZE,
+            Self::NOISE_SIZE,
+            wgpu::TextureFormat::Rg32Float,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            wgpu::FilterMode::Nearest,
+            Some("ssao_noise_texture"),
+        );
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &noise.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(Self::NOISE_SIZE * std::mem::size_of::<[f32; 2]>() as u32),
+                rows_per_image: Some(Self::NOISE_SIZE),
+            },
+            noise.size,
+        );
+        noise
+    }
+
+    fn create_targets(device: &wgpu::Device, depth: &Texture) -> (Texture, Texture) {
+        let create = |label| {
+            Texture::create_2d_texture(
+                device,
+                depth.size.width,
+                depth.size.height,
+                Self::FORMAT,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                wgpu::FilterMode::Nearest,
+                Some(label),
+            )
+        };
+        (
+            create("ssao_occlusion_texture"),
+            create("ssao_scratch_texture"),
+        )
+    }
+
+    fn create_bind_groups(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform: &wgpu::Buffer,
+        depth: &Texture,
+        noise: &Texture,
+        [occlusion, scratch]: [&Texture; 2],
+    ) -> [wgpu::BindGroup; 3] {
+        // A pass can't read the texture it writes, so the occlusion pass gets the scratch texture
+        // even though it doesn't read it
+        [scratch, occlusion, scratch].map(|source| {
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("ssao_bind_group"),
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniform.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&depth.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&noise.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&source.view),
+                    },
+                ],
+            })
+        })
+    }
+
+    /// Recreates the occlusion at the size of the new `depth` buffer. The bind group with
+    /// [Self::bind_group_entry] has to be recreated after this.
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, depth: &Texture) {
+        (self.occlusion, self.scratch) = Self::create_targets(device, depth);
+        self.bind_groups = Self::create_bind_groups(
+            device,
+            &self.layout,
+            &self.uniform,
+            depth,
+            &self.noise,
+            [&self.occlusion, &self.scratch],
+        );
+    }
+
+    /// The entry for reading the occlusion at `binding` in the bind group of another pass.
+    pub(crate) fn layout_entry(
+        binding: u32,
+        visibility: wgpu::ShaderStages,
+    ) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        }
+    }
+
+    /// The occlusion, for [Self::layout_entry].
+    pub(crate) fn bind_group_entry(&self, binding: u32) -> wgpu::BindGroupEntry<'_> {
+        wgpu::BindGroupEntry {
+            binding,
+            resource: wgpu::BindingResource::TextureView(&self.occlusion.view),
+        }
+    }
+
+    pub(crate) fn update(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.uniform,
+            0,
+            bytemuck::cast_slice(&[SsaoUniform::from(self.settings)]),
+        );
+    }
+
+    /// Works out the occlusion from the depth buffer, which has to hold everything opaque by now.
+    pub(crate) fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        let passes = [
+            (
+                "SSAO Pass",
+                &self.occlusion_pipeline,
+                &self.occlusion,
+                &self.bind_groups[0],
+            ),
+            (
+                "SSAO Horizontal Blur Pass",
+                &self.horizontal_blur_pipeline,
+                &self.scratch,
+                &self.bind_groups[1],
+            ),
+            (
+                "SSAO Vertical Blur Pass",
+                &self.vertical_blur_pipeline,
+                &self.occlusion,
+                &self.bind_groups[2],
+            ),
+        ];
+        for (label, pipeline, target, bind_group) in passes {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some(label),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
+                depth_stencil_attachment: None,
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            // Switched off, clearing the occlusion is all there is to do
+            if !self.enabled {
+                break;
+            }
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.set_bind_group(1, camera_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+    }
+}
@@ -1,16 +1,45 @@
 use crate::{
+    animation::{AnimationPlayer, JointPalette},
+    anti_aliasing::AntiAliasing,
+    batch::{BatchStats, DrawArgs, DrawBatch},
+    bloom::Bloom,
     camera::{Camera, CameraController, CameraUniform, Projection},
-    hdr,
-    instance::{Instance as ObjectInstance, InstanceRaw},
+    clustered::{ClusteredLights, PointLight},
+    color_grading::{ColorGrading, LutLoader},
+    culling::{CullStats, Frustum},
+    debug_draw::DebugDraw,
+    debug_view::{DebugMode, DebugView},
+    deferred::{GBuffer, RenderPath},
+    depth_of_field::DepthOfField,
+    fog::Fog,
+    geometry::GeometryArena,
+    gpu_culling::{CullPipeline, CulledInstances},
+    hdr::{self, Tonemapping},
+    instance::{Instance as ObjectInstance, InstanceId, InstanceRaw, InstanceSet},
     light::LightUniform,
-    model::{DrawLight, DrawModel, Model, ModelVertex, Vertex},
+    lod::LodSelector,
+    material_pipelines::{MaterialPass, MaterialPipelines, MaterialPipelinesDescriptor},
+    model::{
+        AlphaMode, DrawLight, Material, MaterialOptions, Model, ModelVertex, SkinnedVertex, Vertex,
+    },
+    morph::MorphPipeline,
+    oit::WeightedBlendedOit,
     resources,
+    scene::{Attachment, NodeId, SceneGraph, Transform},
+    scene_bind_group::{SceneBindGroup, SceneResources},
+    sky::Sky,
+    ssao::Ssao,
     texture::Texture,
+    transparency::{SortedInstances, TransparencyMode},
+    velocity::VelocityBuffer,
 };
-use cgmath::Rotation3;
+use cgmath::{MetricSpace, Rotation3, SquareMatrix};
+use std::f32::consts::SQRT_2;
 use wgpu::util::DeviceExt;
 use wgpu::PipelineLayout;
 use wgpu::{Device, RenderPipeline};
+use winit::event::{ElementState, KeyEvent, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey::Code};
 use winit::window::Window;
 
 pub(super) struct State<'window> {
@@ -22,26 +51,89 @@ pub(super) struct State<'window> {
     // The window must be declared after the surface so it gets dropped after it as the surface
     // contains unsafe references to the window's resources.
     window: &'window Window,
-    render_pipeline: wgpu::RenderPipeline,
+    material_pipelines: MaterialPipelines,
     camera: Camera,
     projection: Projection,
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
     pub(crate) camera_controller: CameraController,
-    instances: Vec<ObjectInstance>,
+    /// Its transparency mode is switched with the T key
+    scene: SceneGraph,
+    carousel: NodeId,
+    lod_selector: LodSelector,
+    /// The level of detail of every cube in the scene, for the hysteresis of the selection
+    cube_lods: Vec<usize>,
+    /// The visible cubes in `instance_buffer`, grouped by level of detail
+    cube_lod_ranges: Vec<std::ops::Range<u32>>,
     instance_buffer: wgpu::Buffer,
+    /// Only used if the adapter supports culling on the GPU, otherwise the cubes are culled on the CPU
+    /// and written into `instance_buffer`.
+    cull_pipeline: Option<CullPipeline>,
+    culled_cubes: Option<CulledInstances>,
+    extra_cubes: InstanceSet,
+    extra_cube_ids: Vec<InstanceId>,
+    /// The visible extra cubes that aren't blended, grouped by material
+    extra_cube_ranges: Vec<(Option<usize>, std::ops::Range<u32>)>,
+    /// The visible extra cubes that are blended, sorted back to front
+    transparent_cubes: SortedInstances,
+    oit: WeightedBlendedOit,
+    /// Switched with the G key
+    render_path: RenderPath,
+    gbuffer: GBuffer,
+    extra_cubes_spawned: u32,
+    /// The materials the extra cubes take turns using
+    extra_cube_materials: [Option<usize>; 4],
+    cull_stats: CullStats,
+    batch_stats: BatchStats,
     depth_texture: Texture,
+    /// Only drawn while temporal anti-aliasing is on
+    velocity: VelocityBuffer,
+    /// Darkens the ambient light where surfaces close in on each other. Switched with the K key.
+    ssao: Ssao,
+    /// Distance and height fog in the lit shaders. Switched with the 8 key.
+    fog: Fog,
+    /// The sky behind the scene, which also places and colors the main light while it is on.
+    /// Switched with F1, with the time of day on F2 and F3, the passing of time on F4 and the
+    /// turbidity on F5 and F6.
+    sky: Sky,
+    /// Replaces the lit color of the models with what goes into it. Cycled with F7 and F8.
+    debug_view: DebugView,
+    /// Lines drawn over the scene, gathered anew every frame.
+    debug_draw: DebugDraw,
+    /// The view projection of the camera when the debug overlay was switched on with F9, whose
+    /// frustum the overlay draws along with the bounds of the cubes and the lights. None while it
+    /// is off.
+    debug_overlay: Option<cgmath::Matrix4<f32>>,
     object_model: Model,
     light: LightUniform,
     light_buffer: wgpu::Buffer,
-    light_bind_group: wgpu::BindGroup,
-    light_bind_group_layout: wgpu::BindGroupLayout,
+    scene_bind_group: SceneBindGroup,
     light_render_pipeline: wgpu::RenderPipeline,
+    /// The small lights between the cubes, binned into clusters every frame.
+    point_lights: Vec<PointLight>,
+    clusters: ClusteredLights,
     hdr: hdr::HdrPipeline,
+    /// The LUT of [LutLoader::files] color grading uses, switched with the 5 key. None switches
+    /// grading off.
+    lut: Option<String>,
+    lut_loader: LutLoader,
+    skinned_material_pipelines: MaterialPipelines,
+    skinned_model: Model,
+    skinned_instance_buffer: wgpu::Buffer,
+    joint_palette: JointPalette,
+    animation_player: AnimationPlayer,
+    morph_pipeline: MorphPipeline,
+    morph_model: Model,
+    morph_instance_buffer: wgpu::Buffer,
+    morph_player: AnimationPlayer,
 }
 
 impl<'window> State<'window> {
+    const NUM_MORPH_INSTANCES: u32 = 3;
+    /// The index the cube model is attached to scene nodes with.
+    const CUBE_MODEL: usize = 0;
+
     // Creating some of the wgpu types requires async code
     pub(crate) async fn new(window: &'window Window) -> Self {
         let size = window.inner_size();
@@ -116,9 +208,11 @@ impl<'window> State<'window> {
             view_formats: vec![],
         };
         surface.configure(&device, &config);
-        let hdr = hdr::HdrPipeline::new(&device, &config);
-
         let depth_texture = Texture::create_depth_texture(&device, &config, "depth_texture");
+        let velocity = VelocityBuffer::new(&device, config.width, config.height);
+
+        let projection =
+            Projection::new(config.width, config.height, cgmath::Deg(45.0), 0.1, 100.0);
 
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -157,13 +251,22 @@ impl<'window> State<'window> {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
+                    // opacity and alpha cutoff
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
 
         let camera = Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0));
-        let projection =
-            Projection::new(config.width, config.height, cgmath::Deg(45.0), 0.1, 100.0);
         let camera_controller = CameraController::new(4.0, 0.4);
 
         let camera_uniform = {
@@ -178,6 +281,17 @@ impl<'window> State<'window> {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let hdr = hdr::HdrPipeline::new(
+            &device,
+            &queue,
+            &config,
+            &projection,
+            &camera_buffer,
+            &velocity,
+            &depth_texture,
+        );
+        let oit = WeightedBlendedOit::new(&device, config.width, config.height, hdr.format());
+
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Camera Bind Group Layout"),
@@ -207,55 +321,308 @@ impl<'window> State<'window> {
             }],
         });
 
-        let (light_buffer, light_bind_group_layout, light_bind_group) =
-            LightUniform::create_bind_group(&device);
+        let clusters = ClusteredLights::new(&device);
+        let ssao = Ssao::new(&device, &queue, &camera_bind_group_layout, &depth_texture);
+        let fog = Fog::new(&device);
+        let sky = Sky::new(&device, &camera_bind_group_layout, hdr.format());
+        let debug_view = DebugView::new(&device);
+        let debug_draw = DebugDraw::new(&device, &camera_bind_group_layout, hdr.format());
+        let light_buffer = LightUniform::create_buffer(&device);
+        let scene_bind_group = SceneBindGroup::new(
+            &device,
+            SceneResources {
+                light: &light_buffer,
+                clusters: &clusters,
+                ssao: &ssao,
+                fog: &fog,
+                sky: &sky,
+                debug_view: &debug_view,
+            },
+        );
+        let gbuffer = GBuffer::new(
+            &device,
+            &depth_texture,
+            &camera_bind_group_layout,
+            scene_bind_group.layout(),
+            hdr.format(),
+        );
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
                     &texture_bind_group_layout,
                     &camera_bind_group_layout,
-                    &light_bind_group_layout,
+                    scene_bind_group.layout(),
                 ],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Shader"),
-                source: wgpu::ShaderSource::Wgsl(
-                    include_str!("shaders/shader_instances.wgsl").into(),
-                ),
-            };
+        // There is no multisampling yet, so masked materials rely on discarding fragments alone
+        let material_pipelines = MaterialPipelines::new(
+            &device,
+            MaterialPipelinesDescriptor {
+                label: "Model Render Pipeline",
+                layout: &render_pipeline_layout,
+                shader: wgpu::ShaderModuleDescriptor {
+                    label: Some("Shader"),
+                    // Only the vertex stage differs between the kinds of meshes
+                    source: wgpu::ShaderSource::Wgsl(
+                        concat!(
+                            include_str!("shaders/scene.wgsl"),
+                            include_str!("shaders/model.wgsl"),
+                            include_str!("shaders/shader_instances.wgsl"),
+                        )
+                        .into(),
+                    ),
+                },
+                vertex_layouts: &[ModelVertex::desc(), InstanceRaw::desc()],
+                velocity_vertex_layouts: &[ModelVertex::desc(), InstanceRaw::velocity_desc()],
+                color_format: hdr.format(),
+                depth_format: Texture::DEPTH_FORMAT,
+            },
+        );
 
-            Self::create_render_pipeline(
+        // All the models share a few large vertex and index buffers
+        let mut geometry = GeometryArena::new("Scene Geometry");
+        // The bevels of the cube are most of its triangles, and simplify away into levels of detail
+        let mut object_model = resources::load_model(
+            "cube.obj",
+            &device,
+            &queue,
+            &texture_bind_group_layout,
+            &mut geometry,
+        )
+        .await
+        .unwrap();
+        // A plain white material, so that the tint of the cubes drawn with it shows as is
+        object_model.materials.push(Material::new(
+            &device,
+            "plain",
+            Texture::from_color(&device, &queue, [255; 4], "plain", false).unwrap(),
+            Texture::from_color(&device, &queue, [128, 128, 255, 255], "plain", true).unwrap(),
+            &texture_bind_group_layout,
+        ));
+        let plain_material = object_model.materials.len() - 1;
+        // Tinted glass, drawn after everything opaque
+        object_model.materials.push(Material::with_options(
+            &device,
+            "glass",
+            Texture::from_color(&device, &queue, [255; 4], "glass", false).unwrap(),
+            Texture::from_color(&device, &queue, [128, 128, 255, 255], "glass", true).unwrap(),
+            MaterialOptions {
+                alpha_mode: AlphaMode::Blend,
+                opacity: 0.35,
+                ..Default::default()
+            },
+            &texture_bind_group_layout,
+        ));
+        let glass_material = object_model.materials.len() - 1;
+        // A grid of round holes, through which the inside of the cube shows
+        let holes = image::RgbaImage::from_fn(64, 64, |x, y| {
+            let (dx, dy) = ((x % 16) as f32 - 7.5, (y % 16) as f32 - 7.5);
+            let alpha = if dx * dx + dy * dy < 36.0 { 0 } else { 255 };
+            image::Rgba([255, 255, 255, alpha])
+        });
+        object_model.materials.push(Material::with_options(
+            &device,
+            "cutout",
+            Texture::from_image(
                 &device,
-                &render_pipeline_layout,
-                hdr.format(),
-                Some(Texture::DEPTH_FORMAT),
-                &[ModelVertex::desc(), InstanceRaw::desc()],
-                shader,
-                wgpu::PrimitiveTopology::TriangleList,
-                Some("Model Render Pipeline"),
+                &queue,
+                &image::DynamicImage::ImageRgba8(holes),
+                Some("cutout"),
+                false,
+            )
+            .unwrap(),
+            Texture::from_color(&device, &queue, [128, 128, 255, 255], "cutout", true).unwrap(),
+            MaterialOptions {
+                alpha_mode: AlphaMode::Mask,
+                double_sided: true,
+                ..Default::default()
+            },
+            &texture_bind_group_layout,
+        ));
+        let cutout_material = object_model.materials.len() - 1;
+        let extra_cube_materials = [
+            None,
+            Some(plain_material),
+            Some(glass_material),
+            Some(cutout_material),
+        ];
+
+        // The grid of cubes sits at the root of the scene. A carousel in the middle carries a small cart
+        // around, with the lamp riding on top of the cart.
+        let mut scene = SceneGraph::new();
+        for instance in ObjectInstance::create_grid() {
+            let node = scene.add_node(instance.transform(), None);
+            scene.attach(node, Attachment::Model(Self::CUBE_MODEL));
+        }
+        let carousel = scene.add_node(Transform::default(), None);
+        let cart = scene.add_node(
+            Transform::new(
+                cgmath::Vector3::new(2.0, 1.5, 2.0),
+                cgmath::Quaternion::from_angle_y(cgmath::Deg(0.0)),
+            ),
+            Some(carousel),
+        );
+        let cart_body = scene.add_node(
+            Transform {
+                scale: cgmath::Vector3::new(0.4, 0.15, 0.25),
+                ..Default::default()
+            },
+            Some(cart),
+        );
+        scene.attach(cart_body, Attachment::Model(Self::CUBE_MODEL));
+        let lamp = scene.add_node(
+            Transform::new(
+                cgmath::Vector3::new(0.0, 0.5, 0.0),
+                cgmath::Quaternion::from_angle_y(cgmath::Deg(0.0)),
+            ),
+            Some(cart),
+        );
+        scene.attach(lamp, Attachment::Light);
+        // The camera moves relative to this rig, so moving the rig moves the camera along with it.
+        let camera_rig = scene.add_node(Transform::default(), None);
+        scene.attach(camera_rig, Attachment::Camera);
+        scene.update_world_matrices();
+
+        let instance_data = scene.model_instances(Self::CUBE_MODEL);
+        let num_instances = instance_data.len() as u32;
+        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Instance Buffer"),
+            contents: bytemuck::cast_slice(&instance_data),
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        });
+        let lod_selector = LodSelector::default();
+        let cull_pipeline =
+            CullPipeline::is_supported(&adapter).then(|| CullPipeline::new(&device));
+        let culled_cubes = cull_pipeline.as_ref().map(|pipeline| {
+            let mut culled = CulledInstances::new(
+                &device,
+                pipeline,
+                &object_model,
+                num_instances,
+                &lod_selector,
+            );
+            culled.write_instances(&queue, &instance_data);
+            culled
+        });
+
+        // Extra cubes that can be added and removed at runtime with the + and - keys
+        let mut extra_cubes = InstanceSet::new(&device, "Extra Cube Instance Buffer", 4);
+        let extra_cubes_spawned = 4;
+        let extra_cube_ids = (0..extra_cubes_spawned)
+            .map(|n| extra_cubes.add(Self::extra_cube(n, &extra_cube_materials)))
+            .collect();
+        extra_cubes.upload(&device, &queue);
+        let transparent_cubes =
+            SortedInstances::new(&device, "Transparent Cube Instance Buffer", 4);
+
+        let joint_bind_group_layout = JointPalette::create_bind_group_layout(&device);
+        let joint_palette = JointPalette::new(&device, &joint_bind_group_layout);
+        let skinned_material_pipelines = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Skinned Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &camera_bind_group_layout,
+                    scene_bind_group.layout(),
+                    &joint_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+            MaterialPipelines::new(
+                &device,
+                MaterialPipelinesDescriptor {
+                    label: "Skinned Render Pipeline",
+                    layout: &layout,
+                    shader: wgpu::ShaderModuleDescriptor {
+                        label: Some("Skinned Shader"),
+                        source: wgpu::ShaderSource::Wgsl(
+                            concat!(
+                                include_str!("shaders/scene.wgsl"),
+                                include_str!("shaders/model.wgsl"),
+                                include_str!("shaders/shader_skinned.wgsl"),
+                            )
+                            .into(),
+                        ),
+                    },
+                    vertex_layouts: &[SkinnedVertex::desc(), InstanceRaw::desc()],
+                    velocity_vertex_layouts: &[SkinnedVertex::desc(), InstanceRaw::velocity_desc()],
+                    color_format: hdr.format(),
+                    depth_format: Texture::DEPTH_FORMAT,
+                },
             )
         };
 
-        let object_model =
-            resources::load_model("cube.obj", &device, &queue, &texture_bind_group_layout)
-                .await
-                .unwrap();
-        let (instances, instance_buffer) = ObjectInstance::create_instances(&device);
+        let skinned_model = resources::load_gltf_model(
+            "skinned_column.gltf",
+            &device,
+            &queue,
+            &texture_bind_group_layout,
+            &mut geometry,
+        )
+        .await
+        .unwrap();
+        // The animated model stands on top of the cube in the middle of the grid.
+        let skinned_instance_buffer =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Skinned Instance Buffer"),
+                contents: bytemuck::cast_slice(&[ObjectInstance::new(
+                    cgmath::Vector3::new(0.0, 1.0, 0.0),
+                    cgmath::Quaternion::from_angle_y(cgmath::Deg(0.0)),
+                )
+                .to_raw()]),
+                usage: wgpu::BufferUsages::VERTEX,
+            });
+        let animation_player =
+            AnimationPlayer::new(skinned_model.find_animation("sway").unwrap_or(0));
+
+        let morph_pipeline = MorphPipeline::new(&device);
+        let mut morph_model = resources::load_gltf_model(
+            "morph_column.gltf",
+            &device,
+            &queue,
+            &texture_bind_group_layout,
+            &mut geometry,
+        )
+        .await
+        .unwrap();
+        morph_pipeline.prepare(&device, &mut morph_model, Self::NUM_MORPH_INSTANCES);
+        // A row of morphing columns in the gaps between the cubes in front of the center
+        let morph_instances = (0..Self::NUM_MORPH_INSTANCES)
+            .map(|i| {
+                ObjectInstance::new(
+                    cgmath::Vector3::new(-1.5 + 3.0 * i as f32, -1.0, 1.5),
+                    cgmath::Quaternion::from_angle_y(cgmath::Deg(0.0)),
+                )
+                .to_raw()
+            })
+            .collect::<Vec<_>>();
+        let morph_instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Morph Instance Buffer"),
+            contents: bytemuck::cast_slice(&morph_instances),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let morph_player = AnimationPlayer::new(morph_model.find_animation("breathe").unwrap_or(0));
 
         let light_render_pipeline = {
             let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Light Render Pipeline Layout"),
-                bind_group_layouts: &[&camera_bind_group_layout, &light_bind_group_layout],
+                bind_group_layouts: &[&camera_bind_group_layout, scene_bind_group.layout()],
                 push_constant_ranges: &[],
             });
 
             let shader = wgpu::ShaderModuleDescriptor {
                 label: Some("Light Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/light.wgsl").into()),
+                source: wgpu::ShaderSource::Wgsl(
+                    concat!(
+                        include_str!("shaders/scene.wgsl"),
+                        include_str!("shaders/light.wgsl"),
+                    )
+                    .into(),
+                ),
             };
 
             Self::create_render_pipeline(
@@ -277,23 +644,59 @@ impl<'window> State<'window> {
             config,
             size,
             window,
-            render_pipeline,
+            material_pipelines,
             camera,
             camera_buffer,
             camera_bind_group,
             camera_uniform,
             camera_controller,
-            instances,
+            scene,
+            carousel,
+            lod_selector,
+            cube_lods: vec![0; num_instances as usize],
+            cube_lod_ranges: Vec::new(),
             instance_buffer,
+            cull_pipeline,
+            culled_cubes,
+            extra_cubes,
+            extra_cube_ids,
+            extra_cube_ranges: Vec::new(),
+            transparent_cubes,
+            oit,
+            render_path: RenderPath::default(),
+            gbuffer,
+            extra_cubes_spawned,
+            extra_cube_materials,
+            cull_stats: CullStats::default(),
+            batch_stats: BatchStats::default(),
             depth_texture,
+            velocity,
+            ssao,
+            fog,
+            sky,
+            debug_view,
+            debug_draw,
+            debug_overlay: None,
             object_model,
             light_buffer,
-            light_bind_group,
-            light_bind_group_layout,
+            scene_bind_group,
             light: LightUniform::default(),
             light_render_pipeline,
+            point_lights: Self::point_lights(),
+            clusters,
             projection,
             hdr,
+            lut: None,
+            lut_loader: LutLoader::new(),
+            skinned_material_pipelines,
+            skinned_model,
+            skinned_instance_buffer,
+            joint_palette,
+            animation_player,
+            morph_pipeline,
+            morph_model,
+            morph_instance_buffer,
+            morph_player,
         }
     }
 
@@ -373,32 +776,681 @@ impl<'window> State<'window> {
             self.surface.configure(&self.device, &self.config);
             self.depth_texture =
                 Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+            self.velocity
+                .resize(&self.device, new_size.width, new_size.height);
             self.hdr
+                .resize(&self.device, &self.velocity, &self.depth_texture);
+            self.oit
                 .resize(&self.device, new_size.width, new_size.height);
+            self.gbuffer.resize(&self.device, &self.depth_texture);
+            self.ssao.resize(&self.device, &self.depth_texture);
+            self.scene_bind_group.rebind(
+                &self.device,
+                SceneResources {
+                    light: &self.light_buffer,
+                    clusters: &self.clusters,
+                    ssao: &self.ssao,
+                    fog: &self.fog,
+                    sky: &self.sky,
+                    debug_view: &self.debug_view,
+                },
+            );
         }
     }
 
-    pub(crate) fn input(&mut self, event: &winit::event::WindowEvent) -> bool {
+    /// The `n`th extra cube. They are spread out on a circle above the grid, taking turns using the
+    /// cube's own material and each of the other `materials`.
+    fn extra_cube(n: u32, materials: &[Option<usize>]) -> ObjectInstance {
+        const TINTS: [[f32; 4]; 6] = [
+            [1.0, 0.3, 0.3, 1.0],
+            [0.3, 1.0, 0.3, 1.0],
+            [0.3, 0.3, 1.0, 1.0],
+            [1.0, 1.0, 0.3, 1.0],
+            [1.0, 0.3, 1.0, 1.0],
+            [0.3, 1.0, 1.0, 1.0],
+        ];
+        let angle = cgmath::Deg(25.0 * n as f32);
+        let mut cube = ObjectInstance::new(
+            cgmath::Quaternion::from_angle_y(angle) * cgmath::Vector3::new(8.0, 3.0, 0.0),
+            cgmath::Quaternion::from_angle_y(angle),
+        );
+        cube.scale = cgmath::Vector3::new(0.3, 0.3 + 0.2 * (n % 4) as f32, 0.3);
+        cube.tint = TINTS[n as usize % TINTS.len()];
+        cube.material = materials[n as usize % materials.len()];
+        cube
+    }
+
+    /// A field of small colored lights hovering over the grid of cubes.
+    fn point_lights() -> Vec<PointLight> {
+        const COLORS: [[f32; 3]; 6] = [
+            [1.0, 0.2, 0.2],
+            [0.2, 1.0, 0.2],
+            [0.2, 0.4, 1.0],
+            [1.0, 0.8, 0.2],
+            [1.0, 0.2, 1.0],
+            [0.2, 1.0, 1.0],
+        ];
+        const LIGHTS_PER_ROW: usize = 16;
+        const SPACE_BETWEEN: f32 = 1.8;
+        (0..LIGHTS_PER_ROW * LIGHTS_PER_ROW)
+            .map(|i| {
+                let (x, z) = (i % LIGHTS_PER_ROW, i / LIGHTS_PER_ROW);
+                let offset = |n: usize| SPACE_BETWEEN * (n as f32 - LIGHTS_PER_ROW as f32 / 2.0);
+                PointLight {
+                    position: [offset(x), 1.2 + 0.4 * (i % 3) as f32, offset(z)],
+                    radius: 2.5,
+                    color: COLORS[(x + 2 * z) % COLORS.len()],
+                    intensity: 3.0,
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn input(&mut self, event: &WindowEvent) -> bool {
+        if let WindowEvent::KeyboardInput {
+            event:
+                KeyEvent {
+                    physical_key: Code(key),
+                    state: ElementState::Pressed,
+                    ..
+                },
+            ..
+        } = event
+        {
+            match key {
+                KeyCode::Equal | KeyCode::NumpadAdd => {
+                    let cube =
+                        Self::extra_cube(self.extra_cubes_spawned, &self.extra_cube_materials);
+                    self.extra_cube_ids.push(self.extra_cubes.add(cube));
+                    self.extra_cubes_spawned += 1;
+                    return true;
+                }
+                KeyCode::Minus | KeyCode::NumpadSubtract => {
+                    // Remove the oldest cube first
+                    if !self.extra_cube_ids.is_empty() {
+                        self.extra_cubes.remove(self.extra_cube_ids.remove(0));
+                    }
+                    return true;
+                }
+                KeyCode::KeyG => {
+                    self.render_path = self.render_path.next();
+                    log::info!("Render path: {:?}", self.render_path);
+                    return true;
+                }
+                // Bloom intensity with [ and ], threshold with ; and ', radius with , and .
+                KeyCode::BracketLeft
+                | KeyCode::BracketRight
+                | KeyCode::Semicolon
+                | KeyCode::Quote
+                | KeyCode::Comma
+                | KeyCode::Period => {
+                    let Some(bloom) = self.hdr.post_process().effect_mut::<Bloom>() else {
+                        return true;
+                    };
+                    let bloom = &mut bloom.settings;
+                    match key {
+                        KeyCode::BracketLeft => bloom.intensity = (bloom.intensity - 0.02).max(0.0),
+                        KeyCode::BracketRight => bloom.intensity += 0.02,
+                        KeyCode::Semicolon => bloom.threshold = (bloom.threshold - 0.1).max(0.0),
+                        KeyCode::Quote => bloom.threshold += 0.1,
+                        KeyCode::Comma => bloom.radius = (bloom.radius - 0.25).max(0.25),
+                        _ => bloom.radius += 0.25,
+                    }
+                    log::info!("Bloom: {bloom:?}");
+                    return true;
+                }
+                KeyCode::KeyB => {
+                    if let Some(enabled) = self.hdr.post_process().toggle("Bloom") {
+                        log::info!("Bloom: {}", if enabled { "on" } else { "off" });
+                    }
+                    return true;
+                }
+                // Moves bloom one stage further along until it is past tonemapping, and then back
+                // in front of it, to show what happens when it runs after tonemapping
+                KeyCode::KeyM => {
+                    let post_process = self.hdr.post_process();
+                    if let Some(index) = post_process.position("Bloom") {
+                        match post_process.position("Tonemapping") {
+                            Some(last) if index < last => {
+                                post_process.move_stage("Bloom", index + 1)
+                            }
+                            // Right in front of tonemapping, so still after TAA
+                            Some(last) => post_process.move_stage("Bloom", last),
+                            None => {}
+                        }
+                        log::info!("Post-processing: {}", post_process.describe());
+                    }
+                    return true;
+                }
+                KeyCode::KeyV => {
+                    let Some(tonemapping) = self.hdr.post_process().effect_mut::<Tonemapping>()
+                    else {
+                        return true;
+                    };
+                    let tonemapping = &mut tonemapping.settings;
+                    tonemapping.auto_exposure = !tonemapping.auto_exposure;
+                    log::info!("Auto exposure: {}", tonemapping.auto_exposure);
+                    return true;
+                }
+                KeyCode::KeyO => {
+                    let Some(tonemapping) = self.hdr.post_process().effect_mut::<Tonemapping>()
+                    else {
+                        return true;
+                    };
+                    let tonemapping = &mut tonemapping.settings;
+                    tonemapping.operator = tonemapping.operator.next();
+                    log::info!("Tonemapping: {:?}", tonemapping.operator);
+                    return true;
+                }
+                // Exposure with Q and E, white point with Z and X
+                KeyCode::KeyQ | KeyCode::KeyE | KeyCode::KeyZ | KeyCode::KeyX => {
+                    let Some(tonemapping) = self.hdr.post_process().effect_mut::<Tonemapping>()
+                    else {
+                        return true;
+                    };
+                    let tonemapping = &mut tonemapping.settings;
+                    match key {
+                        KeyCode::KeyQ => tonemapping.exposure -= 0.5,
+                        KeyCode::KeyE => tonemapping.exposure += 0.5,
+                        KeyCode::KeyZ => {
+                            tonemapping.white_point = (tonemapping.white_point - 1.0).max(1.0)
+                        }
+                        _ => tonemapping.white_point += 1.0,
+                    }
+                    log::info!(
+                        "Exposure: {} EV, white point: {}",
+                        tonemapping.exposure,
+                        tonemapping.white_point
+                    );
+                    return true;
+                }
+                KeyCode::KeyN => {
+                    let anti_aliasing = self.hdr.anti_aliasing().next();
+                    self.hdr.set_anti_aliasing(anti_aliasing);
+                    log::info!("Anti-aliasing: {anti_aliasing:?}");
+                    return true;
+                }
+                KeyCode::KeyT => {
+                    self.scene.transparency = self.scene.transparency.next();
+                    log::info!("Transparency: {:?}", self.scene.transparency);
+                    return true;
+                }
+                KeyCode::KeyK => {
+                    self.ssao.enabled = !self.ssao.enabled;
+                    log::info!("SSAO: {}", if self.ssao.enabled { "on" } else { "off" });
+                    return true;
+                }
+                // SSAO radius with J and L, intensity with U and I, sample count with H
+                KeyCode::KeyJ | KeyCode::KeyL | KeyCode::KeyU | KeyCode::KeyI | KeyCode::KeyH => {
+                    let ssao = &mut self.ssao.settings;
+                    match key {
+                        KeyCode::KeyJ => ssao.radius = (ssao.radius - 0.1).max(0.1),
+                        KeyCode::KeyL => ssao.radius += 0.1,
+                        KeyCode::KeyU => ssao.intensity = (ssao.intensity - 0.25).max(0.25),
+                        KeyCode::KeyI => ssao.intensity += 0.25,
+                        _ => {
+                            ssao.sample_count = match ssao.sample_count {
+                                n if n >= Ssao::MAX_SAMPLES => 8,
+                                n => n * 2,
+                            }
+                        }
+                    }
+                    log::info!("SSAO: {ssao:?}");
+                    return true;
+                }
+                KeyCode::KeyF => {
+                    let post_process = self.hdr.post_process();
+                    if let Some(enabled) = post_process.toggle(DepthOfField::LABEL) {
+                        log::info!("Depth of field: {}", if enabled { "on" } else { "off" });
+                    }
+                    return true;
+                }
+                // Depth of field autofocus with P, f-stop with R and Y, focus distance with 1 and 2,
+                // focal length with 3 and 4
+                KeyCode::KeyP
+                | KeyCode::KeyR
+                | KeyCode::KeyY
+                | KeyCode::Digit1
+                | KeyCode::Digit2
+                | KeyCode::Digit3
+                | KeyCode::Digit4 => {
+                    let Some(dof) = self.hdr.post_process().effect_mut::<DepthOfField>() else {
+                        return true;
+                    };
+                    let dof = &mut dof.settings;
+                    match key {
+                        KeyCode::KeyP => dof.autofocus = !dof.autofocus,
+                        // A full stop at a time, each of which halves the area of the aperture
+                        KeyCode::KeyR => dof.f_stop = (dof.f_stop / SQRT_2).max(1.0),
+                        KeyCode::KeyY => dof.f_stop = (dof.f_stop * SQRT_2).min(22.0),
+                        KeyCode::Digit1 => dof.focus_distance = (dof.focus_distance - 0.5).max(0.5),
+                        KeyCode::Digit2 => dof.focus_distance += 0.5,
+                        KeyCode::Digit3 => dof.focal_length = (dof.focal_length - 10.0).max(10.0),
+                        _ => dof.focal_length = (dof.focal_length + 10.0).min(300.0),
+                    }
+                    log::info!("Depth of field: {dof:?}");
+                    return true;
+                }
+                KeyCode::Digit5 => {
+                    let files = self.lut_loader.files();
+                    let next = match &self.lut {
+                        None => files.first(),
+                        Some(current) => files.iter().skip_while(|f| *f != current).nth(1),
+                    };
+                    self.lut = next.cloned();
+                    match &self.lut {
+                        // Switched on again once it has loaded
+                        Some(file_name) => self.lut_loader.load(file_name),
+                        None => {
+                            let post_process = self.hdr.post_process();
+                            post_process.set_enabled(ColorGrading::LABEL, false);
+                            log::info!("Color grading: off");
+                        }
+                    }
+                    return true;
+                }
+                // Color grading strength with 6 and 7
+                KeyCode::Digit6 | KeyCode::Digit7 => {
+                    let Some(grading) = self.hdr.post_process().effect_mut::<ColorGrading>() else {
+                        return true;
+                    };
+                    let grading = &mut grading.settings;
+                    grading.strength = match key {
+                        KeyCode::Digit6 => (grading.strength - 0.1).max(0.0),
+                        _ => (grading.strength + 0.1).min(1.0),
+                    };
+                    log::info!("Color grading: {grading:?}");
+                    return true;
+                }
+                KeyCode::Digit8 => {
+                    self.fog.enabled = !self.fog.enabled;
+                    log::info!("Fog: {}", if self.fog.enabled { "on" } else { "off" });
+                    return true;
+                }
+                KeyCode::F1 => {
+                    self.sky.enabled = !self.sky.enabled;
+                    log::info!("Sky: {}", if self.sky.enabled { "on" } else { "off" });
+                    return true;
+                }
+                // Time of day with F2 and F3, the passing of time with F4, turbidity with F5 and F6
+                KeyCode::F2 | KeyCode::F3 | KeyCode::F4 | KeyCode::F5 | KeyCode::F6 => {
+                    let sky = &mut self.sky.settings;
+                    match key {
+                        KeyCode::F2 => sky.time_of_day = (sky.time_of_day - 1.0).rem_euclid(24.0),
+                        KeyCode::F3 => sky.time_of_day = (sky.time_of_day + 1.0).rem_euclid(24.0),
+                        // An hour every second
+                        KeyCode::F4 => sky.time_scale = 1.0 - sky.time_scale.min(1.0),
+                        KeyCode::F5 => sky.turbidity = (sky.turbidity - 0.5).max(2.0),
+                        _ => sky.turbidity = (sky.turbidity + 0.5).min(10.0),
+                    }
+                    log::info!("Sky: {sky:?}");
+                    return true;
+                }
+                KeyCode::F7 | KeyCode::F8 => {
+                    let mode = &mut self.debug_view.mode;
+                    *mode = match key {
+                        KeyCode::F7 => mode.next(),
+                        _ => mode.previous(),
+                    };
+                    log::info!("Debug view: {mode:?}");
+                    return true;
+                }
+                // Freeze the frustum where the camera is now, so that it can be looked at from
+                // elsewhere
+                KeyCode::F9 => {
+                    self.debug_overlay = match self.debug_overlay {
+                        Some(_) => None,
+                        None => Some(self.camera_uniform.view_proj()),
+                    };
+                    log::info!("Debug overlay: {}", self.debug_overlay.is_some());
+                    return true;
+                }
+                // Fog density with 9 and 0, for the distance and height fog alike
+                KeyCode::Digit9 | KeyCode::Digit0 => {
+                    let fog = &mut self.fog.settings;
+                    let scale = match key {
+                        KeyCode::Digit9 => 0.5,
+                        _ => 2.0,
+                    };
+                    fog.density *= scale;
+                    fog.height_density *= scale;
+                    log::info!("Fog: {fog:?}");
+                    return true;
+                }
+                _ => {}
+            }
+        }
         self.camera_controller.process_events(event)
     }
 
     pub(crate) fn update(&mut self, dt: instant::Duration) {
+        // Spin the carousel. The cart and the lamp on top of it follow along.
+        let carousel = self.scene.local_mut(self.carousel);
+        carousel.rotation = cgmath::Quaternion::from_angle_y(cgmath::Deg(60.0 * dt.as_secs_f32()))
+            * carousel.rotation;
+        let scene_changed = self.scene.update_world_matrices();
+
+        // Temporal anti-aliasing renders every frame from a slightly different spot in the pixels
+        let jitter = self.hdr.jitter();
+        self.projection
+            .set_jitter(jitter, self.config.width, self.config.height);
+
         self.camera_controller.update_camera(&mut self.camera, dt);
-        self.camera_uniform
-            .update_view_proj(&self.camera, &self.projection);
+        let previous_camera = self.camera_uniform;
+        let camera_parent = self
+            .scene
+            .nodes_with(Attachment::Camera)
+            .next()
+            .map_or(cgmath::Matrix4::identity(), |node| {
+                self.scene.world_matrix(node)
+            });
+        self.camera_uniform.update_view_proj_attached(
+            &self.camera,
+            &self.projection,
+            camera_parent,
+        );
+        if self.camera_uniform.is_cut_from(&previous_camera) {
+            self.hdr.reset_history();
+        }
         self.queue.write_buffer(
             &self.camera_buffer,
             0,
             bytemuck::cast_slice(&[self.camera_uniform]),
         );
+        // Only the LUT that is still selected gets swapped in, if several were loading at once
+        while let Some((file_name, lut)) = self.lut_loader.poll() {
+            if self.lut.as_ref() != Some(&file_name) {
+                continue;
+            }
+            match lut {
+                Ok(lut) => {
+                    let post_process = self.hdr.post_process();
+                    if let Some(grading) = post_process.effect_mut::<ColorGrading>() {
+                        grading.set_lut(&self.device, &self.queue, &lut);
+                        post_process.set_enabled(ColorGrading::LABEL, true);
+                        log::info!(
+                            "Color grading: {}",
+                            lut.title.as_deref().unwrap_or(&file_name)
+                        );
+                    }
+                }
+                Err(e) => log::error!("{e:?}"),
+            }
+        }
+        self.hdr.update(&self.queue, dt);
+        self.ssao.update(&self.queue);
+        self.sky.update(&self.queue, dt);
+        self.fog.update(&self.queue, self.sky.enabled);
+        self.debug_view.update(&self.queue);
+
+        // Slowly spin the extra cubes
+        for &id in &self.extra_cube_ids {
+            if let Some(cube) = self.extra_cubes.get_mut(id) {
+                cube.rotation =
+                    cgmath::Quaternion::from_angle_y(cgmath::Deg(45.0 * dt.as_secs_f32()))
+                        * cube.rotation;
+            }
+        }
+        self.extra_cubes.upload(&self.device, &self.queue);
 
-        // Update the light
-        let old_position: cgmath::Vector3<_> = self.light.position.into();
-        self.light.position =
-            (cgmath::Quaternion::from_angle_y(cgmath::Deg(60.0 * dt.as_secs_f32())) * old_position)
-                .into();
+        // Throw away every cube outside the view before drawing. The GPU only reports how many cubes
+        // survived to the draw calls, so the stats only count the cubes culled on the CPU.
+        let frustum = Frustum::from_matrix(self.camera_uniform.view_proj());
+        let camera_position = self.camera_uniform.view_position();
+        let tan_half_fovy = self.projection.tan_half_fovy();
+        let bounds = self.object_model.bounds;
+        let mut stats = CullStats::default();
+        let mut is_visible = |model: &cgmath::Matrix4<f32>| {
+            let visible = frustum.is_visible(&bounds, model);
+            stats.record(visible);
+            visible
+        };
+        if let Some(culled_cubes) = &mut self.culled_cubes {
+            // The instances only need to be uploaded again when they have moved
+            if scene_changed {
+                culled_cubes
+                    .write_instances(&self.queue, &self.scene.model_instances(Self::CUBE_MODEL));
+            }
+            culled_cubes.set_view(&self.queue, &frustum, camera_position, tan_half_fovy);
+        } else {
+            // Sort the visible cubes by level of detail, so that each level is drawn with a single
+            // range of instances
+            let cubes = self.scene.model_instances(Self::CUBE_MODEL);
+            let num_lods = self.object_model.num_lods();
+            self.cube_lods.resize(cubes.len(), 0);
+            let mut lod_buckets = vec![Vec::new(); num_lods];
+            for (cube, lod) in cubes.iter().zip(&mut self.cube_lods) {
+                let model = cube.model_matrix();
+                if !is_visible(&model) {
+                    continue;
+                }
+                let sphere = bounds.sphere.transform(&model);
+                let screen_size = LodSelector::screen_size(
+                    sphere.radius,
+                    sphere.center.distance(camera_position),
+                    tan_half_fovy,
+                );
+                *lod = self.lod_selector.select(*lod, screen_size, num_lods);
+                lod_buckets[*lod].push(*cube);
+            }
+            let mut start = 0;
+            self.cube_lod_ranges = lod_buckets
+                .iter()
+                .map(|bucket| {
+                    let range = start..start + bucket.len() as u32;
+                    start = range.end;
+                    range
+                })
+                .collect();
+            let visible_cubes = lod_buckets.concat();
+            if !visible_cubes.is_empty() {
+                self.queue.write_buffer(
+                    &self.instance_buffer,
+                    0,
+                    bytemuck::cast_slice(&visible_cubes),
+                );
+            }
+        }
+        // The extra cubes keep their place in the buffer, culled ones are left out of the draw calls.
+        // Blended ones are left out too, and drawn back to front from a buffer of their own instead.
+        // The cube's own material is opaque, so only overrides can be blended.
+        let materials = &self.object_model.materials;
+        let is_blended = |cube: &ObjectInstance| {
+            cube.material
+                .is_some_and(|m| materials[m].alpha_mode == AlphaMode::Blend)
+        };
+        let mut transparent_cubes = Vec::new();
+        self.extra_cube_ranges = self.extra_cubes.draw_ranges(|cube| {
+            let model = cube.transform().to_matrix();
+            if !is_visible(&model) {
+                return false;
+            }
+            if is_blended(cube) {
+                transparent_cubes.push((cube.material, cube.to_raw()));
+                return false;
+            }
+            true
+        });
+        self.transparent_cubes.update(
+            &self.device,
+            &self.queue,
+            camera_position,
+            transparent_cubes,
+        );
+        // The counts change whenever the camera or a cube moves, which is too often for info
+        if stats != self.cull_stats {
+            log::debug!(
+                "Frustum culling: {} instances visible, {} culled",
+                stats.visible,
+                stats.culled
+            );
+            self.cull_stats = stats;
+        }
+
+        // With the sky on, the light is the sun or the moon, far enough away that only its
+        // direction matters. Otherwise it sits wherever its node is.
+        if self.sky.enabled {
+            let (direction, color) = self.sky.main_light();
+            self.light = LightUniform::directional(direction.into(), color);
+        } else if let Some(lamp) = self.scene.nodes_with(Attachment::Light).next() {
+            let position = self.scene.world_position(lamp).into();
+            self.light = LightUniform::new(position, LightUniform::default().color);
+        }
         self.queue
             .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light]));
+
+        // The point lights slowly circle the middle of the grid
+        let spin = cgmath::Quaternion::from_angle_y(cgmath::Deg(10.0 * dt.as_secs_f32()));
+        for light in &mut self.point_lights {
+            light.position = (spin * cgmath::Vector3::from(light.position)).into();
+        }
+        self.clusters.update(
+            &self.queue,
+            &self.camera_uniform,
+            &self.projection,
+            (self.config.width, self.config.height),
+            &self.point_lights,
+        );
+        if let Some(view_proj) = self.debug_overlay {
+            self.draw_debug_overlay(view_proj);
+        }
+
+        // Advance the animation and upload the new joint palette
+        if let Some(skeleton) = &self.skinned_model.skeleton {
+            let mut pose = skeleton.rest_pose();
+            if let Some(clip) = self
+                .skinned_model
+                .animations
+                .get(self.animation_player.clip)
+            {
+                self.animation_player.advance(clip, dt.as_secs_f32());
+                clip.sample(self.animation_player.time, &mut pose);
+            }
+            self.joint_palette
+                .update(&self.queue, &skeleton.compute_palette(&pose));
+        }
+
+        // Every morphing column plays the same clip, each one a bit further along than the last.
+        if let Some(clip) = self.morph_model.animations.get(self.morph_player.clip) {
+            self.morph_player.advance(clip, dt.as_secs_f32());
+            for (i, mesh) in self.morph_model.meshes.iter().enumerate() {
+                let Some(morph) = &mesh.morph_targets else {
+                    continue;
+                };
+                let mut weights = vec![0.0; morph.num_targets() as usize];
+                for instance in 0..Self::NUM_MORPH_INSTANCES {
+                    let time = (self.morph_player.time + instance as f32 * 0.5)
+                        .rem_euclid(clip.duration.max(f32::EPSILON));
+                    clip.sample_morph_weights(time, i, &mut weights);
+                    morph.set_weights(&self.queue, instance, &weights);
+                }
+            }
+        }
+    }
+
+    /// Everything but the light markers and the blended extra cubes, in a batch for `pass`. The batch
+    /// sorts the draws to skip redundant binds.
+    fn opaque_batch(&self, pass: MaterialPass) -> DrawBatch<'_> {
+        let mut batch = DrawBatch::for_pass(pass);
+        match &self.culled_cubes {
+            Some(culled_cubes) => {
+                for lod in 0..culled_cubes.num_lods() {
+                    batch.push_model(
+                        &self.material_pipelines,
+                        &self.object_model,
+                        None,
+                        (culled_cubes.instance_buffer(), culled_cubes.lod_offset(lod)),
+                        None,
+                        DrawArgs::Indirect {
+                            buffer: culled_cubes.indirect_buffer(),
+                            offset: culled_cubes.indirect_offset(lod),
+                        },
+                    );
+                }
+            }
+            None => {
+                for (lod, instances) in self.cube_lod_ranges.iter().cloned().enumerate() {
+                    if !instances.is_empty() {
+                        batch.push_model(
+                            &self.material_pipelines,
+                            &self.object_model,
+                            None,
+                            (&self.instance_buffer, 0),
+                            None,
+                            DrawArgs::Instanced { lod, instances },
+                        );
+                    }
+                }
+            }
+        }
+
+        for (material, instances) in self.extra_cube_ranges.iter().cloned() {
+            batch.push_model(
+                &self.material_pipelines,
+                &self.object_model,
+                material.and_then(|m| self.object_model.materials.get(m)),
+                (self.extra_cubes.buffer(), 0),
+                None,
+                DrawArgs::Instanced { lod: 0, instances },
+            );
+        }
+
+        batch.push_model(
+            &self.material_pipelines,
+            &self.skinned_model,
+            None,
+            (&self.skinned_instance_buffer, 0),
+            Some((
+                &self.skinned_material_pipelines,
+                self.joint_palette.bind_group(),
+            )),
+            DrawArgs::Instanced {
+                lod: 0,
+                instances: 0..1,
+            },
+        );
+
+        batch.push_model(
+            &self.material_pipelines,
+            &self.morph_model,
+            None,
+            (&self.morph_instance_buffer, 0),
+            None,
+            DrawArgs::Instanced {
+                lod: 0,
+                instances: 0..Self::NUM_MORPH_INSTANCES,
+            },
+        );
+
+        batch
+    }
+
+    /// Adds the frustum of `view_proj` to the debug lines, along with the bounds of every cube, in
+    /// green if the frustum holds it and red if it culls it, the reach of the point lights and the
+    /// axes of the lamp.
+    fn draw_debug_overlay(&mut self, view_proj: cgmath::Matrix4<f32>) {
+        let lines = &mut self.debug_draw.depth_tested;
+        lines.grid(cgmath::Point3::new(0.0, 0.0, 0.0), 20, 1.0, [0.2, 0.2, 0.2]);
+        let frustum = Frustum::from_matrix(view_proj);
+        let bounds = self.object_model.bounds;
+        for cube in self.scene.model_instances(Self::CUBE_MODEL) {
+            let model = cube.model_matrix();
+            let color = if frustum.is_visible(&bounds, &model) {
+                [0.0, 1.0, 0.0]
+            } else {
+                [1.0, 0.0, 0.0]
+            };
+            lines.aabb(&bounds.aabb, &model, color);
+        }
+        for light in &self.point_lights {
+            lines.sphere(light.position.into(), light.radius, light.color);
+        }
+
+        let on_top = &mut self.debug_draw.on_top;
+        on_top.frustum(&view_proj, [1.0, 1.0, 0.0]);
+        if let Some(lamp) = self.scene.nodes_with(Attachment::Light).next() {
+            on_top.axes(&self.scene.world_matrix(lamp), 1.0);
+        }
     }
 
     pub(crate) fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -414,30 +1466,114 @@ impl<'window> State<'window> {
                 label: Some("Render Encoder"),
             });
 
+        // The deformed vertices need to be ready before the render pass draws them.
+        self.morph_pipeline.deform(&mut encoder, &self.morph_model);
+        if let (Some(cull_pipeline), Some(culled_cubes)) = (&self.cull_pipeline, &self.culled_cubes)
+        {
+            cull_pipeline.cull(&mut encoder, culled_cubes);
+        }
+        // Every pass that lights anything needs the point lights sorted into clusters first
+        self.clusters.assign(&mut encoder);
+        // And the sky, if the sun moved
+        self.sky.generate(&mut encoder);
+
+        let background = wgpu::Color {
+            r: 0.1,
+            g: 0.2,
+            b: 0.3,
+            a: 1.0,
+        };
+        let mut batch_stats = BatchStats::default();
+        // The G-buffer doesn't keep everything the debug views show, so they are always drawn forward
+        let render_path = match self.debug_view.mode {
+            DebugMode::Off => self.render_path,
+            _ => RenderPath::Forward,
+        };
+        let overdraw = self.debug_view.mode == DebugMode::Overdraw;
+        // The deferred path draws everything opaque into the G-buffer and lights it into the HDR
+        // texture, leaving the color and depth for the forward pass to draw on top of.
+        if render_path == RenderPath::Deferred {
+            {
+                let mut render_pass = self
+                    .gbuffer
+                    .begin_geometry_pass(&mut encoder, &self.depth_texture);
+                batch_stats = self.opaque_batch(MaterialPass::GBuffer).submit(
+                    &mut render_pass,
+                    &self.camera_bind_group,
+                    self.scene_bind_group.bind_group(),
+                );
+            }
+            self.ssao.render(&mut encoder, &self.camera_bind_group);
+            self.gbuffer.light(
+                &mut encoder,
+                self.hdr.view(),
+                background,
+                &self.camera_bind_group,
+                &[self.scene_bind_group.bind_group()],
+            );
+        }
+
+        // The forward path lights the opaque meshes as it draws them, so the ambient occlusion
+        // needs their depth before that, from a prepass.
+        let prepass = render_path == RenderPath::Forward && self.ssao.enabled;
+        if prepass {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Depth Prepass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+            self.opaque_batch(MaterialPass::DepthPrepass).submit(
+                &mut render_pass,
+                &self.camera_bind_group,
+                self.scene_bind_group.bind_group(),
+            );
+        }
+        if render_path == RenderPath::Forward {
+            // Switched off, this only clears the occlusion
+            self.ssao.render(&mut encoder, &self.camera_bind_group);
+        }
+
         // This block is needed, since we can't call encoder.finish() until the mutable borrow in the
         // block is dropped. The block tells Rust to drop any variables within it when the code
         // leaves that scope, thus releasing the mutable borrow on  encoder and allowing us to
         // ```finish()``` it.
         {
+            let (color_load, depth_load) = match render_path {
+                RenderPath::Forward if prepass => {
+                    (wgpu::LoadOp::Clear(background), wgpu::LoadOp::Load)
+                }
+                RenderPath::Forward => (wgpu::LoadOp::Clear(background), wgpu::LoadOp::Clear(1.0)),
+                RenderPath::Deferred => (wgpu::LoadOp::Load, wgpu::LoadOp::Load),
+            };
+            // The overdraw adds up from nothing
+            let color_load = if overdraw {
+                wgpu::LoadOp::Clear(wgpu::Color::BLACK)
+            } else {
+                color_load
+            };
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     view: self.hdr.view(),
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
+                        load: color_load,
                         store: wgpu::StoreOp::Store,
                     },
                 })],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: &self.depth_texture.view,
                     depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
+                        load: depth_load,
                         store: wgpu::StoreOp::Store,
                     }),
                     stencil_ops: None,
@@ -447,24 +1583,139 @@ impl<'window> State<'window> {
             });
 
             render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-            render_pass.set_pipeline(&self.light_render_pipeline);
-            render_pass.draw_light_model(
-                &self.object_model,
+            // The sun in the sky stands in for the marker while the sky is on
+            if !self.sky.enabled && !overdraw {
+                render_pass.set_pipeline(&self.light_render_pipeline);
+                render_pass.draw_light_model(
+                    &self.object_model,
+                    &self.camera_bind_group,
+                    self.scene_bind_group.bind_group(),
+                );
+            }
+
+            if render_path == RenderPath::Forward {
+                let pass = if overdraw {
+                    MaterialPass::Overdraw
+                } else {
+                    MaterialPass::Forward
+                };
+                batch_stats = self.opaque_batch(pass).submit(
+                    &mut render_pass,
+                    &self.camera_bind_group,
+                    self.scene_bind_group.bind_group(),
+                );
+            }
+
+            // Last, so that it only covers what is left of the background
+            if self.sky.enabled && !overdraw {
+                self.sky.draw(&mut render_pass, &self.camera_bind_group);
+            }
+        }
+
+        if self.hdr.anti_aliasing() == AntiAliasing::Taa {
+            let mut render_pass = self.velocity.begin_pass(&mut encoder, &self.depth_texture);
+            self.opaque_batch(MaterialPass::Velocity).submit(
+                &mut render_pass,
                 &self.camera_bind_group,
-                &self.light_bind_group,
+                self.scene_bind_group.bind_group(),
             );
+        }
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.draw_model_instanced(
-                &self.object_model,
-                &self.camera_bind_group,
-                &self.light_bind_group,
-                0..self.instances.len() as u32,
+        // Blended surfaces go on top of everything opaque, testing against its depth without
+        // writing their own. Sorted, they are drawn back to front so that each one blends with the
+        // ones behind it. Weighted blended, they are drawn into targets of their own in any order and
+        // then composited over the scene.
+        if !self.transparent_cubes.runs().is_empty() {
+            // The scene picks how, but the overdraw view adds them up in a pass like the sorted one
+            let transparency_mode = if overdraw {
+                TransparencyMode::Sorted
+            } else {
+                self.scene.transparency
+            };
+            let mut batch = match transparency_mode {
+                TransparencyMode::Sorted if overdraw => DrawBatch::for_pass(MaterialPass::Overdraw),
+                TransparencyMode::Sorted => DrawBatch::ordered(),
+                TransparencyMode::WeightedBlended => {
+                    DrawBatch::for_pass(MaterialPass::WeightedBlended)
+                }
+            };
+            for (material, instances) in self.transparent_cubes.runs().iter().cloned() {
+                batch.push_model(
+                    &self.material_pipelines,
+                    &self.object_model,
+                    material.and_then(|m| self.object_model.materials.get(m)),
+                    (self.transparent_cubes.buffer(), 0),
+                    None,
+                    DrawArgs::Instanced { lod: 0, instances },
+                );
+            }
+
+            match transparency_mode {
+                TransparencyMode::Sorted => {
+                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("Transparent Pass"),
+                        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                            view: self.hdr.view(),
+                            resolve_target: None,
+                            ops: wgpu::Operations {
+                                load: wgpu::LoadOp::Load,
+                                store: wgpu::StoreOp::Store,
+                            },
+                        })],
+                        depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                            view: &self.depth_texture.view,
+                            depth_ops: Some(wgpu::Operations {
+                                load: wgpu::LoadOp::Load,
+                                store: wgpu::StoreOp::Store,
+                            }),
+                            stencil_ops: None,
+                        }),
+                        timestamp_writes: None,
+                        occlusion_query_set: None,
+                    });
+                    batch.submit(
+                        &mut render_pass,
+                        &self.camera_bind_group,
+                        self.scene_bind_group.bind_group(),
+                    );
+                }
+                TransparencyMode::WeightedBlended => {
+                    {
+                        let mut render_pass = self
+                            .oit
+                            .begin_accumulate(&mut encoder, &self.depth_texture.view);
+                        batch.submit(
+                            &mut render_pass,
+                            &self.camera_bind_group,
+                            self.scene_bind_group.bind_group(),
+                        );
+                    }
+                    self.oit.composite(&mut encoder, self.hdr.view());
+                }
+            }
+        }
+
+        // The counts change with every culled cube or level of detail, which is too often for info
+        if batch_stats != self.batch_stats {
+            log::debug!(
+                "Draw batching: {} draws with {} state changes",
+                batch_stats.draws,
+                batch_stats.state_changes
             );
+            self.batch_stats = batch_stats;
         }
 
-        // Apply tonemapping
-        self.hdr.process(&mut encoder, &view);
+        self.debug_draw.render(
+            &self.device,
+            &self.queue,
+            &mut encoder,
+            self.hdr.view(),
+            &self.depth_texture.view,
+            &self.camera_bind_group,
+        );
+
+        // Apply bloom, tonemapping and any other post-processing
+        self.hdr.process(&self.device, &mut encoder, &view);
 
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
@@ -22,6 +22,20 @@ impl Texture {
         Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
 
+    /// Creates a 1x1 texture filled with `color`. This is used in place of textures a material
+    /// doesn't provide, such as a glTF material without a normal map.
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [u8; 4],
+        label: &str,
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        let img =
+            image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(device, queue, &img, Some(label), is_normal_map)
+    }
+
     /// # Srgb and normal textures
     /// We've been using `Rgba8UnormSrgb` for all of our textures. The `Srgb` bit specifies that we
     /// will be using standard RGB color space. This is also known as linear color space. Linear color
@@ -195,6 +209,31 @@ impl Texture {
         )
     }
 
+    /// A texture `size` texels across, down and deep, such as a color lookup table.
+    pub fn create_3d_texture(
+        device: &Device,
+        size: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsages,
+        mag_filter: wgpu::FilterMode,
+        label: Option<&str>,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: size,
+        };
+        Self::create_texture(
+            device,
+            label,
+            size,
+            format,
+            usage,
+            wgpu::TextureDimension::D3,
+            mag_filter,
+        )
+    }
+
     fn create_texture(
         device: &wgpu::Device,
         label: Option<&str>,
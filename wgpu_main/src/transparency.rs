@@ -0,0 +1,112 @@
+use crate::instance::InstanceRaw;
+use cgmath::{EuclideanSpace, InnerSpace};
+use std::mem;
+use std::ops::Range;
+
+/// How blended surfaces are drawn.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum TransparencyMode {
+    /// Back to front, blending each surface over the ones behind it; see [SortedInstances].
+    #[default]
+    Sorted,
+    /// In any order, with [crate::oit::WeightedBlendedOit].
+    WeightedBlended,
+}
+
+impl TransparencyMode {
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Self::Sorted => Self::WeightedBlended,
+            Self::WeightedBlended => Self::Sorted,
+        }
+    }
+}
+
+/// # Sorted Instances
+/// Blending isn't commutative: a red pane of glass in front of a blue one looks different from a blue
+/// one in front of a red one. Blended instances therefore have to be drawn from the one furthest
+/// away to the closest one, which changes whenever the camera moves.
+///
+/// Every frame, `SortedInstances` sorts the blended instances by the distance of their origin to the
+/// camera and writes them into a buffer of their own, in that order. Neighbours that use the same
+/// material are merged into runs, each of which can be drawn with a single draw call; drawing the
+/// runs in order draws every instance back to front.
+///
+/// Sorting by origin is only an approximation. Instances that intersect, or a large instance next to
+/// a small one, can still end up in the wrong order.
+pub(crate) struct SortedInstances {
+    label: String,
+    buffer: wgpu::Buffer,
+    capacity: usize,
+    /// Consecutive instances in `buffer` with the same material override, back to front.
+    runs: Vec<(Option<usize>, Range<u32>)>,
+}
+
+impl SortedInstances {
+    pub(crate) fn new(device: &wgpu::Device, label: &str, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            label: label.to_string(),
+            buffer: Self::create_buffer(device, label, capacity),
+            capacity,
+            runs: Vec::new(),
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, label: &str, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (capacity * mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Sorts `instances` back to front as seen from `camera_position` and uploads them, growing the
+    /// buffer if they don't fit.
+    pub(crate) fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera_position: cgmath::Point3<f32>,
+        instances: impl IntoIterator<Item = (Option<usize>, InstanceRaw)>,
+    ) {
+        let mut instances = instances
+            .into_iter()
+            .map(|(material, raw)| {
+                let position = raw.model_matrix().w.truncate();
+                let distance = (position - camera_position.to_vec()).magnitude2();
+                (distance, material, raw)
+            })
+            .collect::<Vec<_>>();
+        instances.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        self.runs.clear();
+        for (i, &(_, material, _)) in instances.iter().enumerate() {
+            let i = i as u32;
+            match self.runs.last_mut() {
+                Some((run_material, range)) if *run_material == material => range.end = i + 1,
+                _ => self.runs.push((material, i..i + 1)),
+            }
+        }
+        if instances.is_empty() {
+            return;
+        }
+
+        if instances.len() > self.capacity {
+            self.capacity = instances.len().next_power_of_two();
+            self.buffer = Self::create_buffer(device, &self.label, self.capacity);
+        }
+        let data = instances.iter().map(|&(_, _, raw)| raw).collect::<Vec<_>>();
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&data));
+    }
+
+    pub(crate) fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// The runs to draw, in order.
+    pub(crate) fn runs(&self) -> &[(Option<usize>, Range<u32>)] {
+        &self.runs
+    }
+}
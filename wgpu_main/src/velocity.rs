@@ -0,0 +1,78 @@
+use crate::texture::Texture;
+
+/// # Velocity Buffer
+/// How far the surface in every pixel moved on the screen since the last frame, in texture
+/// coordinates. Temporal anti-aliasing follows it back to where each pixel was in the last frame.
+///
+/// The main shaders have no vertex attributes left for the model matrices of the last frame, so the
+/// velocity can't be written along with the color. Instead, the opaque meshes are drawn a second
+/// time after the opaque passes, with the `vs_velocity` and `fs_velocity` entry points of their
+/// shaders. The [crate::material_pipelines::MaterialPass::Velocity] pipelines only draw where the
+/// depth is equal to what the opaque passes left behind, so every pixel gets the velocity of the
+/// surface that is actually visible there. Pixels nothing was drawn in keep a velocity of zero, and
+/// [crate::anti_aliasing::Taa] works out the velocity of the sky there from the camera.
+pub(crate) struct VelocityBuffer {
+    texture: Texture,
+}
+
+impl VelocityBuffer {
+    /// Two channels are enough for a direction on the screen, but they need more precision than 8
+    /// bits to tell apart movements smaller than a pixel.
+    pub(crate) const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+    pub(crate) fn new(device: &wgpu::Device, width: u32, height: u32) -> Self {
+        Self {
+            texture: Self::create_texture(device, width, height),
+        }
+    }
+
+    fn create_texture(device: &wgpu::Device, width: u32, height: u32) -> Texture {
+        Texture::create_2d_texture(
+            device,
+            width,
+            height,
+            Self::FORMAT,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            wgpu::FilterMode::Nearest,
+            Some("velocity_texture"),
+        )
+    }
+
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.texture = Self::create_texture(device, width, height);
+    }
+
+    pub(crate) fn texture(&self) -> &Texture {
+        &self.texture
+    }
+
+    /// Begins the velocity pass, clearing the velocity and testing against the `depth` buffer the
+    /// opaque passes wrote.
+    pub(crate) fn begin_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth: &'a Texture,
+    ) -> wgpu::RenderPass<'a> {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Velocity Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        })
+    }
+}